@@ -1,3 +1,6 @@
+use std::path::PathBuf;
+use std::process::Command;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::compile_protos("proto/hello_world.proto")?;
     tonic_build::compile_protos("proto/grpc_example.proto")?;
@@ -10,5 +13,25 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         ],
         &["proto/axon_server"]
     )?;
+    write_descriptor_set("proto/grpc_example.proto", "grpc_example_descriptor_set.bin")?;
     Ok(())
-}
\ No newline at end of file
+}
+
+// Writes the descriptors of the messages in the proto file to OUT_DIR, for the validation of handler registries.
+fn write_descriptor_set(proto: &str, file_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR")?);
+    let status = Command::new(prost_build::protoc())
+        .arg("--include_imports")
+        .arg("-o")
+        .arg(out_dir.join(file_name))
+        .arg("-I")
+        .arg("proto")
+        .arg("-I")
+        .arg(prost_build::protoc_include())
+        .arg(proto)
+        .status()?;
+    if !status.success() {
+        return Err(format!("protoc failed for {}: {}", proto, status).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,181 @@
+use anyhow::{anyhow,Result};
+use bytes::Bytes;
+use tracing::{debug,info};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tonic::Request;
+use tonic::transport::Channel;
+use uuid::Uuid;
+use super::{AxonClients,AxonServerHandle};
+use super::aggregate_migration::{now_millis,read_highest_sequence_nr};
+use super::correlation::EventCorrelation;
+use super::event_processor::{EventProcessorConfig,TokenStore,TrackingConfig,event_processor_with_config};
+use super::handler_registry::{HandlerRegistry,TheHandlerRegistry,empty_handler_registry};
+use super::message_size::explain_status;
+use super::meta_data_stamping::stamp_meta_data;
+use super::read_only::check_writable;
+use super::redaction::log_safe;
+use crate::axon_server::{MetaDataValue,SerializedObject};
+use crate::axon_server::meta_data_value::Data;
+use crate::axon_server::event::Event;
+use crate::axon_server::event::event_store_client::EventStoreClient;
+
+/// Meta-data key that refers the first events of an aggregate to the message identifier of the event that created it.
+pub const CREATED_FROM: &str = "createdFrom";
+
+/// The first events of a new aggregate.
+#[derive(Debug,Clone)]
+pub struct AggregateCreation {
+    pub aggregate_id: String,
+    pub payloads: Vec<SerializedObject>,
+}
+
+pub type AggregateCreator = Arc<dyn Fn(&Event) -> Result<Option<AggregateCreation>> + Send + Sync>;
+
+/// Creates aggregates in response to events of other aggregates or sagas, instead of commands.
+///
+/// For each creation event type, a creator derives the identifier and the first events of the new aggregate from the
+/// event, or returns `None` to skip it. An aggregate that already has events is left alone, so that redelivered
+/// creation events are harmless. The first events carry the message identifier of the creation event under
+/// `createdFrom`, and as their causation id, with the correlation id of the creation event.
+#[derive(Clone)]
+pub struct AggregateFactory {
+    pub aggregate_type: String,
+    creators: HashMap<String,AggregateCreator>,
+}
+
+pub fn create_aggregate_factory(aggregate_type: &str) -> AggregateFactory {
+    AggregateFactory {
+        aggregate_type: aggregate_type.to_string(),
+        creators: HashMap::new(),
+    }
+}
+
+impl AggregateFactory {
+    pub fn with_creation_event(mut self, event_type: &str, creator: impl Fn(&Event) -> Result<Option<AggregateCreation>> + Send + Sync + 'static) -> Self {
+        self.creators.insert(event_type.to_string(), Arc::new(creator));
+        self
+    }
+
+    pub fn creation_event_types(&self) -> Vec<String> {
+        self.creators.keys().cloned().collect()
+    }
+
+    /// Creates the aggregate for the given event, if its type is a creation event type. Returns whether an aggregate
+    /// was created.
+    pub async fn create_from_event(&self, client: &mut EventStoreClient<Channel>, event: &Event) -> Result<bool> {
+        let event_type = event.payload.as_ref().map(|payload| payload.r#type.as_str()).unwrap_or("");
+        let creator = match self.creators.get(event_type) {
+            Some(creator) => creator,
+            None => return Ok(false),
+        };
+        let creation = match creator(event)? {
+            Some(creation) => creation,
+            None => return Ok(false),
+        };
+        let highest_sequence_nr = read_highest_sequence_nr(client, &creation.aggregate_id).await?;
+        if highest_sequence_nr >= 0 {
+            debug!("Aggregate already exists: {:?}: created from: {:?}", creation.aggregate_id, event.message_identifier);
+            return Ok(false);
+        }
+
+        let timestamp = now_millis()?;
+        let mut meta_data = EventCorrelation::from_event(event).follow_up_meta_data();
+        meta_data.insert(CREATED_FROM.to_string(), MetaDataValue {
+            data: Some(Data::TextValue(event.message_identifier.clone())),
+        });
+        let meta_data = stamp_meta_data(None, meta_data);
+        let events: Vec<Event> = creation.payloads.iter().enumerate().map(|(index, payload)| Event {
+            message_identifier: format!("{:?}", Uuid::new_v4().to_simple()),
+            timestamp,
+            aggregate_identifier: creation.aggregate_id.clone(),
+            aggregate_sequence_number: index as i64,
+            aggregate_type: self.aggregate_type.clone(),
+            payload: Some(payload.clone()),
+            meta_data: meta_data.clone(),
+            snapshot: false,
+        }).collect();
+        if events.is_empty() {
+            return Err(anyhow!("No events to create aggregate: {:?}", creation.aggregate_id));
+        }
+        debug!("Create aggregate: {:?}", log_safe(&events));
+        check_writable("append events")?;
+        client.append_event(Request::new(futures_util::stream::iter(events))).await.map_err(explain_status)?;
+        info!("Created aggregate: {:?}: {:?}: from: {:?}", self.aggregate_type, creation.aggregate_id, event.message_identifier);
+        Ok(true)
+    }
+}
+
+/// Query model of the event processor of an aggregate factory: the envelope of the current event, with the tracking
+/// token in the given token store.
+#[derive(Clone)]
+struct FactoryContext<T> {
+    token_store: T,
+    factory: Arc<AggregateFactory>,
+    client: EventStoreClient<Channel>,
+    event: Option<Event>,
+}
+
+#[tonic::async_trait]
+impl<T: TokenStore + Send + Sync + Clone> TokenStore for FactoryContext<T> {
+    async fn store_token(&self, token: i64) -> Result<()> {
+        self.token_store.store_token(token).await
+    }
+
+    async fn retrieve_token(&self) -> Result<Option<i64>> {
+        self.token_store.retrieve_token().await
+    }
+
+    async fn store_token_with_window(&self, token: i64, window: &[String]) -> Result<()> {
+        self.token_store.store_token_with_window(token, window).await
+    }
+
+    async fn retrieve_deduplication_window(&self) -> Result<Vec<String>> {
+        self.token_store.retrieve_deduplication_window().await
+    }
+
+    fn for_tracking(&self, tracking: &TrackingConfig) -> Self {
+        let mut context = self.clone();
+        context.token_store = self.token_store.for_tracking(tracking);
+        context
+    }
+
+    fn for_event(&self, event: &Event, _token: i64) -> Self {
+        let mut context = self.clone();
+        context.event = Some(event.clone());
+        context
+    }
+}
+
+impl<T> FactoryContext<T> {
+    async fn create(mut self) -> Result<()> {
+        let event = self.event.take().ok_or_else(|| anyhow!("No creation event"))?;
+        self.factory.create_from_event(&mut self.client, &event).await?;
+        Ok(())
+    }
+}
+
+/// Runs an event processor that creates aggregates with the given factory. The token store keeps the progress of the
+/// processor, under the tracking configuration of `config`.
+pub async fn aggregate_factory_processor<T: TokenStore + Send + Sync + Clone + 'static>(
+    axon_server_handle: AxonServerHandle,
+    factory: AggregateFactory,
+    token_store: T,
+    config: EventProcessorConfig
+) -> Result<()> {
+    let context = FactoryContext {
+        token_store,
+        client: axon_server_handle.event_store_client(),
+        factory: Arc::new(factory),
+        event: None,
+    };
+    let mut event_handler_registry: TheHandlerRegistry<FactoryContext<T>,Option<FactoryContext<T>>> = empty_handler_registry();
+    for event_type in context.factory.creation_event_types() {
+        event_handler_registry.insert(
+            &event_type,
+            &|data: Bytes| Ok(data),
+            &|_, context: FactoryContext<T>| Box::pin(context.create())
+        )?;
+    }
+    event_processor_with_config(axon_server_handle, context, event_handler_registry, config).await
+}
@@ -0,0 +1,90 @@
+use anyhow::{anyhow,Result};
+use tracing::debug;
+use prost::Message;
+use serde::Serialize;
+use std::sync::Arc;
+use super::{AxonServerHandle,VecU8Message,axon_serialize};
+use super::command_worker::{AggregateContext,AggregateDefinition};
+use super::query_processor::QueryResult;
+use super::time_travel::{AsOf,project_aggregate_as_of};
+
+/// Name of the query that asks for the current state of an aggregate.
+pub const INSPECT_AGGREGATE: &str = "InspectAggregate";
+
+/// Name of the response to an `InspectAggregate` query.
+pub const AGGREGATE_STATE: &str = "AggregateState";
+
+/// Query for the state of an aggregate, optionally as of a sequence number.
+#[derive(Clone,PartialEq,Message)]
+pub struct InspectAggregate {
+    #[prost(string, tag = "1")]
+    pub aggregate_type: String,
+    #[prost(string, tag = "2")]
+    pub aggregate_id: String,
+    #[prost(int64, optional, tag = "3")]
+    pub as_of_sequence: Option<i64>,
+}
+
+/// The projection of an aggregate, serialized as JSON.
+#[derive(Clone,PartialEq,Message)]
+pub struct AggregateState {
+    #[prost(string, tag = "1")]
+    pub aggregate_type: String,
+    #[prost(string, tag = "2")]
+    pub aggregate_id: String,
+    #[prost(string, tag = "3")]
+    pub json: String,
+}
+
+/// Sources an aggregate and returns its projection as JSON.
+#[tonic::async_trait]
+pub trait AggregateInspector: Send + Sync {
+    async fn inspect(&self, aggregate_id: &str, as_of: AsOf) -> Result<String>;
+}
+
+/// Inspects the aggregates of one aggregate definition. The projection has to implement `serde::Serialize`.
+pub struct AggregateInspection<P: VecU8Message + Send + Clone + 'static> {
+    axon_server_handle: AxonServerHandle,
+    aggregate_definition: AggregateDefinition<P>,
+}
+
+pub fn create_aggregate_inspection<P: VecU8Message + Send + Clone>(
+    axon_server_handle: AxonServerHandle,
+    aggregate_definition: AggregateDefinition<P>
+) -> AggregateInspection<P> {
+    AggregateInspection {
+        axon_server_handle,
+        aggregate_definition,
+    }
+}
+
+#[tonic::async_trait]
+impl<P: VecU8Message + AggregateContext + Serialize + Send + Sync + Clone + std::fmt::Debug + 'static> AggregateInspector for AggregateInspection<P> {
+    async fn inspect(&self, aggregate_id: &str, as_of: AsOf) -> Result<String> {
+        let projection = project_aggregate_as_of(&self.axon_server_handle, &self.aggregate_definition, aggregate_id, as_of).await?;
+        Ok(serde_json::to_string(&projection)?)
+    }
+}
+
+/// Gives the `InspectAggregate` query handler access to the inspectors, by aggregate type (the projection name).
+pub trait InspectionContext {
+    fn aggregate_inspector(&self, aggregate_type: &str) -> Option<Arc<dyn AggregateInspector>>;
+}
+
+/// Opt-in query handler that lets support engineers inspect the live state of aggregates, e.g.:
+/// `registry.insert_with_output(INSPECT_AGGREGATE, &InspectAggregate::decode, &(|q, c| Box::pin(handle_inspect_aggregate(q, c))))`.
+pub async fn handle_inspect_aggregate<Q: InspectionContext>(query: InspectAggregate, context: Q) -> Result<Option<QueryResult>> {
+    debug!("Inspect aggregate: {:?}", query);
+    let inspector = context.aggregate_inspector(&query.aggregate_type)
+        .ok_or_else(|| anyhow!("No inspector for aggregate type: {:?}", query.aggregate_type))?;
+    let as_of = AsOf::Sequence(query.as_of_sequence.unwrap_or(i64::MAX));
+    let json = inspector.inspect(&query.aggregate_id, as_of).await?;
+    let response = AggregateState {
+        aggregate_type: query.aggregate_type,
+        aggregate_id: query.aggregate_id,
+        json,
+    };
+    Ok(Some(QueryResult {
+        payload: Some(axon_serialize(AGGREGATE_STATE, &response)?),
+    }))
+}
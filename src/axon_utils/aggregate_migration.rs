@@ -0,0 +1,140 @@
+use anyhow::{anyhow,Result};
+use tracing::{debug,info};
+use std::collections::HashMap;
+use tonic::Request;
+use tonic::transport::Channel;
+use uuid::Uuid;
+use super::{AxonClients,AxonServerHandle};
+use super::event_query::query_events_from_client;
+use super::message_size::explain_status;
+use super::read_only::check_writable;
+use super::redaction::log_safe;
+use crate::axon_server::{MetaDataValue,SerializedObject};
+use crate::axon_server::meta_data_value::Data;
+use crate::axon_server::event::{Event,ReadHighestSequenceNrRequest};
+use crate::axon_server::event::event_store_client::EventStoreClient;
+
+/// Meta-data key that refers migrated events to the event they were derived from (`<aggregate id>:<sequence nr>`).
+pub const MIGRATED_FROM: &str = "migratedFrom";
+
+/// Describes how the event stream of one aggregate is copied to a new aggregate.
+///
+/// When a tombstone is given, it is appended to the source aggregate after the copy succeeded, so that command
+/// handlers can refuse further commands for the old identity.
+#[derive(Debug,Clone)]
+pub struct AggregateMigration {
+    pub source_aggregate_id: String,
+    pub target_aggregate_id: String,
+    pub target_aggregate_type: String,
+    pub tombstone: Option<SerializedObject>,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct MigrationReport {
+    pub events_read: usize,
+    pub events_written: usize,
+    pub tombstoned: bool,
+}
+
+pub fn create_aggregate_migration(source_aggregate_id: &str, target_aggregate_id: &str, target_aggregate_type: &str) -> AggregateMigration {
+    AggregateMigration {
+        source_aggregate_id: source_aggregate_id.to_string(),
+        target_aggregate_id: target_aggregate_id.to_string(),
+        target_aggregate_type: target_aggregate_type.to_string(),
+        tombstone: None,
+    }
+}
+
+impl AggregateMigration {
+    pub fn with_tombstone(mut self, tombstone: SerializedObject) -> Self {
+        self.tombstone = Some(tombstone);
+        self
+    }
+}
+
+/// Copies the events of the source aggregate to the target aggregate. The transform maps each source event to zero
+/// or more payloads for the target aggregate, e.g., to rename event types or to split events. Migrated events keep the
+/// timestamp and meta-data of the original event.
+///
+/// The target aggregate must not have any events yet. All migrated events are appended in a single transaction.
+pub async fn migrate_aggregate<F>(axon_server_handle: &AxonServerHandle, migration: &AggregateMigration, transform: F) -> Result<MigrationReport>
+where F: Fn(&Event) -> Result<Vec<SerializedObject>>
+{
+    let mut client = axon_server_handle.event_store_client();
+    migrate_aggregate_with_client(&mut client, migration, transform).await
+}
+
+pub async fn migrate_aggregate_with_client<F>(client: &mut EventStoreClient<Channel>, migration: &AggregateMigration, transform: F) -> Result<MigrationReport>
+where F: Fn(&Event) -> Result<Vec<SerializedObject>>
+{
+    info!("Migrate aggregate: {:?} -> {:?}", migration.source_aggregate_id, migration.target_aggregate_id);
+    check_writable("migrate aggregate")?;
+    let target_highest = read_highest_sequence_nr(client, &migration.target_aggregate_id).await?;
+    if target_highest >= 0 {
+        return Err(anyhow!("Target aggregate already has events: {:?}: {:?}", migration.target_aggregate_id, target_highest));
+    }
+
+    let source_events = query_events_from_client(client, &migration.source_aggregate_id).await?;
+    let mut target_events = Vec::new();
+    for event in &source_events {
+        let mut meta_data = event.meta_data.clone();
+        meta_data.insert(MIGRATED_FROM.to_string(), MetaDataValue {
+            data: Some(Data::TextValue(format!("{}:{}", event.aggregate_identifier, event.aggregate_sequence_number))),
+        });
+        for payload in transform(event)? {
+            target_events.push(Event {
+                message_identifier: format!("{:?}", Uuid::new_v4().to_simple()),
+                timestamp: event.timestamp,
+                aggregate_identifier: migration.target_aggregate_id.clone(),
+                aggregate_sequence_number: target_events.len() as i64,
+                aggregate_type: migration.target_aggregate_type.clone(),
+                payload: Some(payload),
+                meta_data: meta_data.clone(),
+                snapshot: false,
+            });
+        }
+    }
+    debug!("Migrated events: {:?}", log_safe(&target_events));
+
+    let events_written = target_events.len();
+    if events_written > 0 {
+        client.append_event(Request::new(futures_util::stream::iter(target_events))).await.map_err(explain_status)?;
+    }
+
+    let mut tombstoned = false;
+    if let (Some(tombstone), Some(last_event)) = (&migration.tombstone, source_events.last()) {
+        let tombstone_event = Event {
+            message_identifier: format!("{:?}", Uuid::new_v4().to_simple()),
+            timestamp: now_millis()?,
+            aggregate_identifier: migration.source_aggregate_id.clone(),
+            aggregate_sequence_number: last_event.aggregate_sequence_number + 1,
+            aggregate_type: last_event.aggregate_type.clone(),
+            payload: Some(tombstone.clone()),
+            meta_data: HashMap::new(),
+            snapshot: false,
+        };
+        client.append_event(Request::new(futures_util::stream::iter(vec![tombstone_event]))).await.map_err(explain_status)?;
+        tombstoned = true;
+    }
+
+    info!("Migrated aggregate: {:?} -> {:?}: events: {:?} -> {:?}", migration.source_aggregate_id, migration.target_aggregate_id, source_events.len(), events_written);
+    Ok(MigrationReport {
+        events_read: source_events.len(),
+        events_written,
+        tombstoned,
+    })
+}
+
+pub(crate) async fn read_highest_sequence_nr(client: &mut EventStoreClient<Channel>, aggregate_id: &str) -> Result<i64> {
+    let request = ReadHighestSequenceNrRequest {
+        aggregate_id: aggregate_id.to_string(),
+        from_sequence_nr: 0,
+    };
+    let response = client.read_highest_sequence_nr(request).await?.into_inner();
+    Ok(response.to_sequence_nr)
+}
+
+pub(crate) fn now_millis() -> Result<i64> {
+    let now = std::time::SystemTime::now();
+    Ok(now.duration_since(std::time::UNIX_EPOCH)?.as_millis() as i64)
+}
@@ -0,0 +1,71 @@
+use anyhow::Result;
+use tracing::debug;
+use std::fmt::{Display,Formatter};
+use std::future::Future;
+use std::time::{Duration,Instant};
+use tokio::time::delay_for;
+use super::{CommandSink,VecU8Message};
+use crate::axon_server::SerializedObject;
+
+/// How long and how often `send_command_and_await_projection` checks the query model.
+#[derive(Debug,Clone)]
+pub struct AwaitProjection {
+    pub timeout: Duration,
+    pub poll_interval: Duration,
+}
+
+impl Default for AwaitProjection {
+    fn default() -> Self {
+        AwaitProjection {
+            timeout: Duration::from_secs(10),
+            poll_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Error for a command that was handled, while the query model did not reflect it before the timeout.
+#[derive(Debug,Clone)]
+pub struct ProjectionTimeoutError {
+    pub command_type: String,
+    pub timeout: Duration,
+}
+
+impl Display for ProjectionTimeoutError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Projection did not catch up with command {:?} within {:?}", self.command_type, self.timeout)
+    }
+}
+
+impl std::error::Error for ProjectionTimeoutError {}
+
+/// Sends a command and waits until the predicate confirms that the query model reflects it. The predicate receives the
+/// response to the command and is called until it returns `true`, or until the timeout expires. In the latter case a
+/// `ProjectionTimeoutError` is returned, even though the command itself succeeded.
+pub async fn send_command_and_await_projection<S, P, F>(
+    command_sink: &S,
+    command_type: &str,
+    command: Box<&(dyn VecU8Message + Sync)>,
+    settings: &AwaitProjection,
+    mut predicate: P
+) -> Result<Option<SerializedObject>>
+where S: CommandSink + Sync,
+      P: FnMut(Option<SerializedObject>) -> F,
+      F: Future<Output = Result<bool>>
+{
+    let response = command_sink.send_command(command_type, command).await?;
+    let deadline = Instant::now() + settings.timeout;
+    loop {
+        if predicate(response.clone()).await? {
+            return Ok(response);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(ProjectionTimeoutError {
+                command_type: command_type.to_string(),
+                timeout: settings.timeout,
+            }.into());
+        }
+        debug!("Await projection: {:?}: not yet", command_type);
+        delay_for(settings.poll_interval.min(deadline - now)).await;
+    }
+}
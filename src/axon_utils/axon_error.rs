@@ -0,0 +1,115 @@
+use tonic::{Code,Status};
+use super::business_rules::BusinessRuleError;
+
+pub type AxonResult<T> = std::result::Result<T,AxonError>;
+
+/// Error of the client API of AxonServer (connecting, sending commands and queries), by kind, so that callers can
+/// match on the kind of failure instead of on the message.
+///
+/// `CommandSink::send_command`, `QuerySink::send_query` and the other functions of `axon_utils` return `anyhow` errors;
+/// `CommandSink::try_send_command` and `QuerySink::try_send_query` return an `AxonError`. `AxonError` converts both
+/// ways: an `AxonError` is an `std::error::Error`, so `?` turns it into an `anyhow::Error`, and `AxonError::from`
+/// recovers the kind of an `anyhow::Error` from the error that it wraps. Errors of other kinds, e.g., a
+/// `ReadOnlyError`, are kept in `Other`.
+#[derive(Debug,thiserror::Error)]
+pub enum AxonError {
+    /// AxonServer cannot be reached, or is unavailable.
+    #[error("Cannot reach AxonServer: {0}")]
+    Connection(String),
+    /// A message cannot be encoded, or a payload cannot be decoded.
+    #[error("Serialization error: {0}")]
+    Serialization(String),
+    /// There is no handler for the command, query or event with the given name.
+    #[error("No handler for: {0:?}")]
+    MissingHandler(String),
+    /// AxonServer refused the request.
+    #[error("AxonServer refused the request: {0}")]
+    Status(#[source] Box<Status>),
+    /// AxonServer, or the handler of the request, responded with an error that is not a business rule violation.
+    #[error("Error response: {error_code}: {message}")]
+    ErrorResponse { error_code: String, message: String },
+    /// The handler of the command rejected it.
+    #[error("Business rule violation: {0}")]
+    Business(#[source] BusinessRuleError),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<Status> for AxonError {
+    fn from(status: Status) -> Self {
+        match status.code() {
+            Code::Unavailable => AxonError::Connection(status.message().to_string()),
+            _ => AxonError::Status(Box::new(status)),
+        }
+    }
+}
+
+impl From<tonic::transport::Error> for AxonError {
+    fn from(error: tonic::transport::Error) -> Self {
+        AxonError::Connection(error.to_string())
+    }
+}
+
+impl From<prost::EncodeError> for AxonError {
+    fn from(error: prost::EncodeError) -> Self {
+        AxonError::Serialization(error.to_string())
+    }
+}
+
+impl From<prost::DecodeError> for AxonError {
+    fn from(error: prost::DecodeError) -> Self {
+        AxonError::Serialization(error.to_string())
+    }
+}
+
+impl From<BusinessRuleError> for AxonError {
+    fn from(error: BusinessRuleError) -> Self {
+        AxonError::Business(error)
+    }
+}
+
+impl From<anyhow::Error> for AxonError {
+    fn from(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<AxonError>() {
+            Ok(axon_error) => return axon_error,
+            Err(error) => error,
+        };
+        let error = match error.downcast::<BusinessRuleError>() {
+            Ok(business_rule_error) => return AxonError::Business(business_rule_error),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<Status>() {
+            Ok(status) => return AxonError::from(status),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<tonic::transport::Error>() {
+            Ok(transport_error) => return AxonError::from(transport_error),
+            Err(error) => error,
+        };
+        if error.is::<prost::EncodeError>() || error.is::<prost::DecodeError>() {
+            return AxonError::Serialization(error.to_string());
+        }
+        AxonError::Other(error)
+    }
+}
+
+impl AxonError {
+    // Unwraps the errors that the `anyhow` API returned before there was an `AxonError`, e.g., a `BusinessRuleError`,
+    // so that callers that downcast to them keep working.
+    pub(crate) fn into_anyhow(self) -> anyhow::Error {
+        match self {
+            AxonError::Status(status) => anyhow::Error::from(*status),
+            AxonError::Business(business_rule_error) => anyhow::Error::from(business_rule_error),
+            AxonError::Other(error) => error,
+            axon_error => anyhow::Error::from(axon_error),
+        }
+    }
+
+    /// Returns the error with the given type that caused this error, if any, e.g., a `ReadOnlyError` in `Other`.
+    pub fn downcast_ref<E: std::fmt::Display + std::fmt::Debug + Send + Sync + 'static>(&self) -> Option<&E> {
+        match self {
+            AxonError::Other(error) => error.downcast_ref::<E>(),
+            _ => None,
+        }
+    }
+}
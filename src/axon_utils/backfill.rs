@@ -0,0 +1,276 @@
+use anyhow::{Result,anyhow};
+use futures_util::stream::{self,StreamExt};
+use tracing::{debug,info,warn};
+use std::collections::HashMap;
+use std::path::{Path,PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt,BufReader,Lines};
+use super::AxonServerHandle;
+use super::command_submit::{build_command,dispatch_command};
+use super::event_processor::TokenStore;
+use crate::axon_server::SerializedObject;
+
+/// A row of an external source, by column name.
+pub type BackfillRow = HashMap<String,String>;
+
+/// Source of the rows of a backfill, e.g., a CSV file or a PostgreSQL query. Rows are numbered from zero, and the
+/// same row number must give the same row in every run, so that an interrupted backfill can resume.
+#[tonic::async_trait]
+pub trait BackfillSource {
+    /// Returns at most `limit` rows, starting at the row with the given number. Returns fewer rows at the end.
+    async fn read_rows(&mut self, offset: u64, limit: usize) -> Result<Vec<BackfillRow>>;
+}
+
+/// Settings of a backfill. Each batch of rows is converted to commands that are sent with at most `concurrency`
+/// commands in flight. When more than `max_failures` commands fail in total, the backfill stops.
+#[derive(Debug,Clone)]
+pub struct BackfillJob {
+    pub batch_size: usize,
+    pub concurrency: usize,
+    pub max_failures: usize,
+}
+
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct BackfillReport {
+    pub rows_read: usize,
+    pub rows_skipped: usize,
+    pub commands_sent: usize,
+    pub commands_failed: usize,
+    pub checkpoint: Option<i64>,
+}
+
+pub fn create_backfill_job() -> BackfillJob {
+    BackfillJob {
+        batch_size: 100,
+        concurrency: 4,
+        max_failures: 0,
+    }
+}
+
+impl BackfillJob {
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    pub fn with_max_failures(mut self, max_failures: usize) -> Self {
+        self.max_failures = max_failures;
+        self
+    }
+}
+
+/// Migrates legacy data into the event store: reads the rows of the source, converts each row into a command with
+/// the mapping, and sends the commands to AxonServer. The mapping returns `None` for rows that are to be skipped. Build
+/// the command with `axon_serialize`.
+///
+/// The checkpoint store keeps the number of the last row of the last batch that was sent completely, as its token, so
+/// that a backfill that is started again resumes after it. Use a token store of its own, e.g., a `FileTokenStore`. The
+/// checkpoint is only advanced after all commands of a batch were answered, so an interrupted backfill sends some
+/// commands again; the command handlers should reject those, e.g., because the aggregate already exists.
+pub async fn run_backfill<S, T, F>(
+    axon_server_handle: &AxonServerHandle,
+    source: &mut S,
+    job: &BackfillJob,
+    checkpoint_store: &T,
+    mapping: F
+) -> Result<BackfillReport>
+where S: BackfillSource + Send, T: TokenStore, F: Fn(&BackfillRow) -> Result<Option<SerializedObject>>
+{
+    let mut report = BackfillReport::default();
+    let mut offset = (checkpoint_store.retrieve_token().await?.unwrap_or(-1) + 1) as u64;
+    info!("Backfill: start: row: {:?}", offset);
+    loop {
+        let rows = source.read_rows(offset, job.batch_size.max(1)).await?;
+        if rows.is_empty() {
+            break;
+        }
+        report.rows_read += rows.len();
+        let mut commands = Vec::new();
+        for row in &rows {
+            match mapping(row)? {
+                Some(message) => commands.push(build_command(axon_server_handle, &message, HashMap::new())),
+                None => report.rows_skipped += 1,
+            }
+        }
+        let results: Vec<_> = stream::iter(commands)
+            .map(|command| dispatch_command(axon_server_handle, command))
+            .buffer_unordered(job.concurrency.max(1))
+            .collect()
+            .await;
+        for result in results {
+            match result {
+                Ok(_) => report.commands_sent += 1,
+                Err(e) => {
+                    warn!("Backfill: command failed: {:?}", e);
+                    report.commands_failed += 1;
+                }
+            }
+        }
+        if report.commands_failed > job.max_failures {
+            return Err(anyhow!("Backfill stopped after {:?} failed commands: {:?}", report.commands_failed, report));
+        }
+        offset += rows.len() as u64;
+        let checkpoint = offset as i64 - 1;
+        checkpoint_store.store_token(checkpoint).await?;
+        report.checkpoint = Some(checkpoint);
+        debug!("Backfill: checkpoint: {:?}", checkpoint);
+    }
+    info!("Backfill: done: {:?}", report);
+    Ok(report)
+}
+
+/// Reads the rows of a CSV file with a header line. Fields may be quoted with double quotes, to contain commas,
+/// quotes (doubled) and line breaks.
+pub struct CsvBackfillSource {
+    path: PathBuf,
+    header: Vec<String>,
+    lines: Option<Lines<BufReader<File>>>,
+    position: u64,
+}
+
+pub fn create_csv_backfill_source<P: AsRef<Path>>(path: P) -> CsvBackfillSource {
+    CsvBackfillSource {
+        path: path.as_ref().to_path_buf(),
+        header: Vec::new(),
+        lines: None,
+        position: 0,
+    }
+}
+
+impl CsvBackfillSource {
+    async fn open(&mut self) -> Result<()> {
+        let mut lines = BufReader::new(File::open(&self.path).await?).lines();
+        self.header = read_record(&mut lines).await?.ok_or_else(|| anyhow!("CSV file has no header: {:?}", self.path))?;
+        self.lines = Some(lines);
+        self.position = 0;
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl BackfillSource for CsvBackfillSource {
+    async fn read_rows(&mut self, offset: u64, limit: usize) -> Result<Vec<BackfillRow>> {
+        if self.lines.is_none() || self.position > offset {
+            self.open().await?;
+        }
+        let lines = match self.lines.as_mut() {
+            Some(lines) => lines,
+            None => return Err(anyhow!("CSV file is not open: {:?}", self.path)),
+        };
+        let mut rows = Vec::new();
+        while rows.len() < limit {
+            let fields = match read_record(lines).await? {
+                Some(fields) => fields,
+                None => break,
+            };
+            self.position += 1;
+            if self.position <= offset {
+                continue;
+            }
+            rows.push(self.header.iter().cloned().zip(fields).collect());
+        }
+        Ok(rows)
+    }
+}
+
+// Reads lines until the quotes of the record are balanced, and splits the record into fields.
+async fn read_record(lines: &mut Lines<BufReader<File>>) -> Result<Option<Vec<String>>> {
+    let mut record = String::new();
+    while let Some(line) = lines.next_line().await? {
+        if !record.is_empty() {
+            record.push('\n');
+        }
+        record.push_str(line.trim_end_matches('\r'));
+        if let Some(fields) = split_csv_record(&record) {
+            if record.is_empty() {
+                continue;
+            }
+            return Ok(Some(fields));
+        }
+    }
+    if record.is_empty() {
+        Ok(None)
+    } else {
+        Err(anyhow!("Unterminated quoted field in CSV record: {:?}", record))
+    }
+}
+
+fn split_csv_record(record: &str) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = record.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            (c, _) => field.push(c),
+        }
+    }
+    if quoted {
+        return None;
+    }
+    fields.push(field);
+    Some(fields)
+}
+
+#[cfg(feature = "postgres")]
+pub use postgres_source::{PostgresBackfillSource,create_postgres_backfill_source};
+
+#[cfg(feature = "postgres")]
+mod postgres_source {
+    use anyhow::Result;
+    use serde_json::Value;
+    use std::sync::Arc;
+    use tokio_postgres::Client;
+    use super::{BackfillRow,BackfillSource};
+
+    /// Reads the rows of a PostgreSQL query, in pages. The query must order the rows, e.g., by primary key, so that the
+    /// row numbers are stable. It is used as is in SQL statements, so it must come from configuration, never from
+    /// input. Values are converted to text; NULL values are left out of the row.
+    pub struct PostgresBackfillSource {
+        client: Arc<Client>,
+        query: String,
+    }
+
+    pub fn create_postgres_backfill_source(client: Arc<Client>, query: &str) -> PostgresBackfillSource {
+        PostgresBackfillSource {
+            client,
+            query: query.to_string(),
+        }
+    }
+
+    #[tonic::async_trait]
+    impl BackfillSource for PostgresBackfillSource {
+        async fn read_rows(&mut self, offset: u64, limit: usize) -> Result<Vec<BackfillRow>> {
+            let statement = format!("SELECT row_to_json(backfill)::TEXT FROM ({}) AS backfill LIMIT $1 OFFSET $2", self.query);
+            let rows = self.client.query(statement.as_str(), &[&(limit as i64), &(offset as i64)]).await?;
+            let mut result = Vec::new();
+            for row in rows {
+                let json: String = row.get(0);
+                let columns = match serde_json::from_str(&json)? {
+                    Value::Object(columns) => columns,
+                    _ => continue,
+                };
+                let row: BackfillRow = columns.into_iter()
+                    .filter_map(|(name, value)| match value {
+                        Value::Null => None,
+                        Value::String(text) => Some((name, text)),
+                        value => Some((name, value.to_string())),
+                    })
+                    .collect();
+                result.push(row);
+            }
+            Ok(result)
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use anyhow::Result;
+use std::fmt::{Display,Formatter};
+
+/// Error code for commands that target an aggregate that was deleted.
+pub const DELETED_ERROR_CODE: &str = "DELETED";
+
+/// Error for a command that violates a business rule. The command worker passes the error code on to the sender of
+/// the command, instead of the generic `ERROR`.
+#[derive(Debug,Clone)]
+pub struct BusinessRuleError {
+    pub error_code: String,
+    pub message: String,
+}
+
+impl Display for BusinessRuleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for BusinessRuleError {}
+
+/// Returns a `BusinessRuleError` with the given error code and message unless the condition holds, e.g.,
+/// `require(amount > 0, "INVALID_AMOUNT", "Amount must be positive")?`.
+pub fn require(condition: bool, error_code: &str, message: &str) -> Result<()> {
+    if condition {
+        return Ok(());
+    }
+    Err(BusinessRuleError {
+        error_code: error_code.to_string(),
+        message: message.to_string(),
+    }.into())
+}
+
+/// Implemented by projections of aggregates that can be deleted.
+pub trait Deletable {
+    fn is_deleted(&self) -> bool;
+}
+
+/// Rejects the command with error code `DELETED` if the aggregate was deleted.
+pub fn reject_if_deleted<P: Deletable>(projection: &P) -> Result<()> {
+    require(!projection.is_deleted(), DELETED_ERROR_CODE, "Aggregate was deleted")
+}
@@ -0,0 +1,68 @@
+use anyhow::Result;
+use tracing::info;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tonic::transport::Channel;
+use crate::axon_server::event::GetLastTokenRequest;
+use crate::axon_server::event::event_store_client::EventStoreClient;
+
+/// Tells whether an event processor has caught up with the head of the event store, e.g., to delay marking a service
+/// as ready until its projections are warm.
+///
+/// The processor is caught up when the token of the last event that it processed is within `threshold` events of the
+/// head of the event store. Once caught up, the signal stays caught up. All clones share the same state.
+#[derive(Debug,Clone)]
+pub struct CatchUpSignal {
+    pub threshold: i64,
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+pub fn create_catch_up_signal(threshold: i64) -> CatchUpSignal {
+    let (sender, receiver) = watch::channel(false);
+    CatchUpSignal {
+        threshold,
+        sender: Arc::new(sender),
+        receiver,
+    }
+}
+
+impl CatchUpSignal {
+    pub fn is_caught_up(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Returns when the processor has caught up.
+    pub async fn wait_until_caught_up(&self) {
+        let mut receiver = self.receiver.clone();
+        while !*receiver.borrow() {
+            if receiver.recv().await.is_none() {
+                return;
+            }
+        }
+    }
+
+    /// Reports the processor as caught up if the token is near the head. The head is the last known head, and it is
+    /// read again from the event store (which may have grown meanwhile) before the processor is reported as caught up.
+    pub(crate) async fn check(&self, client: &mut EventStoreClient<Channel>, processor_name: &str, token: i64, head: &mut i64) -> Result<()> {
+        if self.is_caught_up() || !self.is_near(token, *head) {
+            return Ok(());
+        }
+        *head = read_head_token(client).await?;
+        if self.is_near(token, *head) {
+            info!("Event processor caught up: {:?}: token: {:?}: head: {:?}", processor_name, token, head);
+            self.sender.broadcast(true).ok();
+        }
+        Ok(())
+    }
+
+    fn is_near(&self, token: i64, head: i64) -> bool {
+        token >= head - self.threshold
+    }
+}
+
+/// Returns the token of the last event in the event store.
+async fn read_head_token(client: &mut EventStoreClient<Channel>) -> Result<i64> {
+    let response = client.get_last_token(GetLastTokenRequest {}).await?.into_inner();
+    Ok(response.token)
+}
@@ -0,0 +1,96 @@
+use anyhow::{anyhow,Result};
+use tracing::debug;
+use sha2::{Digest,Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::axon_server::{MetaDataValue,SerializedObject};
+use crate::axon_server::command::Command;
+use crate::axon_server::event::Event;
+use crate::axon_server::meta_data_value::Data;
+use crate::object_storage_utils::ObjectStore;
+
+/// Meta-data key that holds the key of the object that contains the data of the payload of a message.
+pub const CLAIM_CHECK: &str = "claimCheck";
+
+/// Keeps large payloads out of the event store.
+///
+/// The data of a payload that is larger than `threshold` bytes is stored in the object store under
+/// `<key_prefix>/<hash of the data>`. The message keeps the type and revision of the payload, but its data is empty and
+/// the meta-data refers to the stored object. Resolving the message restores the data.
+#[derive(Debug,Clone)]
+pub struct ClaimCheck {
+    pub store: Arc<dyn ObjectStore>,
+    pub threshold: usize,
+    pub key_prefix: String,
+}
+
+pub fn create_claim_check(store: Arc<dyn ObjectStore>, threshold: usize) -> ClaimCheck {
+    ClaimCheck {
+        store,
+        threshold,
+        key_prefix: "claim-check".to_string(),
+    }
+}
+
+impl ClaimCheck {
+    pub fn with_key_prefix(mut self, key_prefix: &str) -> Self {
+        self.key_prefix = key_prefix.to_string();
+        self
+    }
+
+    /// Moves the data of the payload to the object store if it is larger than the threshold. Returns whether it did.
+    pub async fn check_in(&self, payload: &mut SerializedObject, meta_data: &mut HashMap<String,MetaDataValue>) -> Result<bool> {
+        if payload.data.len() <= self.threshold || meta_data.contains_key(CLAIM_CHECK) {
+            return Ok(false);
+        }
+        let hash = base64::encode_config(Sha256::digest(&payload.data), base64::URL_SAFE_NO_PAD);
+        let key = format!("{}/{}", self.key_prefix.trim_end_matches('/'), hash);
+        let data = std::mem::take(&mut payload.data);
+        debug!("Claim check: check in: {:?}: {:?}: size: {:?}", payload.r#type, key, data.len());
+        let key = self.store.put_object(&key, "application/octet-stream", data).await?;
+        meta_data.insert(CLAIM_CHECK.to_string(), MetaDataValue {
+            data: Some(Data::TextValue(key)),
+        });
+        Ok(true)
+    }
+
+    /// Restores the data of the payload if the meta-data refers to an object in the store. Returns whether it did.
+    pub async fn resolve(&self, payload: &mut SerializedObject, meta_data: &HashMap<String,MetaDataValue>) -> Result<bool> {
+        let key = match meta_data.get(CLAIM_CHECK).and_then(|value| value.data.as_ref()) {
+            Some(Data::TextValue(key)) => key,
+            Some(other) => return Err(anyhow!("Invalid claim check: {:?}", other)),
+            None => return Ok(false),
+        };
+        debug!("Claim check: resolve: {:?}: {:?}", payload.r#type, key);
+        payload.data = self.store.get_object(key).await?;
+        Ok(true)
+    }
+
+    pub async fn check_in_event(&self, event: &mut Event) -> Result<bool> {
+        match event.payload.as_mut() {
+            Some(payload) => self.check_in(payload, &mut event.meta_data).await,
+            None => Ok(false),
+        }
+    }
+
+    pub async fn resolve_event(&self, event: &mut Event) -> Result<bool> {
+        match event.payload.as_mut() {
+            Some(payload) => self.resolve(payload, &event.meta_data).await,
+            None => Ok(false),
+        }
+    }
+
+    pub async fn check_in_command(&self, command: &mut Command) -> Result<bool> {
+        match command.payload.as_mut() {
+            Some(payload) => self.check_in(payload, &mut command.meta_data).await,
+            None => Ok(false),
+        }
+    }
+
+    pub async fn resolve_command(&self, command: &mut Command) -> Result<bool> {
+        match command.payload.as_mut() {
+            Some(payload) => self.resolve(payload, &command.meta_data).await,
+            None => Ok(false),
+        }
+    }
+}
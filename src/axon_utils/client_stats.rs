@@ -0,0 +1,43 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use super::AxonServerHandle;
+use super::command_buffer::BUFFER_DEPTH;
+use super::command_worker::{MAILBOX_DEPTH,PERMITS_OUTSTANDING as COMMAND_PERMITS_OUTSTANDING};
+use super::query_processor::{IN_FLIGHT as QUERIES_IN_FLIGHT,PERMITS_OUTSTANDING as QUERY_PERMITS_OUTSTANDING};
+
+const RECONNECTS_SUFFIX: &str = "_reconnects";
+
+/// Load of the workers that share a connection to AxonServer, e.g., as a signal for backpressure or autoscaling.
+///
+/// The permits are the flow-control permits that AxonServer has not used yet, plus those of the messages that are still
+/// in flight. Commands in flight were received and not yet answered, including those that wait in the mailbox of the
+/// command worker. The buffered commands wait in the `CommandBuffer` for AxonServer to become available. Reconnects are
+/// counted per worker, since the start of the process. Values of workers that are not running are zero.
+#[derive(Debug,Clone,Default,PartialEq,Serialize)]
+pub struct ClientStats {
+    pub command_permits: i64,
+    pub commands_in_flight: i64,
+    pub query_permits: i64,
+    pub queries_in_flight: i64,
+    pub buffered_commands: i64,
+    pub reconnects: BTreeMap<String,i64>,
+}
+
+impl AxonServerHandle {
+    /// Returns the current load of the workers of this handle, from its metrics. Unlike `diagnostics`, it does not
+    /// call AxonServer, so it is cheap enough to poll.
+    pub fn stats(&self) -> ClientStats {
+        let metrics = self.metrics.snapshot();
+        let gauge = |name: &str| metrics.gauges.get(name).cloned().unwrap_or(0);
+        ClientStats {
+            command_permits: gauge(COMMAND_PERMITS_OUTSTANDING),
+            commands_in_flight: gauge(MAILBOX_DEPTH),
+            query_permits: gauge(QUERY_PERMITS_OUTSTANDING),
+            queries_in_flight: gauge(QUERIES_IN_FLIGHT),
+            buffered_commands: gauge(BUFFER_DEPTH),
+            reconnects: metrics.counters.iter()
+                .filter_map(|(name, count)| name.strip_suffix(RECONNECTS_SUFFIX).map(|worker| (worker.to_string(), *count)))
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use anyhow::{Result,anyhow};
+use tracing::{debug,warn};
+use prost::Message;
+use std::collections::{HashMap,VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tokio::time::delay_for;
+use super::{AxonServerHandle,CommandSink,VecU8Message};
+use super::axon_error::AxonError;
+use super::command_submit::{build_command,dispatch_command,serialize_command};
+use super::read_only::ReadOnlyError;
+use crate::axon_server::SerializedObject;
+use crate::axon_server::command::Command;
+
+/// Settings for the local command buffer.
+///
+/// At most `capacity` commands are buffered; when the buffer is full, new commands fail instead of being buffered.
+/// With a persistence path, the buffer is written to that file after every change and read back when the buffer is
+/// created, so that buffered commands survive a restart. The file is replaced atomically, so that a crash while it is
+/// written does not lose the buffer.
+#[derive(Debug,Clone)]
+pub struct CommandBufferConfig {
+    pub capacity: usize,
+    pub persistence_path: Option<PathBuf>,
+    pub flush_interval: Duration,
+}
+
+impl Default for CommandBufferConfig {
+    fn default() -> Self {
+        CommandBufferConfig {
+            capacity: 1000,
+            persistence_path: None,
+            flush_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+impl CommandBufferConfig {
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    pub fn with_persistence_path(mut self, persistence_path: &str) -> Self {
+        self.persistence_path = Some(PathBuf::from(persistence_path));
+        self
+    }
+
+    pub fn with_flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+}
+
+/// Whether a command was sent to AxonServer, or buffered until the connection is restored.
+#[derive(Debug,Clone)]
+pub enum BufferedOutcome {
+    Sent(Option<SerializedObject>),
+    Buffered { message_identifier: String },
+}
+
+pub(crate) const BUFFER_DEPTH: &str = "command_buffer_depth";
+
+/// Opt-in buffer for outgoing commands, for deployments where the link to AxonServer is unreliable.
+///
+/// A command that cannot be sent because AxonServer is unavailable is kept in the buffer, and the buffer is flushed,
+/// in order, when the connection is restored (see `run_command_buffer`). The response of a buffered command is lost.
+/// A buffered command keeps its message identifier and is removed from the buffer (and from the persisted file) as
+/// soon as AxonServer accepts it, so that it is not sent again after a restart. A command with the same message
+/// identifier as a command that is still in the buffer is not buffered a second time; commands with the same name and
+/// payload are distinct commands. Commands are also buffered while the client is in read-only mode.
+#[derive(Debug,Clone)]
+pub struct CommandBuffer {
+    axon_server_handle: AxonServerHandle,
+    config: CommandBufferConfig,
+    pending: Arc<Mutex<VecDeque<Command>>>,
+}
+
+/// Creates a command buffer, restoring the commands of the persisted buffer (if any).
+pub async fn create_command_buffer(axon_server_handle: AxonServerHandle, config: CommandBufferConfig) -> Result<CommandBuffer> {
+    let pending = match &config.persistence_path {
+        Some(path) => load(path).await?,
+        None => VecDeque::new(),
+    };
+    debug!("Command buffer: restored: {:?}", pending.len());
+    axon_server_handle.metrics.set_gauge(BUFFER_DEPTH, pending.len() as i64);
+    Ok(CommandBuffer {
+        axon_server_handle,
+        config,
+        pending: Arc::new(Mutex::new(pending)),
+    })
+}
+
+impl CommandBuffer {
+    pub async fn send_or_buffer(&self, command_type: &str, command: &(dyn VecU8Message + Sync)) -> Result<BufferedOutcome> {
+        let serialized_command = serialize_command(command_type, command)?;
+        let command = build_command(&self.axon_server_handle, &serialized_command, HashMap::new());
+        if !self.is_empty().await {
+            // Keep the order: later commands wait until the buffered commands are sent.
+            return self.buffer(command).await;
+        }
+        match dispatch_command(&self.axon_server_handle, command.clone()).await {
+            Ok(response) => Ok(BufferedOutcome::Sent(response)),
+            Err(e) if is_unavailable(&e) => {
+                debug!("AxonServer is unavailable: buffer command: {:?}", e);
+                self.buffer(command).await
+            }
+            Err(e) => Err(e.into_anyhow()),
+        }
+    }
+
+    async fn buffer(&self, command: Command) -> Result<BufferedOutcome> {
+        let mut pending = self.pending.lock().await;
+        if pending.iter().any(|buffered| buffered.message_identifier == command.message_identifier) {
+            debug!("Command is already buffered: {:?}", command.message_identifier);
+            return Ok(BufferedOutcome::Buffered { message_identifier: command.message_identifier });
+        }
+        if pending.len() >= self.config.capacity {
+            return Err(anyhow!("Command buffer is full: capacity: {:?}", self.config.capacity));
+        }
+        let message_identifier = command.message_identifier.clone();
+        pending.push_back(command);
+        self.persist(&pending).await?;
+        self.axon_server_handle.metrics.increment("command_buffer_buffered", 1);
+        Ok(BufferedOutcome::Buffered { message_identifier })
+    }
+
+    /// Sends the buffered commands, in order, until the buffer is empty or AxonServer is unavailable. A command that
+    /// is rejected by AxonServer or by its handler is dropped with a warning. Returns the number of commands sent.
+    pub async fn flush(&self) -> Result<usize> {
+        let mut pending = self.pending.lock().await;
+        let mut count = 0;
+        while let Some(command) = pending.front().cloned() {
+            match dispatch_command(&self.axon_server_handle, command.clone()).await {
+                Ok(_) => count += 1,
+                Err(e) if is_unavailable(&e) => break,
+                Err(e) => {
+                    warn!("Dropped buffered command: {:?}: {:?}: {:?}", command.name, command.message_identifier, e);
+                    self.axon_server_handle.metrics.increment("command_buffer_dropped", 1);
+                }
+            }
+            pending.pop_front();
+            self.persist(&pending).await?;
+        }
+        if count > 0 {
+            debug!("Command buffer: flushed: {:?}: remaining: {:?}", count, pending.len());
+            self.axon_server_handle.metrics.increment("command_buffer_flushed", count as i64);
+        }
+        Ok(count)
+    }
+
+    pub async fn len(&self) -> usize {
+        self.pending.lock().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.pending.lock().await.is_empty()
+    }
+
+    async fn persist(&self, pending: &VecDeque<Command>) -> Result<()> {
+        self.axon_server_handle.metrics.set_gauge(BUFFER_DEPTH, pending.len() as i64);
+        let path = match &self.config.persistence_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut buf = Vec::new();
+        for command in pending {
+            command.encode_length_delimited(&mut buf)?;
+        }
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut temporary_path = path.clone().into_os_string();
+        temporary_path.push(".tmp");
+        let mut file = tokio::fs::File::create(&temporary_path).await?;
+        file.write_all(&buf).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&temporary_path, path).await?;
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl CommandSink for CommandBuffer {
+    /// Returns no response for a command that was buffered.
+    // The signature, including the boxed reference, is imposed by `CommandSink`.
+    #[allow(clippy::redundant_allocation)]
+    async fn send_command(&self, command_type: &str, command: Box<&(dyn VecU8Message + Sync)>) -> Result<Option<SerializedObject>> {
+        match self.send_or_buffer(command_type, *command).await? {
+            BufferedOutcome::Sent(response) => Ok(response),
+            BufferedOutcome::Buffered { .. } => Ok(None),
+        }
+    }
+}
+
+/// Flushes the command buffer at the configured interval. Run it in a separate task.
+pub async fn run_command_buffer(command_buffer: CommandBuffer) {
+    loop {
+        delay_for(command_buffer.config.flush_interval).await;
+        if let Err(e) = command_buffer.flush().await {
+            warn!("Error while flushing command buffer: {:?}", e);
+        }
+    }
+}
+
+async fn load(path: &PathBuf) -> Result<VecDeque<Command>> {
+    let buf = match tokio::fs::read(path).await {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(VecDeque::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut slice = &buf[..];
+    let mut pending = VecDeque::new();
+    while !slice.is_empty() {
+        pending.push_back(Command::decode_length_delimited(&mut slice)?);
+    }
+    Ok(pending)
+}
+
+// Commands that are refused in read-only mode are kept, like commands that AxonServer could not receive.
+fn is_unavailable(error: &AxonError) -> bool {
+    match error {
+        AxonError::Connection(_) => true,
+        AxonError::Other(error) => error.is::<ReadOnlyError>(),
+        _ => false,
+    }
+}
@@ -0,0 +1,53 @@
+use anyhow::{anyhow,Result};
+use tracing::debug;
+use tonic::transport::Channel;
+use super::axon_error::AxonError;
+use super::command_worker::{AggregateHandle,CommandOutcome};
+use super::handler_metrics::HandlerLabels;
+use super::handler_registry::TheHandlerRegistry;
+use super::redaction::log_safe;
+use crate::axon_server::SerializedObject;
+use crate::axon_server::command::Command;
+use crate::axon_server::event::event_store_client::EventStoreClient;
+
+/// Command handlers that are not bound to an aggregate, e.g., for validation-only or integration commands.
+///
+/// No events are sourced and nothing is appended to the event store. The handlers receive the command envelope as
+/// context and return an optional response, e.g., registered with `insert_with_mapped_output` and `&axon_serialize` as
+/// wrapper. Insert the definition in the aggregate registry of a command worker, so that its commands are served on the
+/// same command stream as those of the aggregates.
+pub struct CommandHandlerDefinition {
+    pub name: String,
+    handler_registry: TheHandlerRegistry<Command,SerializedObject>,
+}
+
+pub fn create_command_handler_definition(name: &str, handler_registry: TheHandlerRegistry<Command,SerializedObject>) -> CommandHandlerDefinition {
+    CommandHandlerDefinition {
+        name: name.to_string(),
+        handler_registry,
+    }
+}
+
+#[tonic::async_trait]
+impl AggregateHandle for CommandHandlerDefinition {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    async fn handle(&self, command: &Command, _client: &mut EventStoreClient<Channel>) -> Result<CommandOutcome> {
+        debug!("Incoming command for plain handler: {:?}", log_safe(command));
+        let handler = self.handler_registry.handlers.get(&command.name)
+            .ok_or_else(|| AxonError::MissingHandler(command.name.clone()))?;
+        let data = command.payload.clone().map(|p| p.data).ok_or(anyhow!("No payload data for: {:?}", command.name))?;
+        let response = handler.handle(data, command.clone()).await?;
+        Ok(CommandOutcome::Handled { response })
+    }
+
+    fn command_names(&self) -> Vec<String> {
+        self.handler_registry.handlers.keys().cloned().collect()
+    }
+
+    fn handler_labels(&self, command_name: &str) -> Option<HandlerLabels> {
+        self.handler_registry.labels(command_name).cloned()
+    }
+}
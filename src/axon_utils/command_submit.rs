@@ -1,59 +1,162 @@
-use anyhow::{Result,anyhow};
-use log::{debug};
+use anyhow::Result;
+use tracing::{debug};
+use tonic::Status;
+use tonic::transport::Channel;
 use std::collections::HashMap;
 use std::vec::Vec;
 use uuid::Uuid;
-use super::{CommandSink, AxonServerHandle, wait_for_server, VecU8Message};
-use crate::axon_server::SerializedObject;
+use super::{AxonClients, CommandSink, AxonServerHandle, ConnectionConfig, wait_for_server_with_config, VecU8Message};
+use super::connection::{AxonConnectionBuilder,DEFAULT_AXON_SERVER_HOST,DEFAULT_AXON_SERVER_PORT};
+use super::axon_error::{AxonError,AxonResult};
+use super::business_rules::BusinessRuleError;
+use super::command_worker::{BUSY_ERROR_CODE,QUARANTINED_ERROR_CODE};
+use super::conflict::{EXPECTED_VERSION,expected_version_meta_data};
+use super::message_size::{check_message_size,explain_status};
+use super::read_only::check_writable;
+use super::redaction::log_safe;
+use super::tenant_quota::{command_quota_interceptor,max_message_size,quota_exceeded_from_status};
+use crate::axon_server::{MetaDataValue,SerializedObject};
 use crate::axon_server::command::Command;
 use crate::axon_server::command::command_service_client::CommandServiceClient;
 
 pub async fn init() -> Result<AxonServerHandle> {
-    let axon_connection = wait_for_server("proxy", 8124, "API").await.unwrap();
+    init_with_server(DEFAULT_AXON_SERVER_HOST, DEFAULT_AXON_SERVER_PORT).await
+}
+
+pub async fn init_with_server(host: &str, port: u32) -> Result<AxonServerHandle> {
+    init_with_config(host, port, ConnectionConfig::default()).await
+}
+
+/// Connects to AxonServer with the given settings, e.g., an access token.
+pub async fn init_with_config(host: &str, port: u32, config: ConnectionConfig) -> Result<AxonServerHandle> {
+    let axon_connection = wait_for_server_with_config(host, port, "API", config).await?;
     debug!("Axon connection: {:?}", axon_connection);
-    let command_sink = AxonServerHandle { display_name: axon_connection.id, conn: axon_connection.conn };
-    Ok(command_sink)
+    Ok(AxonServerHandle::from(axon_connection))
+}
+
+/// Connects to AxonServer with the endpoint, identity and settings of the builder.
+pub async fn init_with_builder(builder: AxonConnectionBuilder) -> Result<AxonServerHandle> {
+    let axon_server_handle = builder.connect_handle().await?;
+    debug!("Axon server handle: {:?}", axon_server_handle);
+    Ok(axon_server_handle)
 }
 
 #[tonic::async_trait]
 impl CommandSink for AxonServerHandle {
     async fn send_command(&self, command_type: &str, command: Box<&(dyn VecU8Message + Sync)>) -> Result<Option<SerializedObject>> {
         debug!("Sending command: {:?}: {:?}", command_type, self.display_name);
-        let mut buf = Vec::new();
-        command.encode_u8(&mut buf).unwrap();
-        let buffer_length = buf.len();
-        debug!("Buffer length: {:?}", buffer_length);
-        let serialized_command = SerializedObject {
-            r#type: command_type.to_string(),
-            revision: "1".to_string(),
-            data: buf,
-        };
-        submit_command(self, &serialized_command).await
+        let serialized_command = serialize_command(command_type, *command).map_err(AxonError::into_anyhow)?;
+        submit_command(self, &serialized_command, HashMap::new()).await.map_err(AxonError::into_anyhow)
     }
 }
 
-async fn submit_command(this: &AxonServerHandle, message: &SerializedObject) -> Result<Option<SerializedObject>> {
-    debug!("Message: {:?}", message);
-    let this = this.clone();
-    let conn = this.conn;
-    let mut client = CommandServiceClient::new(conn);
-    debug!("Command Service Client: {:?}", client);
+/// Sends a command that is only handled if the aggregate is still at the expected version, or if the conflict resolver
+/// of the aggregate accepts the events that were appended since. Otherwise it fails with a `BusinessRuleError` with
+/// error code `CONFLICT`.
+pub async fn send_command_with_expected_version(axon_server_handle: &AxonServerHandle, command_type: &str, command: Box<&(dyn VecU8Message + Sync)>, expected_version: i64) -> Result<Option<SerializedObject>> {
+    debug!("Sending command: {:?}: {:?}: expected version: {:?}", command_type, axon_server_handle.display_name, expected_version);
+    let serialized_command = serialize_command(command_type, *command).map_err(AxonError::into_anyhow)?;
+    let mut meta_data = HashMap::new();
+    meta_data.insert(EXPECTED_VERSION.to_string(), expected_version_meta_data(expected_version));
+    submit_command(axon_server_handle, &serialized_command, meta_data).await.map_err(AxonError::into_anyhow)
+}
+
+/// Sends a command with the given meta-data, e.g., the user, the tenant or the trace of the request that caused it
+/// (see `text_meta_data`). Command handlers read it from the `CommandEnvelope`.
+pub async fn send_command_with_meta_data(axon_server_handle: &AxonServerHandle, command_type: &str, command: Box<&(dyn VecU8Message + Sync)>, meta_data: HashMap<String,MetaDataValue>) -> Result<Option<SerializedObject>> {
+    debug!("Sending command: {:?}: {:?}: meta-data: {:?}", command_type, axon_server_handle.display_name, meta_data.keys());
+    let serialized_command = serialize_command(command_type, *command).map_err(AxonError::into_anyhow)?;
+    submit_command(axon_server_handle, &serialized_command, meta_data).await.map_err(AxonError::into_anyhow)
+}
+
+pub(crate) fn serialize_command(command_type: &str, command: &(dyn VecU8Message + Sync)) -> AxonResult<SerializedObject> {
+    let mut buf = Vec::new();
+    command.encode_u8(&mut buf).map_err(|e| AxonError::Serialization(e.to_string()))?;
+    let buffer_length = buf.len();
+    debug!("Buffer length: {:?}", buffer_length);
+    Ok(SerializedObject {
+        r#type: command_type.to_string(),
+        revision: "1".to_string(),
+        data: buf,
+    })
+}
+
+async fn submit_command(this: &AxonServerHandle, message: &SerializedObject, meta_data: HashMap<String,MetaDataValue>) -> AxonResult<Option<SerializedObject>> {
+    let command = build_command(this, message, meta_data);
+    dispatch_command(this, command).await
+}
+
+pub(crate) fn build_command(this: &AxonServerHandle, message: &SerializedObject, meta_data: HashMap<String,MetaDataValue>) -> Command {
+    debug!("Message: {:?}", log_safe(message));
     let uuid = Uuid::new_v4();
-    let command = Command {
+    Command {
         message_identifier: format!("{:?}", uuid.to_simple()),
         name: message.r#type.clone(),
         payload: Some(message.clone()),
-        client_id: "yyy".to_string(),
-        component_name: "RustCommandClient".to_string(),
-        meta_data: HashMap::new(),
+        client_id: this.display_name.clone(),
+        component_name: this.component_name.clone(),
+        meta_data,
         processing_instructions: Vec::new(),
         timestamp: 0,
-    };
-    let response = client.dispatch(command).await?;
-    debug!("Response: {:?}", response);
+    }
+}
+
+pub(crate) async fn dispatch_command(this: &AxonServerHandle, command: Command) -> AxonResult<Option<SerializedObject>> {
+    check_writable(&format!("command {}", command.name))?;
+    let mut client = dispatch_client(this);
+    debug!("Command Service Client: {:?}", client);
+    check_message_size("Command", &command, max_message_size(this.context.as_deref(), this.max_message_size()))?;
+    let response = client.dispatch(command).await.map_err(dispatch_error)?;
+    debug!("Response: {:?}", log_safe(response.get_ref()));
     let response = response.into_inner();
     if let Some(error_message) = response.error_message {
-        return Err(anyhow!(error_message.message));
+        // The error code of the handler itself, if any, is in the error message; the response has the error code of
+        // AxonServer.
+        let error_code = if error_message.error_code.is_empty() { response.error_code } else { error_message.error_code };
+        if is_business_error_code(&error_code) {
+            return Err(BusinessRuleError { error_code, message: error_message.message }.into());
+        }
+        return Err(AxonError::ErrorResponse { error_code, message: error_message.message });
     }
     Ok(response.payload)
 }
+
+// The command client of the connection, with the dispatch interceptors: the quota of the tenant of the connection.
+fn dispatch_client(this: &AxonServerHandle) -> CommandServiceClient<Channel> {
+    let interceptors = this.interceptors.with(command_quota_interceptor(this.context.as_deref(), &this.metrics));
+    match interceptors.interceptor(this.context.as_deref()) {
+        Some(interceptor) => CommandServiceClient::with_interceptor(this.channel(), interceptor),
+        None => CommandServiceClient::new(this.channel()),
+    }
+}
+
+fn dispatch_error(status: Status) -> AxonError {
+    match quota_exceeded_from_status(&status) {
+        Some(quota_exceeded) => AxonError::Other(quota_exceeded.into()),
+        None => AxonError::from(explain_status(status)),
+    }
+}
+
+// Error codes of AxonServer itself start with `AXONIQ-`; earlier versions of the command worker used `ERROR` for errors
+// that are not business rule violations. The command worker rejects commands with `BUSY` (a full mailbox) and
+// `QUARANTINED` (a command that crashed its handler too often), which are not business rule violations either.
+fn is_business_error_code(error_code: &str) -> bool {
+    !error_code.is_empty()
+        && error_code != "ERROR"
+        && error_code != BUSY_ERROR_CODE
+        && error_code != QUARANTINED_ERROR_CODE
+        && !error_code.starts_with("AXONIQ-")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn worker_rejections_are_not_business_errors() {
+        assert!(!is_business_error_code(BUSY_ERROR_CODE));
+        assert!(!is_business_error_code(QUARANTINED_ERROR_CODE));
+        assert!(!is_business_error_code("AXONIQ-4002"));
+        assert!(is_business_error_code("CONFLICT"));
+    }
+}
@@ -1,22 +1,52 @@
 use anyhow::{anyhow,Result};
 use async_stream::stream;
 use futures_core::stream::Stream;
-use log::{debug,error,warn};
+use futures_util::FutureExt;
+use tracing::{Instrument,debug,debug_span,error,info,info_span,warn};
 use prost::Message;
+use std::any::Any;
 use std::collections::HashMap;
+use std::fmt::{Display,Formatter};
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::time::Instant;
+use tokio::sync::Notify;
 use tokio::sync::mpsc::{Sender,Receiver, channel};
-use tonic::Request;
+use tokio::time::delay_for;
+use tokio::sync::mpsc::error::TrySendError;
+use tonic::{Request,Status};
 use tonic::transport::Channel;
 use uuid::Uuid;
-use super::{ApplicableTo, AxonConnection, VecU8Message, axon_serialize};
-use super::event_query::query_events_from_client;
-use super::handler_registry::{HandlerRegistry,TheHandlerRegistry};
-use crate::axon_server::{ErrorMessage,FlowControl,SerializedObject};
+use super::{ApplicableTo, AxonClients, AxonConnection, Metrics, PauseSwitch, VecU8Message, WorkerHealth, axon_serialize};
+use super::axon_error::AxonError;
+use super::business_rules::BusinessRuleError;
+use super::claim_check::ClaimCheck;
+use super::conflict::{CONFLICT_ERROR_CODE,ConflictResolver,check_expected_version,expected_version};
+use super::error_classification::{ReconnectPolicy,classify_error};
+use super::correlation::{CommandAuditRecord,CommandAuditStore,correlation_id,correlation_meta_data};
+use super::event_query::{query_events_from_client,query_events_from_snapshot};
+#[cfg(feature = "fault-injection")]
+use super::fault_injection::{DroppedCommand,FaultTarget,fault_injector};
+use super::flow_control::{FlowControlMode,PermitController};
+use super::handler_errors::{HandlerErrorKind,business_rule_error,classify_handler_error,error_message};
+use super::message_size::{check_message_size,check_payload_size,explain_status};
+use super::meta_data_stamping::{TENANT_ID,TRACE_ID,USER_ID,meta_data_text,stamp_meta_data};
+use super::handler_metrics::HandlerLabels;
+use super::read_only::check_writable;
+use super::redaction::{Redact,RedactionPolicy,log_safe,redaction_policy};
+use super::sequence_gaps::SequenceGapPolicy;
+use super::slow_handler::SlowHandlerThresholds;
+use super::snapshot::SnapshotConfig;
+use super::tenant_quota::TenantScope;
+use super::quarantine::{InMemoryQuarantineStore,QuarantineStore,QuarantinedCommand,QuarantinedError,quarantine_key};
+use super::priority::{DEFAULT_HIGH_PRIORITY_THRESHOLD,LaneReceivers,PriorityLane,message_priority,message_routing_key,priority_lanes};
+use super::handler_registry::{HandlerRegistry,PayloadAdapter,TheHandlerRegistry};
+use crate::axon_server::{FlowControl,MetaDataValue,SerializedObject};
 use crate::axon_server::command::{CommandProviderOutbound,CommandResponse,CommandSubscription};
 use crate::axon_server::command::{command_provider_inbound,Command};
 use crate::axon_server::command::command_provider_outbound;
-use crate::axon_server::command::command_service_client::CommandServiceClient;
-use crate::axon_server::event::{Event,ReadHighestSequenceNrRequest};
+use crate::axon_server::event::Event;
 use crate::axon_server::event::event_store_client::EventStoreClient;
 use std::fmt::Debug;
 
@@ -57,8 +87,22 @@ pub struct EmitEventsAndResponse {
 
 #[derive(Debug)]
 pub struct EmitApplicableEventsAndResponse<P> {
-    events: Vec<(String,Box<dyn ApplicableTo<P>>)>,
-    response: Option<SerializedObject>,
+    pub(crate) events: Vec<(String,Box<dyn ApplicableTo<P>>)>,
+    pub(crate) response: Option<SerializedObject>,
+}
+
+impl<P: std::fmt::Debug> EmitApplicableEventsAndResponse<P> {
+    // Decoded events cannot be redacted field by field, so events of redacted types are logged by type only.
+    fn log_safe_events(&self) -> Vec<String> {
+        let policy = redaction_policy();
+        self.events.iter()
+            .map(|(type_name, event)| if policy.is_redacted(type_name) {
+                format!("{}: <redacted>", type_name)
+            } else {
+                format!("{}: {:?}", type_name, event)
+            })
+            .collect()
+    }
 }
 
 impl<P> Clone for EmitApplicableEventsAndResponse<P> {
@@ -112,20 +156,132 @@ pub fn empty_aggregate_registry() -> TheAggregateRegistry {
     }
 }
 
+pub trait AggregateContext: Clone {
+    /// Returns the projection that is passed to the handler of the given command. Override this method to give
+    /// handlers access to the envelope of the command (message identifier, meta-data, processing instructions), e.g.,
+    /// for idempotency keys or auditing, for instance by keeping a `CommandEnvelope` in the projection. By default the
+    /// envelope is ignored.
+    fn for_command(&self, _command: &Command) -> Self {
+        self.clone()
+    }
+
+    /// Returns the projection before the given event is applied to it by its sourcing handler. Override this method
+    /// to track the envelope of the event (sequence number, timestamp, snapshot flag), e.g., to keep the version of
+    /// the aggregate in the projection. By default the envelope is ignored.
+    fn for_sourcing_event(&self, _event: &Event) -> Self {
+        self.clone()
+    }
+}
+
+/// The envelope of an incoming command: everything but the payload and the processing instructions. Aggregate command
+/// handlers get it through `AggregateContext::for_command`; plain command handlers get the command itself.
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct CommandEnvelope {
+    pub message_identifier: String,
+    pub name: String,
+    pub meta_data: HashMap<String,MetaDataValue>,
+}
+
+impl CommandEnvelope {
+    pub fn from_command(command: &Command) -> Self {
+        CommandEnvelope {
+            message_identifier: command.message_identifier.clone(),
+            name: command.name.clone(),
+            meta_data: command.meta_data.clone(),
+        }
+    }
+
+    /// Returns the text value of the given meta-data key, if any.
+    pub fn meta_data_text(&self, key: &str) -> Option<&str> {
+        meta_data_text(&self.meta_data, key)
+    }
+
+    pub fn user_id(&self) -> Option<&str> {
+        self.meta_data_text(USER_ID)
+    }
+
+    pub fn tenant_id(&self) -> Option<&str> {
+        self.meta_data_text(TENANT_ID)
+    }
+
+    pub fn trace_id(&self) -> Option<&str> {
+        self.meta_data_text(TRACE_ID)
+    }
+}
+
+/// Outcome of a command that was handled without errors.
+#[derive(Debug,Clone,PartialEq)]
+pub enum CommandOutcome {
+    /// The command handler returned `None`: nothing was emitted and there is no response.
+    Ignored,
+    /// The command handler emitted zero or more events, which were stored, and possibly a response.
+    Handled { response: Option<SerializedObject> },
+}
+
+impl CommandOutcome {
+    pub fn response(self) -> Option<SerializedObject> {
+        match self {
+            CommandOutcome::Ignored => None,
+            CommandOutcome::Handled { response } => response,
+        }
+    }
+}
+
+impl Redact for CommandOutcome {
+    fn redact(&mut self, policy: &RedactionPolicy) {
+        if let CommandOutcome::Handled { response } = self {
+            response.redact(policy);
+        }
+    }
+}
+
+impl From<EmitEventsAndResponse> for CommandOutcome {
+    fn from(emit_events: EmitEventsAndResponse) -> Self {
+        CommandOutcome::Handled { response: emit_events.response }
+    }
+}
+
 #[tonic::async_trait]
 pub trait AggregateHandle: Send + Sync {
     fn name(&self) -> String;
-    async fn handle(&self, command: &Command, client: &mut EventStoreClient<Channel>) -> Result<Option<EmitEventsAndResponse>>;
+    async fn handle(&self, command: &Command, client: &mut EventStoreClient<Channel>) -> Result<CommandOutcome>;
     fn command_names(&self) -> Vec<String>;
+
+    /// Handles the command for the tenant of the given AxonServer context, whose quota of events per day the appended
+    /// events count against (see `TenantQuotas`); refusals are counted in the metrics. By default the context is
+    /// ignored.
+    async fn handle_in_context(&self, command: &Command, client: &mut EventStoreClient<Channel>, _context: Option<&str>, _metrics: &Metrics) -> Result<CommandOutcome> {
+        self.handle(command, client).await
+    }
+
+    /// Returns the names of the events that the aggregate is sourced from.
+    fn event_names(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Returns the labels of the handler of the given command, if it was labelled.
+    fn handler_labels(&self, _command_name: &str) -> Option<HandlerLabels> {
+        None
+    }
 }
 
 #[tonic::async_trait]
-impl<P: VecU8Message + Send + Clone + std::fmt::Debug + 'static> AggregateHandle for AggregateDefinition<P> {
+impl<P: VecU8Message + AggregateContext + Send + Clone + std::fmt::Debug + 'static> AggregateHandle for AggregateDefinition<P> {
     fn name(&self) -> String {
         self.projection_name.clone()
     }
-    async fn handle(&self, command: &Command, client: &mut EventStoreClient<Channel>) -> Result<Option<EmitEventsAndResponse>> {
-        handle_command(command, self, client).await
+    async fn handle(&self, command: &Command, client: &mut EventStoreClient<Channel>) -> Result<CommandOutcome> {
+        self.handle_in_context(command, client, None, &Metrics::default()).await
+    }
+    async fn handle_in_context(&self, command: &Command, client: &mut EventStoreClient<Channel>, context: Option<&str>, metrics: &Metrics) -> Result<CommandOutcome> {
+        let tenant = TenantScope {
+            context: context.map(str::to_string),
+            metrics: metrics.clone(),
+        };
+        match &self.event_store_client {
+            Some(own_client) => handle_command(command, self, &mut own_client.clone(), &tenant).await,
+            None => handle_command(command, self, client, &tenant).await,
+        }
     }
     fn command_names(&self) -> Vec<String> {
         let mut result = Vec::new();
@@ -134,14 +290,26 @@ impl<P: VecU8Message + Send + Clone + std::fmt::Debug + 'static> AggregateHandle
         }
         result
     }
+    fn event_names(&self) -> Vec<String> {
+        self.sourcing_handler_registry.handlers.keys().cloned().collect()
+    }
+    fn handler_labels(&self, command_name: &str) -> Option<HandlerLabels> {
+        self.command_handler_registry.labels(command_name).cloned()
+    }
 }
 
 pub struct AggregateDefinition<P: VecU8Message + Send + Clone + 'static> {
     pub projection_name: String,
+    aggregate_type: String,
     empty_projection: Box<dyn Fn() -> P + Send + Sync>,
     aggregate_id_extractor_registry: TheHandlerRegistry<(),String>,
     command_handler_registry: TheHandlerRegistry<P,EmitApplicableEventsAndResponse<P>>,
     sourcing_handler_registry: TheHandlerRegistry<P,P>,
+    claim_check: Option<ClaimCheck>,
+    conflict_resolver: Option<ConflictResolver>,
+    event_store_client: Option<EventStoreClient<Channel>>,
+    snapshot: Option<SnapshotConfig<P>>,
+    sequence_gap_policy: SequenceGapPolicy,
 }
 
 pub fn create_aggregate_definition<P: VecU8Message + Send + Clone>(
@@ -152,17 +320,198 @@ pub fn create_aggregate_definition<P: VecU8Message + Send + Clone>(
     sourcing_handler_registry: TheHandlerRegistry<P,P>
 ) -> AggregateDefinition<P>{
     AggregateDefinition {
+        aggregate_type: projection_name.clone(),
         projection_name, empty_projection, aggregate_id_extractor_registry, command_handler_registry, sourcing_handler_registry,
+        claim_check: None,
+        conflict_resolver: None,
+        event_store_client: None,
+        snapshot: None,
+        sequence_gap_policy: SequenceGapPolicy::default(),
+    }
+}
+
+impl<P: VecU8Message + Send + Clone> AggregateDefinition<P> {
+    /// Stores the events and snapshots of this aggregate with the given aggregate type, instead of the projection name.
+    pub fn with_aggregate_type(mut self, aggregate_type: &str) -> Self {
+        self.aggregate_type = aggregate_type.to_string();
+        self
+    }
+
+    /// Moves large event payloads of this aggregate to an object store and resolves payloads of incoming commands and
+    /// replayed events that refer to the object store.
+    pub fn with_claim_check(mut self, claim_check: ClaimCheck) -> Self {
+        self.claim_check = Some(claim_check);
+        self
+    }
+
+    /// Lets commands with an expected version proceed when the aggregate changed since that version, if the resolver
+    /// accepts the intervening events. Without a resolver, such commands are rejected with error code `CONFLICT`.
+    pub fn with_conflict_resolver(mut self, resolver: impl Fn(&Command, &[Event]) -> Result<bool> + Send + Sync + 'static) -> Self {
+        self.conflict_resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Sources and stores the events of this aggregate in the given event store, instead of the event store of the
+    /// connection of the command worker, e.g., with a connection to another context (see `ConnectionConfig::with_context`).
+    pub fn with_event_store_client(mut self, event_store_client: EventStoreClient<Channel>) -> Self {
+        self.event_store_client = Some(event_store_client);
+        self
+    }
+
+    /// Accepts commands under another name as well, e.g., the wire name of another version of a command. The adapter
+    /// converts the payload of the alias to the payload that the handler of the target expects (see
+    /// `payload_adapter`). The aggregate identifier is extracted from the adapted payload.
+    pub fn alias_command(&mut self, alias: &str, target: &str, adapter: PayloadAdapter) -> Result<()> {
+        if self.aggregate_id_extractor_registry.handlers.contains_key(target) {
+            self.aggregate_id_extractor_registry.alias(alias, target, adapter.clone())?;
+        }
+        self.command_handler_registry.alias(alias, target, adapter)
+    }
+
+    /// Restores the projection of this aggregate from its latest snapshot, and stores new snapshots as configured.
+    /// Commands with an expected version are always sourced from the first event, so that conflicts can be resolved.
+    pub fn with_snapshots(mut self, snapshot: SnapshotConfig<P>) -> Self {
+        self.snapshot = Some(snapshot);
+        self
+    }
+
+    /// Determines what happens when the events that this aggregate is sourced from have irregular sequence numbers.
+    pub fn with_sequence_gap_policy(mut self, policy: SequenceGapPolicy) -> Self {
+        self.sequence_gap_policy = policy;
+        self
     }
 }
 
-async fn handle_command<P: VecU8Message + Send + Clone + std::fmt::Debug + 'static>(
+impl<P: VecU8Message + AggregateContext + Send + Clone + std::fmt::Debug + 'static> AggregateDefinition<P> {
+    /// Returns a new, empty projection of this aggregate.
+    pub fn empty_projection(&self) -> P {
+        (self.empty_projection)()
+    }
+
+    /// Applies the sourcing handlers for the given events to the projection. Payloads that were moved to the object
+    /// store by the claim check, have to be resolved beforehand.
+    pub async fn replay(&self, mut projection: P, events: Vec<Event>) -> Result<P> {
+        for event in events {
+            debug!("Replaying event: {:?}", log_safe(&event));
+            projection = projection.for_sourcing_event(&event);
+            if let Some(payload) = event.payload {
+                let sourcing_handler = self.sourcing_handler_registry.get(&payload.r#type).ok_or(anyhow!("Missing sourcing handler for {:?}", payload.r#type))?;
+                let projection_clone = projection.clone();
+                if let Some(p) = (sourcing_handler).handle(payload.data, projection_clone).await? {
+                    projection = p;
+                }
+            }
+        }
+        Ok(projection)
+    }
+
+    pub fn claim_check(&self) -> Option<&ClaimCheck> {
+        self.claim_check.as_ref()
+    }
+
+    pub(crate) fn event_store_client(&self) -> Option<&EventStoreClient<Channel>> {
+        self.event_store_client.as_ref()
+    }
+
+    pub(crate) fn sequence_gap_policy(&self) -> &SequenceGapPolicy {
+        &self.sequence_gap_policy
+    }
+
+    /// Returns the projection of the latest snapshot of the aggregate (or an empty projection) and the events after
+    /// it, with their payloads resolved by the claim check. Snapshots are only used when they are enabled and allowed.
+    /// The sequence numbers of the events are checked according to the sequence gap policy. Also returns the sequence
+    /// number of the last event that the aggregate is sourced from (-1 for a new aggregate), for appending new events.
+    pub(crate) async fn load_events(&self, client: &mut EventStoreClient<Channel>, aggregate_id: &str, allow_snapshot: bool) -> Result<(P,Vec<Event>,i64)> {
+        let mut projection = self.empty_projection();
+        let mut first_expected = 0;
+        let mut events = match (&self.snapshot, allow_snapshot) {
+            (Some(_), true) => query_events_from_snapshot(client, aggregate_id).await?,
+            _ => query_events_from_client(client, aggregate_id).await?,
+        };
+        if events.first().map(|event| event.snapshot).unwrap_or(false) {
+            let snapshot = events.remove(0);
+            let restored = match (&self.snapshot, &snapshot.payload) {
+                (Some(snapshot_config), Some(payload)) => snapshot_config.restore(payload),
+                _ => Err(anyhow!("Unexpected snapshot")),
+            };
+            match restored {
+                Ok(restored) => {
+                    projection = restored.for_sourcing_event(&snapshot);
+                    first_expected = snapshot.aggregate_sequence_number + 1;
+                }
+                Err(e) => {
+                    warn!("Ignore snapshot: {:?}: {:?}: {:?}", aggregate_id, snapshot.aggregate_sequence_number, e);
+                    events = query_events_from_client(client, aggregate_id).await?;
+                }
+            }
+        }
+        self.sequence_gap_policy.apply(aggregate_id, first_expected, &events)?;
+        let last_sequence_nr = events.iter().map(|event| event.aggregate_sequence_number).max().unwrap_or(first_expected - 1);
+        if let Some(claim_check) = &self.claim_check {
+            for event in events.iter_mut() {
+                claim_check.resolve_event(event).await?;
+            }
+        }
+        Ok((projection, events, last_sequence_nr))
+    }
+
+    /// Stores the events of the result after the position that the projection was sourced from, and a snapshot when
+    /// enough events were applied since the last one. AxonServer rejects the events when other events were appended to
+    /// the aggregate in the meantime. Failing to store the snapshot is only logged.
+    pub(crate) async fn store_result(
+        &self,
+        client: &mut EventStoreClient<Channel>,
+        aggregate_id: &str,
+        position: SourcingPosition,
+        projection: P,
+        result: &EmitApplicableEventsAndResponse<P>,
+        meta_data: HashMap<String,MetaDataValue>
+    ) -> Result<()> {
+        debug!("Emit events: {:?}", result.log_safe_events());
+        let last_sequence_nr = store_events(client, &self.aggregate_type, aggregate_id, position.last_sequence_nr, result, meta_data, self.claim_check.as_ref()).await?;
+        if let Some(snapshot_config) = &self.snapshot {
+            if position.events_since_snapshot + result.events.len() >= snapshot_config.threshold {
+                let stored = store_snapshot(client, snapshot_config, &self.aggregate_type, aggregate_id, last_sequence_nr, projection, result).await;
+                if let Err(e) = stored {
+                    warn!("Failed to store snapshot: {:?}: {:?}", aggregate_id, e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How far an aggregate was sourced: the number of events after the latest snapshot, and the sequence number of the
+/// last event, after which new events are appended.
+#[derive(Debug,Clone,Copy)]
+pub(crate) struct SourcingPosition {
+    pub(crate) events_since_snapshot: usize,
+    pub(crate) last_sequence_nr: i64,
+}
+
+impl Default for SourcingPosition {
+    /// The position of a new aggregate.
+    fn default() -> Self {
+        SourcingPosition {
+            events_since_snapshot: 0,
+            last_sequence_nr: -1,
+        }
+    }
+}
+
+async fn handle_command<P: VecU8Message + AggregateContext + Send + Clone + std::fmt::Debug + 'static>(
     command: &Command,
     aggregate_definition: &AggregateDefinition<P>,
-    client: &mut EventStoreClient<Channel>
-) -> Result<Option<EmitEventsAndResponse>> {
-    debug!("Incoming command: {:?}", command);
-    let data = command.payload.clone().map(|p| p.data).ok_or(anyhow!("No payload data for: {:?}", command.name))?;
+    client: &mut EventStoreClient<Channel>,
+    tenant: &TenantScope
+) -> Result<CommandOutcome> {
+    debug!("Incoming command: {:?}", log_safe(command));
+    let claim_check = aggregate_definition.claim_check.as_ref();
+    let mut payload = command.payload.clone();
+    if let (Some(claim_check), Some(payload)) = (claim_check, payload.as_mut()) {
+        claim_check.resolve(payload, &command.meta_data).await?;
+    }
+    let data = payload.map(|p| p.data).ok_or(anyhow!("No payload data for: {:?}", command.name))?;
 
     let mut aggregate_id = None;
     if let Some(aggregate_id_extractor) = aggregate_definition.aggregate_id_extractor_registry.get(&command.name){
@@ -170,23 +519,34 @@ async fn handle_command<P: VecU8Message + Send + Clone + std::fmt::Debug + 'stat
     }
     debug!("Aggregate ID: {:?}", aggregate_id);
 
-    let handler = aggregate_definition.command_handler_registry.get(&command.name).ok_or(anyhow!("No handler for: {:?}", command.name))?;
+    let handler = aggregate_definition.command_handler_registry.get(&command.name).ok_or_else(|| AxonError::MissingHandler(command.name.clone()))?;
     let mut projection = (aggregate_definition.empty_projection)();
+    let mut position = SourcingPosition::default();
+    let expected_version = expected_version(command)?;
+    if let (None, Some(expected_version)) = (&aggregate_id, expected_version) {
+        return Err(BusinessRuleError {
+            error_code: CONFLICT_ERROR_CODE.to_string(),
+            message: format!("Expected version {} of the aggregate, but the command has no aggregate identifier", expected_version),
+        }.into());
+    }
     if let Some(aggregate_id) = &aggregate_id {
-        let events = query_events_from_client(client, &aggregate_id).await?;
-        for event in events {
-            debug!("Replaying event: {:?}", event);
-            if let Some(payload) = event.payload {
-                let sourcing_handler = aggregate_definition.sourcing_handler_registry.get(&payload.r#type).ok_or(anyhow!("Missing sourcing handler for {:?}", payload.r#type))?;
-                let projection_clone = projection.clone();
-                if let Some(p) = (sourcing_handler).handle(payload.data, projection_clone).await? {
-                    projection = p;
-                }
-            }
+        let (restored, events, last_sequence_nr) = aggregate_definition.load_events(client, aggregate_id, expected_version.is_none()).await?;
+        if let Some(expected_version) = expected_version {
+            check_expected_version(command, expected_version, &events, aggregate_definition.conflict_resolver.as_ref())?;
         }
+        position = SourcingPosition {
+            events_since_snapshot: events.len(),
+            last_sequence_nr,
+        };
+        projection = aggregate_definition.replay(restored, events).await?;
+    }
+    // Projections are decoded state that cannot be redacted field by field, so they are only logged without redaction.
+    if redaction_policy().is_active() {
+        debug!("Restored projection: <redacted>");
+    } else {
+        debug!("Restored projection: {:?}", projection);
     }
-    debug!("Restored projection: {:?}", projection);
-    let result = handler.handle(data, projection).await?;
+    let result = handler.handle(data, projection.for_command(command)).await?;
     if let (None,Some(EmitApplicableEventsAndResponse{ response: Some(r), ..})) = (&aggregate_id,result.as_ref()) {
         let response_type = r.r#type.clone();
         if let Some(aggregate_id_extractor) = aggregate_definition.aggregate_id_extractor_registry.get(&response_type) {
@@ -194,23 +554,16 @@ async fn handle_command<P: VecU8Message + Send + Clone + std::fmt::Debug + 'stat
             aggregate_id = aggregate_id_extractor.handle(response_data, ()).await?
         }
     }
-    if let Some(aggregate_id) = aggregate_id {
-
-        if let Some(result) = result.as_ref() {
-            debug!("Emit events: {:?}", &result.events);
-            store_events(client, &aggregate_id, &result).await?;
-        }
-
-        let wrapped_result = result.map(
-            |r| EmitEventsAndResponse {
-                events: vec![],
-                response: r.response.clone()
-            }
-        );
-
-        return Ok(wrapped_result)
+    let result = match result {
+        Some(result) => result,
+        None => return Ok(CommandOutcome::Ignored),
+    };
+    if !result.events.is_empty() {
+        let aggregate_id = aggregate_id.ok_or_else(|| anyhow!("Missing aggregate identifier"))?;
+        tenant.check_event_quota(result.events.len())?;
+        aggregate_definition.store_result(client, &aggregate_id, position, projection, &result, stamp_meta_data(Some(command), correlation_meta_data(command))).await?;
     }
-    Err(anyhow!("Missing aggregate identifier"))
+    Ok(CommandOutcome::Handled { response: result.response })
 }
 
 pub fn emit<T: Message>(holder: &mut EmitEventsAndResponse, type_name: &str, event: &T) -> Result<()> {
@@ -224,74 +577,494 @@ pub fn emit_applicable<P: VecU8Message + Send + Clone>(holder: &mut EmitApplicab
     Ok(())
 }
 
+/// Fluent alternative for `emit_applicable_events_and_response` and `emit_applicable`, e.g.,
+/// `CommandResult::reply(acknowledgement).event(GreetedEvent { .. })`. Type names are derived from the Rust types.
+pub type CommandResult<P> = EmitApplicableEventsAndResponse<P>;
+
+impl<P: VecU8Message + Send + Clone> EmitApplicableEventsAndResponse<P> {
+    pub fn reply<T: Message>(response: T) -> Self {
+        let mut data = Vec::new();
+        response.encode(&mut data).expect("Encoding into a Vec does not fail");
+        EmitApplicableEventsAndResponse {
+            events: Vec::new(),
+            response: Some(SerializedObject {
+                r#type: message_type_name::<T>().to_string(),
+                revision: "".to_string(),
+                data,
+            }),
+        }
+    }
+
+    pub fn no_reply() -> Self {
+        EmitApplicableEventsAndResponse {
+            events: Vec::new(),
+            response: None,
+        }
+    }
+
+    pub fn event<E: ApplicableTo<P> + 'static>(mut self, event: E) -> Self {
+        self.events.push((message_type_name::<E>().to_string(), Box::new(event)));
+        self
+    }
+
+    /// Appends the events in the given order, e.g.,
+    /// `emit_applicable_events_and_response("Empty", &())?.sequence(vec![("StartedEvent", Box::from(StartedEvent {}))])`.
+    pub fn sequence(mut self, events: Vec<(&str, Box<dyn ApplicableTo<P>>)>) -> Self {
+        for (type_name, event) in events {
+            self.events.push((type_name.to_string(), event));
+        }
+        self
+    }
+}
+
+/// Returns the name of a message type as it is used in AxonServer: the name of the Rust type without its module path.
+/// The unit type is called `Empty`.
+pub fn message_type_name<T>() -> &'static str {
+    let name = std::any::type_name::<T>();
+    if name == "()" {
+        return "Empty";
+    }
+    let name = name.split('<').next().unwrap_or(name);
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+const WORKER_NAME: &str = "command_worker";
+pub(crate) const MAILBOX_DEPTH: &str = "command_worker_mailbox_depth";
+const COMMANDS_HANDLED: &str = "command_worker_commands_handled";
+const COMMANDS_SHED: &str = "command_worker_commands_shed";
+const COMMANDS_QUARANTINED: &str = "command_worker_commands_quarantined";
+const PERMITS_WITHHELD: &str = "command_worker_permits_withheld";
+const PERMIT_WINDOW: &str = "command_worker_permit_window";
+pub(crate) const PERMITS_OUTSTANDING: &str = "command_worker_permits_outstanding";
+pub(crate) const RECONNECTS: &str = "command_worker_reconnects";
+const HANDLER_LATENCY_MS: &str = "command_worker_handler_latency_ms";
+/// Error code for commands that are rejected because the mailbox of the command worker is full.
+pub const BUSY_ERROR_CODE: &str = "BUSY";
+/// Error code for commands that are rejected because they crashed their handler too often.
+pub const QUARANTINED_ERROR_CODE: &str = "QUARANTINED";
+
+/// Settings for the command worker.
+///
+/// Incoming commands are queued in a mailbox that holds at most `mailbox_capacity` commands. When the mailbox is
+/// full, a new command is rejected right away with error code `BUSY`. While the mailbox holds `withhold_permits_depth`
+/// commands or more, no new flow-control permits are sent to AxonServer, so that it can route commands to other
+/// instances. The number of permits that is advertised otherwise is determined by `flow_control`.
+///
+/// Commands with a `PRIORITY` processing instruction of at least `high_priority_threshold` are queued in a separate
+/// lane (with its own `mailbox_capacity`) that is always served before the normal lane.
+///
+/// When the handler panics on the same command (name and payload) `poison_threshold` times in a row, the command is
+/// recorded in the `quarantine_store` and rejected with error code `QUARANTINED` from then on.
+///
+/// While the `pause_switch` is paused, no new flow-control permits are sent, and the commands that were already
+/// received are handled as usual. Handlers that take longer than their `slow_handler` threshold are logged and counted.
+///
+/// Commands with a payload that is larger than `max_payload_size` are rejected with a `PayloadTooLargeError` before they
+/// are decoded. Handled commands are recorded in the `audit_store`, if any.
+///
+/// A failed command is answered with the error code of AxonServer for the kind of failure (see
+/// `classify_handler_error`). The error message of the response carries the error code of the handler, e.g., `BUSY`,
+/// `QUARANTINED` or the code of the business rule, and the messages of the error and its causes as details.
+///
+/// When the stream to AxonServer fails with a retryable error, the worker opens a new stream after a delay that the
+/// `reconnect_policy` determines, subscribes to its commands again, and starts over with flow control. The commands that
+/// were in the mailbox are dropped, because AxonServer no longer waits for their results. Each reconnect is counted in
+/// `command_worker_reconnects`. A fatal error, or a retryable error after `max_attempts` reconnects in a row, stops the
+/// worker. When AxonServer requests a reconnect, the worker opens a new stream on the rebuilt channel right away.
+#[derive(Debug,Clone)]
+pub struct CommandWorkerConfig {
+    pub mailbox_capacity: usize,
+    pub withhold_permits_depth: usize,
+    pub flow_control: FlowControlMode,
+    pub high_priority_threshold: i64,
+    pub poison_threshold: u32,
+    pub quarantine_store: Arc<dyn QuarantineStore>,
+    pub pause_switch: PauseSwitch,
+    pub slow_handler: SlowHandlerThresholds,
+    pub max_payload_size: Option<usize>,
+    pub audit_store: Option<Arc<dyn CommandAuditStore>>,
+    pub reconnect_policy: ReconnectPolicy,
+}
+
+impl Default for CommandWorkerConfig {
+    fn default() -> Self {
+        CommandWorkerConfig {
+            mailbox_capacity: 20,
+            withhold_permits_depth: 10,
+            flow_control: FlowControlMode::default(),
+            high_priority_threshold: DEFAULT_HIGH_PRIORITY_THRESHOLD,
+            poison_threshold: 3,
+            quarantine_store: Arc::new(InMemoryQuarantineStore::default()),
+            pause_switch: PauseSwitch::default(),
+            slow_handler: SlowHandlerThresholds::default(),
+            max_payload_size: None,
+            audit_store: None,
+            reconnect_policy: ReconnectPolicy::default(),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct AxonCommandResult {
     message_identifier: String,
-    result: Result<Option<EmitEventsAndResponse>>,
+    received: Instant,
+    result: Result<CommandOutcome>,
 }
 
+#[derive(Debug)]
+struct BusyError;
+
+impl Display for BusyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Command worker is busy")
+    }
+}
+
+impl std::error::Error for BusyError {}
+
 pub async fn command_worker(
     axon_connection: AxonConnection,
     aggregate_registry: TheAggregateRegistry
 ) -> Result<()> {
-    debug!("Command worker: start");
+    command_worker_with_config(axon_connection, aggregate_registry, CommandWorkerConfig::default()).await
+}
 
-    let axon_connection_clone = axon_connection.clone();
-    let mut client = CommandServiceClient::new(axon_connection.conn);
-    let mut event_store_client = EventStoreClient::new(axon_connection_clone.conn);
-    let client_id = axon_connection.id.clone();
+pub async fn command_worker_with_config(
+    axon_connection: AxonConnection,
+    aggregate_registry: TheAggregateRegistry,
+    config: CommandWorkerConfig
+) -> Result<()> {
+    let span = info_span!("command_worker", client_id = %axon_connection.id);
+    run_command_worker(axon_connection, aggregate_registry, config).instrument(span).await
+}
+
+async fn run_command_worker(
+    axon_connection: AxonConnection,
+    aggregate_registry: TheAggregateRegistry,
+    config: CommandWorkerConfig
+) -> Result<()> {
+    debug!("Command worker: start: {:?}", config);
+
+    let health = axon_connection.health.clone();
+    health.report(WORKER_NAME, WorkerHealth::Starting);
+    let metrics = axon_connection.metrics.clone();
 
     let mut command_to_aggregate_mapping = HashMap::new();
     let mut command_vec: Vec<String> = vec![];
     aggregate_registry.register(&mut command_vec, &mut command_to_aggregate_mapping);
-    let command_box = Box::new(command_vec);
+    health.report_subscriptions(WORKER_NAME, command_vec.clone());
+
+    let mut mailbox_handler = MailboxHandler {
+        aggregate_registry,
+        command_to_aggregate_mapping,
+        event_store_client: axon_connection.event_store_client(),
+        context: axon_connection.context.clone(),
+        mailbox_depth: Arc::new(AtomicUsize::new(0)),
+        metrics: metrics.clone(),
+        poison_threshold: config.poison_threshold,
+        slow_handler: config.slow_handler.clone(),
+        max_payload_size: config.max_payload_size,
+        quarantine_store: config.quarantine_store.clone(),
+        audit_store: config.audit_store.clone(),
+        failures: HashMap::new(),
+    };
+
+    let mut attempt = 0;
+    loop {
+        let mut established = false;
+        let (handler, error) = run_command_stream(&axon_connection, &command_vec, mailbox_handler, &config, &mut established).await?;
+        mailbox_handler = handler;
+        let error = match error {
+            Some(error) => error,
+            None => {
+                info!("Command worker: reconnect on request of AxonServer");
+                mailbox_handler.event_store_client = axon_connection.event_store_client();
+                attempt = 0;
+                continue;
+            }
+        };
+        if established {
+            attempt = 0;
+        }
+        attempt += 1;
+        match config.reconnect_policy.next_delay(&error, attempt) {
+            Some(delay) => {
+                warn!("Command worker: reconnect: attempt: {:?}: delay: {:?}: {:?}", attempt, delay, error);
+                health.report(WORKER_NAME, WorkerHealth::Reconnecting {
+                    error_class: classify_error(&error),
+                    attempt,
+                });
+                metrics.increment(RECONNECTS, 1);
+                delay_for(delay).await;
+            }
+            None => return Err(error),
+        }
+    }
+}
+
+// Opens a stream to AxonServer, subscribes to the commands, and passes the commands that arrive to the mailbox handler
+// until the stream fails or AxonServer requests a reconnect. Returns the mailbox handler when it finished, so that the
+// next stream can use it, with the error of the stream, or `None` on a reconnect request.
+async fn run_command_stream(
+    axon_connection: &AxonConnection,
+    command_vec: &[String],
+    mailbox_handler: MailboxHandler,
+    config: &CommandWorkerConfig,
+    established: &mut bool
+) -> Result<(MailboxHandler,Option<anyhow::Error>)> {
+    let health = axon_connection.health.clone();
+    let reconnect = axon_connection.reconnect.clone();
+    let generation = reconnect.generation();
+    let metrics = axon_connection.metrics.clone();
+    let mut client = axon_connection.command_client();
+    let client_id = axon_connection.id.clone();
+    let command_box = Box::new(command_vec.to_vec());
 
     let (mut tx, rx): (Sender<AxonCommandResult>, Receiver<AxonCommandResult>) = channel(10);
+    let (mut mailbox_tx, mailbox_rx) = priority_lanes::<(Command,Instant)>(config.mailbox_capacity);
+    let high_priority_threshold = config.high_priority_threshold;
+    let mailbox_depth = mailbox_handler.mailbox_depth.clone();
+    mailbox_depth.store(0, Ordering::SeqCst);
+    metrics.set_gauge(MAILBOX_DEPTH, 0);
 
-    let outbound = create_output_stream(client_id, command_box, rx);
+    let outbound = create_output_stream(client_id, command_box, rx, mailbox_depth.clone(), config.clone(), axon_connection.clone());
 
     debug!("Command worker: calling open_stream");
-    let response = client.open_stream(Request::new(outbound)).await?;
+    let response = match client.open_stream(Request::new(outbound)).await {
+        Ok(response) => response,
+        Err(e) => return Ok((mailbox_handler, Some(health.stream_failed(WORKER_NAME, e).into()))),
+    };
     debug!("Stream response: {:?}", response);
+    health.report(WORKER_NAME, WorkerHealth::Running);
+    *established = true;
+
+    let stop = Arc::new(Notify::new());
+    let mailbox = tokio::spawn(mailbox_handler.run(mailbox_rx, tx.clone(), stop.clone()).in_current_span());
 
     let mut inbound = response.into_inner();
-    loop {
-        match inbound.message().await {
+    let error = loop {
+        let message = tokio::select! {
+            message = inbound.message() => message,
+            _ = reconnect.requested_since(generation) => break None,
+        };
+        match message {
             Ok(Some(inbound)) => {
-                debug!("Inbound message: {:?}", inbound);
+                debug!("Inbound message: {:?}", log_safe(&inbound));
                 if let Some(command_provider_inbound::Request::Command(command)) = inbound.request {
-                    let command_name = command.name.clone();
-                    let mut result = Err(anyhow!("Could not find aggregate handler"));
-                    if let Some(aggregate_name) = command_to_aggregate_mapping.get(&command_name) {
-                        if let Some(aggregate_definition) = aggregate_registry.get(aggregate_name) {
-                            result = aggregate_definition.handle(&command, &mut event_store_client).await
+                    let lane = PriorityLane::for_priority(message_priority(&command.processing_instructions), high_priority_threshold);
+                    debug!("Command worker: lane: {:?}: {:?}", lane, command.name);
+                    let depth = mailbox_depth.fetch_add(1, Ordering::SeqCst) + 1;
+                    match mailbox_tx.try_send(lane, (command, Instant::now())) {
+                        Ok(()) => metrics.set_gauge(MAILBOX_DEPTH, depth as i64),
+                        Err(TrySendError::Full((command, received))) => {
+                            mailbox_depth.fetch_sub(1, Ordering::SeqCst);
+                            warn!("Command worker: mailbox full: reject command: {:?}: {:?}", command.name, command.message_identifier);
+                            metrics.increment(COMMANDS_SHED, 1);
+                            let axon_command_result = AxonCommandResult {
+                                message_identifier: command.message_identifier,
+                                received,
+                                result: Err(BusyError.into()),
+                            };
+                            if tx.send(axon_command_result).await.is_err() {
+                                break Some(anyhow!("Command worker: output stream closed"));
+                            }
+                        }
+                        Err(TrySendError::Closed(_)) => {
+                            break Some(anyhow!("Command worker: mailbox closed"));
                         }
                     }
-
-                    match result.as_ref() {
-                        Err(e) => warn!("Error while handling command: {:?}", e),
-                        Ok(result) => debug!("Result from command handler: {:?}", result),
-                    }
-
-                    let axon_command_result = AxonCommandResult {
-                        message_identifier: command.message_identifier,
-                        result
-                    };
-                    tx.send(axon_command_result).await.unwrap();
                 }
             }
             Ok(None) => {
-                debug!("None incoming");
+                debug!("Command worker: stream closed by AxonServer");
+                break Some(health.stream_failed(WORKER_NAME, Status::unavailable("Command stream closed by AxonServer")).into());
             }
             Err(e) => {
                 error!("Error from AxonServer: {:?}", e);
-                return Err(anyhow!(e.code()));
+                break Some(health.stream_failed(WORKER_NAME, e).into());
+            }
+        }
+    };
+
+    // AxonServer no longer waits for the results of the commands that are still in the mailbox, and may have routed
+    // them to another instance, so the mailbox handler drops them.
+    stop.notify();
+    drop(mailbox_tx);
+    match mailbox.await {
+        Ok(mailbox_handler) => Ok((mailbox_handler, error)),
+        Err(e) => Err(anyhow!("Command worker: mailbox handler failed: {:?}: after: {:?}", e, error)),
+    }
+}
+
+struct MailboxHandler {
+    aggregate_registry: TheAggregateRegistry,
+    command_to_aggregate_mapping: HashMap<String,String>,
+    event_store_client: EventStoreClient<Channel>,
+    context: Option<String>,
+    mailbox_depth: Arc<AtomicUsize>,
+    metrics: Metrics,
+    poison_threshold: u32,
+    quarantine_store: Arc<dyn QuarantineStore>,
+    failures: HashMap<String,u32>,
+    slow_handler: SlowHandlerThresholds,
+    max_payload_size: Option<usize>,
+    audit_store: Option<Arc<dyn CommandAuditStore>>,
+}
+
+impl MailboxHandler {
+    // Stops when the mailbox is closed, when the output stream is closed, or when the stream to AxonServer failed.
+    async fn run(mut self, mut mailbox_rx: LaneReceivers<(Command,Instant)>, mut tx: Sender<AxonCommandResult>, stop: Arc<Notify>) -> Self {
+        loop {
+            let (command, received) = tokio::select! {
+                next = mailbox_rx.recv() => match next {
+                    Some(next) => next,
+                    None => break,
+                },
+                _ = stop.notified() => break,
+            };
+            let started = Instant::now();
+            let span = debug_span!("command", name = %command.name, message_identifier = %command.message_identifier);
+            let result = self.handle(&command).instrument(span).await;
+            if let Some(labels) = self.handler_labels(&command.name) {
+                labels.record(&self.metrics, "command", &command.name, started.elapsed(), result.is_ok());
+            }
+            self.slow_handler.check(WORKER_NAME, &self.metrics, &command.name, message_routing_key(&command.processing_instructions).as_deref(), started.elapsed());
+
+            match result.as_ref() {
+                Err(e) => warn!("Error while handling command: {:?}", e),
+                Ok(result) => debug!("Result from command handler: {:?}", log_safe(result)),
+            }
+            self.audit(&command, &result).await;
+            let depth = self.mailbox_depth.fetch_sub(1, Ordering::SeqCst) - 1;
+            self.metrics.set_gauge(MAILBOX_DEPTH, depth as i64);
+            self.metrics.increment(COMMANDS_HANDLED, 1);
+
+            let axon_command_result = AxonCommandResult {
+                message_identifier: command.message_identifier,
+                received,
+                result
+            };
+            let sent = tokio::select! {
+                result = tx.send(axon_command_result) => result.is_ok(),
+                _ = stop.notified() => false,
+            };
+            if !sent {
+                debug!("Command worker: output stream closed");
+                break;
             }
         }
+        debug!("Command worker: mailbox: stop");
+        self
+    }
+
+    async fn audit(&self, command: &Command, result: &Result<CommandOutcome>) {
+        let audit_store = match &self.audit_store {
+            Some(audit_store) => audit_store,
+            None => return,
+        };
+        let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|now| now.as_millis() as i64).unwrap_or(0);
+        let record = CommandAuditRecord {
+            message_identifier: command.message_identifier.clone(),
+            command_name: command.name.clone(),
+            correlation_id: correlation_id(command),
+            timestamp,
+            error: result.as_ref().err().map(|e| format!("{:?}", e)),
+        };
+        if let Err(e) = audit_store.record(record).await {
+            warn!("Failed to record command in audit store: {:?}: {:?}", command.message_identifier, e);
+        }
+    }
+
+    fn handler_labels(&self, command_name: &str) -> Option<HandlerLabels> {
+        self.command_to_aggregate_mapping.get(command_name)
+            .and_then(|aggregate_name| self.aggregate_registry.get(aggregate_name))
+            .and_then(|aggregate_definition| aggregate_definition.handler_labels(command_name))
+    }
+
+    async fn handle(&mut self, command: &Command) -> Result<CommandOutcome> {
+        let key = quarantine_key(command);
+        if self.quarantine_store.is_quarantined(&key).await? {
+            warn!("Command worker: reject quarantined command: {:?}: {:?}", command.name, key);
+            return Err(QuarantinedError { key }.into());
+        }
+        if let Some(payload) = command.payload.as_ref() {
+            check_payload_size(payload, self.max_payload_size)?;
+        }
+
+        let aggregate_registry = &self.aggregate_registry;
+        let aggregate_definition = self.command_to_aggregate_mapping.get(&command.name)
+            .and_then(|aggregate_name| aggregate_registry.get(aggregate_name))
+            .ok_or_else(|| anyhow!("Could not find aggregate handler"))?;
+        #[cfg(feature = "fault-injection")]
+        {
+            if fault_injector().inject(FaultTarget::Command).await? {
+                return Err(DroppedCommand.into());
+            }
+        }
+        let outcome = AssertUnwindSafe(aggregate_definition.handle_in_context(command, &mut self.event_store_client, self.context.as_deref(), &self.metrics))
+            .catch_unwind()
+            .await;
+        let panic = match outcome {
+            Ok(result) => {
+                self.failures.remove(&key);
+                return result;
+            }
+            Err(panic) => panic_message(panic),
+        };
+
+        error!("Command handler crashed: {:?}: {}", command.name, panic);
+        let failures = self.failures.entry(key.clone()).or_insert(0);
+        *failures += 1;
+        let failures = *failures;
+        if failures < self.poison_threshold {
+            return Err(anyhow!("Command handler crashed: {}", panic));
+        }
+
+        self.failures.remove(&key);
+        self.metrics.increment(COMMANDS_QUARANTINED, 1);
+        self.quarantine_store.quarantine(QuarantinedCommand {
+            key: key.clone(),
+            command_name: command.name.clone(),
+            message_identifier: command.message_identifier.clone(),
+            payload: command.payload.clone(),
+            failures,
+            last_error: panic,
+        }).await?;
+        Err(QuarantinedError { key }.into())
+    }
+}
+
+#[cfg(feature = "fault-injection")]
+fn is_dropped(result: &Result<CommandOutcome>) -> bool {
+    matches!(result, Err(e) if e.is::<DroppedCommand>())
+}
+
+#[cfg(not(feature = "fault-injection"))]
+fn is_dropped(_result: &Result<CommandOutcome>) -> bool {
+    false
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Unknown panic".to_string()
     }
 }
 
-fn create_output_stream(client_id: String, command_box: Box<Vec<String>>, mut rx: Receiver<AxonCommandResult>) -> impl Stream<Item = CommandProviderOutbound> {
+fn create_output_stream(
+    client_id: String,
+    command_box: Box<Vec<String>>,
+    mut rx: Receiver<AxonCommandResult>,
+    mailbox_depth: Arc<AtomicUsize>,
+    config: CommandWorkerConfig,
+    axon_connection: AxonConnection
+) -> impl Stream<Item = CommandProviderOutbound> {
+    let metrics = axon_connection.metrics.clone();
+    let health = axon_connection.health.clone();
+    let max_message_size = axon_connection.max_message_size();
     stream! {
         debug!("Command worker: stream: start: {:?}", rx);
         for command_name in command_box.iter() {
@@ -301,7 +1074,7 @@ fn create_output_stream(client_id: String, command_box: Box<Vec<String>>, mut rx
                 message_id: format!("{:?}", subscription_id.to_simple()),
                 command: command_name.to_string().clone(),
                 client_id: client_id.clone(),
-                component_name: client_id.clone(),
+                component_name: axon_connection.component_name.clone(),
                 load_factor: 100,
             };
             debug!("Subscribe command: Subscription: {:?}", subscription);
@@ -314,22 +1087,46 @@ fn create_output_stream(client_id: String, command_box: Box<Vec<String>>, mut rx
             yield instruction.to_owned();
         }
 
-        let permits_batch_size: i64 = 3;
-        let mut permits = permits_batch_size * 2;
-        debug!("Command worker: stream: send initial flow-control permits: amount: {:?}", permits);
-        let flow_control = FlowControl {
-            client_id: client_id.clone(),
-            permits,
-        };
-        let instruction_id = Uuid::new_v4();
-        let instruction = CommandProviderOutbound {
-            instruction_id: format!("{:?}", instruction_id.to_simple()),
-            request: Some(command_provider_outbound::Request::FlowControl(flow_control)),
-        };
-        yield instruction.to_owned();
+        let pause_switch = config.pause_switch.clone();
+        let mut permit_controller = PermitController::new(config.flow_control.clone());
+        if pause_switch.is_paused() {
+            health.report(WORKER_NAME, WorkerHealth::Paused);
+        } else {
+            let permits = permit_controller.window();
+            permit_controller.granted(permits);
+            metrics.set_gauge(PERMIT_WINDOW, permits);
+            metrics.set_gauge(PERMITS_OUTSTANDING, permit_controller.outstanding());
+            debug!("Command worker: stream: send initial flow-control permits: amount: {:?}", permits);
+            yield flow_control_instruction(&client_id, permits);
+        }
 
-        while let Some(axon_command_result) = rx.recv().await {
-            debug!("Send command response: {:?}", axon_command_result);
+        loop {
+            let next = tokio::select! {
+                result = rx.recv() => Some(result),
+                _ = pause_switch.changed() => None,
+            };
+            let axon_command_result = match next {
+                Some(Some(axon_command_result)) => axon_command_result,
+                Some(None) => break,
+                None if pause_switch.is_paused() => {
+                    warn!("Command worker: stream: paused: withhold flow-control permits");
+                    health.report(WORKER_NAME, WorkerHealth::Paused);
+                    continue;
+                }
+                None => {
+                    warn!("Command worker: stream: resumed");
+                    health.report(WORKER_NAME, WorkerHealth::Running);
+                    let permits = permit_controller.permits_due();
+                    if permits > 0 {
+                        debug!("Command worker: stream: send flow-control permits after resume: amount: {:?}", permits);
+                        yield flow_control_instruction(&client_id, permits);
+                        permit_controller.granted(permits);
+                    }
+                    continue;
+                }
+            };
+            debug!("Send command response: {:?}: {:?}", axon_command_result.message_identifier, axon_command_result.result.as_ref().map(log_safe));
+            let dropped = is_dropped(&axon_command_result.result);
             let response_id = Uuid::new_v4();
             let mut response = CommandResponse {
                 message_identifier: format!("{:?}", response_id.to_simple()),
@@ -340,18 +1137,29 @@ fn create_output_stream(client_id: String, command_box: Box<Vec<String>>, mut rx
                 meta_data: HashMap::new(),
                 processing_instructions: Vec::new(),
             };
-            match axon_command_result.result {
-                Ok(result) => {
-                    response.payload = result.map(|r| r.response).flatten();
+            let result = axon_command_result.result
+                .map(CommandOutcome::response)
+                .and_then(|payload| {
+                    if let Some(payload) = payload.as_ref() {
+                        check_message_size("CommandResponse", payload, max_message_size)?;
+                    }
+                    Ok(payload)
+                });
+            match result {
+                Ok(payload) => {
+                    response.payload = payload;
                 }
                 Err(e) => {
-                    response.error_code = "ERROR".to_string();
-                    response.error_message = Some(ErrorMessage {
-                        message: e.to_string(),
-                        location: "".to_string(),
-                        details: Vec::new(),
-                        error_code: "ERROR".to_string(),
-                    });
+                    let (kind, handler_error_code) = if e.is::<BusyError>() {
+                        (HandlerErrorKind::Transient, Some(BUSY_ERROR_CODE))
+                    } else if e.is::<QuarantinedError>() {
+                        (HandlerErrorKind::Technical, Some(QUARANTINED_ERROR_CODE))
+                    } else {
+                        (classify_handler_error(&e), business_rule_error(&e).map(|rule| rule.error_code.as_str()))
+                    };
+                    let error_code = kind.command_error_code();
+                    response.error_code = error_code.to_string();
+                    response.error_message = Some(error_message(&e, handler_error_code.unwrap_or(error_code), &client_id));
                 }
             }
             let instruction_id = Uuid::new_v4();
@@ -359,42 +1167,64 @@ fn create_output_stream(client_id: String, command_box: Box<Vec<String>>, mut rx
                 instruction_id: format!("{:?}", instruction_id.to_simple()),
                 request: Some(command_provider_outbound::Request::CommandResponse(response)),
             };
-            yield instruction.to_owned();
-            permits -= 1;
-            if permits <= permits_batch_size {
-                debug!("Command worker: stream: send more flow-control permits: amount: {:?}", permits_batch_size);
-                let flow_control = FlowControl {
-                    client_id: client_id.clone(),
-                    permits: permits_batch_size,
-                };
-                let instruction_id = Uuid::new_v4();
-                let instruction = CommandProviderOutbound {
-                    instruction_id: format!("{:?}", instruction_id.to_simple()),
-                    request: Some(command_provider_outbound::Request::FlowControl(flow_control)),
-                };
+            if dropped {
+                debug!("Command worker: stream: drop command response: {:?}", log_safe(&instruction));
+            } else {
                 yield instruction.to_owned();
-                permits += permits_batch_size;
             }
-            debug!("Command worker: stream: flow-control permits: balance: {:?}", permits);
+            let latency = axon_command_result.received.elapsed();
+            let depth = mailbox_depth.load(Ordering::SeqCst);
+            permit_controller.record_response(latency, depth);
+            metrics.set_gauge(HANDLER_LATENCY_MS, latency.as_millis() as i64);
+            metrics.set_gauge(PERMIT_WINDOW, permit_controller.window());
+            let permits = permit_controller.permits_due();
+            if permits > 0 && pause_switch.is_paused() {
+                debug!("Command worker: stream: withhold flow-control permits: paused");
+                metrics.increment(PERMITS_WITHHELD, 1);
+            } else if permits > 0 && depth >= config.withhold_permits_depth {
+                debug!("Command worker: stream: withhold flow-control permits: mailbox depth: {:?}", depth);
+                metrics.increment(PERMITS_WITHHELD, 1);
+            } else if permits > 0 {
+                debug!("Command worker: stream: send more flow-control permits: amount: {:?}", permits);
+                yield flow_control_instruction(&client_id, permits);
+                permit_controller.granted(permits);
+            }
+            metrics.set_gauge(PERMITS_OUTSTANDING, permit_controller.outstanding());
+            debug!("Command worker: stream: flow-control permits: balance: {:?}", permit_controller.outstanding());
         }
 
         // debug!("Command worker: stream: stop");
     }
 }
 
-async fn store_events<P: std::fmt::Debug>(client: &mut EventStoreClient<Channel>, aggregate_id: &str, events: &EmitApplicableEventsAndResponse<P>) -> Result<()>{
-    debug!("Client: {:?}: events: {:?}", client, events);
-    let request = ReadHighestSequenceNrRequest {
-        aggregate_id: aggregate_id.to_string(),
-        from_sequence_nr: 0,
+fn flow_control_instruction(client_id: &str, permits: i64) -> CommandProviderOutbound {
+    let flow_control = FlowControl {
+        client_id: client_id.to_string(),
+        permits,
     };
-    let response = client.read_highest_sequence_nr(request).await?.into_inner();
+    let instruction_id = Uuid::new_v4();
+    CommandProviderOutbound {
+        instruction_id: format!("{:?}", instruction_id.to_simple()),
+        request: Some(command_provider_outbound::Request::FlowControl(flow_control)),
+    }
+}
 
-    let message_identifier = Uuid::new_v4();
+/// Stores the events after the given sequence number and returns the sequence number of the last one.
+async fn store_events<P: std::fmt::Debug>(
+    client: &mut EventStoreClient<Channel>,
+    aggregate_type: &str,
+    aggregate_id: &str,
+    after_sequence_nr: i64,
+    events: &EmitApplicableEventsAndResponse<P>,
+    meta_data: HashMap<String,MetaDataValue>,
+    claim_check: Option<&ClaimCheck>
+) -> Result<i64>{
+    debug!("Client: {:?}: events: {:?}", client, events.log_safe_events());
     let now = std::time::SystemTime::now();
     let timestamp = now.duration_since(std::time::UNIX_EPOCH)?.as_millis() as i64;
-    let event_messages: Vec<Event> = events.events.iter().map(move |e| {
+    let mut event_messages: Vec<Event> = events.events.iter().zip(after_sequence_nr + 1..).map(move |(e, sequence_nr)| {
         let (type_name, event) = e;
+        let message_identifier = Uuid::new_v4();
         let mut buf = Vec::new();
         event.encode_u8(&mut buf).unwrap();
         let e = SerializedObject {
@@ -406,14 +1236,47 @@ async fn store_events<P: std::fmt::Debug>(client: &mut EventStoreClient<Channel>
             message_identifier: format!("{:?}", message_identifier.to_simple()),
             timestamp,
             aggregate_identifier: aggregate_id.to_string(),
-            aggregate_sequence_number: response.to_sequence_nr + 1,
-            aggregate_type: "Greeting".to_string(),
+            aggregate_sequence_number: sequence_nr,
+            aggregate_type: aggregate_type.to_string(),
             payload: Some(e),
-            meta_data: HashMap::new(),
+            meta_data: meta_data.clone(),
             snapshot: false,
         }
     }).collect();
+    if let Some(claim_check) = claim_check {
+        for event in event_messages.iter_mut() {
+            claim_check.check_in_event(event).await?;
+        }
+    }
+    let last_sequence_nr = event_messages.last().map(|event| event.aggregate_sequence_number).unwrap_or(-1);
     let request = Request::new(futures_util::stream::iter(event_messages));
-    client.append_event(request).await?;
+    #[cfg(feature = "fault-injection")]
+    {
+        if fault_injector().inject(FaultTarget::Append).await? {
+            return Ok(last_sequence_nr);
+        }
+    }
+    check_writable("append events")?;
+    client.append_event(request).await.map_err(explain_status)?;
+    Ok(last_sequence_nr)
+}
+
+/// Applies the events to the projection and stores the result as a snapshot of the aggregate.
+async fn store_snapshot<P: VecU8Message + Send + Clone>(
+    client: &mut EventStoreClient<Channel>,
+    snapshot_config: &SnapshotConfig<P>,
+    aggregate_type: &str,
+    aggregate_id: &str,
+    sequence_nr: i64,
+    mut projection: P,
+    events: &EmitApplicableEventsAndResponse<P>
+) -> Result<()> {
+    for (_, event) in &events.events {
+        event.apply_to(&mut projection)?;
+    }
+    let snapshot = snapshot_config.snapshot_event(aggregate_type, aggregate_id, sequence_nr, &projection)?;
+    debug!("Store snapshot: {:?}: {:?}: revision: {:?}", aggregate_id, sequence_nr, snapshot_config.revision);
+    check_writable("append snapshot")?;
+    client.append_snapshot(snapshot).await.map_err(explain_status)?;
     Ok(())
 }
\ No newline at end of file
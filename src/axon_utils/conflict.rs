@@ -0,0 +1,60 @@
+use anyhow::{anyhow,Result};
+use tracing::debug;
+use std::sync::Arc;
+use super::business_rules::BusinessRuleError;
+use crate::axon_server::MetaDataValue;
+use crate::axon_server::command::Command;
+use crate::axon_server::event::Event;
+use crate::axon_server::meta_data_value::Data;
+
+/// Meta-data key of a command for the version (sequence number of the last event) of the aggregate that the sender of
+/// the command based its decision on.
+pub const EXPECTED_VERSION: &str = "expectedVersion";
+
+/// Error code for commands that are rejected because the aggregate changed since the expected version, or because they
+/// have an expected version but no aggregate identifier.
+pub const CONFLICT_ERROR_CODE: &str = "CONFLICT";
+
+/// Decides whether a command may proceed although the aggregate changed since the expected version. It receives the
+/// command and the events that were appended after the expected version.
+pub type ConflictResolver = Arc<dyn Fn(&Command, &[Event]) -> Result<bool> + Send + Sync>;
+
+pub fn expected_version_meta_data(expected_version: i64) -> MetaDataValue {
+    MetaDataValue {
+        data: Some(Data::NumberValue(expected_version)),
+    }
+}
+
+/// Returns the expected version of the aggregate from the meta-data of the command, if any.
+pub fn expected_version(command: &Command) -> Result<Option<i64>> {
+    match command.meta_data.get(EXPECTED_VERSION).and_then(|value| value.data.as_ref()) {
+        Some(Data::NumberValue(version)) => Ok(Some(*version)),
+        Some(Data::TextValue(version)) => Ok(Some(version.parse()?)),
+        Some(other) => Err(anyhow!("Invalid expected version: {:?}", other)),
+        None => Ok(None),
+    }
+}
+
+// Compares the expected version with the actual version (-1 for a new aggregate). Events are the replayed events.
+pub(crate) fn check_expected_version(command: &Command, expected_version: i64, events: &[Event], resolver: Option<&ConflictResolver>) -> Result<()> {
+    let actual_version = events.last().map(|event| event.aggregate_sequence_number).unwrap_or(-1);
+    if actual_version == expected_version {
+        return Ok(());
+    }
+    if expected_version < actual_version {
+        if let Some(resolver) = resolver {
+            let intervening: Vec<Event> = events.iter()
+                .filter(|event| event.aggregate_sequence_number > expected_version)
+                .cloned()
+                .collect();
+            debug!("Resolve conflict: {:?}: intervening events: {:?}", command.message_identifier, intervening.len());
+            if resolver(command, &intervening)? {
+                return Ok(());
+            }
+        }
+    }
+    Err(BusinessRuleError {
+        error_code: CONFLICT_ERROR_CODE.to_string(),
+        message: format!("Expected version {} of the aggregate, but found version {}", expected_version, actual_version),
+    }.into())
+}
@@ -1,63 +1,518 @@
-use anyhow::Result;
-use log::debug;
-use std::time;
-use tokio::time::delay_for;
+use anyhow::{Result,anyhow};
+use tracing::{debug,warn};
+use std::collections::HashMap;
+use std::fmt::{Debug,Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{delay_for,timeout};
 use tonic;
-use tonic::Request;
-use tonic::transport::Channel;
+use tonic::{Code,Interceptor,Request,Status};
+use tonic::metadata::{Ascii,MetadataValue};
+use tonic::transport::{Channel,Endpoint};
 use uuid::Uuid;
-use super::AxonConnection;
+use super::{AxonConnection,AxonServerHandle,create_reconnect_signal};
+use super::message_size::DEFAULT_MAX_MESSAGE_SIZE;
+use crate::axon_server::command::command_service_client::CommandServiceClient;
 use crate::axon_server::control::ClientIdentification;
 use crate::axon_server::control::platform_service_client::PlatformServiceClient;
+use crate::axon_server::event::event_store_client::EventStoreClient;
+use crate::axon_server::query::query_service_client::QueryServiceClient;
+
+/// gRPC header that selects the AxonServer context of a request.
+pub const CONTEXT_HEADER: &str = "axoniq-context";
+
+/// gRPC header that carries the access token of the client, for AxonServer with access control enabled.
+pub const ACCESS_TOKEN_HEADER: &str = "axoniq-access-token";
+
+/// Version of this client library, as reported to AxonServer.
+pub const CLIENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub const DEFAULT_AXON_SERVER_HOST: &str = "proxy";
+pub const DEFAULT_AXON_SERVER_PORT: u32 = 8124;
+
+/// Component name that is reported to AxonServer when the builder is not given one.
+pub const DEFAULT_COMPONENT_NAME: &str = "Rust client";
+
+pub type InterceptorFn = Arc<dyn Fn(Request<()>) -> Result<Request<()>,Status> + Send + Sync>;
+pub type EndpointSetup = Arc<dyn Fn(Endpoint) -> Endpoint + Send + Sync>;
+
+/// The interceptors of a connection, which are applied in order to every request of the clients of the connection.
+#[derive(Clone,Default)]
+pub struct InterceptorChain {
+    interceptors: Vec<InterceptorFn>,
+}
+
+impl Debug for InterceptorChain {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InterceptorChain")
+            .field("interceptors", &self.interceptors.len())
+            .finish()
+    }
+}
+
+impl InterceptorChain {
+    /// Returns a chain that applies the given interceptor after the interceptors of this chain, e.g., a dispatch
+    /// interceptor for the client of one kind of request.
+    pub(crate) fn with(&self, interceptor: InterceptorFn) -> Self {
+        let mut interceptors = self.interceptors.clone();
+        interceptors.push(interceptor);
+        InterceptorChain { interceptors }
+    }
+
+    /// Returns the interceptor that applies the chain, and then selects the given AxonServer context, if any.
+    // The signature of the interceptor, including the size of `Status`, is imposed by tonic.
+    #[allow(clippy::result_large_err)]
+    pub fn interceptor(&self, context: Option<&str>) -> Option<Interceptor> {
+        let context = context.map(|context| context.parse::<MetadataValue<Ascii>>()
+            .map_err(|_| Status::invalid_argument(format!("Invalid AxonServer context: {:?}", context))));
+        if self.interceptors.is_empty() && context.is_none() {
+            return None;
+        }
+        let interceptors = self.interceptors.clone();
+        Some(Interceptor::new(move |request| {
+            let mut request = interceptors.iter().try_fold(request, |request, interceptor| interceptor(request))?;
+            if let Some(context) = &context {
+                request.metadata_mut().insert(CONTEXT_HEADER, context.clone()?);
+            }
+            Ok(request)
+        }))
+    }
+}
+
+/// Settings for the connection to AxonServer.
+///
+/// The interceptors are applied, in order, to every request of every client that is created for the connection, e.g.,
+/// to add authentication headers or to log requests. The endpoint setup can adjust the channel before it connects
+/// (timeouts, keep-alive, TLS). Arbitrary tower layers are not supported, because all clients are built on a plain
+/// `Channel`; an interceptor covers the common cases of adding metadata, logging and counting requests.
+///
+/// Commands, queries, responses and events that are larger than `max_message_size` are refused with a
+/// `MessageTooLargeError` before they are sent, instead of being rejected by AxonServer. Set it to the
+/// `axoniq.axonserver.max-message-size` of the server. The tonic version that is used can neither limit the size of
+/// the messages that the clients receive, nor compress them with gzip, so the connection has no settings for those.
+/// Limit inbound payloads per worker instead, with the `max_payload_size` of `CommandWorkerConfig`,
+/// `QueryProcessorConfig` and `EventProcessorConfig`, which is checked before a payload is decoded.
+///
+/// The tags (e.g., region, version, capability flags) are sent to AxonServer in the client identification, so that
+/// tag-based routing and the dashboards of AxonServer EE can distinguish between nodes.
+///
+/// Requests go to the `context` of the connection, or to the default context of AxonServer. A single connection can
+/// serve several bounded contexts: `AxonServerHandle::with_context` and `AxonConnection::with_context` return a handle
+/// that shares the channel, but sends its requests to another context.
+///
+/// When AxonServer requires an access token (`axoniq.axonserver.accesscontrol.enabled`), set it with
+/// `with_access_token`. The token is added to every request, including the requests of the command, query, event
+/// store and platform clients, and is never logged.
+#[derive(Clone)]
+pub struct ConnectionConfig {
+    pub interceptors: Vec<InterceptorFn>,
+    pub endpoint_setup: Option<EndpointSetup>,
+    pub max_message_size: Option<usize>,
+    pub tags: HashMap<String,String>,
+    pub context: Option<String>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        ConnectionConfig {
+            interceptors: Vec::new(),
+            endpoint_setup: None,
+            max_message_size: Some(DEFAULT_MAX_MESSAGE_SIZE),
+            tags: HashMap::new(),
+            context: None,
+        }
+    }
+}
+
+impl Debug for ConnectionConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionConfig")
+            .field("interceptors", &self.interceptors.len())
+            .field("endpoint_setup", &self.endpoint_setup.is_some())
+            .field("max_message_size", &self.max_message_size)
+            .field("tags", &self.tags)
+            .field("context", &self.context)
+            .finish()
+    }
+}
+
+impl ConnectionConfig {
+    pub fn with_interceptor(mut self, interceptor: impl Fn(Request<()>) -> Result<Request<()>,Status> + Send + Sync + 'static) -> Self {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    pub fn with_endpoint_setup(mut self, endpoint_setup: impl Fn(Endpoint) -> Endpoint + Send + Sync + 'static) -> Self {
+        self.endpoint_setup = Some(Arc::new(endpoint_setup));
+        self
+    }
+
+    /// Sends all requests to the given AxonServer context, instead of the default context, e.g., to bind aggregate
+    /// definitions to the event store of their own bounded context.
+    pub fn with_context(mut self, context: &str) -> Self {
+        self.context = Some(context.to_string());
+        self
+    }
+
+    /// Authenticates all requests with the given access token, e.g., the token of an application in AxonServer EE or
+    /// the `axoniq.axonserver.accesscontrol.token` of AxonServer SE.
+    #[allow(clippy::result_large_err)]
+    pub fn with_access_token(self, access_token: &str) -> Self {
+        let value = access_token.parse::<MetadataValue<Ascii>>()
+            .map_err(|_| Status::unauthenticated("Invalid AxonServer access token"));
+        self.with_interceptor(move |mut request| {
+            request.metadata_mut().insert(ACCESS_TOKEN_HEADER, value.clone()?);
+            Ok(request)
+        })
+    }
+
+    pub fn with_max_message_size(mut self, max_message_size: Option<usize>) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    pub fn with_tag(mut self, key: &str, value: &str) -> Self {
+        self.tags.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    fn interceptor_chain(&self) -> InterceptorChain {
+        InterceptorChain {
+            interceptors: self.interceptors.clone(),
+        }
+    }
+}
+
+/// Settings of the endpoint of AxonServer and of the identity of the client, on top of the `ConnectionConfig`.
+///
+/// The client id identifies this instance; it is the `display_name` of the handle, and a random id by default. The
+/// component name identifies the application: AxonServer groups the instances of a component in its dashboard, and
+/// sends each query to one instance per component. It is reported by all command, query and event streams of the
+/// connection.
+///
+/// Until AxonServer answers, the builder tries to connect again after `retry_interval`. Each attempt to open the
+/// channel is abandoned after `connect_timeout`, if set.
+///
+/// With `with_environment`, the settings are read from environment variables, where present:
+/// `AXON_SERVER_HOST`, `AXON_SERVER_PORT`, `AXON_SERVER_CLIENT_ID`, `AXON_SERVER_COMPONENT_NAME`,
+/// `AXON_SERVER_CONNECT_TIMEOUT_MS`, `AXON_SERVER_RETRY_INTERVAL_MS`, `AXON_SERVER_CONTEXT` and `AXON_SERVER_TOKEN`.
+#[derive(Debug,Clone)]
+pub struct AxonConnectionBuilder {
+    pub host: String,
+    pub port: u32,
+    pub client_id: Option<String>,
+    pub component_name: String,
+    pub connect_timeout: Option<Duration>,
+    pub retry_interval: Duration,
+    pub config: ConnectionConfig,
+}
+
+impl Default for AxonConnectionBuilder {
+    fn default() -> Self {
+        create_axon_connection_builder()
+    }
+}
+
+pub fn create_axon_connection_builder() -> AxonConnectionBuilder {
+    AxonConnectionBuilder {
+        host: DEFAULT_AXON_SERVER_HOST.to_string(),
+        port: DEFAULT_AXON_SERVER_PORT,
+        client_id: None,
+        component_name: DEFAULT_COMPONENT_NAME.to_string(),
+        connect_timeout: None,
+        retry_interval: Duration::from_secs(1),
+        config: ConnectionConfig::default(),
+    }
+}
+
+impl AxonConnectionBuilder {
+    pub fn with_host(mut self, host: &str) -> Self {
+        self.host = host.to_string();
+        self
+    }
+
+    pub fn with_port(mut self, port: u32) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn with_client_id(mut self, client_id: &str) -> Self {
+        self.client_id = Some(client_id.to_string());
+        self
+    }
+
+    pub fn with_component_name(mut self, component_name: &str) -> Self {
+        self.component_name = component_name.to_string();
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    pub fn with_retry_interval(mut self, retry_interval: Duration) -> Self {
+        self.retry_interval = retry_interval;
+        self
+    }
+
+    pub fn with_config(mut self, config: ConnectionConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Overrides the settings with the environment variables that are set (see `AxonConnectionBuilder`).
+    pub fn with_environment(mut self) -> Result<Self> {
+        if let Some(host) = env_var("AXON_SERVER_HOST") {
+            self.host = host;
+        }
+        if let Some(port) = env_var("AXON_SERVER_PORT") {
+            self.port = port.parse().map_err(|e| anyhow!("Invalid AXON_SERVER_PORT: {:?}: {:?}", port, e))?;
+        }
+        if let Some(client_id) = env_var("AXON_SERVER_CLIENT_ID") {
+            self.client_id = Some(client_id);
+        }
+        if let Some(component_name) = env_var("AXON_SERVER_COMPONENT_NAME") {
+            self.component_name = component_name;
+        }
+        if let Some(millis) = env_var("AXON_SERVER_CONNECT_TIMEOUT_MS") {
+            self.connect_timeout = Some(parse_millis("AXON_SERVER_CONNECT_TIMEOUT_MS", &millis)?);
+        }
+        if let Some(millis) = env_var("AXON_SERVER_RETRY_INTERVAL_MS") {
+            self.retry_interval = parse_millis("AXON_SERVER_RETRY_INTERVAL_MS", &millis)?;
+        }
+        if let Some(context) = env_var("AXON_SERVER_CONTEXT") {
+            self.config = self.config.with_context(&context);
+        }
+        if let Some(access_token) = env_var("AXON_SERVER_TOKEN") {
+            self.config = self.config.with_access_token(&access_token);
+        }
+        Ok(self)
+    }
+
+    /// Connects to AxonServer, and waits until it is available.
+    pub async fn connect(self) -> Result<AxonConnection> {
+        let url = format!("http://{}:{}", self.host, self.port);
+        let id = self.client_id.clone().unwrap_or_else(|| format!("{:?}", Uuid::new_v4().to_simple()));
+        let interceptors = self.config.interceptor_chain();
+        let interceptor = interceptors.interceptor(self.config.context.as_deref());
+        let (conn, server_version) = wait_for_connection(&url, &id, &self, &interceptor).await;
+        debug!("Connection: {:?}: server version: {:?}", conn, server_version);
+        let reconnect = create_reconnect_signal(endpoint(&url, &self.config).ok(), self.connect_timeout);
+        let connection = AxonConnection {
+            id,
+            component_name: self.component_name,
+            conn,
+            interceptors,
+            context: self.config.context,
+            max_message_size: self.config.max_message_size,
+            health: Default::default(),
+            reconnect,
+            metrics: Default::default(),
+            query_updates: Default::default(),
+            tags: self.config.tags,
+            server_version,
+        };
+        Ok(connection)
+    }
+
+    pub async fn connect_handle(self) -> Result<AxonServerHandle> {
+        self.connect().await.map(AxonServerHandle::from)
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn parse_millis(name: &str, millis: &str) -> Result<Duration> {
+    let millis = millis.parse().map_err(|e| anyhow!("Invalid {}: {:?}: {:?}", name, millis, e))?;
+    Ok(Duration::from_millis(millis))
+}
 
 pub async fn wait_for_server(host: &str, port: u32, label: &str) -> Result<AxonConnection> {
-    let url = format!("http://{}:{}", host, port);
-    let conn = wait_for_connection(&url, label).await;
-    debug!("Connection: {:?}", conn);
-    let uuid = Uuid::new_v4();
-    let connection = AxonConnection {
-        id: format!("{:?}", uuid.to_simple()),
-        conn
-    };
-    Ok(connection)
+    wait_for_server_with_config(host, port, label, ConnectionConfig::default()).await
 }
 
-async fn wait_for_connection(url: &str, label: &str) -> Channel {
-    let interval = time::Duration::from_secs(1);
+pub async fn wait_for_server_with_config(host: &str, port: u32, label: &str, config: ConnectionConfig) -> Result<AxonConnection> {
+    create_axon_connection_builder()
+        .with_host(host)
+        .with_port(port)
+        .with_component_name(&format!("{} {}", DEFAULT_COMPONENT_NAME, label))
+        .with_config(config)
+        .connect()
+        .await
+}
+
+async fn wait_for_connection(url: &str, client_id: &str, builder: &AxonConnectionBuilder, interceptor: &Option<Interceptor>) -> (Channel,Option<i32>) {
     loop {
-        if let Some(conn)= try_to_connect(url, label).await {
-            return conn;
+        if let Some(connected) = try_to_connect(url, client_id, builder, interceptor).await {
+            return connected;
         }
-        delay_for(interval).await;
-        continue;
+        delay_for(builder.retry_interval).await;
     }
 }
 
-async fn try_to_connect(url: &str, label: &str) -> Option<Channel> {
-    connect(url, label).await
+async fn try_to_connect(url: &str, client_id: &str, builder: &AxonConnectionBuilder, interceptor: &Option<Interceptor>) -> Option<(Channel,Option<i32>)> {
+    connect(url, client_id, builder, interceptor).await
         .map_err(|e| {
             debug!("Error while trying to connect to AxonServer: {:?}", e);
         })
         .ok().flatten()
 }
 
-async fn connect(url: &str, label: &str) -> Result<Option<Channel>> {
-    let conn = tonic::transport::Endpoint::from_shared(url.to_string())?.connect().await
-        .map_err(|_| debug!(". Can't connect to AxonServer (yet)"))
-        .ok();
+async fn connect(url: &str, client_id: &str, builder: &AxonConnectionBuilder, interceptor: &Option<Interceptor>) -> Result<Option<(Channel,Option<i32>)>> {
+    let config = &builder.config;
+    let endpoint = endpoint(url, config)?;
+    let conn = match builder.connect_timeout {
+        Some(connect_timeout) => timeout(connect_timeout, endpoint.connect()).await
+            .map_err(|_| debug!(". Timeout while connecting to AxonServer"))
+            .ok()
+            .and_then(|conn| conn.map_err(|_| debug!(". Can't connect to AxonServer (yet)")).ok()),
+        None => endpoint.connect().await
+            .map_err(|_| debug!(". Can't connect to AxonServer (yet)"))
+            .ok(),
+    };
     let conn = match conn {
         Some(conn) => conn,
         None => { return Ok(None) },
     };
-    let mut client = PlatformServiceClient::new(conn.clone());
-    let mut client_identification = ClientIdentification::default();
-    client_identification.component_name = format!("Rust client {}", &*label);
+    let mut client = match interceptor {
+        Some(interceptor) => PlatformServiceClient::with_interceptor(conn.clone(), interceptor.clone()),
+        None => PlatformServiceClient::new(conn.clone()),
+    };
+    let client_identification = ClientIdentification {
+        client_id: client_id.to_string(),
+        component_name: builder.component_name.clone(),
+        tags: config.tags.clone(),
+        version: CLIENT_VERSION.to_string(),
+    };
     let response = client.get_platform_server(Request::new(client_identification)).await
-        .map_err(|_| debug!(". AxonServer is not available (yet)"))
+        .map_err(|status| match status.code() {
+            Code::Unauthenticated | Code::PermissionDenied => warn!("AxonServer refused the access token: {:?}", status.message()),
+            _ => debug!(". AxonServer is not available (yet)"),
+        })
         .ok();
-    if response.is_none() {
-        return Ok(None);
+    let platform_info = match response {
+        Some(response) => response.into_inner(),
+        None => { return Ok(None) },
+    };
+    debug!("Platform info: {:?}", platform_info);
+    let server_version = platform_info.primary.map(|node_info| node_info.version);
+    return Ok(Some((conn, server_version)));
+}
+
+fn endpoint(url: &str, config: &ConnectionConfig) -> Result<Endpoint> {
+    let mut endpoint = Endpoint::from_shared(url.to_string())?;
+    if let Some(endpoint_setup) = &config.endpoint_setup {
+        endpoint = endpoint_setup(endpoint);
+    }
+    Ok(endpoint)
+}
+
+/// Creates clients for the AxonServer services that share a connection, with the interceptors of the connection.
+///
+/// Once AxonServer requested a reconnect, the clients use the channel that the `ReconnectSignal` of the connection
+/// rebuilt, instead of the original channel.
+pub trait AxonClients {
+    fn channel(&self) -> Channel;
+    fn client_interceptor(&self) -> Option<Interceptor>;
+    fn max_message_size(&self) -> Option<usize>;
+
+    fn command_client(&self) -> CommandServiceClient<Channel> {
+        match self.client_interceptor() {
+            Some(interceptor) => CommandServiceClient::with_interceptor(self.channel(), interceptor),
+            None => CommandServiceClient::new(self.channel()),
+        }
+    }
+
+    fn query_client(&self) -> QueryServiceClient<Channel> {
+        match self.client_interceptor() {
+            Some(interceptor) => QueryServiceClient::with_interceptor(self.channel(), interceptor),
+            None => QueryServiceClient::new(self.channel()),
+        }
+    }
+
+    fn event_store_client(&self) -> EventStoreClient<Channel> {
+        match self.client_interceptor() {
+            Some(interceptor) => EventStoreClient::with_interceptor(self.channel(), interceptor),
+            None => EventStoreClient::new(self.channel()),
+        }
+    }
+
+    fn platform_client(&self) -> PlatformServiceClient<Channel> {
+        match self.client_interceptor() {
+            Some(interceptor) => PlatformServiceClient::with_interceptor(self.channel(), interceptor),
+            None => PlatformServiceClient::new(self.channel()),
+        }
+    }
+}
+
+impl AxonServerHandle {
+    /// Returns a handle that shares the connection, health and metrics of this handle, but sends its requests to the
+    /// given AxonServer context.
+    pub fn with_context(&self, context: &str) -> Self {
+        AxonServerHandle {
+            context: Some(context.to_string()),
+            ..self.clone()
+        }
+    }
+}
+
+impl AxonConnection {
+    /// Returns a connection that shares the channel, health and metrics of this connection, but sends its requests to
+    /// the given AxonServer context, e.g., for a command worker of another bounded context.
+    pub fn with_context(&self, context: &str) -> Self {
+        AxonConnection {
+            context: Some(context.to_string()),
+            ..self.clone()
+        }
+    }
+}
+
+impl From<AxonConnection> for AxonServerHandle {
+    fn from(connection: AxonConnection) -> Self {
+        AxonServerHandle {
+            display_name: connection.id,
+            component_name: connection.component_name,
+            conn: connection.conn,
+            interceptors: connection.interceptors,
+            context: connection.context,
+            max_message_size: connection.max_message_size,
+            health: connection.health,
+            reconnect: connection.reconnect,
+            metrics: connection.metrics,
+            query_updates: connection.query_updates,
+            tags: connection.tags,
+            server_version: connection.server_version,
+        }
+    }
+}
+
+impl AxonClients for AxonServerHandle {
+    fn channel(&self) -> Channel {
+        self.reconnect.channel().unwrap_or_else(|| self.conn.clone())
+    }
+
+    fn client_interceptor(&self) -> Option<Interceptor> {
+        self.interceptors.interceptor(self.context.as_deref())
+    }
+
+    fn max_message_size(&self) -> Option<usize> {
+        self.max_message_size
+    }
+}
+
+impl AxonClients for AxonConnection {
+    fn channel(&self) -> Channel {
+        self.reconnect.channel().unwrap_or_else(|| self.conn.clone())
+    }
+
+    fn client_interceptor(&self) -> Option<Interceptor> {
+        self.interceptors.interceptor(self.context.as_deref())
+    }
+
+    fn max_message_size(&self) -> Option<usize> {
+        self.max_message_size
     }
-    debug!("Response: {:?}", response);
-    return Ok(Some(conn));
 }
@@ -0,0 +1,145 @@
+use anyhow::Result;
+use tracing::{debug,info,warn};
+use serde_json::Value;
+use std::collections::{BTreeMap,BTreeSet};
+use super::{AxonClients,AxonServerHandle};
+use super::claim_check::ClaimCheck;
+use super::event_processor::EventContext;
+use super::handler_registry::TheHandlerRegistry;
+use super::parallel_sourcing::{DEFAULT_SOURCING_CONCURRENCY,EventStoreClientPool,create_event_store_client_pool,source_aggregates};
+
+/// Read access to the documents that a projection holds for an aggregate, keyed by document id.
+#[tonic::async_trait]
+pub trait ProjectionDocuments {
+    async fn documents_for_aggregate(&self, aggregate_id: &str) -> Result<BTreeMap<String,Value>>;
+}
+
+/// Selects the aggregates whose projection documents are checked.
+#[derive(Debug,Clone)]
+pub struct ConsistencyCheck {
+    pub aggregate_ids: Vec<String>,
+    /// Number of aggregates that are read from the event store at the same time.
+    pub concurrency: usize,
+    /// Resolves payloads that were moved to an object store before they are passed to the event handlers.
+    pub claim_check: Option<ClaimCheck>,
+}
+
+pub fn create_consistency_check() -> ConsistencyCheck {
+    ConsistencyCheck {
+        aggregate_ids: Vec::new(),
+        concurrency: DEFAULT_SOURCING_CONCURRENCY,
+        claim_check: None,
+    }
+}
+
+impl ConsistencyCheck {
+    pub fn with_aggregate(mut self, aggregate_id: &str) -> Self {
+        self.aggregate_ids.push(aggregate_id.to_string());
+        self
+    }
+
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    pub fn with_claim_check(mut self, claim_check: ClaimCheck) -> Self {
+        self.claim_check = Some(claim_check);
+        self
+    }
+}
+
+/// A document that differs between the recomputed projection and the live read model. The expected document is
+/// missing when the live read model has a document that the event handlers did not produce, and vice versa.
+#[derive(Debug,Clone,PartialEq)]
+pub struct DocumentDrift {
+    pub aggregate_id: String,
+    pub document_id: String,
+    pub expected: Option<Value>,
+    pub actual: Option<Value>,
+}
+
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct ConsistencyReport {
+    pub aggregates_checked: usize,
+    pub events_replayed: usize,
+    pub documents_compared: usize,
+    pub drift: Vec<DocumentDrift>,
+}
+
+impl ConsistencyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.drift.is_empty()
+    }
+}
+
+/// Re-sources the selected aggregates, applies their events to a scratch query model with the registered event
+/// handlers, and compares the resulting documents with those of the live read model, e.g., after a bug in a projection
+/// was fixed.
+///
+/// The aggregates are read concurrently, but their events are applied one aggregate at a time, in sequence order, with
+/// token `-1`, because reading an aggregate does not yield the tokens of its events. Events without a registered
+/// handler are skipped. The scratch query model must be empty, and must not share its storage with the live read
+/// model. Nothing is written to the event store or to the live read model.
+pub async fn check_query_model_consistency<Q, L>(
+    axon_server_handle: &AxonServerHandle,
+    check: &ConsistencyCheck,
+    scratch_model: Q,
+    event_handler_registry: &TheHandlerRegistry<Q,Option<Q>>,
+    live_model: &L
+) -> Result<ConsistencyReport>
+where Q: EventContext + ProjectionDocuments + Send + Sync + Clone, L: ProjectionDocuments + Sync
+{
+    let pool = create_event_store_client_pool(vec![axon_server_handle.event_store_client()])?;
+    check_query_model_consistency_with_pool(&pool, check, scratch_model, event_handler_registry, live_model).await
+}
+
+/// Like `check_query_model_consistency`, with the aggregates read through the clients of the pool.
+pub async fn check_query_model_consistency_with_pool<Q, L>(
+    pool: &EventStoreClientPool,
+    check: &ConsistencyCheck,
+    scratch_model: Q,
+    event_handler_registry: &TheHandlerRegistry<Q,Option<Q>>,
+    live_model: &L
+) -> Result<ConsistencyReport>
+where Q: EventContext + ProjectionDocuments + Send + Sync + Clone, L: ProjectionDocuments + Sync
+{
+    let mut report = ConsistencyReport::default();
+    let mut sourced = source_aggregates(pool, &check.aggregate_ids, check.concurrency).await?;
+    for aggregate_id in &check.aggregate_ids {
+        let mut events = sourced.remove(aggregate_id).unwrap_or_default();
+        debug!("Consistency check: {:?}: events: {:?}", aggregate_id, events.len());
+        for event in events.iter_mut() {
+            if let Some(claim_check) = check.claim_check.as_ref() {
+                claim_check.resolve_event(event).await?;
+            }
+            if let Some(payload) = &event.payload {
+                if let Some(event_handler) = event_handler_registry.handlers.get(&payload.r#type) {
+                    event_handler.handle(payload.data.clone(), scratch_model.for_event(event, -1)).await?;
+                    report.events_replayed += 1;
+                }
+            }
+        }
+
+        let mut expected = scratch_model.documents_for_aggregate(aggregate_id).await?;
+        let mut actual = live_model.documents_for_aggregate(aggregate_id).await?;
+        let document_ids: BTreeSet<String> = expected.keys().chain(actual.keys()).cloned().collect();
+        for document_id in document_ids {
+            report.documents_compared += 1;
+            let expected_document = expected.remove(&document_id);
+            let actual_document = actual.remove(&document_id);
+            if expected_document != actual_document {
+                warn!("Projection drift: {:?}: {:?}", aggregate_id, document_id);
+                report.drift.push(DocumentDrift {
+                    aggregate_id: aggregate_id.clone(),
+                    document_id,
+                    expected: expected_document,
+                    actual: actual_document,
+                });
+            }
+        }
+        report.aggregates_checked += 1;
+    }
+    info!("Consistency check: aggregates: {:?}: documents: {:?}: drift: {:?}", report.aggregates_checked, report.documents_compared, report.drift.len());
+    Ok(report)
+}
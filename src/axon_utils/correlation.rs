@@ -0,0 +1,187 @@
+use anyhow::Result;
+use tracing::debug;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc,Mutex};
+use super::AxonServerHandle;
+use super::event_statistics::query_event_store;
+use super::meta_data_stamping::meta_data_text;
+use crate::axon_server::MetaDataValue;
+use crate::axon_server::command::Command;
+use crate::axon_server::event::Event;
+use crate::axon_server::event::QueryValue;
+use crate::axon_server::event::query_value::Data as ValueData;
+use crate::axon_server::meta_data_value::Data;
+
+/// Meta-data key that ties the events of a command to the request that caused it.
+///
+/// The correlation id of a command is its `correlationId` meta-data value, or its message identifier if it has none.
+/// The command worker adds the correlation id of a command to the events that its handler emits, with the message
+/// identifier of the command as their causation id.
+pub const CORRELATION_ID: &str = "correlationId";
+
+/// Meta-data key that refers an event to the message identifier of the command (or event) that caused it directly.
+pub const CAUSATION_ID: &str = "causationId";
+
+pub fn correlation_id(command: &Command) -> String {
+    match command.meta_data.get(CORRELATION_ID).and_then(|value| value.data.as_ref()) {
+        Some(Data::TextValue(correlation_id)) if !correlation_id.is_empty() => correlation_id.clone(),
+        _ => command.message_identifier.clone(),
+    }
+}
+
+/// Returns the meta-data that ties events to the given command: its correlation id, and its message identifier as the
+/// causation id.
+pub fn correlation_meta_data(command: &Command) -> HashMap<String,MetaDataValue> {
+    chain_meta_data(correlation_id(command), &command.message_identifier)
+}
+
+/// The correlation and causation ids of an event, as stamped by the command worker, e.g., for an event handler that
+/// sends commands of its own and wants them to be part of the same workflow.
+#[derive(Debug,Clone,PartialEq)]
+pub struct EventCorrelation {
+    pub message_identifier: String,
+    pub correlation_id: Option<String>,
+    pub causation_id: Option<String>,
+}
+
+impl EventCorrelation {
+    pub fn from_event(event: &Event) -> Self {
+        EventCorrelation {
+            message_identifier: event.message_identifier.clone(),
+            correlation_id: text(&event.meta_data, CORRELATION_ID),
+            causation_id: text(&event.meta_data, CAUSATION_ID),
+        }
+    }
+
+    /// Returns the meta-data for a command that is caused by this event: the same correlation id (or the message
+    /// identifier of the event if it has none), and the message identifier of the event as the causation id. Send it
+    /// with `send_command_with_meta_data`.
+    pub fn follow_up_meta_data(&self) -> HashMap<String,MetaDataValue> {
+        let correlation_id = self.correlation_id.clone().unwrap_or_else(|| self.message_identifier.clone());
+        chain_meta_data(correlation_id, &self.message_identifier)
+    }
+}
+
+fn chain_meta_data(correlation_id: String, causation_id: &str) -> HashMap<String,MetaDataValue> {
+    let mut meta_data = HashMap::new();
+    meta_data.insert(CORRELATION_ID.to_string(), MetaDataValue {
+        data: Some(Data::TextValue(correlation_id)),
+    });
+    meta_data.insert(CAUSATION_ID.to_string(), MetaDataValue {
+        data: Some(Data::TextValue(causation_id.to_string())),
+    });
+    meta_data
+}
+
+fn text(meta_data: &HashMap<String,MetaDataValue>, key: &str) -> Option<String> {
+    meta_data_text(meta_data, key).filter(|text| !text.is_empty()).map(str::to_string)
+}
+
+/// A command as it was handled by a command worker.
+#[derive(Debug,Clone,PartialEq)]
+pub struct CommandAuditRecord {
+    pub message_identifier: String,
+    pub command_name: String,
+    pub correlation_id: String,
+    /// Time at which the command was handled, in milliseconds since the epoch.
+    pub timestamp: i64,
+    /// The error, if the command failed.
+    pub error: Option<String>,
+}
+
+/// Keeps a record of the commands that a command worker handled, for debugging.
+#[tonic::async_trait]
+pub trait CommandAuditStore: Debug + Send + Sync {
+    async fn record(&self, record: CommandAuditRecord) -> Result<()>;
+    async fn commands_for_correlation(&self, correlation_id: &str) -> Result<Vec<CommandAuditRecord>>;
+}
+
+/// Command audit store that keeps the records in memory.
+#[derive(Debug,Clone,Default)]
+pub struct InMemoryCommandAuditStore {
+    records: Arc<Mutex<Vec<CommandAuditRecord>>>,
+}
+
+#[tonic::async_trait]
+impl CommandAuditStore for InMemoryCommandAuditStore {
+    async fn record(&self, record: CommandAuditRecord) -> Result<()> {
+        if let Ok(mut records) = self.records.lock() {
+            records.push(record);
+        }
+        Ok(())
+    }
+
+    async fn commands_for_correlation(&self, correlation_id: &str) -> Result<Vec<CommandAuditRecord>> {
+        Ok(self.records.lock()
+            .map(|records| records.iter().filter(|record| record.correlation_id == correlation_id).cloned().collect())
+            .unwrap_or_default())
+    }
+}
+
+/// An event that carries a correlation id.
+#[derive(Debug,Clone,PartialEq)]
+pub struct CorrelatedEvent {
+    pub token: i64,
+    pub message_identifier: String,
+    pub aggregate_identifier: String,
+    pub aggregate_sequence_number: i64,
+    pub payload_type: String,
+    pub timestamp: i64,
+}
+
+/// The commands and events that share a correlation id, each in the order in which they happened.
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct CorrelationChain {
+    pub correlation_id: String,
+    pub commands: Vec<CommandAuditRecord>,
+    pub events: Vec<CorrelatedEvent>,
+}
+
+/// Returns what happened to the request with the given correlation id: the commands from the audit store and the
+/// events from an ad-hoc query on the event store. The query matches events whose meta-data contains the correlation
+/// id.
+pub async fn correlation_chain(axon_server_handle: &AxonServerHandle, audit_store: &dyn CommandAuditStore, correlation_id: &str) -> Result<CorrelationChain> {
+    let mut commands = audit_store.commands_for_correlation(correlation_id).await?;
+    commands.sort_by_key(|record| record.timestamp);
+
+    let escaped = correlation_id.replace('\\', "\\\\").replace('"', "\\\"");
+    let query = format!(
+        "metaData contains \"{}\" | select(token, eventIdentifier, aggregateIdentifier, aggregateSequenceNumber, payloadType, timestamp)",
+        escaped
+    );
+    let rows = query_event_store(axon_server_handle, &query).await?;
+    let mut events: Vec<CorrelatedEvent> = rows.iter().map(|row| CorrelatedEvent {
+        token: number_value(row, "token"),
+        message_identifier: text_value(row, "eventIdentifier"),
+        aggregate_identifier: text_value(row, "aggregateIdentifier"),
+        aggregate_sequence_number: number_value(row, "aggregateSequenceNumber"),
+        payload_type: text_value(row, "payloadType"),
+        timestamp: number_value(row, "timestamp"),
+    }).collect();
+    events.sort_by_key(|event| event.token);
+    debug!("Correlation chain: {:?}: commands: {:?}: events: {:?}", correlation_id, commands.len(), events.len());
+
+    Ok(CorrelationChain {
+        correlation_id: correlation_id.to_string(),
+        commands,
+        events,
+    })
+}
+
+fn text_value(row: &HashMap<String,QueryValue>, column: &str) -> String {
+    match row.get(column).and_then(|value| value.data.as_ref()) {
+        Some(ValueData::TextValue(text)) => text.clone(),
+        Some(ValueData::NumberValue(number)) => number.to_string(),
+        _ => "".to_string(),
+    }
+}
+
+fn number_value(row: &HashMap<String,QueryValue>, column: &str) -> i64 {
+    match row.get(column).and_then(|value| value.data.as_ref()) {
+        Some(ValueData::NumberValue(number)) => *number,
+        Some(ValueData::DoubleValue(number)) => *number as i64,
+        Some(ValueData::TextValue(text)) => text.parse().unwrap_or(-1),
+        _ => -1,
+    }
+}
@@ -0,0 +1,57 @@
+use anyhow::Result;
+use tracing::{debug,info,warn};
+use super::event_processor::EventContext;
+use super::handler_group::HandlerGroup;
+use super::handler_timeout::DeadLetterStore;
+
+/// Outcome of `redeliver_dead_letters`.
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct RedeliveryReport {
+    /// Dead letters that were handled and removed from the store.
+    pub redelivered: usize,
+    /// Dead letters whose handler failed again. They stay in the store.
+    pub failed: usize,
+    /// Dead letters of other groups, or without a handler in the group. They stay in the store.
+    pub skipped: usize,
+}
+
+/// Passes the dead-lettered events of the handler group to its handlers again, e.g., after the bug that made them fail
+/// was fixed, and removes the events that were handled from the store. Dead letters without a group are re-delivered
+/// to the first group that has a handler for them.
+///
+/// The events are re-delivered in the order of their tokens, but after the events that the processor handled since,
+/// also those of the same aggregate, so handlers must tolerate events that arrive late. Retries and policies of the
+/// group do not apply.
+pub async fn redeliver_dead_letters<Q: EventContext + Send + Sync + Clone>(
+    store: &dyn DeadLetterStore,
+    query_model: &Q,
+    handler_group: &HandlerGroup<Q>
+) -> Result<RedeliveryReport> {
+    let mut report = RedeliveryReport::default();
+    for dead_letter in store.dead_letters().await? {
+        let in_group = dead_letter.group.as_ref().map(|group| group == &handler_group.name).unwrap_or(true);
+        let payload = dead_letter.event.payload.as_ref();
+        let handler = payload.and_then(|payload| handler_group.registry.handlers.get(&payload.r#type));
+        let (payload, handler) = match (in_group, payload, handler) {
+            (true, Some(payload), Some(handler)) => (payload, handler),
+            _ => {
+                report.skipped += 1;
+                continue;
+            }
+        };
+        let key = dead_letter.key();
+        debug!("Redeliver dead letter: {:?}: token: {:?}", key, dead_letter.token);
+        match handler.handle(payload.data.clone(), query_model.for_event(&dead_letter.event, dead_letter.token)).await {
+            Ok(_) => {
+                store.remove_dead_letter(&key).await?;
+                report.redelivered += 1;
+            }
+            Err(e) => {
+                warn!("Redelivery failed: {:?}: group: {:?}: {:?}", key, handler_group.name, e);
+                report.failed += 1;
+            }
+        }
+    }
+    info!("Redelivered dead letters: group: {:?}: {:?}", handler_group.name, report);
+    Ok(report)
+}
@@ -0,0 +1,57 @@
+use std::collections::{HashSet,VecDeque};
+
+/// Message identifiers of the most recent events that an event processor handled, oldest first. AxonServer may deliver
+/// an event again, e.g., when it was appended twice. The processor skips the events in the window, so that handlers
+/// need not all be idempotent.
+///
+/// The window is bounded: when it is full, the oldest identifier is forgotten. The processor records an event only
+/// after its handlers succeeded, and the token store keeps the window in the same write as the token, so that it
+/// survives a restart.
+#[derive(Debug,Clone,Default)]
+pub(crate) struct DeduplicationWindow {
+    capacity: usize,
+    order: VecDeque<String>,
+    identifiers: HashSet<String>,
+}
+
+pub(crate) fn create_deduplication_window(capacity: usize, identifiers: Vec<String>) -> DeduplicationWindow {
+    let mut window = DeduplicationWindow {
+        capacity,
+        order: VecDeque::with_capacity(capacity),
+        identifiers: HashSet::with_capacity(capacity),
+    };
+    for identifier in identifiers {
+        window.record(identifier);
+    }
+    window
+}
+
+impl DeduplicationWindow {
+    pub fn contains(&self, message_identifier: &str) -> bool {
+        self.identifiers.contains(message_identifier)
+    }
+
+    pub fn record(&mut self, message_identifier: String) {
+        if self.capacity == 0 || self.identifiers.contains(&message_identifier) {
+            return;
+        }
+        while self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.identifiers.remove(&oldest);
+            }
+        }
+        self.identifiers.insert(message_identifier.clone());
+        self.order.push_back(message_identifier);
+    }
+
+    /// Forgets all identifiers, e.g., when the processor is reset, so that the replayed events are handled again.
+    pub fn clear(&mut self) {
+        self.order.clear();
+        self.identifiers.clear();
+    }
+
+    /// Returns the identifiers in the window, oldest first.
+    pub fn identifiers(&self) -> Vec<String> {
+        self.order.iter().cloned().collect()
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::Result;
+use tracing::{info,warn};
+use serde::Serialize;
+use std::collections::{BTreeMap,HashMap};
+use super::{AxonClients,AxonServerHandle};
+use super::metrics::label_value;
+use crate::axon_server::event::GetLastTokenRequest;
+
+const PROCESSOR_TOKEN_GAUGE: &str = "event_processor_token";
+
+/// Returns the name of the gauge that holds the last token that the named event processor handled.
+pub(crate) fn processor_token_gauge(processor_name: &str) -> String {
+    format!("{}{{processor={}}}", PROCESSOR_TOKEN_GAUGE, label_value(processor_name))
+}
+
+/// Snapshot of the state of the workers that share a connection to AxonServer, for support.
+///
+/// It holds the identity of the connection, the health and subscriptions of each worker, the token and lag of each
+/// event processor, the last error of each worker that failed, and all counters and gauges (including the depths of
+/// mailboxes and buffers). It serializes to JSON, e.g., for an admin endpoint.
+#[derive(Debug,Clone,Default,PartialEq,Serialize)]
+pub struct Diagnostics {
+    pub display_name: String,
+    pub server_version: Option<i32>,
+    pub tags: BTreeMap<String,String>,
+    pub healthy: bool,
+    pub workers: BTreeMap<String,String>,
+    pub subscriptions: BTreeMap<String,Vec<String>>,
+    /// Token of the last event in the event store, if it could be read.
+    pub head_token: Option<i64>,
+    pub processors: Vec<ProcessorDiagnostics>,
+    pub last_errors: BTreeMap<String,String>,
+    pub counters: BTreeMap<String,i64>,
+    pub gauges: BTreeMap<String,i64>,
+}
+
+#[derive(Debug,Clone,PartialEq,Serialize)]
+pub struct ProcessorDiagnostics {
+    pub name: String,
+    pub token: i64,
+    /// Number of events between the last handled event and the head of the event store.
+    pub lag: Option<i64>,
+}
+
+impl AxonServerHandle {
+    /// Collects diagnostics for the workers of this handle. The head of the event store is read from AxonServer; when
+    /// that fails, the lag of the processors is unknown.
+    pub async fn diagnostics(&self) -> Diagnostics {
+        let head_token = match read_head_token(self).await {
+            Ok(head_token) => Some(head_token),
+            Err(e) => {
+                warn!("Diagnostics: cannot read head token: {:?}", e);
+                None
+            }
+        };
+        let metrics = self.metrics.snapshot();
+        let processors = metrics.gauges.iter()
+            .filter_map(|(name, token)| processor_name(name).map(|name| ProcessorDiagnostics {
+                name,
+                token: *token,
+                lag: head_token.map(|head_token| (head_token - *token).max(0)),
+            }))
+            .collect();
+        Diagnostics {
+            display_name: self.display_name.clone(),
+            server_version: self.server_version,
+            tags: sorted(self.tags.clone()),
+            healthy: self.health.is_healthy(),
+            workers: self.health.snapshot().into_iter().map(|(worker, health)| (worker, format!("{:?}", health))).collect(),
+            subscriptions: sorted(self.health.subscriptions()),
+            head_token,
+            processors,
+            last_errors: sorted(self.health.last_errors()),
+            counters: metrics.counters,
+            gauges: metrics.gauges,
+        }
+    }
+}
+
+/// Logs the diagnostics of the handle as JSON whenever the process receives SIGUSR1. Run it in a separate task. On
+/// platforms without SIGUSR1, it returns immediately.
+pub async fn log_diagnostics_on_signal(axon_server_handle: AxonServerHandle) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind,signal};
+        let mut user_defined = match signal(SignalKind::user_defined1()) {
+            Ok(user_defined) => user_defined,
+            Err(e) => {
+                warn!("Cannot listen for SIGUSR1: {:?}", e);
+                return;
+            }
+        };
+        while user_defined.recv().await.is_some() {
+            let diagnostics = axon_server_handle.diagnostics().await;
+            match serde_json::to_string(&diagnostics) {
+                Ok(json) => info!("Diagnostics: {}", json),
+                Err(e) => warn!("Cannot serialize diagnostics: {:?}", e),
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = axon_server_handle;
+    }
+}
+
+async fn read_head_token(axon_server_handle: &AxonServerHandle) -> Result<i64> {
+    let mut client = axon_server_handle.event_store_client();
+    Ok(client.get_last_token(GetLastTokenRequest {}).await?.into_inner().token)
+}
+
+fn processor_name(gauge_name: &str) -> Option<String> {
+    let quoted = gauge_name.strip_prefix(PROCESSOR_TOKEN_GAUGE)?.strip_prefix("{processor=")?.strip_suffix('}')?;
+    serde_json::from_str(quoted).ok()
+}
+
+fn sorted<V>(map: HashMap<String,V>) -> BTreeMap<String,V> {
+    map.into_iter().collect()
+}
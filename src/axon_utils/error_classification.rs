@@ -0,0 +1,97 @@
+use anyhow::Error;
+use std::fmt::{Display,Formatter};
+use std::time::Duration;
+use tonic::{Code,Status};
+use super::axon_error::AxonError;
+
+/// Tells whether a worker that failed with an error can be restarted.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ErrorClass {
+    Retryable,
+    Fatal,
+}
+
+pub fn classify_status(status: &Status) -> ErrorClass {
+    match status.code() {
+        Code::Unavailable | Code::ResourceExhausted | Code::Aborted | Code::DeadlineExceeded
+            | Code::Cancelled | Code::Unknown | Code::Internal => ErrorClass::Retryable,
+        _ => ErrorClass::Fatal,
+    }
+}
+
+/// Classifies an error returned by one of the workers. Errors that did not originate from AxonServer are fatal, except
+/// for connection errors.
+pub fn classify_error(error: &Error) -> ErrorClass {
+    if let Some(stream_error) = error.downcast_ref::<AxonStreamError>() {
+        return stream_error.error_class;
+    }
+    if let Some(status) = error.downcast_ref::<Status>() {
+        return classify_status(status);
+    }
+    match error.downcast_ref::<AxonError>() {
+        Some(AxonError::Connection(_)) => ErrorClass::Retryable,
+        Some(AxonError::Status(status)) => classify_status(status),
+        Some(AxonError::Other(error)) => classify_error(error),
+        _ => ErrorClass::Fatal,
+    }
+}
+
+/// Error that ended the stream between a worker and AxonServer.
+#[derive(Debug,Clone)]
+pub struct AxonStreamError {
+    pub code: Code,
+    pub message: String,
+    pub error_class: ErrorClass,
+}
+
+impl From<Status> for AxonStreamError {
+    fn from(status: Status) -> Self {
+        AxonStreamError {
+            code: status.code(),
+            message: status.message().to_string(),
+            error_class: classify_status(&status),
+        }
+    }
+}
+
+impl Display for AxonStreamError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AxonServer stream error: {:?}: {:?}: {}", self.error_class, self.code, self.message)
+    }
+}
+
+impl std::error::Error for AxonStreamError {}
+
+#[derive(Debug,Clone)]
+pub struct ReconnectPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Returns the delay before the given attempt to restart a worker that failed with the given error, or `None` if
+    /// the worker should not be restarted. Attempts are counted from 1.
+    pub fn next_delay(&self, error: &Error, attempt: u32) -> Option<Duration> {
+        if classify_error(error) == ErrorClass::Fatal {
+            return None;
+        }
+        if let Some(max_attempts) = self.max_attempts {
+            if attempt > max_attempts {
+                return None;
+            }
+        }
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        Some(self.initial_backoff.checked_mul(factor).unwrap_or(self.max_backoff).min(self.max_backoff))
+    }
+}
@@ -0,0 +1,75 @@
+use tracing::debug;
+use std::fmt::{Debug,Formatter};
+use std::sync::Arc;
+use super::Metrics;
+use crate::axon_server::event::Event;
+use crate::axon_server::meta_data_value::Data;
+
+pub type EventPredicate = Arc<dyn Fn(&Event) -> bool + Send + Sync>;
+
+/// Named predicates on the envelope of an event (aggregate type, meta-data, payload type) that an event processor
+/// evaluates before the payload is resolved and decoded.
+///
+/// An event is passed to its handler only if all predicates accept it. Other events are skipped cheaply: their token
+/// is stored, and they are counted in the `<worker>_skipped_events` metric. Without predicates, all events are
+/// accepted.
+#[derive(Clone,Default)]
+pub struct EventFilter {
+    predicates: Vec<(String,EventPredicate)>,
+}
+
+impl Debug for EventFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventFilter")
+            .field("predicates", &self.predicates.iter().map(|(name, _)| name).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+pub fn create_event_filter() -> EventFilter {
+    EventFilter::default()
+}
+
+impl EventFilter {
+    pub fn with_predicate(mut self, name: &str, predicate: impl Fn(&Event) -> bool + Send + Sync + 'static) -> Self {
+        self.predicates.push((name.to_string(), Arc::new(predicate)));
+        self
+    }
+
+    /// Accepts only events of aggregates of the given types. Events that do not belong to an aggregate have an empty
+    /// aggregate type.
+    pub fn with_aggregate_types(self, aggregate_types: &[&str]) -> Self {
+        let aggregate_types: Vec<String> = aggregate_types.iter().map(|aggregate_type| aggregate_type.to_string()).collect();
+        let name = format!("aggregate_type in {:?}", aggregate_types);
+        self.with_predicate(&name, move |event| aggregate_types.contains(&event.aggregate_type))
+    }
+
+    /// Accepts only events with a text meta-data value with the given key and value.
+    pub fn with_meta_data(self, key: &str, value: &str) -> Self {
+        let key = key.to_string();
+        let value = value.to_string();
+        let name = format!("{}={:?}", key, value);
+        self.with_predicate(&name, move |event| match event.meta_data.get(&key).and_then(|meta_data_value| meta_data_value.data.as_ref()) {
+            Some(Data::TextValue(text)) => text == &value,
+            _ => false,
+        })
+    }
+
+    /// Returns the name of the first predicate that rejects the event, if any.
+    pub fn rejected_by(&self, event: &Event) -> Option<&str> {
+        self.predicates.iter()
+            .find(|(_, predicate)| !predicate(event))
+            .map(|(name, _)| name.as_str())
+    }
+
+    pub(crate) fn skip(&self, worker: &str, metrics: &Metrics, event: &Event) -> bool {
+        match self.rejected_by(event) {
+            Some(name) => {
+                debug!("Skip event: worker={} message={:?} predicate={:?}", worker, event.message_identifier, name);
+                metrics.increment(&format!("{}_skipped_events", worker), 1);
+                true
+            }
+            None => false,
+        }
+    }
+}
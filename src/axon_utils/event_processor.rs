@@ -1,12 +1,32 @@
-use anyhow::Result;
+use anyhow::{anyhow,Result};
 use async_stream::stream;
 use futures_core::stream::Stream;
-use log::debug;
+use tracing::{Instrument,debug,debug_span,info,info_span};
+use std::collections::HashMap;
+use std::sync::{Arc,Mutex};
 use tokio::sync::mpsc::{Sender,Receiver, channel};
-use super::AxonServerHandle;
+use super::{AxonClients,AxonServerHandle,PauseSwitch,WorkerHealth};
+use super::catch_up::CatchUpSignal;
+use super::diagnostics::processor_token_gauge;
+use super::claim_check::ClaimCheck;
+use super::deduplication::create_deduplication_window;
+use super::event_filter::EventFilter;
+use super::handler_concurrency::ConcurrencyLimits;
+use super::handler_group::{DEFAULT_HANDLER_GROUP,HandlerGroup,create_handler_group};
 use super::handler_registry::TheHandlerRegistry;
+use super::handler_timeout::HandlerTimeouts;
+use super::message_size::check_payload_size;
+use super::slow_handler::SlowHandlerThresholds;
+use super::projection_schema::{ProjectionSchema,ensure_schema_version};
+use super::redaction::log_safe;
+use super::retry_policy::RetryPolicy;
+use super::replay::{ReplaySignal,TokenPosition,position_token,requested_position};
+use super::segments::Segment;
+#[cfg(feature = "fault-injection")]
+use super::fault_injection::{FaultTarget,fault_injector};
 use crate::axon_server::event::{Event,EventWithToken,GetEventsRequest};
-use crate::axon_server::event::event_store_client::EventStoreClient;
+
+const WORKER_NAME: &str = "event_processor";
 
 #[derive(Debug)]
 struct AxonEventProcessed {
@@ -15,8 +35,272 @@ struct AxonEventProcessed {
 
 #[tonic::async_trait]
 pub trait TokenStore {
-    async fn store_token(&self, token: i64);
-    async fn retrieve_token(&self) -> Result<i64>;
+    async fn store_token(&self, token: i64) -> Result<()>;
+
+    /// Returns the stored token, or `None` if this store has no token yet. Errors are only for failures to read the
+    /// store, so that a store that cannot be read is never mistaken for an empty one.
+    async fn retrieve_token(&self) -> Result<Option<i64>>;
+
+    /// Stores the schema version of the projection next to the tracking token. Required when the event processor is
+    /// configured with a `ProjectionSchema`.
+    async fn store_schema_version(&self, _version: i64) -> Result<()> {
+        Err(anyhow!("This token store does not support schema versions"))
+    }
+
+    async fn retrieve_schema_version(&self) -> Result<Option<i64>> {
+        Ok(None)
+    }
+
+    /// Stores the token together with the message identifiers of the most recent events, when the event processor is
+    /// configured with a `deduplication_window`. Override this method, and `retrieve_deduplication_window`, to keep the
+    /// window across restarts. By default only the token is stored, so that the window starts empty after a restart.
+    async fn store_token_with_window(&self, token: i64, _window: &[String]) -> Result<()> {
+        self.store_token(token).await
+    }
+
+    async fn retrieve_deduplication_window(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// Returns the query model that is passed to the handler of the given event. Override this method to give
+    /// handlers access to the envelope of the event (timestamp, aggregate, meta-data), e.g., an `EventCorrelation` to
+    /// trace the workflow that the event is part of. By default the envelope is ignored.
+    fn for_event(&self, _event: &Event, _token: i64) -> Self where Self: Sized + Clone {
+        self.clone()
+    }
+
+    /// Returns the token store that the event processor uses. Override this method to keep the token under the index
+    /// and key of the `TrackingConfig`, instead of a location that is hard-coded in the implementation. By default the
+    /// configuration is ignored.
+    fn for_tracking(&self, _tracking: &TrackingConfig) -> Self where Self: Sized + Clone {
+        self.clone()
+    }
+
+    /// Claims the segment of a segmented event processor whose token this store keeps, for the given owner. Returns
+    /// whether the owner holds the claim. Override this method to divide the segments over instances, e.g., by
+    /// recording the owner next to the token. By default every claim succeeds, which is only safe when a single
+    /// instance runs the processor.
+    async fn claim_segment(&self, _owner: &str) -> Result<bool> {
+        Ok(true)
+    }
+
+    /// Renews the claim of the given owner on the segment, without taking the segment over. Returns whether the owner
+    /// still holds the claim. Override this method, together with `claim_segment`, when claims expire. By default the
+    /// claim is kept.
+    async fn renew_segment_claim(&self, _owner: &str) -> Result<bool> {
+        Ok(true)
+    }
+
+    async fn release_segment(&self, _owner: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Stores the token of a reset (see `ReplaySignal` and `reset_tracking_token`). Override this method to also clear
+    /// state that belongs to the old position. By default the token is stored as usual.
+    async fn reset_token(&self, token: i64) -> Result<()> {
+        self.store_token(token).await
+    }
+
+    /// Stores the token for the initial position of the event processor, if this store has no token yet.
+    async fn initialize_token(&self, token: i64) -> Result<()> {
+        if self.retrieve_token().await?.is_none() {
+            self.store_token(token).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Identifies an event processor and the location of its tracking token.
+///
+/// The processor name is reported to AxonServer. Token stores keep the token of the processor in `token_index` under
+/// `token_key`, and record `owner` as the instance that claimed it. When `owner` is empty, the display name of the
+/// AxonServer handle is used.
+#[derive(Debug,Clone)]
+pub struct TrackingConfig {
+    pub processor_name: String,
+    pub token_index: String,
+    pub token_key: String,
+    pub owner: String,
+}
+
+impl Default for TrackingConfig {
+    fn default() -> Self {
+        create_tracking_config("Event Processor")
+    }
+}
+
+/// Returns the tracking configuration for the named processor, with the token in index `tracking-token` under the
+/// name of the processor.
+pub fn create_tracking_config(processor_name: &str) -> TrackingConfig {
+    TrackingConfig {
+        processor_name: processor_name.to_string(),
+        token_index: "tracking-token".to_string(),
+        token_key: processor_name.to_string(),
+        owner: "".to_string(),
+    }
+}
+
+impl TrackingConfig {
+    pub fn with_token_index(mut self, token_index: &str) -> Self {
+        self.token_index = token_index.to_string();
+        self
+    }
+
+    pub fn with_token_key(mut self, token_key: &str) -> Self {
+        self.token_key = token_key.to_string();
+        self
+    }
+
+    pub fn with_owner(mut self, owner: &str) -> Self {
+        self.owner = owner.to_string();
+        self
+    }
+}
+
+/// Token store that keeps the tokens in memory, for tests and demos that run an event processor without an external
+/// store. All clones share the same tokens. Tokens are kept per token key, so that the segments of a segmented
+/// processor each keep their own. Claims of segments always succeed for the owner that holds them, and for any owner
+/// when they are released.
+///
+/// It can also serve as the query model of a processor whose handlers keep no state.
+#[derive(Debug,Clone,Default)]
+pub struct InMemoryTokenStore {
+    token_key: String,
+    tokens: Arc<Mutex<HashMap<String,i64>>>,
+    windows: Arc<Mutex<HashMap<String,Vec<String>>>>,
+    schema_versions: Arc<Mutex<HashMap<String,i64>>>,
+    owners: Arc<Mutex<HashMap<String,String>>>,
+}
+
+impl InMemoryTokenStore {
+    /// Returns the token that is stored under the key of this store, if any.
+    pub fn token(&self) -> Option<i64> {
+        self.tokens.lock().ok().and_then(|tokens| tokens.get(&self.token_key).cloned())
+    }
+
+    /// Returns the tokens under all keys, e.g., of all segments of a processor.
+    pub fn tokens(&self) -> HashMap<String,i64> {
+        self.tokens.lock().map(|tokens| tokens.clone()).unwrap_or_default()
+    }
+}
+
+#[tonic::async_trait]
+impl TokenStore for InMemoryTokenStore {
+    async fn store_token(&self, token: i64) -> Result<()> {
+        if let Ok(mut tokens) = self.tokens.lock() {
+            tokens.insert(self.token_key.clone(), token);
+        }
+        Ok(())
+    }
+
+    async fn retrieve_token(&self) -> Result<Option<i64>> {
+        Ok(self.token())
+    }
+
+    async fn store_token_with_window(&self, token: i64, window: &[String]) -> Result<()> {
+        self.store_token(token).await?;
+        if let Ok(mut windows) = self.windows.lock() {
+            windows.insert(self.token_key.clone(), window.to_vec());
+        }
+        Ok(())
+    }
+
+    async fn retrieve_deduplication_window(&self) -> Result<Vec<String>> {
+        Ok(self.windows.lock().ok().and_then(|windows| windows.get(&self.token_key).cloned()).unwrap_or_default())
+    }
+
+    async fn store_schema_version(&self, version: i64) -> Result<()> {
+        if let Ok(mut schema_versions) = self.schema_versions.lock() {
+            schema_versions.insert(self.token_key.clone(), version);
+        }
+        Ok(())
+    }
+
+    async fn retrieve_schema_version(&self) -> Result<Option<i64>> {
+        Ok(self.schema_versions.lock().ok().and_then(|schema_versions| schema_versions.get(&self.token_key).cloned()))
+    }
+
+    fn for_tracking(&self, tracking: &TrackingConfig) -> Self {
+        let mut store = self.clone();
+        store.token_key = tracking.token_key.clone();
+        store
+    }
+
+    async fn claim_segment(&self, owner: &str) -> Result<bool> {
+        let mut owners = self.owners.lock().map_err(|_| anyhow!("Token store is poisoned"))?;
+        let holder = owners.entry(self.token_key.clone()).or_insert_with(|| owner.to_string());
+        Ok(holder == owner)
+    }
+
+    async fn renew_segment_claim(&self, owner: &str) -> Result<bool> {
+        let owners = self.owners.lock().map_err(|_| anyhow!("Token store is poisoned"))?;
+        Ok(owners.get(&self.token_key).map(|holder| holder == owner).unwrap_or(false))
+    }
+
+    async fn release_segment(&self, owner: &str) -> Result<()> {
+        if let Ok(mut owners) = self.owners.lock() {
+            if owners.get(&self.token_key).map(|holder| holder == owner).unwrap_or(false) {
+                owners.remove(&self.token_key);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The query model that is passed to event handlers, for the envelope of the event. Every `TokenStore` is an
+/// `EventContext` through `TokenStore::for_event`; implement it directly for query models that do not keep a token,
+/// e.g., the scratch model of a consistency check.
+pub trait EventContext: Clone {
+    /// Returns the query model that is passed to the handler of the given event. By default the envelope is ignored.
+    fn for_event(&self, _event: &Event, _token: i64) -> Self {
+        self.clone()
+    }
+}
+
+impl<T: TokenStore + Clone> EventContext for T {
+    fn for_event(&self, event: &Event, token: i64) -> Self {
+        TokenStore::for_event(self, event, token)
+    }
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct EventProcessorConfig {
+    /// Resolves payloads that were moved to an object store before they are passed to the event handlers.
+    pub claim_check: Option<ClaimCheck>,
+    /// Rebuilds the projection on startup when its schema version changed.
+    pub schema: Option<ProjectionSchema>,
+    /// Name of the processor and location of its tracking token.
+    pub tracking: TrackingConfig,
+    /// Latency thresholds above which handlers are logged and counted as slow.
+    pub slow_handler: SlowHandlerThresholds,
+    /// Maximum size of the payload of an event. A larger event stops the processor with a `PayloadTooLargeError`,
+    /// before it is decoded.
+    pub max_payload_size: Option<usize>,
+    /// Reports when the processor has caught up with the head of the event store.
+    pub catch_up: Option<CatchUpSignal>,
+    /// Skips events before their payload is resolved and decoded.
+    pub filter: EventFilter,
+    /// Abandons handlers that take too long, and decides what happens with their events.
+    pub handler_timeouts: HandlerTimeouts,
+    /// Limits the number of handler calls of this processor that are in progress at the same time, across its segments
+    /// and replay partitions.
+    pub concurrency: ConcurrencyLimits,
+    /// Tries failed handlers again, with exponential backoff, in handler groups without retries of their own.
+    pub retry: RetryPolicy,
+    /// Handles only the events of this segment, and keeps the token of the segment (see `segmented_event_processor`).
+    pub segment: Option<Segment>,
+    /// Position to start from when the token store has no token yet. Without it, the processor starts at the tail.
+    pub initial_position: Option<TokenPosition>,
+    /// Resets the token of the running processor when it is triggered.
+    pub replay: ReplaySignal,
+    /// Stops the processor from handling events while it is paused, e.g., by AxonServer through the platform listener.
+    pub pause_switch: PauseSwitch,
+    /// Number of recent events whose message identifiers the processor remembers, to skip them when AxonServer
+    /// delivers them again. The identifier of an event is recorded only after its handlers succeeded, and the token
+    /// store keeps the window in the same write as the token (see `TokenStore::store_token_with_window`), so that an
+    /// event whose handler failed, or that was being handled when the process died, is handled again. Without it,
+    /// every delivered event is handled.
+    pub deduplication_window: Option<usize>,
 }
 
 pub async fn event_processor<Q: TokenStore + Send + Sync + Clone>(
@@ -24,41 +308,186 @@ pub async fn event_processor<Q: TokenStore + Send + Sync + Clone>(
     query_model: Q,
     event_handler_registry: TheHandlerRegistry<Q,Option<Q>>
 ) -> Result<()> {
-    let conn = axon_server_handle.conn;
-    let mut client = EventStoreClient::new(conn);
+    event_processor_with_config(axon_server_handle, query_model, event_handler_registry, EventProcessorConfig::default()).await
+}
+
+pub async fn event_processor_with_config<Q: TokenStore + Send + Sync + Clone>(
+    axon_server_handle: AxonServerHandle,
+    query_model: Q,
+    event_handler_registry: TheHandlerRegistry<Q,Option<Q>>,
+    config: EventProcessorConfig
+) -> Result<()> {
+    let group = create_handler_group(DEFAULT_HANDLER_GROUP, event_handler_registry);
+    event_processor_with_groups(axon_server_handle, query_model, vec![group], config).await
+}
+
+/// Runs an event processor that passes each event to the handler groups in the given order. Each group applies its
+/// own error policy, so that a flaky handler in one group does not stall the handlers in the other groups.
+pub async fn event_processor_with_groups<Q: TokenStore + Send + Sync + Clone>(
+    axon_server_handle: AxonServerHandle,
+    query_model: Q,
+    handler_groups: Vec<HandlerGroup<Q>>,
+    config: EventProcessorConfig
+) -> Result<()> {
+    run_event_processor(axon_server_handle, query_model, &handler_groups, config).await
+}
 
-    let (mut tx, rx): (Sender<AxonEventProcessed>, Receiver<AxonEventProcessed>) = channel(10);
+pub(crate) async fn run_event_processor<Q: TokenStore + Send + Sync + Clone>(
+    axon_server_handle: AxonServerHandle,
+    query_model: Q,
+    handler_groups: &[HandlerGroup<Q>],
+    config: EventProcessorConfig
+) -> Result<()> {
+    let span = info_span!(
+        "event_processor",
+        processor = %config.tracking.processor_name,
+        segment = ?config.segment.map(|segment| segment.segment_id),
+    );
+    process_events(axon_server_handle, query_model, handler_groups, config).instrument(span).await
+}
 
-    let initial_token = query_model.retrieve_token().await.unwrap_or(-1) + 1;
-    debug!("Initial token: {:?}", initial_token);
-    let outbound = create_output_stream(axon_server_handle.display_name, initial_token, rx);
+async fn process_events<Q: TokenStore + Send + Sync + Clone>(
+    axon_server_handle: AxonServerHandle,
+    query_model: Q,
+    handler_groups: &[HandlerGroup<Q>],
+    config: EventProcessorConfig
+) -> Result<()> {
+    let health = axon_server_handle.health.clone();
+    let metrics = axon_server_handle.metrics.clone();
+    health.report(WORKER_NAME, WorkerHealth::Starting);
+    let mut client = axon_server_handle.event_store_client();
 
-    debug!("Event Processor: calling open_stream");
-    let response = client.list_events(outbound).await?;
-    debug!("Stream response: {:?}", response);
+    let mut tracking = config.tracking.clone();
+    if tracking.owner.is_empty() {
+        tracking.owner = axon_server_handle.display_name.clone();
+    }
+    let mut progress_name = tracking.processor_name.clone();
+    if let Some(segment) = &config.segment {
+        tracking.token_key = segment.key(&tracking.token_key);
+        progress_name = segment.key(&progress_name);
+    }
+    debug!("Event processor: tracking: {:?}: segment: {:?}", tracking, config.segment);
+    let query_model = query_model.for_tracking(&tracking);
 
-    let mut events = response.into_inner();
+    if let Some(schema) = &config.schema {
+        ensure_schema_version(&query_model, schema).await?;
+    }
+    if let Some(position) = config.initial_position {
+        let token = position_token(&mut client, position).await?;
+        query_model.initialize_token(token).await?;
+    }
+    let mut replay_requests = config.replay.subscribe().await;
+    let mut initial_token = query_model.retrieve_token().await?.unwrap_or(-1) + 1;
+    let mut window = match config.deduplication_window {
+        Some(capacity) => Some(create_deduplication_window(capacity, query_model.retrieve_deduplication_window().await?)),
+        None => None,
+    };
     loop {
-        let event_with_token = events.message().await?;
-        debug!("Event with token: {:?}", event_with_token);
+        debug!("Initial token: {:?}", initial_token);
+        let token_gauge = processor_token_gauge(&progress_name);
+        metrics.set_gauge(&token_gauge, initial_token - 1);
+        let mut head = initial_token - 1;
+        let mut stored_token = initial_token - 1;
+        if let Some(catch_up) = &config.catch_up {
+            catch_up.check(&mut client, &progress_name, initial_token - 1, &mut head).await?;
+        }
+        let (mut tx, rx): (Sender<AxonEventProcessed>, Receiver<AxonEventProcessed>) = channel(10);
+        let outbound = create_output_stream(axon_server_handle.display_name.clone(), axon_server_handle.component_name.clone(), tracking.processor_name.clone(), initial_token, rx);
 
-        if let Some(EventWithToken { event: Some(event), token, ..}) = event_with_token {
-            if let Event { payload: Some(serialized_object), .. } = event {
-                if let Some(event_handler) = event_handler_registry.handlers.get(&serialized_object.r#type) {
-                    (event_handler).handle(serialized_object.data, query_model.clone()).await?;
-                }
+        debug!("Event Processor: calling open_stream");
+        let generation = axon_server_handle.reconnect.generation();
+        let response = client.list_events(outbound).await
+            .map_err(|e| health.stream_failed(WORKER_NAME, e))?;
+        debug!("Stream response: {:?}", response);
+        health.report(WORKER_NAME, WorkerHealth::Running);
+
+        let mut events = response.into_inner();
+        let position = loop {
+            let event_with_token = tokio::select! {
+                event_with_token = events.message() => event_with_token.map_err(|e| health.stream_failed(WORKER_NAME, e))?,
+                position = requested_position(&mut replay_requests) => break Some(position),
+                _ = axon_server_handle.reconnect.requested_since(generation) => break None,
+            };
+            debug!("Event with token: {:?}", log_safe(&event_with_token));
+
+            if config.pause_switch.is_paused() {
+                info!("Event processor: paused: {:?}", progress_name);
+                health.report(WORKER_NAME, WorkerHealth::Paused);
+                config.pause_switch.resumed().await;
+                info!("Event processor: resumed: {:?}", progress_name);
+                health.report(WORKER_NAME, WorkerHealth::Running);
             }
 
-            query_model.store_token(token).await;
+            if let Some(EventWithToken { event: Some(mut event), token, ..}) = event_with_token {
+                let in_segment = config.segment.map(|segment| segment.matches(&event)).unwrap_or(true);
+                let duplicate = window.as_ref().map(|window| window.contains(&event.message_identifier)).unwrap_or(false);
+                if duplicate {
+                    debug!("Event processor: skip duplicate event: {:?}: token: {:?}", event.message_identifier, token);
+                    metrics.increment(&format!("{}_duplicate_events", WORKER_NAME), 1);
+                } else if in_segment && !config.filter.skip(WORKER_NAME, &metrics, &event) {
+                    if let Some(claim_check) = config.claim_check.as_ref() {
+                        claim_check.resolve_event(&mut event).await?;
+                    }
+                    if let Event { payload: Some(serialized_object), .. } = &event {
+                        check_payload_size(serialized_object, config.max_payload_size)?;
+                        #[cfg(feature = "fault-injection")]
+                        let dropped = fault_injector().inject(FaultTarget::Event).await?;
+                        #[cfg(not(feature = "fault-injection"))]
+                        let dropped = false;
+                        if dropped {
+                            debug!("Event processor: skip dropped event: {:?}", event.message_identifier);
+                        } else {
+                            let span = debug_span!("event", token, message_identifier = %event.message_identifier, payload_type = %serialized_object.r#type);
+                            async {
+                                for handler_group in handler_groups {
+                                    handler_group.handle(WORKER_NAME, &metrics, &config, &event, token, &query_model).await?;
+                                }
+                                Ok::<(),anyhow::Error>(())
+                            }.instrument(span).await?;
+                        }
+                    }
+                }
+
+                match window.as_mut() {
+                    Some(window) => {
+                        window.record(event.message_identifier.clone());
+                        query_model.store_token_with_window(token, &window.identifiers()).await?;
+                    }
+                    None => query_model.store_token(token).await?,
+                }
+                stored_token = token;
+                metrics.set_gauge(&token_gauge, token);
+                if let Some(catch_up) = &config.catch_up {
+                    catch_up.check(&mut client, &progress_name, token, &mut head).await?;
+                }
 
-            tx.send(AxonEventProcessed {
-                message_identifier: event.message_identifier,
-            }).await?;
+                tx.send(AxonEventProcessed {
+                    message_identifier: event.message_identifier,
+                }).await?;
+            }
+        };
+
+        let position = match position {
+            Some(position) => position,
+            None => {
+                info!("Event processor: reconnect on request of AxonServer: {:?}", progress_name);
+                client = axon_server_handle.event_store_client();
+                initial_token = stored_token + 1;
+                continue;
+            }
+        };
+        let token = position_token(&mut client, position).await?;
+        info!("Event processor: replay: {:?}: {:?}: token: {:?}", progress_name, position, token);
+        query_model.reset_token(token).await?;
+        if let Some(window) = window.as_mut() {
+            window.clear();
+            query_model.store_token_with_window(token, &[]).await?;
         }
+        initial_token = token + 1;
     }
 }
 
-fn create_output_stream(client_id: String, initial_token: i64, mut rx: Receiver<AxonEventProcessed>) -> impl Stream<Item = GetEventsRequest> {
+fn create_output_stream(client_id: String, component_name: String, processor_name: String, initial_token: i64, mut rx: Receiver<AxonEventProcessed>) -> impl Stream<Item = GetEventsRequest> {
     stream! {
         debug!("Event Processor: stream: start: {:?}", rx);
 
@@ -69,8 +498,8 @@ fn create_output_stream(client_id: String, initial_token: i64, mut rx: Receiver<
             tracking_token: initial_token,
             number_of_permits: permits,
             client_id: client_id,
-            component_name: "Dendrite".to_string(),
-            processor: "Event Processor".to_string(),
+            component_name,
+            processor: processor_name,
             blacklist: Vec::new(),
             force_read_from_leader: false,
         };
@@ -1,27 +1,108 @@
-use anyhow::Result;
+use anyhow::{anyhow,Result};
 use tonic::transport::Channel;
 use crate::axon_server::event::{Event,GetAggregateEventsRequest};
 use crate::axon_server::event::event_store_client::EventStoreClient;
-use super::AxonServerHandle;
+use super::{AxonClients,AxonServerHandle};
+
+/// Which part of the history of an aggregate `query_events_with_options` returns.
+///
+/// With `allow_snapshots`, AxonServer starts with the latest snapshot of the aggregate (within the range), followed by
+/// the events after it, so that consumers that compute the state of an aggregate need not read its whole history. The
+/// snapshot is an `Event` with `snapshot` set; its payload is the serialized state of the aggregate, not an event. Use
+/// `split_snapshot` to separate it from the events.
+#[derive(Debug,Clone,Copy)]
+pub struct EventQueryOptions {
+    pub allow_snapshots: bool,
+    pub initial_sequence: i64,
+    pub max_sequence: i64,
+}
+
+impl Default for EventQueryOptions {
+    fn default() -> Self {
+        EventQueryOptions {
+            allow_snapshots: false,
+            initial_sequence: 0,
+            max_sequence: i64::MAX,
+        }
+    }
+}
+
+impl EventQueryOptions {
+    pub fn with_snapshots(mut self) -> Self {
+        self.allow_snapshots = true;
+        self
+    }
+
+    pub fn with_initial_sequence(mut self, initial_sequence: i64) -> Self {
+        self.initial_sequence = initial_sequence;
+        self
+    }
+
+    /// Returns the events up to and including the given sequence number, which must not be negative.
+    pub fn with_max_sequence(mut self, max_sequence: i64) -> Self {
+        self.max_sequence = max_sequence;
+        self
+    }
+}
 
 pub async fn query_events(axon_server_handle: &AxonServerHandle, aggregate_identifier: &str) -> Result<Vec<Event>> {
-    let axon_server_handle = axon_server_handle.clone();
-    let conn = axon_server_handle.conn;
-    let mut client = EventStoreClient::new(conn);
+    let mut client = axon_server_handle.event_store_client();
     query_events_from_client(&mut client, aggregate_identifier).await
 }
 
+/// Returns the latest snapshot of the aggregate, if any, followed by the events after it.
+pub async fn query_events_with_snapshot(axon_server_handle: &AxonServerHandle, aggregate_identifier: &str) -> Result<Vec<Event>> {
+    query_events_with_options(axon_server_handle, aggregate_identifier, EventQueryOptions::default().with_snapshots()).await
+}
+
+pub async fn query_events_with_options(axon_server_handle: &AxonServerHandle, aggregate_identifier: &str, options: EventQueryOptions) -> Result<Vec<Event>> {
+    let mut client = axon_server_handle.event_store_client();
+    list_aggregate_events(&mut client, aggregate_identifier, options).await
+}
+
+/// Separates the snapshot at the start of the result of a query with snapshots, if any, from the events after it.
+pub fn split_snapshot(mut events: Vec<Event>) -> (Option<Event>,Vec<Event>) {
+    if events.first().map(|event| event.snapshot).unwrap_or(false) {
+        let snapshot = events.remove(0);
+        return (Some(snapshot), events);
+    }
+    (None, events)
+}
+
 pub async fn query_events_from_client(client: &mut EventStoreClient<Channel>, aggregate_identifier: &str) -> Result<Vec<Event>> {
+    query_events_up_to(client, aggregate_identifier, i64::MAX).await
+}
+
+/// Returns the events of the aggregate up to and including the given sequence number.
+pub async fn query_events_up_to(client: &mut EventStoreClient<Channel>, aggregate_identifier: &str, max_sequence: i64) -> Result<Vec<Event>> {
+    list_aggregate_events(client, aggregate_identifier, EventQueryOptions::default().with_max_sequence(max_sequence)).await
+}
+
+/// Returns the latest snapshot of the aggregate, if any, followed by the events after it.
+pub async fn query_events_from_snapshot(client: &mut EventStoreClient<Channel>, aggregate_identifier: &str) -> Result<Vec<Event>> {
+    list_aggregate_events(client, aggregate_identifier, EventQueryOptions::default().with_snapshots()).await
+}
+
+async fn list_aggregate_events(client: &mut EventStoreClient<Channel>, aggregate_identifier: &str, options: EventQueryOptions) -> Result<Vec<Event>> {
+    if options.max_sequence < 0 {
+        return Err(anyhow!("Invalid max sequence: {:?}: {:?}", aggregate_identifier, options.max_sequence));
+    }
+    // AxonServer reads a max sequence of 0 as no limit, so the first event is selected here instead, without a snapshot
+    // that could cover more than the first event.
+    let only_first = options.max_sequence == 0;
     let request = GetAggregateEventsRequest {
         aggregate_id: aggregate_identifier.to_string(),
-        allow_snapshots: false,
-        initial_sequence: 0,
-        max_sequence: std::i64::MAX,
+        allow_snapshots: options.allow_snapshots && !only_first,
+        initial_sequence: options.initial_sequence,
+        max_sequence: if only_first { 1 } else { options.max_sequence },
         min_token: 0,
     };
     let mut result = Vec::new();
     let mut stream = client.list_aggregate_events(request).await?.into_inner();
     while let Some(event) = stream.message().await? {
+        if only_first && event.aggregate_sequence_number > 0 {
+            continue;
+        }
         result.push(event.clone());
     }
     Ok(result)
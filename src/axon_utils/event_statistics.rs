@@ -0,0 +1,127 @@
+use anyhow::{anyhow,Result};
+use async_stream::stream;
+use tracing::debug;
+use std::collections::HashMap;
+use tokio::sync::mpsc::channel;
+use tonic::Request;
+use super::{AxonClients,AxonServerHandle};
+use super::event_stream::{first_token,last_token};
+use crate::axon_server::event::{QueryEventsRequest,QueryValue};
+use crate::axon_server::event::query_events_response::Data;
+use crate::axon_server::event::query_value::Data as ValueData;
+
+const QUERY_PERMITS: i64 = 1000;
+
+/// Store-level statistics of the global event stream. The event count is derived from the tokens, so it includes
+/// snapshots and events of all aggregate types.
+#[derive(Debug,Clone,PartialEq)]
+pub struct EventStoreStatistics {
+    pub first_token: i64,
+    pub head_token: i64,
+    pub event_count: i64,
+}
+
+pub async fn event_store_statistics(axon_server_handle: &AxonServerHandle) -> Result<EventStoreStatistics> {
+    let mut client = axon_server_handle.event_store_client();
+    let first_token = first_token(&mut client).await?;
+    let head_token = last_token(&mut client).await?;
+    Ok(EventStoreStatistics {
+        first_token,
+        head_token,
+        event_count: (head_token - first_token + 1).max(0),
+    })
+}
+
+/// Runs an ad-hoc query against the historic events, e.g., `groupby(payloadType, count())`, and returns the resulting
+/// rows as column name to value mappings. Rows that are updated while the query runs are only returned once, with their
+/// final values.
+pub async fn query_event_store(axon_server_handle: &AxonServerHandle, query: &str) -> Result<Vec<HashMap<String,QueryValue>>> {
+    debug!("Query event store: {:?}", query);
+    let mut client = axon_server_handle.event_store_client();
+    let (mut tx, mut rx) = channel::<QueryEventsRequest>(2);
+    let request = QueryEventsRequest {
+        query: query.to_string(),
+        number_of_permits: QUERY_PERMITS,
+        live_events: false,
+        force_read_from_leader: false,
+    };
+    tx.send(request.clone()).await?;
+    let outbound = stream! {
+        while let Some(request) = rx.recv().await {
+            yield request;
+        }
+    };
+    let mut responses = client.query_events(Request::new(outbound)).await?.into_inner();
+
+    let mut row_ids: HashMap<String,usize> = HashMap::new();
+    let mut rows = Vec::new();
+    let mut permits = QUERY_PERMITS;
+    while let Some(response) = responses.message().await? {
+        match response.data {
+            Some(Data::Row(row)) => {
+                let id = format!("{:?}", row.id_values);
+                match row_ids.get(&id) {
+                    Some(index) if !row.id_values.is_empty() => rows[*index] = row.values,
+                    _ => {
+                        row_ids.insert(id, rows.len());
+                        rows.push(row.values);
+                    }
+                }
+                permits -= 1;
+                if permits <= QUERY_PERMITS / 2 {
+                    tx.send(QueryEventsRequest { number_of_permits: QUERY_PERMITS / 2, ..request.clone() }).await?;
+                    permits += QUERY_PERMITS / 2;
+                }
+            }
+            Some(Data::FilesCompleted(_)) => break,
+            Some(Data::Columns(columns)) => debug!("Query event store: columns: {:?}", columns.column),
+            None => (),
+        }
+    }
+    debug!("Query event store: rows: {:?}", rows.len());
+    Ok(rows)
+}
+
+/// Returns the approximate number of events per payload type.
+pub async fn count_events_per_payload_type(axon_server_handle: &AxonServerHandle) -> Result<HashMap<String,i64>> {
+    count_events_grouped_by(axon_server_handle, "payloadType").await
+}
+
+/// Returns the approximate number of events per aggregate type.
+pub async fn count_events_per_aggregate_type(axon_server_handle: &AxonServerHandle) -> Result<HashMap<String,i64>> {
+    count_events_grouped_by(axon_server_handle, "aggregateType").await
+}
+
+/// Returns the approximate number of events of a single aggregate.
+pub async fn count_aggregate_events(axon_server_handle: &AxonServerHandle, aggregate_identifier: &str) -> Result<i64> {
+    let escaped = aggregate_identifier.replace('\\', "\\\\").replace('"', "\\\"");
+    let query = format!("aggregateIdentifier = \"{}\" | count()", escaped);
+    let rows = query_event_store(axon_server_handle, &query).await?;
+    match rows.last() {
+        Some(row) => count_value(row).ok_or_else(|| anyhow!("Missing count in result: {:?}", row)),
+        None => Ok(0),
+    }
+}
+
+async fn count_events_grouped_by(axon_server_handle: &AxonServerHandle, field: &str) -> Result<HashMap<String,i64>> {
+    let query = format!("groupby({}, count())", field);
+    let rows = query_event_store(axon_server_handle, &query).await?;
+    let mut counts = HashMap::new();
+    for row in rows {
+        let key = match row.get(field).and_then(|value| value.data.as_ref()) {
+            Some(ValueData::TextValue(key)) => key.clone(),
+            other => return Err(anyhow!("Unexpected value for {:?}: {:?}", field, other)),
+        };
+        let count = count_value(&row).ok_or_else(|| anyhow!("Missing count in result: {:?}", row))?;
+        counts.insert(key, count);
+    }
+    Ok(counts)
+}
+
+fn count_value(row: &HashMap<String,QueryValue>) -> Option<i64> {
+    match row.get("count").and_then(|value| value.data.as_ref()) {
+        Some(ValueData::NumberValue(count)) => Some(*count),
+        Some(ValueData::DoubleValue(count)) => Some(*count as i64),
+        _ => None,
+    }
+}
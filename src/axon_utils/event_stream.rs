@@ -0,0 +1,73 @@
+use anyhow::Result;
+use async_stream::stream;
+use tokio::sync::mpsc::{Sender,channel};
+use tonic::Request;
+use tonic::Streaming;
+use tonic::transport::Channel;
+use crate::axon_server::event::{EventWithToken,GetEventsRequest,GetFirstTokenRequest,GetLastTokenRequest,GetTokenAtRequest};
+use crate::axon_server::event::event_store_client::EventStoreClient;
+
+/// Reads the global event stream from a given token, granting flow-control permits in batches.
+pub(crate) struct EventStreamReader {
+    tx: Sender<GetEventsRequest>,
+    request: GetEventsRequest,
+    events: Streaming<EventWithToken>,
+    permits: i64,
+    batch_size: i64,
+}
+
+impl EventStreamReader {
+    pub(crate) async fn open(client: &mut EventStoreClient<Channel>, client_id: &str, component_name: &str, processor: &str, from_token: i64, batch_size: usize) -> Result<Self> {
+        let batch_size = batch_size.max(1) as i64;
+        let (mut tx, mut rx) = channel::<GetEventsRequest>(2);
+        let mut request = GetEventsRequest {
+            tracking_token: from_token,
+            number_of_permits: batch_size * 2,
+            client_id: client_id.to_string(),
+            component_name: component_name.to_string(),
+            processor: processor.to_string(),
+            blacklist: Vec::new(),
+            force_read_from_leader: false,
+        };
+        tx.send(request.clone()).await?;
+        request.number_of_permits = batch_size;
+        let outbound = stream! {
+            while let Some(request) = rx.recv().await {
+                yield request;
+            }
+        };
+        let events = client.list_events(Request::new(outbound)).await?.into_inner();
+        Ok(EventStreamReader {
+            tx,
+            request,
+            events,
+            permits: batch_size * 2,
+            batch_size,
+        })
+    }
+
+    pub(crate) async fn next(&mut self) -> Result<Option<EventWithToken>> {
+        let event_with_token = self.events.message().await?;
+        if event_with_token.is_some() {
+            self.permits -= 1;
+            if self.permits <= self.batch_size {
+                self.tx.send(self.request.clone()).await?;
+                self.permits += self.batch_size;
+            }
+        }
+        Ok(event_with_token)
+    }
+}
+
+pub(crate) async fn first_token(client: &mut EventStoreClient<Channel>) -> Result<i64> {
+    Ok(client.get_first_token(GetFirstTokenRequest {}).await?.into_inner().token)
+}
+
+pub(crate) async fn last_token(client: &mut EventStoreClient<Channel>) -> Result<i64> {
+    Ok(client.get_last_token(GetLastTokenRequest {}).await?.into_inner().token)
+}
+
+/// Returns the token of the first event at or after the given time (in milliseconds since the epoch), or -1 if there is none.
+pub(crate) async fn token_at(client: &mut EventStoreClient<Channel>, instant: i64) -> Result<i64> {
+    Ok(client.get_token_at(GetTokenAtRequest { instant }).await?.into_inner().token)
+}
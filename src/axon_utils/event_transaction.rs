@@ -0,0 +1,129 @@
+use anyhow::{anyhow,Result};
+use tracing::{debug,info};
+use std::collections::HashMap;
+use tonic::Request;
+use tonic::transport::Channel;
+use uuid::Uuid;
+use super::{AxonClients,AxonServerHandle};
+use super::aggregate_migration::{now_millis,read_highest_sequence_nr};
+use super::message_size::explain_status;
+use super::meta_data_stamping::stamping_policy;
+use super::read_only::check_writable;
+use super::redaction::log_safe;
+use super::tenant_quota::TenantScope;
+use crate::axon_server::{MetaDataValue,SerializedObject};
+use crate::axon_server::event::Event;
+use crate::axon_server::event::event_store_client::EventStoreClient;
+
+/// Events for multiple aggregates that are appended to the event store in a single transaction.
+///
+/// Each aggregate continues from its highest sequence number in the event store, or from the expected sequence number
+/// (the sequence number of its last event, `-1` for a new aggregate) if one is given. AxonServer rejects the whole
+/// transaction if any of the sequence numbers is taken. Use it sparingly: for migration tooling and for the rare
+/// invariants that span aggregates.
+#[derive(Debug,Clone,Default)]
+pub struct EventTransaction {
+    pub aggregates: Vec<AggregateEvents>,
+}
+
+#[derive(Debug,Clone)]
+pub struct AggregateEvents {
+    pub aggregate_id: String,
+    pub aggregate_type: String,
+    pub expected_sequence_nr: Option<i64>,
+    pub payloads: Vec<SerializedObject>,
+    pub meta_data: HashMap<String,MetaDataValue>,
+}
+
+pub fn create_event_transaction() -> EventTransaction {
+    EventTransaction::default()
+}
+
+impl EventTransaction {
+    pub fn with_events(self, aggregate_id: &str, aggregate_type: &str, payloads: Vec<SerializedObject>) -> Self {
+        self.add(aggregate_id, aggregate_type, None, payloads)
+    }
+
+    pub fn with_expected_events(self, aggregate_id: &str, aggregate_type: &str, expected_sequence_nr: i64, payloads: Vec<SerializedObject>) -> Self {
+        self.add(aggregate_id, aggregate_type, Some(expected_sequence_nr), payloads)
+    }
+
+    fn add(mut self, aggregate_id: &str, aggregate_type: &str, expected_sequence_nr: Option<i64>, payloads: Vec<SerializedObject>) -> Self {
+        self.aggregates.push(AggregateEvents {
+            aggregate_id: aggregate_id.to_string(),
+            aggregate_type: aggregate_type.to_string(),
+            expected_sequence_nr,
+            payloads,
+            meta_data: HashMap::new(),
+        });
+        self
+    }
+}
+
+/// Tells whether the connected AxonServer appends the events of multiple aggregates atomically.
+///
+/// AxonServer commits all events of one append call in a single transaction, within one context, and checks the
+/// sequence numbers of every aggregate in it. The capability is only assumed for a server that identified itself when
+/// the connection was set up (see `AxonServerHandle::server_version`); a proxy or a stand-in that does not answer the
+/// platform service gives no such guarantee.
+pub fn supports_multi_aggregate_append(axon_server_handle: &AxonServerHandle) -> bool {
+    axon_server_handle.server_version.is_some()
+}
+
+/// Appends the events of the transaction in a single call and returns the sequence number of the last event of each
+/// aggregate. Fails without appending anything if the server does not support multi-aggregate transactions, or if the
+/// events exceed the quota of the tenant (see `TenantQuotas`).
+pub async fn append_event_transaction(axon_server_handle: &AxonServerHandle, transaction: &EventTransaction) -> Result<HashMap<String,i64>> {
+    if !supports_multi_aggregate_append(axon_server_handle) {
+        return Err(anyhow!("AxonServer does not support appending events of multiple aggregates in one transaction"));
+    }
+    let event_count = transaction.aggregates.iter().map(|aggregate| aggregate.payloads.len()).sum();
+    let tenant = TenantScope {
+        context: axon_server_handle.context.clone(),
+        metrics: axon_server_handle.metrics.clone(),
+    };
+    tenant.check_event_quota(event_count)?;
+    let mut client = axon_server_handle.event_store_client();
+    append_event_transaction_with_client(&mut client, transaction).await
+}
+
+/// Like `append_event_transaction`, without the capability check.
+pub async fn append_event_transaction_with_client(client: &mut EventStoreClient<Channel>, transaction: &EventTransaction) -> Result<HashMap<String,i64>> {
+    let timestamp = now_millis()?;
+    let policy = stamping_policy();
+    let mut last_sequence_nrs: HashMap<String,i64> = HashMap::new();
+    let mut events = Vec::new();
+    for aggregate in &transaction.aggregates {
+        let last_sequence_nr = match (last_sequence_nrs.get(&aggregate.aggregate_id), aggregate.expected_sequence_nr) {
+            (Some(&last_sequence_nr), _) => last_sequence_nr,
+            (None, Some(expected_sequence_nr)) => expected_sequence_nr,
+            (None, None) => read_highest_sequence_nr(client, &aggregate.aggregate_id).await?,
+        };
+        let mut meta_data = aggregate.meta_data.clone();
+        policy.stamp(None, &mut meta_data);
+        let mut sequence_nr = last_sequence_nr;
+        for payload in &aggregate.payloads {
+            sequence_nr += 1;
+            events.push(Event {
+                message_identifier: format!("{:?}", Uuid::new_v4().to_simple()),
+                timestamp,
+                aggregate_identifier: aggregate.aggregate_id.clone(),
+                aggregate_sequence_number: sequence_nr,
+                aggregate_type: aggregate.aggregate_type.clone(),
+                payload: Some(payload.clone()),
+                meta_data: meta_data.clone(),
+                snapshot: false,
+            });
+        }
+        last_sequence_nrs.insert(aggregate.aggregate_id.clone(), sequence_nr);
+    }
+    debug!("Event transaction: {:?}", log_safe(&events));
+
+    let count = events.len();
+    if count > 0 {
+        check_writable("append events")?;
+        client.append_event(Request::new(futures_util::stream::iter(events))).await.map_err(explain_status)?;
+    }
+    info!("Appended event transaction: aggregates: {:?}: events: {:?}", last_sequence_nrs.len(), count);
+    Ok(last_sequence_nrs)
+}
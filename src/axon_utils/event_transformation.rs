@@ -0,0 +1,134 @@
+use anyhow::Result;
+use tracing::{debug,info};
+use std::collections::HashMap;
+use tonic::Request;
+use tonic::transport::Channel;
+use super::{AxonClients,AxonServerHandle};
+use super::event_stream::{EventStreamReader,last_token};
+use super::message_size::explain_status;
+use super::read_only::check_writable;
+use super::redaction::log_safe;
+use crate::axon_server::SerializedObject;
+use crate::axon_server::event::{Event,EventWithToken};
+use crate::axon_server::event::event_store_client::EventStoreClient;
+
+/// What to do with an event while copying the event store.
+#[derive(Debug,Clone)]
+pub enum EventTransformation {
+    Keep,
+    Replace(SerializedObject),
+    Remove,
+}
+
+/// Selects the range of the global event stream that is copied. When `to_token` is `None`, events are copied up to
+/// the last token at the start of the copy. A `to_token` beyond the end of the stream waits for new events.
+#[derive(Debug,Clone)]
+pub struct EventCopyJob {
+    pub from_token: i64,
+    pub to_token: Option<i64>,
+    pub batch_size: usize,
+}
+
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct TransformationReport {
+    pub events_read: usize,
+    pub events_kept: usize,
+    pub events_replaced: usize,
+    pub events_removed: usize,
+    pub last_token: Option<i64>,
+}
+
+pub fn create_event_copy_job() -> EventCopyJob {
+    EventCopyJob {
+        from_token: 0,
+        to_token: None,
+        batch_size: 100,
+    }
+}
+
+/// Copies the events of the source event store to the target event store, passing each event through the transform.
+///
+/// AxonServer does not allow events to be changed in place, so payload fixes (PII scrubbing, misspelled type names)
+/// are made by copying the store into a fresh context and switching the applications over when the copy is done.
+/// When events are removed, the sequence numbers of the later events of the same aggregate are shifted down. Without
+/// a target the copy is a dry run that only produces the report. The `last_token` of the report can be used as the
+/// `from_token` of a follow-up job to copy the events that were added in the meantime.
+pub async fn copy_transform_events<F>(
+    source: &AxonServerHandle,
+    target: Option<&AxonServerHandle>,
+    job: &EventCopyJob,
+    transform: F
+) -> Result<TransformationReport>
+where F: Fn(&Event) -> Result<EventTransformation>
+{
+    let mut source_client = source.event_store_client();
+    let mut target_client = target.map(|target| target.event_store_client());
+    let to_token = match job.to_token {
+        Some(to_token) => to_token,
+        None => last_token(&mut source_client).await?,
+    };
+    info!("Copy events: from: {:?}: to: {:?}: dry run: {:?}", job.from_token, to_token, target.is_none());
+
+    let mut report = TransformationReport::default();
+    if to_token < job.from_token {
+        return Ok(report);
+    }
+
+    let batch_size = job.batch_size.max(1);
+    let mut events = EventStreamReader::open(&mut source_client, &source.display_name, &source.component_name, "Event Transformation", job.from_token, batch_size).await?;
+
+    let mut removed: HashMap<String,i64> = HashMap::new();
+    let mut batch = Vec::new();
+    while let Some(EventWithToken { event, token, .. }) = events.next().await? {
+        if let Some(mut event) = event {
+            report.events_read += 1;
+            let transformation = transform(&event)?;
+            debug!("Event transformation: {:?}: {:?}", token, log_safe(&transformation));
+            match transformation {
+                EventTransformation::Remove => {
+                    report.events_removed += 1;
+                    if !event.aggregate_identifier.is_empty() {
+                        *removed.entry(event.aggregate_identifier.clone()).or_insert(0) += 1;
+                    }
+                }
+                transformation => {
+                    if let EventTransformation::Replace(payload) = transformation {
+                        report.events_replaced += 1;
+                        event.payload = Some(payload);
+                    } else {
+                        report.events_kept += 1;
+                    }
+                    if let Some(shift) = removed.get(&event.aggregate_identifier) {
+                        event.aggregate_sequence_number -= shift;
+                    }
+                    batch.push(event);
+                }
+            }
+        }
+        report.last_token = Some(token);
+
+        if batch.len() >= batch_size || token >= to_token {
+            append_batch(target_client.as_mut(), &mut batch).await?;
+        }
+        if token >= to_token {
+            break;
+        }
+    }
+    append_batch(target_client.as_mut(), &mut batch).await?;
+
+    info!("Copied events: {:?}", report);
+    Ok(report)
+}
+
+async fn append_batch(client: Option<&mut EventStoreClient<Channel>>, batch: &mut Vec<Event>) -> Result<()> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let events = std::mem::take(batch);
+    if let Some(client) = client {
+        debug!("Append batch of events: {:?}", events.len());
+        check_writable("append events")?;
+        client.append_event(Request::new(futures_util::stream::iter(events))).await.map_err(explain_status)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,130 @@
+use anyhow::Result;
+use tracing::warn;
+use once_cell::sync::Lazy;
+use rand::Rng;
+use std::collections::HashMap;
+use std::fmt::{Display,Formatter};
+use std::sync::{Arc,Mutex};
+use std::time::Duration;
+use tokio::time::delay_for;
+
+static FAULT_INJECTOR: Lazy<FaultInjector> = Lazy::new(FaultInjector::default);
+
+/// Returns the process-wide fault injector that is consulted by the workers.
+pub fn fault_injector() -> &'static FaultInjector {
+    &FAULT_INJECTOR
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum FaultTarget {
+    /// Commands that are about to be handled by the command worker.
+    Command,
+    /// Events that are about to be handled by an event processor.
+    Event,
+    /// Events that are about to be appended to the event store.
+    Append,
+}
+
+/// Probabilities (between 0.0 and 1.0) of the faults that are injected for a target. At most one fault is injected
+/// per message, so the sum of the probabilities should not exceed 1.0.
+#[derive(Debug,Clone,Default)]
+pub struct FaultRule {
+    pub drop_probability: f64,
+    pub error_probability: f64,
+    pub delay_probability: f64,
+    pub delay: Duration,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Fault {
+    Drop,
+    Error,
+    Delay(Duration),
+}
+
+/// Injects faults into the processing of messages, so that the retry, quarantine and reconnect behavior can be
+/// tested under controlled failure. No faults are injected until a rule is set.
+#[derive(Debug,Clone,Default)]
+pub struct FaultInjector {
+    rules: Arc<Mutex<HashMap<FaultTarget,FaultRule>>>,
+}
+
+impl FaultInjector {
+    pub fn set_rule(&self, target: FaultTarget, rule: FaultRule) {
+        if let Ok(mut rules) = self.rules.lock() {
+            rules.insert(target, rule);
+        }
+    }
+
+    pub fn clear_rule(&self, target: FaultTarget) {
+        if let Ok(mut rules) = self.rules.lock() {
+            rules.remove(&target);
+        }
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut rules) = self.rules.lock() {
+            rules.clear();
+        }
+    }
+
+    pub fn choose(&self, target: FaultTarget) -> Option<Fault> {
+        let rule = self.rules.lock().ok().and_then(|rules| rules.get(&target).cloned())?;
+        let draw: f64 = rand::thread_rng().gen();
+        if draw < rule.drop_probability {
+            Some(Fault::Drop)
+        } else if draw < rule.drop_probability + rule.error_probability {
+            Some(Fault::Error)
+        } else if draw < rule.drop_probability + rule.error_probability + rule.delay_probability {
+            Some(Fault::Delay(rule.delay))
+        } else {
+            None
+        }
+    }
+
+    /// Applies a randomly chosen fault for the target. Waits in case of a delay, returns an `InjectedFault` error in
+    /// case of an error, and returns `true` if the message should be dropped.
+    pub async fn inject(&self, target: FaultTarget) -> Result<bool> {
+        match self.choose(target) {
+            Some(Fault::Drop) => {
+                warn!("Fault injection: drop: {:?}", target);
+                Ok(true)
+            }
+            Some(Fault::Error) => {
+                warn!("Fault injection: error: {:?}", target);
+                Err(InjectedFault { target }.into())
+            }
+            Some(Fault::Delay(delay)) => {
+                warn!("Fault injection: delay: {:?}: {:?}", target, delay);
+                delay_for(delay).await;
+                Ok(false)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InjectedFault {
+    pub target: FaultTarget,
+}
+
+impl Display for InjectedFault {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Injected fault: {:?}", self.target)
+    }
+}
+
+impl std::error::Error for InjectedFault {}
+
+/// Marks a command that was dropped by the fault injector, so that no response is sent for it.
+#[derive(Debug)]
+pub(crate) struct DroppedCommand;
+
+impl Display for DroppedCommand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Command dropped by fault injection")
+    }
+}
+
+impl std::error::Error for DroppedCommand {}
@@ -0,0 +1,117 @@
+use anyhow::{anyhow,Result};
+use serde::{Deserialize,Serialize};
+use std::path::{Path,PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::debug;
+use super::event_processor::{TokenStore,TrackingConfig};
+
+/// Token store that keeps each tracking token in a file of its own, for single-instance deployments that need no
+/// database to remember their position.
+///
+/// The file of a token key is `<directory>/<token key>.json`, with characters that are not safe in file names replaced
+/// by `_`. Every write goes to a temporary file that is synced and then renamed over the old file, so a crash leaves
+/// either the old or the new token. Segment claims are not recorded: the default claims always succeed, so run a
+/// single instance.
+#[derive(Debug,Clone)]
+pub struct FileTokenStore {
+    directory: PathBuf,
+    token_key: String,
+    lock: Arc<Mutex<()>>,
+}
+
+#[derive(Debug,Clone,Default,Serialize,Deserialize)]
+struct TokenFile {
+    token: Option<i64>,
+    schema_version: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    recent_events: Vec<String>,
+}
+
+pub fn create_file_token_store<P: AsRef<Path>>(directory: P, tracking: &TrackingConfig) -> FileTokenStore {
+    FileTokenStore {
+        directory: directory.as_ref().to_path_buf(),
+        token_key: tracking.token_key.clone(),
+        lock: Arc::new(Mutex::new(())),
+    }
+}
+
+impl FileTokenStore {
+    /// Returns the path of the file that holds the token of this store.
+    pub fn path(&self) -> PathBuf {
+        let file_name: String = self.token_key.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || "-_.#".contains(c) { c } else { '_' })
+            .collect();
+        self.directory.join(format!("{}.json", file_name))
+    }
+
+    async fn read(&self) -> Result<TokenFile> {
+        let buf = match tokio::fs::read(self.path()).await {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(TokenFile::default()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(serde_json::from_slice(&buf)?)
+    }
+
+    async fn update(&self, change: impl FnOnce(&mut TokenFile)) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut token_file = self.read().await?;
+        change(&mut token_file);
+        let path = self.path();
+        let temporary_path = path.with_extension("json.tmp");
+        tokio::fs::create_dir_all(&self.directory).await?;
+        let mut file = tokio::fs::File::create(&temporary_path).await?;
+        file.write_all(&serde_json::to_vec(&token_file)?).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&temporary_path, &path).await?;
+        debug!("Stored token file: {:?}: {:?}", path, token_file);
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl TokenStore for FileTokenStore {
+    async fn store_token(&self, token: i64) -> Result<()> {
+        self.update(|token_file| token_file.token = Some(token)).await
+            .map_err(|e| anyhow!("Cannot store tracking token: {:?}: {:?}: {:?}", self.path(), token, e))
+    }
+
+    async fn retrieve_token(&self) -> Result<Option<i64>> {
+        Ok(self.read().await?.token)
+    }
+
+    async fn store_token_with_window(&self, token: i64, window: &[String]) -> Result<()> {
+        self.update(|token_file| {
+            token_file.token = Some(token);
+            token_file.recent_events = window.to_vec();
+        }).await.map_err(|e| anyhow!("Cannot store tracking token: {:?}: {:?}: {:?}", self.path(), token, e))
+    }
+
+    async fn retrieve_deduplication_window(&self) -> Result<Vec<String>> {
+        Ok(self.read().await?.recent_events)
+    }
+
+    async fn store_schema_version(&self, version: i64) -> Result<()> {
+        self.update(|token_file| token_file.schema_version = Some(version)).await
+    }
+
+    async fn retrieve_schema_version(&self) -> Result<Option<i64>> {
+        Ok(self.read().await?.schema_version)
+    }
+
+    fn for_tracking(&self, tracking: &TrackingConfig) -> Self {
+        let mut store = self.clone();
+        store.token_key = tracking.token_key.clone();
+        store
+    }
+
+    async fn reset_token(&self, token: i64) -> Result<()> {
+        self.update(|token_file| {
+            token_file.token = Some(token);
+            token_file.recent_events.clear();
+        }).await
+    }
+}
+
@@ -0,0 +1,107 @@
+use tracing::debug;
+use std::time::Duration;
+
+/// Determines how many flow-control permits a worker advertises to AxonServer.
+#[derive(Debug,Clone)]
+pub enum FlowControlMode {
+    /// Keeps between `batch_size` and `2 * batch_size` permits outstanding.
+    Fixed { batch_size: i64 },
+    /// Adjusts the number of outstanding permits to the observed handler latency.
+    Adaptive(AdaptiveFlowControl),
+}
+
+impl Default for FlowControlMode {
+    fn default() -> Self {
+        FlowControlMode::Fixed { batch_size: 3 }
+    }
+}
+
+/// Settings for additive-increase / multiplicative-decrease (AIMD) flow control.
+///
+/// The window is the number of permits that is kept outstanding at AxonServer. While the handlers answer within
+/// `target_latency` and the window is actually used, the window grows by `additive_increase`. When the latency of a
+/// response exceeds `target_latency`, the window shrinks by `multiplicative_decrease`.
+#[derive(Debug,Clone)]
+pub struct AdaptiveFlowControl {
+    pub initial_window: i64,
+    pub min_window: i64,
+    pub max_window: i64,
+    pub target_latency: Duration,
+    pub additive_increase: i64,
+    pub multiplicative_decrease: f64,
+}
+
+impl Default for AdaptiveFlowControl {
+    fn default() -> Self {
+        AdaptiveFlowControl {
+            initial_window: 6,
+            min_window: 1,
+            max_window: 100,
+            target_latency: Duration::from_millis(250),
+            additive_increase: 1,
+            multiplicative_decrease: 0.5,
+        }
+    }
+}
+
+/// Keeps track of the permits that a worker granted to AxonServer.
+#[derive(Debug)]
+pub(crate) struct PermitController {
+    mode: FlowControlMode,
+    window: i64,
+    outstanding: i64,
+}
+
+impl PermitController {
+    pub(crate) fn new(mode: FlowControlMode) -> Self {
+        let window = match &mode {
+            FlowControlMode::Fixed { batch_size } => batch_size * 2,
+            FlowControlMode::Adaptive(settings) => settings.initial_window.max(settings.min_window).min(settings.max_window),
+        };
+        PermitController {
+            mode,
+            window,
+            outstanding: 0,
+        }
+    }
+
+    pub(crate) fn window(&self) -> i64 {
+        self.window
+    }
+
+    /// Records that a message was answered, given the time it took and the number of messages that are still
+    /// being handled.
+    pub(crate) fn record_response(&mut self, latency: Duration, in_flight: usize) {
+        self.outstanding -= 1;
+        if let FlowControlMode::Adaptive(settings) = &self.mode {
+            if latency > settings.target_latency {
+                let decreased = (self.window as f64 * settings.multiplicative_decrease).floor() as i64;
+                self.window = decreased.max(settings.min_window);
+                debug!("Flow control: latency {:?} above target: window: {:?}", latency, self.window);
+            } else if in_flight as i64 + self.outstanding + 1 >= self.window {
+                self.window = (self.window + settings.additive_increase).min(settings.max_window);
+                debug!("Flow control: latency {:?} below target: window: {:?}", latency, self.window);
+            }
+        }
+    }
+
+    /// Returns the number of permits that should be granted now, possibly zero.
+    pub(crate) fn permits_due(&self) -> i64 {
+        match &self.mode {
+            FlowControlMode::Fixed { batch_size } => {
+                if self.outstanding <= *batch_size { *batch_size } else { 0 }
+            }
+            FlowControlMode::Adaptive(_) => {
+                if self.outstanding * 2 <= self.window { self.window - self.outstanding } else { 0 }
+            }
+        }
+    }
+
+    pub(crate) fn granted(&mut self, permits: i64) {
+        self.outstanding += permits;
+    }
+
+    pub(crate) fn outstanding(&self) -> i64 {
+        self.outstanding
+    }
+}
@@ -0,0 +1,199 @@
+use anyhow::Result;
+use serde::{Deserialize,Serialize};
+use std::collections::HashMap;
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex,MutexGuard,PoisonError};
+use tokio::sync::Notify;
+use tracing::{debug,info,warn};
+
+/// Concurrency limits of event processors and their handlers, e.g., read from a file that operators edit:
+///
+/// `{"processors": {"greeting": {"limit": 4, "handlers": {"GreetedEvent": 1}}}}`
+///
+/// The limit of a processor applies to all handler calls of the processor together, across its segments and replay
+/// partitions. The limit of a handler, by message type, applies to the calls of that handler within the processor.
+/// Processors and handlers without a limit are not limited.
+#[derive(Debug,Clone,Default,PartialEq,Serialize,Deserialize)]
+pub struct ConcurrencyConfig {
+    #[serde(default)]
+    pub processors: HashMap<String,ProcessorConcurrency>,
+}
+
+#[derive(Debug,Clone,Default,PartialEq,Serialize,Deserialize)]
+pub struct ProcessorConcurrency {
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub handlers: HashMap<String,usize>,
+}
+
+/// Reads a `ConcurrencyConfig` from a JSON file.
+pub async fn load_concurrency_config<P: AsRef<Path>>(path: P) -> Result<ConcurrencyConfig> {
+    let buf = tokio::fs::read(path.as_ref()).await?;
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+// Processor name and, for the limit of a handler, its message type.
+type LimitKey = (String,Option<String>);
+
+/// Concurrency limits that event processors apply to their handler calls (see `EventProcessorConfig`). The limits can
+/// be changed while the processors run: a lower limit lets the calls in progress finish, and holds new calls until
+/// fewer calls than the limit are in progress. Clones share their limits.
+#[derive(Debug,Clone,Default)]
+pub struct ConcurrencyLimits {
+    limiters: Arc<Mutex<HashMap<LimitKey,Arc<Limiter>>>>,
+}
+
+impl ConcurrencyLimits {
+    pub fn set_processor_limit(&self, processor: &str, limit: Option<usize>) {
+        self.set_limit((processor.to_string(), None), limit);
+    }
+
+    pub fn set_handler_limit(&self, processor: &str, message_type: &str, limit: Option<usize>) {
+        self.set_limit((processor.to_string(), Some(message_type.to_string())), limit);
+    }
+
+    /// Replaces all limits by the limits of the configuration. Limits that are missing from the configuration are
+    /// lifted.
+    pub fn apply(&self, config: &ConcurrencyConfig) {
+        let mut limits: HashMap<LimitKey,Option<usize>> = lock(&self.limiters).keys()
+            .map(|key| (key.clone(), None))
+            .collect();
+        for (processor, processor_config) in &config.processors {
+            limits.insert((processor.clone(), None), processor_config.limit);
+            for (message_type, limit) in &processor_config.handlers {
+                limits.insert((processor.clone(), Some(message_type.clone())), Some(*limit));
+            }
+        }
+        for (key, limit) in limits {
+            self.set_limit(key, limit);
+        }
+    }
+
+    fn set_limit(&self, key: LimitKey, limit: Option<usize>) {
+        let limiter = lock(&self.limiters).entry(key.clone()).or_default().clone();
+        let limit = limit.map(|limit| limit.max(1));
+        if limiter.set_limit(limit) {
+            info!("Concurrency limit: processor: {:?}: handler: {:?}: {:?}", key.0, key.1, limit);
+        }
+    }
+
+    /// Waits until a call of the handler of the given message type may start within the limits, and returns the
+    /// permit that holds its place until it is dropped.
+    pub async fn acquire(&self, processor: &str, message_type: &str) -> ConcurrencyPermit {
+        let (handler_limiter, processor_limiter) = {
+            let limiters = lock(&self.limiters);
+            (
+                limiters.get(&(processor.to_string(), Some(message_type.to_string()))).cloned(),
+                limiters.get(&(processor.to_string(), None)).cloned(),
+            )
+        };
+        let mut permits = Vec::new();
+        for limiter in handler_limiter.into_iter().chain(processor_limiter) {
+            permits.push(limiter.acquire().await);
+        }
+        ConcurrencyPermit { _permits: permits }
+    }
+}
+
+/// Place of a handler call within the concurrency limits. Dropping it frees the place.
+#[derive(Debug)]
+pub struct ConcurrencyPermit {
+    _permits: Vec<LimiterPermit>,
+}
+
+#[derive(Debug,Default)]
+struct Limiter {
+    state: Mutex<LimiterState>,
+    available: Notify,
+}
+
+#[derive(Debug,Default)]
+struct LimiterState {
+    limit: Option<usize>,
+    in_use: usize,
+}
+
+impl LimiterState {
+    fn has_room(&self) -> bool {
+        self.limit.map(|limit| self.in_use < limit).unwrap_or(true)
+    }
+}
+
+impl Limiter {
+    // Returns true if the limit changed.
+    fn set_limit(&self, limit: Option<usize>) -> bool {
+        let mut state = lock(&self.state);
+        let changed = state.limit != limit;
+        state.limit = limit;
+        if state.has_room() {
+            self.available.notify();
+        }
+        changed
+    }
+
+    async fn acquire(self: Arc<Self>) -> LimiterPermit {
+        loop {
+            {
+                let mut state = lock(&self.state);
+                if state.has_room() {
+                    state.in_use += 1;
+                    // Pass the turn on to the next waiter, in case the limit was raised.
+                    if state.has_room() {
+                        self.available.notify();
+                    }
+                    drop(state);
+                    return LimiterPermit { limiter: self };
+                }
+            }
+            self.available.notified().await;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LimiterPermit {
+    limiter: Arc<Limiter>,
+}
+
+impl Drop for LimiterPermit {
+    fn drop(&mut self) {
+        let mut state = lock(&self.limiter.state);
+        state.in_use = state.in_use.saturating_sub(1);
+        if state.has_room() {
+            self.limiter.available.notify();
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_,T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Reads the configuration file again and applies it to the limits whenever the process receives SIGHUP. Run it in a
+/// separate task. A file that cannot be read or parsed is logged, and the limits stay as they were. On platforms
+/// without SIGHUP, it returns immediately.
+pub async fn reload_concurrency_on_signal(limits: ConcurrencyLimits, path: PathBuf) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind,signal};
+        let mut hang_up = match signal(SignalKind::hangup()) {
+            Ok(hang_up) => hang_up,
+            Err(e) => {
+                warn!("Cannot listen for SIGHUP: {:?}", e);
+                return;
+            }
+        };
+        while hang_up.recv().await.is_some() {
+            debug!("Reload concurrency limits: {:?}", path);
+            match load_concurrency_config(&path).await {
+                Ok(config) => limits.apply(&config),
+                Err(e) => warn!("Cannot reload concurrency limits: {:?}: {:?}", path, e),
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (limits, path);
+    }
+}
@@ -0,0 +1,78 @@
+use anyhow::Error;
+use super::axon_error::AxonError;
+use super::business_rules::BusinessRuleError;
+use super::conflict::CONFLICT_ERROR_CODE;
+use super::retry_policy::is_transient_error;
+use crate::axon_server::ErrorMessage;
+
+/// Error code of AxonServer for a command that has no handler.
+pub const NO_HANDLER_FOR_COMMAND: &str = "AXONIQ-4000";
+/// Error code of AxonServer for a command that was rejected by its handler, e.g., because of a business rule, or that
+/// failed with an error that may go away on retry.
+pub const COMMAND_EXECUTION_ERROR: &str = "AXONIQ-4002";
+/// Error code of AxonServer for a command that failed because the aggregate was changed concurrently.
+pub const CONCURRENCY_EXCEPTION: &str = "AXONIQ-4004";
+/// Error code of AxonServer for a command that failed with an error that will not go away on retry.
+pub const COMMAND_EXECUTION_NON_TRANSIENT_ERROR: &str = "AXONIQ-4005";
+
+/// Kind of failure of a handler, which determines the error code of AxonServer in the response.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum HandlerErrorKind {
+    Business,
+    Concurrency,
+    MissingHandler,
+    Transient,
+    Technical,
+}
+
+impl HandlerErrorKind {
+    pub fn command_error_code(self) -> &'static str {
+        match self {
+            HandlerErrorKind::Business | HandlerErrorKind::Transient => COMMAND_EXECUTION_ERROR,
+            HandlerErrorKind::Concurrency => CONCURRENCY_EXCEPTION,
+            HandlerErrorKind::MissingHandler => NO_HANDLER_FOR_COMMAND,
+            HandlerErrorKind::Technical => COMMAND_EXECUTION_NON_TRANSIENT_ERROR,
+        }
+    }
+}
+
+/// Classifies the error of a handler: business rule violations (with error code `CONFLICT` for concurrency
+/// conflicts), missing handlers, errors that are likely to go away by themselves (see `is_transient_error`), and other
+/// technical errors.
+pub fn classify_handler_error(error: &Error) -> HandlerErrorKind {
+    if let Some(business_rule_error) = business_rule_error(error) {
+        if business_rule_error.error_code == CONFLICT_ERROR_CODE {
+            return HandlerErrorKind::Concurrency;
+        }
+        return HandlerErrorKind::Business;
+    }
+    if let Some(AxonError::MissingHandler(_)) = error.downcast_ref::<AxonError>() {
+        return HandlerErrorKind::MissingHandler;
+    }
+    if is_transient_error(error) {
+        return HandlerErrorKind::Transient;
+    }
+    HandlerErrorKind::Technical
+}
+
+pub(crate) fn business_rule_error(error: &Error) -> Option<&BusinessRuleError> {
+    if let Some(business_rule_error) = error.downcast_ref::<BusinessRuleError>() {
+        return Some(business_rule_error);
+    }
+    match error.downcast_ref::<AxonError>() {
+        Some(AxonError::Business(business_rule_error)) => Some(business_rule_error),
+        _ => None,
+    }
+}
+
+// The error message of a response carries the error code of the handler itself, e.g., the code of a business rule, so
+// that Rust clients can recover it; the response carries the error code of AxonServer. The details are the messages of
+// the error and its causes, like the details that Axon Framework sends.
+pub(crate) fn error_message(error: &Error, error_code: &str, location: &str) -> ErrorMessage {
+    ErrorMessage {
+        message: error.to_string(),
+        location: location.to_string(),
+        details: error.chain().map(|cause| cause.to_string()).collect(),
+        error_code: error_code.to_string(),
+    }
+}
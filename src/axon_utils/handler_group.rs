@@ -0,0 +1,114 @@
+use anyhow::Result;
+use tracing::warn;
+use std::time::{Duration,Instant};
+use super::Metrics;
+use super::event_processor::{EventContext,EventProcessorConfig};
+use super::handler_registry::TheHandlerRegistry;
+use super::handler_timeout::{DeadLetteredEvent,TimeoutPolicy};
+use super::metrics::label_value;
+use crate::axon_server::event::Event;
+
+/// Name of the group of the handlers of `event_processor_with_config`.
+pub const DEFAULT_HANDLER_GROUP: &str = "default";
+
+/// Event handlers of one processor that share an error policy, e.g., "critical" handlers that feed user-facing read
+/// models and "best-effort" handlers for analytics.
+///
+/// A handler that fails is tried again `retries` times, after `retry_delay`, or, when the group has no retries of its
+/// own, as the retry policy of the processor prescribes (see `RetryPolicy`). Then the policy of the group is applied:
+/// `Fail` stops the processor, `Skip` logs the failure and moves on, and `DeadLetter` hands the event to the store. Each
+/// failure is counted in `<worker>_handler_failures{group="<name>"}`. The groups handle an event in the order in which
+/// they were given to the processor, so put groups that fail the processor first.
+pub struct HandlerGroup<Q: Send + Clone> {
+    pub name: String,
+    pub registry: TheHandlerRegistry<Q,Option<Q>>,
+    pub retries: u32,
+    pub retry_delay: Duration,
+    pub policy: TimeoutPolicy,
+}
+
+pub fn create_handler_group<Q: Send + Clone>(name: &str, registry: TheHandlerRegistry<Q,Option<Q>>) -> HandlerGroup<Q> {
+    HandlerGroup {
+        name: name.to_string(),
+        registry,
+        retries: 0,
+        retry_delay: Duration::from_millis(0),
+        policy: TimeoutPolicy::Fail,
+    }
+}
+
+impl<Q: EventContext + Send + Sync> HandlerGroup<Q> {
+    pub fn with_retries(mut self, retries: u32, retry_delay: Duration) -> Self {
+        self.retries = retries;
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: TimeoutPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Calls the handler of this group for the event, if it has one. Returns `Ok(())` when the policy skipped or
+    /// dead-lettered the event.
+    pub(crate) async fn handle(&self, worker: &str, metrics: &Metrics, config: &EventProcessorConfig, event: &Event, token: i64, query_model: &Q) -> Result<()> {
+        let serialized_object = match &event.payload {
+            Some(serialized_object) => serialized_object,
+            None => return Ok(()),
+        };
+        let message_name = &serialized_object.r#type;
+        let event_handler = match self.registry.handlers.get(message_name) {
+            Some(event_handler) => event_handler,
+            None => return Ok(()),
+        };
+        let mut attempt = 0;
+        let error = loop {
+            let permit = config.concurrency.acquire(&config.tracking.processor_name, message_name).await;
+            let started = Instant::now();
+            let result = config.handler_timeouts.call(worker, metrics, &self.name, event, token, || {
+                (event_handler).handle(serialized_object.data.clone(), query_model.for_event(event, token))
+            }).await;
+            drop(permit);
+            // An event that was skipped or dead-lettered after a timeout counts as a failure.
+            if let Some(labels) = self.registry.labels(message_name) {
+                labels.record(metrics, "event", message_name, started.elapsed(), matches!(result, Ok(true)));
+            }
+            match result {
+                Ok(finished) => {
+                    if finished {
+                        config.slow_handler.check(worker, metrics, message_name, Some(&event.aggregate_identifier), started.elapsed());
+                    }
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!(
+                        "Handler failed: worker={} group={:?} message={:?} aggregate_id={:?} attempt={}: {:?}",
+                        worker, self.name, message_name, event.aggregate_identifier, attempt + 1, e
+                    );
+                    metrics.increment(&format!("{}_handler_failures{{group={}}}", worker, label_value(&self.name)), 1);
+                    let delay = if self.retries > 0 {
+                        Some(self.retry_delay).filter(|_| attempt < self.retries)
+                    } else {
+                        config.retry.next_delay(&e, attempt + 1)
+                    };
+                    match delay {
+                        Some(delay) => tokio::time::delay_for(delay).await,
+                        None => break e,
+                    }
+                }
+            }
+            attempt += 1;
+        };
+        match &self.policy {
+            TimeoutPolicy::Fail => Err(error),
+            TimeoutPolicy::Skip => {
+                warn!("Skip event after handler failure: worker={} group={:?} message={:?} token={}", worker, self.name, event.message_identifier, token);
+                Ok(())
+            }
+            TimeoutPolicy::DeadLetter(store) => {
+                let reason = format!("Handler group {:?} failed: {}", self.name, error);
+                store.dead_letter(DeadLetteredEvent::from_event(event, token, reason, Some(&self.name))).await
+            }
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use tracing::debug;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use super::Metrics;
+use super::metrics::label_value;
+
+/// Logical name and labels of a registered handler, e.g., `create_handler_labels("GreetedEvent->ES")`.
+///
+/// Workers emit metrics per labelled handler, in addition to the metrics per worker: the number of calls, the number of
+/// errors and the total latency, e.g., `event_handler_calls{name="GreetedEvent->ES"}`. The labels are sorted by key.
+/// Handlers without labels only count towards the metrics of their worker.
+#[derive(Debug,Clone,PartialEq)]
+pub struct HandlerLabels {
+    pub name: String,
+    pub labels: BTreeMap<String,String>,
+}
+
+pub fn create_handler_labels(name: &str) -> HandlerLabels {
+    HandlerLabels {
+        name: name.to_string(),
+        labels: BTreeMap::new(),
+    }
+}
+
+impl HandlerLabels {
+    pub fn with_label(mut self, key: &str, value: &str) -> Self {
+        self.labels.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Returns the name of the metric for this handler, e.g., `event_handler_calls{name="GreetedEvent->ES"}`. The label
+    /// values are escaped as in the text format of Prometheus.
+    pub fn metric_name(&self, metric: &str) -> String {
+        let mut labels = vec![format!("name={}", label_value(&self.name))];
+        labels.extend(self.labels.iter().map(|(key, value)| format!("{}={}", key, label_value(value))));
+        format!("{}{{{}}}", metric, labels.join(","))
+    }
+
+    pub(crate) fn record(&self, metrics: &Metrics, kind: &str, message_name: &str, elapsed: Duration, success: bool) {
+        debug!("Handler: {}_handler: name={:?} message={:?} elapsed_ms={} success={}", kind, self.name, message_name, elapsed.as_millis(), success);
+        metrics.increment(&self.metric_name(&format!("{}_handler_calls", kind)), 1);
+        if !success {
+            metrics.increment(&self.metric_name(&format!("{}_handler_errors", kind)), 1);
+        }
+        metrics.increment(&self.metric_name(&format!("{}_handler_latency_ms", kind)), elapsed.as_millis() as i64);
+    }
+}
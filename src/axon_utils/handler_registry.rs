@@ -2,8 +2,11 @@ use anyhow::{anyhow,Result};
 use bytes::Bytes;
 use futures_core::Future;
 use futures_util::__private::Pin;
-use prost::DecodeError;
+use prost::{DecodeError,Message};
 use std::collections::HashMap;
+use std::sync::Arc;
+use super::axon_error::AxonError;
+use super::handler_metrics::HandlerLabels;
 
 // I tried to make it possible to pass an `async fn` directly to parameter `handler`, but the return
 // type after desugaring is unnameable
@@ -52,6 +55,68 @@ pub trait HandlerRegistry<P,W>: Send {
 
 pub struct TheHandlerRegistry<P: Send,W: Clone> {
     pub handlers: HashMap<String,Box<dyn SubscriptionHandle<P,W>>>,
+    pub labels: HashMap<String,HandlerLabels>,
+}
+
+impl<P: Send,W: Clone> TheHandlerRegistry<P,W> {
+    /// Tags the handler that is registered under the given name, so that metrics are emitted for it separately.
+    pub fn label(&mut self, name: &str, labels: HandlerLabels) -> Result<()> {
+        if !self.handlers.contains_key(name) {
+            return Err(AxonError::MissingHandler(name.to_string()).into())
+        }
+        self.labels.insert(name.to_string(), labels);
+        Ok(())
+    }
+
+    pub fn labels(&self, name: &str) -> Option<&HandlerLabels> {
+        self.labels.get(name)
+    }
+}
+
+/// Converts a payload that was received under an alias to the payload that the handler of the target expects.
+pub type PayloadAdapter = Arc<dyn Fn(Vec<u8>) -> Result<Vec<u8>> + Send + Sync>;
+
+impl<P: Send + 'static, W: Clone + 'static> TheHandlerRegistry<P,W> {
+    /// Registers the handler of `target` under another name as well, e.g., the wire name of another version of a
+    /// command, so that clients can move to a new schema one at a time. The adapter converts the payload of the
+    /// alias to the payload that the handler expects. The alias inherits the labels of the target.
+    pub fn alias(&mut self, alias: &str, target: &str, adapter: PayloadAdapter) -> Result<()> {
+        if self.handlers.contains_key(alias) {
+            return Err(anyhow!("Handler already registered: {:?}", alias))
+        }
+        let target_handle = self.handlers.get(target).ok_or_else(|| AxonError::MissingHandler(target.to_string()))?;
+        let handle: Box<dyn SubscriptionHandle<P,W>> = Box::new(AliasSubscription {
+            name: alias.to_string(),
+            target: target_handle.box_clone(),
+            adapter,
+        });
+        self.handlers.insert(alias.to_string(), handle);
+        if let Some(labels) = self.labels.get(target).cloned() {
+            self.labels.insert(alias.to_string(), labels);
+        }
+        Ok(())
+    }
+
+    /// Registers an alias for a payload that has the same encoding as that of the target.
+    pub fn alias_as_is(&mut self, alias: &str, target: &str) -> Result<()> {
+        self.alias(alias, target, Arc::new(Ok))
+    }
+
+    /// Registers an alias with an adapter that converts the decoded payload of the alias to the message that the
+    /// handler expects, e.g., `alias_with("GreetCommandV2", "GreetCommand", |v2: GreetCommandV2| GreetCommand { .. })`.
+    pub fn alias_with<A: Message + Default, T: Message>(&mut self, alias: &str, target: &str, adapt: impl Fn(A) -> T + Send + Sync + 'static) -> Result<()> {
+        self.alias(alias, target, payload_adapter(adapt))
+    }
+}
+
+/// Returns an adapter that decodes the payload as an `A`, converts it, and encodes the resulting `T`.
+pub fn payload_adapter<A: Message + Default, T: Message>(adapt: impl Fn(A) -> T + Send + Sync + 'static) -> PayloadAdapter {
+    Arc::new(move |data| {
+        let message = adapt(A::decode(Bytes::from(data))?);
+        let mut buf = Vec::new();
+        message.encode(&mut buf)?;
+        Ok(buf)
+    })
 }
 
 impl<P: Send + Clone, W: Clone + 'static> HandlerRegistry<P,W> for TheHandlerRegistry<P,W> {
@@ -154,6 +219,7 @@ impl<P: Send + Clone, W: Clone + 'static> HandlerRegistry<P,W> for TheHandlerReg
 pub fn empty_handler_registry<P: Send, W: Clone>() -> TheHandlerRegistry<P,W> {
     TheHandlerRegistry {
         handlers: HashMap::new(),
+        labels: HashMap::new(),
     }
 }
 
@@ -226,3 +292,29 @@ impl<P: Send + Clone, T: Send + Clone, W: Clone + 'static> SubscriptionHandle<P,
         Box::from(SubscriptionVoid::clone(&self))
     }
 }
+
+struct AliasSubscription<P,W> {
+    name: String,
+    target: Box<dyn SubscriptionHandle<P,W>>,
+    adapter: PayloadAdapter,
+}
+
+#[tonic::async_trait]
+impl<P: Send + 'static, W: Clone + 'static> SubscriptionHandle<P,W> for AliasSubscription<P,W>
+{
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    async fn handle(&self, buf: Vec<u8>, projection: P) -> Result<Option<W>> {
+        self.target.handle((self.adapter)(buf)?, projection).await
+    }
+
+    fn box_clone(&self) -> Box<dyn SubscriptionHandle<P,W>> {
+        Box::from(AliasSubscription {
+            name: self.name.clone(),
+            target: self.target.box_clone(),
+            adapter: self.adapter.clone(),
+        })
+    }
+}
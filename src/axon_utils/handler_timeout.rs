@@ -0,0 +1,205 @@
+use anyhow::{anyhow,Result};
+use futures_core::Future;
+use tracing::{error,warn};
+use std::collections::HashMap;
+use std::fmt::{Debug,Display,Formatter};
+use std::sync::{Arc,Mutex};
+use std::time::Duration;
+use super::Metrics;
+use crate::axon_server::event::Event;
+
+/// What an event processor does with an event when its handler timed out on every attempt. Handler groups apply the
+/// same policies to handlers that failed on every attempt.
+#[derive(Debug,Clone,Default)]
+pub enum TimeoutPolicy {
+    /// Stop the processor with a `HandlerTimeoutError`. The event is handled again when the processor restarts.
+    #[default]
+    Fail,
+    /// Store the token of the event as if it was handled.
+    Skip,
+    /// Hand the event to the store for inspection, and store its token as if it was handled.
+    DeadLetter(Arc<dyn DeadLetterStore>),
+}
+
+/// Timeouts for event handlers, by message name, with a default for the other messages.
+///
+/// A handler that does not finish within its timeout is abandoned, logged as a warning, and counted in the
+/// `<worker>_handler_timeouts` metric. It is tried again `retries` times, and then the policy is applied, so that one
+/// stuck call (e.g., to Elasticsearch) does not hang the whole processor. Without timeouts, handlers may take as long
+/// as they take.
+///
+/// An abandoned handler may have done part of its work, so by default it is not tried again: `retries` is 0, and the
+/// retry policy of the processor does not retry a `HandlerTimeoutError`. Only set `retries`, or make the retry policy
+/// accept timeouts (e.g., `with_retryable(|e| is_transient_error(e) || e.is::<HandlerTimeoutError>())`), for handlers
+/// that are idempotent.
+#[derive(Debug,Clone,Default)]
+pub struct HandlerTimeouts {
+    pub default: Option<Duration>,
+    pub per_message: HashMap<String,Duration>,
+    pub retries: u32,
+    pub policy: TimeoutPolicy,
+}
+
+impl HandlerTimeouts {
+    pub fn with_default(mut self, timeout: Duration) -> Self {
+        self.default = Some(timeout);
+        self
+    }
+
+    pub fn with_timeout(mut self, message_name: &str, timeout: Duration) -> Self {
+        self.per_message.insert(message_name.to_string(), timeout);
+        self
+    }
+
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: TimeoutPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn timeout(&self, message_name: &str) -> Option<Duration> {
+        self.per_message.get(message_name).cloned().or(self.default)
+    }
+
+    /// Calls the handler of the event in the named handler group, within the timeout for its message name. Returns
+    /// whether the handler finished: `Ok(false)` when the policy skipped or dead-lettered the event.
+    pub(crate) async fn call<F, Fut, T>(&self, worker: &str, metrics: &Metrics, group: &str, event: &Event, token: i64, mut handle: F) -> Result<bool>
+    where F: FnMut() -> Fut, Fut: Future<Output=Result<T>>
+    {
+        let message_name = event.payload.as_ref().map(|payload| payload.r#type.as_str()).unwrap_or("");
+        let timeout = match self.timeout(message_name) {
+            Some(timeout) => timeout,
+            None => return handle().await.map(|_| true),
+        };
+        for attempt in 0..=self.retries {
+            match tokio::time::timeout(timeout, handle()).await {
+                Ok(result) => return result.map(|_| true),
+                Err(_) => {
+                    warn!(
+                        "Handler timeout: worker={} message={:?} aggregate_id={:?} attempt={} timeout_ms={}",
+                        worker, message_name, event.aggregate_identifier, attempt + 1, timeout.as_millis()
+                    );
+                    metrics.increment(&format!("{}_handler_timeouts", worker), 1);
+                }
+            }
+        }
+        let timeout_error = HandlerTimeoutError {
+            message_name: message_name.to_string(),
+            timeout,
+            attempts: self.retries + 1,
+        };
+        match &self.policy {
+            TimeoutPolicy::Fail => Err(timeout_error.into()),
+            TimeoutPolicy::Skip => {
+                warn!("Skip event after handler timeout: worker={} message={:?} token={}", worker, event.message_identifier, token);
+                Ok(false)
+            }
+            TimeoutPolicy::DeadLetter(store) => {
+                store.dead_letter(DeadLetteredEvent::from_event(event, token, timeout_error.to_string(), Some(group))).await?;
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Error for an event whose handler did not finish within its timeout.
+#[derive(Debug,Clone)]
+pub struct HandlerTimeoutError {
+    pub message_name: String,
+    pub timeout: Duration,
+    pub attempts: u32,
+}
+
+impl Display for HandlerTimeoutError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Handler for {:?} timed out after {}ms ({} attempts)", self.message_name, self.timeout.as_millis(), self.attempts)
+    }
+}
+
+impl std::error::Error for HandlerTimeoutError {}
+
+/// An event that an event processor gave up on, with its envelope, so that it can be re-delivered.
+#[derive(Debug,Clone)]
+pub struct DeadLetteredEvent {
+    pub token: i64,
+    pub event: Event,
+    pub reason: String,
+    /// Name of the handler group whose handler failed or timed out, if known.
+    pub group: Option<String>,
+}
+
+impl DeadLetteredEvent {
+    pub fn from_event(event: &Event, token: i64, reason: String, group: Option<&str>) -> Self {
+        DeadLetteredEvent {
+            token,
+            event: event.clone(),
+            reason,
+            group: group.map(String::from),
+        }
+    }
+
+    /// Returns the key of the dead letter in its store: the message identifier of the event, followed by the name of
+    /// the group, if any, because the same event can be dead-lettered by more than one group.
+    pub fn key(&self) -> String {
+        match &self.group {
+            Some(group) => format!("{}#{}", self.event.message_identifier, group),
+            None => self.event.message_identifier.clone(),
+        }
+    }
+}
+
+/// Keeps dead-lettered events for inspection and re-delivery (see `redeliver_dead_letters`).
+#[tonic::async_trait]
+pub trait DeadLetterStore: Debug + Send + Sync {
+    async fn dead_letter(&self, event: DeadLetteredEvent) -> Result<()>;
+
+    /// Returns the dead-lettered events in the order of their tokens.
+    async fn dead_letters(&self) -> Result<Vec<DeadLetteredEvent>> {
+        Err(anyhow!("This dead-letter store cannot list its events"))
+    }
+
+    /// Forgets the dead-lettered event with the given key, e.g., after it was re-delivered.
+    async fn remove_dead_letter(&self, _key: &str) -> Result<()> {
+        Err(anyhow!("This dead-letter store cannot remove events"))
+    }
+}
+
+/// Dead-letter store that keeps the events in memory. Dead-lettered events are logged as errors.
+#[derive(Debug,Clone,Default)]
+pub struct InMemoryDeadLetterStore {
+    events: Arc<Mutex<Vec<DeadLetteredEvent>>>,
+}
+
+impl InMemoryDeadLetterStore {
+    pub fn list(&self) -> Vec<DeadLetteredEvent> {
+        self.events.lock().map(|events| events.clone()).unwrap_or_default()
+    }
+}
+
+#[tonic::async_trait]
+impl DeadLetterStore for InMemoryDeadLetterStore {
+    async fn dead_letter(&self, event: DeadLetteredEvent) -> Result<()> {
+        error!("Dead-lettered event: {:?}: token: {:?}: {}", event.key(), event.token, event.reason);
+        if let Ok(mut events) = self.events.lock() {
+            events.push(event);
+        }
+        Ok(())
+    }
+
+    async fn dead_letters(&self) -> Result<Vec<DeadLetteredEvent>> {
+        let mut events = self.list();
+        events.sort_by_key(|event| event.token);
+        Ok(events)
+    }
+
+    async fn remove_dead_letter(&self, key: &str) -> Result<()> {
+        if let Ok(mut events) = self.events.lock() {
+            events.retain(|event| event.key() != key);
+        }
+        Ok(())
+    }
+}
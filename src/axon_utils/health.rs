@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::sync::{Arc,Mutex};
+use tonic::Status;
+use super::{AxonStreamError,ErrorClass};
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum WorkerHealth {
+    Starting,
+    Running,
+    Paused,
+    Reconnecting { error_class: ErrorClass, attempt: u32 },
+    Failed { error_class: ErrorClass, message: String },
+}
+
+/// Health of the workers that share a connection to AxonServer, keyed by worker name, with the message names that
+/// each worker subscribed to and the last error of each worker that failed.
+#[derive(Debug,Clone,Default)]
+pub struct HealthStatus {
+    workers: Arc<Mutex<HashMap<String,WorkerHealth>>>,
+    subscriptions: Arc<Mutex<HashMap<String,Vec<String>>>>,
+    last_errors: Arc<Mutex<HashMap<String,String>>>,
+}
+
+impl HealthStatus {
+    pub fn report(&self, worker: &str, health: WorkerHealth) {
+        if let Ok(mut workers) = self.workers.lock() {
+            workers.insert(worker.to_string(), health);
+        }
+    }
+
+    pub fn get(&self, worker: &str) -> Option<WorkerHealth> {
+        self.workers.lock().ok().and_then(|workers| workers.get(worker).cloned())
+    }
+
+    pub fn snapshot(&self) -> HashMap<String,WorkerHealth> {
+        self.workers.lock().map(|workers| workers.clone()).unwrap_or_default()
+    }
+
+    pub fn report_subscriptions(&self, worker: &str, message_names: Vec<String>) {
+        if let Ok(mut subscriptions) = self.subscriptions.lock() {
+            subscriptions.insert(worker.to_string(), message_names);
+        }
+    }
+
+    pub fn subscriptions(&self) -> HashMap<String,Vec<String>> {
+        self.subscriptions.lock().map(|subscriptions| subscriptions.clone()).unwrap_or_default()
+    }
+
+    /// Returns the last error of each worker that failed, also when the worker recovered since.
+    pub fn last_errors(&self) -> HashMap<String,String> {
+        self.last_errors.lock().map(|last_errors| last_errors.clone()).unwrap_or_default()
+    }
+
+    /// Records that the stream of the given worker ended with an error and returns the classified error.
+    pub fn stream_failed(&self, worker: &str, status: Status) -> AxonStreamError {
+        let error = AxonStreamError::from(status);
+        if let Ok(mut last_errors) = self.last_errors.lock() {
+            last_errors.insert(worker.to_string(), error.to_string());
+        }
+        self.report(worker, WorkerHealth::Failed {
+            error_class: error.error_class,
+            message: error.to_string(),
+        });
+        error
+    }
+
+    /// True if no worker has failed permanently.
+    pub fn is_healthy(&self) -> bool {
+        self.snapshot().values().all(|health| !matches!(health, WorkerHealth::Failed { .. }))
+    }
+}
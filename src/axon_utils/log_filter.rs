@@ -0,0 +1,62 @@
+use anyhow::{anyhow,Result};
+use tracing::{debug,warn};
+use prost::Message;
+use std::sync::Arc;
+use super::axon_serialize;
+use super::query_processor::QueryResult;
+
+/// Name of the query that reads or replaces the log filter of the application.
+pub const SET_LOG_FILTER: &str = "SetLogFilter";
+
+/// Name of the response to a `SetLogFilter` query.
+pub const LOG_FILTER: &str = "LogFilter";
+
+/// Query that replaces the log filter, in the syntax of `RUST_LOG`, e.g., `info,rustic_dendrite=debug`. With an empty
+/// filter, the current filter is returned and left as it is.
+#[derive(Clone,PartialEq,Message)]
+pub struct SetLogFilter {
+    #[prost(string, tag = "1")]
+    pub filter: String,
+}
+
+/// The log filter that is in effect, after the query.
+#[derive(Clone,PartialEq,Message)]
+pub struct LogFilter {
+    #[prost(string, tag = "1")]
+    pub filter: String,
+}
+
+/// Reads and replaces the filter of the subscriber that the application installed, e.g., through the reload handle of
+/// `tracing-subscriber`.
+pub trait LogFilterControl: Send + Sync {
+    fn current_filter(&self) -> String;
+    fn set_filter(&self, filter: &str) -> Result<()>;
+}
+
+/// Gives the `SetLogFilter` query handler access to the log filter.
+pub trait LogFilterContext {
+    fn log_filter_control(&self) -> Option<Arc<dyn LogFilterControl>>;
+}
+
+/// Opt-in query handler that lets operators change the log filter of a running instance, e.g., to debug an incident
+/// without a restart:
+/// `registry.insert_with_output(SET_LOG_FILTER, &SetLogFilter::decode, &(|q, c| Box::pin(handle_set_log_filter(q, c))))`.
+///
+/// Every change is logged as a warning with the old and the new filter. Register the handler only in deployments where
+/// everyone who can send queries to AxonServer may change the log filter.
+pub async fn handle_set_log_filter<Q: LogFilterContext>(query: SetLogFilter, context: Q) -> Result<Option<QueryResult>> {
+    let control = context.log_filter_control().ok_or_else(|| anyhow!("Log filter cannot be changed"))?;
+    let previous = control.current_filter();
+    if query.filter.is_empty() {
+        debug!("Log filter: {:?}", previous);
+    } else {
+        control.set_filter(&query.filter)?;
+        warn!("Log filter changed: from: {:?}: to: {:?}", previous, control.current_filter());
+    }
+    let response = LogFilter {
+        filter: control.current_filter(),
+    };
+    Ok(Some(QueryResult {
+        payload: Some(axon_serialize(LOG_FILTER, &response)?),
+    }))
+}
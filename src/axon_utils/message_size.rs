@@ -0,0 +1,83 @@
+use anyhow::{Error,Result};
+use prost::Message;
+use std::fmt::{Display,Formatter};
+use tonic::{Code,Status};
+use crate::axon_server::SerializedObject;
+
+/// Default maximum size of a gRPC message, in bytes. Matches the default of AxonServer (`axoniq.axonserver.max-message-size`).
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Error for a message that is refused before it is sent, because AxonServer would reject it anyway.
+#[derive(Debug,Clone)]
+pub struct MessageTooLargeError {
+    pub message_type: String,
+    pub size: usize,
+    pub max_size: usize,
+}
+
+impl Display for MessageTooLargeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Message too large: {:?}: {} bytes (maximum: {} bytes)", self.message_type, self.size, self.max_size)
+    }
+}
+
+impl std::error::Error for MessageTooLargeError {}
+
+/// Returns an error if the encoded message is larger than the maximum size. There is no maximum if it is `None`.
+pub fn check_message_size<M: Message>(message_type: &str, message: &M, max_size: Option<usize>) -> Result<()> {
+    let max_size = match max_size {
+        Some(max_size) => max_size,
+        None => return Ok(()),
+    };
+    let size = message.encoded_len();
+    if size > max_size {
+        return Err(MessageTooLargeError { message_type: message_type.to_string(), size, max_size }.into());
+    }
+    Ok(())
+}
+
+/// Error for an incoming payload that is larger than the maximum payload size of the worker that received it.
+#[derive(Debug,Clone)]
+pub struct PayloadTooLargeError {
+    pub payload_type: String,
+    pub size: usize,
+    pub max_size: usize,
+}
+
+impl Display for PayloadTooLargeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Payload too large: {:?}: {} bytes (maximum: {} bytes)", self.payload_type, self.size, self.max_size)
+    }
+}
+
+impl std::error::Error for PayloadTooLargeError {}
+
+/// Returns an error if the data of the payload is larger than the maximum size. There is no maximum if it is `None`.
+pub fn check_payload_size(payload: &SerializedObject, max_size: Option<usize>) -> Result<()> {
+    match max_size {
+        Some(max_size) if payload.data.len() > max_size => Err(PayloadTooLargeError {
+            payload_type: payload.r#type.clone(),
+            size: payload.data.len(),
+            max_size,
+        }.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Decodes the payload after checking its size, directly from the buffer of the payload.
+///
+/// Tonic receives each gRPC message as a whole, so the encoded payload is always in memory. The maximum prevents that
+/// a handler allocates the (possibly larger) decoded message for a payload that is too large.
+pub fn decode_payload<T: Message + Default>(payload: &SerializedObject, max_size: Option<usize>) -> Result<T> {
+    check_payload_size(payload, max_size)?;
+    Ok(T::decode(payload.data.as_slice())?)
+}
+
+// AxonServer answers with RESOURCE_EXHAUSTED when a message exceeds its maximum message size, with a message that does
+// not say much about the cause.
+pub(crate) fn explain_status(status: Status) -> Error {
+    if status.code() == Code::ResourceExhausted {
+        return Error::new(status).context("AxonServer rejected the request, possibly because a message exceeds its maximum message size");
+    }
+    status.into()
+}
@@ -0,0 +1,142 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fmt::{Debug,Formatter};
+use std::sync::{Arc,RwLock};
+use crate::axon_server::MetaDataValue;
+use crate::axon_server::command::Command;
+use crate::axon_server::meta_data_value::Data;
+
+/// Meta-data key for the name of the service that emitted an event.
+pub const SERVICE_NAME: &str = "serviceName";
+
+/// Meta-data key for the version of the service that emitted an event.
+pub const SERVICE_VERSION: &str = "serviceVersion";
+
+/// Meta-data key for the node (instance) of the service that emitted an event.
+pub const NODE_ID: &str = "nodeId";
+
+/// Meta-data key for the user on whose behalf a command was sent.
+pub const USER_ID: &str = "userId";
+
+/// Meta-data key for the tenant on whose behalf a command was sent.
+pub const TENANT_ID: &str = "tenantId";
+
+/// Meta-data key for the trace of the request that caused a command, e.g., a W3C trace id.
+pub const TRACE_ID: &str = "traceId";
+
+/// Computes meta-data for an event from the command that caused it, if any.
+pub type MetaDataStamper = Arc<dyn Fn(Option<&Command>, &mut HashMap<String,MetaDataValue>) + Send + Sync>;
+
+static STAMPING_POLICY: Lazy<RwLock<Arc<StampingPolicy>>> = Lazy::new(|| RwLock::new(Arc::new(StampingPolicy::default())));
+
+/// Determines the standard meta-data that is stamped on every event that the application emits: the events of command
+/// handlers, of aggregate factories, of repositories, and of event transactions. Configure it once, at startup, with
+/// `set_stamping_policy`, instead of adding the meta-data in each handler.
+///
+/// The fixed values (e.g., service name, version, node id) are added to every event. The command keys are copied from
+/// the meta-data of the command, e.g., the id of the user on whose behalf it was sent. The stampers run last, in order.
+/// Stamps never replace meta-data that the event already has, such as the correlation id. By default, nothing is
+/// stamped.
+#[derive(Clone,Default)]
+pub struct StampingPolicy {
+    pub values: HashMap<String,MetaDataValue>,
+    pub command_keys: Vec<String>,
+    pub stampers: Vec<MetaDataStamper>,
+}
+
+impl Debug for StampingPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StampingPolicy")
+            .field("values", &self.values)
+            .field("command_keys", &self.command_keys)
+            .field("stampers", &self.stampers.len())
+            .finish()
+    }
+}
+
+impl StampingPolicy {
+    pub fn with_value(mut self, key: &str, value: &str) -> Self {
+        self.values.insert(key.to_string(), MetaDataValue {
+            data: Some(Data::TextValue(value.to_string())),
+        });
+        self
+    }
+
+    pub fn with_service(self, service_name: &str, service_version: &str) -> Self {
+        self.with_value(SERVICE_NAME, service_name).with_value(SERVICE_VERSION, service_version)
+    }
+
+    pub fn with_node_id(self, node_id: &str) -> Self {
+        self.with_value(NODE_ID, node_id)
+    }
+
+    /// Copies the meta-data value with the given key from the command to its events, e.g., `USER_ID`.
+    pub fn with_command_key(mut self, key: &str) -> Self {
+        self.command_keys.push(key.to_string());
+        self
+    }
+
+    pub fn with_stamper(mut self, stamper: impl Fn(Option<&Command>, &mut HashMap<String,MetaDataValue>) + Send + Sync + 'static) -> Self {
+        self.stampers.push(Arc::new(stamper));
+        self
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.values.is_empty() || !self.command_keys.is_empty() || !self.stampers.is_empty()
+    }
+
+    /// Adds the stamps to the meta-data of an event, caused by the given command, if any.
+    pub fn stamp(&self, command: Option<&Command>, meta_data: &mut HashMap<String,MetaDataValue>) {
+        for (key, value) in &self.values {
+            meta_data.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+        if let Some(command) = command {
+            for key in &self.command_keys {
+                if let Some(value) = command.meta_data.get(key) {
+                    meta_data.entry(key.clone()).or_insert_with(|| value.clone());
+                }
+            }
+        }
+        if self.stampers.is_empty() {
+            return;
+        }
+        let mut stamps = HashMap::new();
+        for stamper in &self.stampers {
+            stamper(command, &mut stamps);
+        }
+        for (key, value) in stamps {
+            meta_data.entry(key).or_insert(value);
+        }
+    }
+}
+
+pub fn set_stamping_policy(policy: StampingPolicy) {
+    if let Ok(mut current) = STAMPING_POLICY.write() {
+        *current = Arc::new(policy);
+    }
+}
+
+pub fn stamping_policy() -> Arc<StampingPolicy> {
+    STAMPING_POLICY.read().map(|policy| policy.clone()).unwrap_or_default()
+}
+
+// Returns the meta-data, stamped according to the current policy.
+pub(crate) fn stamp_meta_data(command: Option<&Command>, mut meta_data: HashMap<String,MetaDataValue>) -> HashMap<String,MetaDataValue> {
+    stamping_policy().stamp(command, &mut meta_data);
+    meta_data
+}
+
+/// Returns meta-data with the given text values, e.g., to send with a command.
+pub fn text_meta_data(values: &[(&str, &str)]) -> HashMap<String,MetaDataValue> {
+    values.iter()
+        .map(|(key, value)| (key.to_string(), MetaDataValue { data: Some(Data::TextValue(value.to_string())) }))
+        .collect()
+}
+
+/// Returns the text value of the given key, if the meta-data has one.
+pub fn meta_data_text<'a>(meta_data: &'a HashMap<String,MetaDataValue>, key: &str) -> Option<&'a str> {
+    match meta_data.get(key).and_then(|value| value.data.as_ref()) {
+        Some(Data::TextValue(text)) => Some(text.as_str()),
+        _ => None,
+    }
+}
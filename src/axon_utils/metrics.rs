@@ -0,0 +1,91 @@
+use std::collections::{BTreeMap,HashMap};
+use std::sync::{Arc,Mutex};
+
+/// Counters and gauges of the workers that share a connection to AxonServer.
+#[derive(Debug,Clone,Default)]
+pub struct Metrics {
+    inner: Arc<Mutex<MetricValues>>,
+}
+
+#[derive(Debug,Default)]
+struct MetricValues {
+    counters: HashMap<String,i64>,
+    gauges: HashMap<String,i64>,
+}
+
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct MetricsSnapshot {
+    pub counters: BTreeMap<String,i64>,
+    pub gauges: BTreeMap<String,i64>,
+}
+
+impl Metrics {
+    pub fn increment(&self, name: &str, amount: i64) {
+        if let Ok(mut values) = self.inner.lock() {
+            *values.counters.entry(name.to_string()).or_insert(0) += amount;
+        }
+    }
+
+    pub fn set_gauge(&self, name: &str, value: i64) {
+        if let Ok(mut values) = self.inner.lock() {
+            values.gauges.insert(name.to_string(), value);
+        }
+    }
+
+    pub fn counter(&self, name: &str) -> i64 {
+        self.inner.lock().ok().and_then(|values| values.counters.get(name).cloned()).unwrap_or(0)
+    }
+
+    pub fn gauge(&self, name: &str) -> i64 {
+        self.inner.lock().ok().and_then(|values| values.gauges.get(name).cloned()).unwrap_or(0)
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.inner.lock().map(|values| MetricsSnapshot {
+            counters: values.counters.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+            gauges: values.gauges.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+        }).unwrap_or_default()
+    }
+}
+
+impl MetricsSnapshot {
+    /// Renders the metrics in the text format of Prometheus. Names that carry labels, e.g.,
+    /// `event_handler_calls{name="GreetedEvent->ES"}`, are grouped under the name before the labels.
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        render_family(&mut text, "counter", &self.counters);
+        render_family(&mut text, "gauge", &self.gauges);
+        text
+    }
+}
+
+fn render_family(text: &mut String, metric_type: &str, values: &BTreeMap<String,i64>) {
+    let mut families: BTreeMap<&str,Vec<(&String,&i64)>> = BTreeMap::new();
+    for (name, value) in values {
+        let family = name.split('{').next().unwrap_or(name);
+        families.entry(family).or_default().push((name, value));
+    }
+    for (family, samples) in families {
+        text.push_str(&format!("# TYPE {} {}\n", family, metric_type));
+        for (name, value) in samples {
+            text.push_str(&format!("{} {}\n", name, value));
+        }
+    }
+}
+
+/// Quotes a label value for the text format of Prometheus, e.g., `name="GreetedEvent->ES"`. Only the backslash, the
+/// double quote and the line feed are escaped; all other characters, including non-ASCII ones, are kept as they are.
+pub(crate) fn label_value(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
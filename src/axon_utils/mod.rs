@@ -1,39 +1,209 @@
 use anyhow::{anyhow,Result};
-use log::debug;
+use tracing::debug;
 use prost::Message;
+use std::collections::HashMap;
 use tonic::transport::Channel;
 
 use crate::axon_server::SerializedObject;
 
+mod aggregate_factory;
+mod aggregate_inspection;
+mod aggregate_migration;
+mod await_projection;
+mod axon_error;
+mod backfill;
+mod business_rules;
+mod catch_up;
+mod claim_check;
+mod client_stats;
+mod command_buffer;
+mod command_handler;
 mod command_submit;
 mod command_worker;
+mod conflict;
 mod connection;
+mod consistency_check;
+mod correlation;
+mod dead_letter;
+mod deduplication;
+mod diagnostics;
+mod error_classification;
+mod event_filter;
 mod event_processor;
 mod event_query;
+mod event_statistics;
+mod event_stream;
+mod event_transaction;
+mod event_transformation;
+#[cfg(feature = "fault-injection")]
+mod fault_injection;
+mod file_token_store;
+mod flow_control;
+mod handler_concurrency;
+mod handler_errors;
+mod handler_group;
+mod handler_metrics;
 mod handler_registry;
+mod handler_timeout;
+mod health;
+mod log_filter;
+mod message_size;
+mod meta_data_stamping;
+mod metrics;
+mod parallel_replay;
+mod parallel_sourcing;
+mod pause;
+mod platform;
+#[cfg(feature = "postgres")]
+mod postgres_projection;
+mod priority;
+mod projection_conflict;
+mod projection_schema;
+mod quarantine;
 mod query_processor;
 mod query_submit;
+mod read_only;
+mod rebuild_projection;
+mod reconnect_signal;
+mod redaction;
+mod registry_validation;
+mod replay;
+mod repository;
+mod retention;
+mod retry_policy;
+mod segments;
+mod sequence_gaps;
+mod shutdown;
+mod slow_handler;
+mod snapshot;
+mod state_machine;
+mod status_mapping;
+mod subscription_query;
+mod tenant_quota;
+mod time_travel;
+#[cfg(feature = "postgres")]
+mod token_store;
+mod tombstone;
 
+pub use aggregate_factory::{AggregateCreation,AggregateCreator,AggregateFactory,CREATED_FROM,aggregate_factory_processor,create_aggregate_factory};
+pub use aggregate_inspection::{AGGREGATE_STATE,AggregateInspection,AggregateInspector,AggregateState,INSPECT_AGGREGATE,InspectAggregate,InspectionContext,create_aggregate_inspection,handle_inspect_aggregate};
+pub use aggregate_migration::{AggregateMigration,MIGRATED_FROM,MigrationReport,create_aggregate_migration,migrate_aggregate,migrate_aggregate_with_client};
+pub use await_projection::{AwaitProjection,ProjectionTimeoutError,send_command_and_await_projection};
+pub use axon_error::{AxonError,AxonResult};
+pub use backfill::{BackfillJob,BackfillReport,BackfillRow,BackfillSource,CsvBackfillSource,create_backfill_job,create_csv_backfill_source,run_backfill};
+#[cfg(feature = "postgres")]
+pub use backfill::{PostgresBackfillSource,create_postgres_backfill_source};
+pub use business_rules::{BusinessRuleError,DELETED_ERROR_CODE,Deletable,reject_if_deleted,require};
+pub use catch_up::{CatchUpSignal,create_catch_up_signal};
+pub use claim_check::{CLAIM_CHECK,ClaimCheck,create_claim_check};
+pub use client_stats::ClientStats;
+pub use command_buffer::{BufferedOutcome,CommandBuffer,CommandBufferConfig,create_command_buffer,run_command_buffer};
+pub use command_handler::{CommandHandlerDefinition,create_command_handler_definition};
 pub use command_submit::init as init_command_sender;
+pub use command_submit::init_with_server as init_command_sender_with_server;
+pub use command_submit::init_with_config as init_command_sender_with_config;
+pub use command_submit::init_with_builder as init_command_sender_with_builder;
+pub use command_submit::{send_command_with_expected_version,send_command_with_meta_data};
 pub use command_worker::command_worker as command_worker;
-pub use command_worker::{AggregateDefinition,AggregateRegistry,EmitApplicableEventsAndResponse,EmitEventsAndResponse,TheAggregateRegistry,create_aggregate_definition,emit,emit_applicable,emit_applicable_events_and_response,emit_events,emit_events_and_response,empty_aggregate_registry};
+pub use command_worker::{CommandWorkerConfig,command_worker_with_config};
+pub use command_worker::{BUSY_ERROR_CODE,CommandOutcome,CommandResult,QUARANTINED_ERROR_CODE,message_type_name};
+pub use command_worker::{AggregateContext,CommandEnvelope,AggregateDefinition,AggregateRegistry,EmitApplicableEventsAndResponse,EmitEventsAndResponse,TheAggregateRegistry,create_aggregate_definition,emit,emit_applicable,emit_applicable_events_and_response,emit_events,emit_events_and_response,empty_aggregate_registry};
+pub use conflict::{CONFLICT_ERROR_CODE,ConflictResolver,EXPECTED_VERSION,expected_version,expected_version_meta_data};
 pub use connection::wait_for_server as wait_for_server;
+pub use connection::{ACCESS_TOKEN_HEADER,AxonClients,AxonConnectionBuilder,CLIENT_VERSION,CONTEXT_HEADER,ConnectionConfig,DEFAULT_AXON_SERVER_HOST,DEFAULT_AXON_SERVER_PORT,DEFAULT_COMPONENT_NAME,EndpointSetup,InterceptorChain,InterceptorFn,create_axon_connection_builder,wait_for_server_with_config};
+pub use consistency_check::{ConsistencyCheck,ConsistencyReport,DocumentDrift,ProjectionDocuments,check_query_model_consistency,check_query_model_consistency_with_pool,create_consistency_check};
+pub use correlation::{CAUSATION_ID,CORRELATION_ID,CommandAuditRecord,CommandAuditStore,CorrelatedEvent,CorrelationChain,EventCorrelation,InMemoryCommandAuditStore,correlation_chain,correlation_id,correlation_meta_data};
+pub use dead_letter::{RedeliveryReport,redeliver_dead_letters};
+pub use diagnostics::{Diagnostics,ProcessorDiagnostics,log_diagnostics_on_signal};
+pub use error_classification::{AxonStreamError,ErrorClass,ReconnectPolicy,classify_error,classify_status};
+pub use event_filter::{EventFilter,EventPredicate,create_event_filter};
+pub use event_processor::{EventContext,EventProcessorConfig,InMemoryTokenStore,TokenStore,TrackingConfig,create_tracking_config,event_processor,event_processor_with_config,event_processor_with_groups};
+pub use event_query::{EventQueryOptions,query_events,query_events_with_options,query_events_with_snapshot,split_snapshot};
+pub use event_statistics::{EventStoreStatistics,count_aggregate_events,count_events_per_aggregate_type,count_events_per_payload_type,event_store_statistics,query_event_store};
+pub use event_transaction::{AggregateEvents,EventTransaction,append_event_transaction,append_event_transaction_with_client,create_event_transaction,supports_multi_aggregate_append};
+pub use event_transformation::{EventCopyJob,EventTransformation,TransformationReport,copy_transform_events,create_event_copy_job};
+#[cfg(feature = "fault-injection")]
+pub use fault_injection::{Fault,FaultInjector,FaultRule,FaultTarget,InjectedFault,fault_injector};
+pub use file_token_store::{FileTokenStore,create_file_token_store};
+pub use flow_control::{AdaptiveFlowControl,FlowControlMode};
+pub use handler_concurrency::{ConcurrencyConfig,ConcurrencyLimits,ConcurrencyPermit,ProcessorConcurrency,load_concurrency_config,reload_concurrency_on_signal};
+pub use handler_errors::{COMMAND_EXECUTION_ERROR,COMMAND_EXECUTION_NON_TRANSIENT_ERROR,CONCURRENCY_EXCEPTION,HandlerErrorKind,NO_HANDLER_FOR_COMMAND,classify_handler_error};
+pub use handler_group::{DEFAULT_HANDLER_GROUP,HandlerGroup,create_handler_group};
+pub use handler_metrics::{HandlerLabels,create_handler_labels};
 pub use handler_registry::empty_handler_registry as empty_handler_registry;
-pub use handler_registry::{HandlerRegistry,TheHandlerRegistry};
-pub use event_processor::{TokenStore,event_processor};
-pub use event_query::query_events;
-pub use query_processor::{QueryContext,QueryResult,query_processor};
+pub use handler_registry::{HandlerRegistry,PayloadAdapter,TheHandlerRegistry,payload_adapter};
+pub use handler_timeout::{DeadLetterStore,DeadLetteredEvent,HandlerTimeoutError,HandlerTimeouts,InMemoryDeadLetterStore,TimeoutPolicy};
+pub use health::{HealthStatus,WorkerHealth};
+pub use log_filter::{LOG_FILTER,LogFilter,LogFilterContext,LogFilterControl,SET_LOG_FILTER,SetLogFilter,handle_set_log_filter};
+pub use message_size::{DEFAULT_MAX_MESSAGE_SIZE,MessageTooLargeError,PayloadTooLargeError,check_message_size,check_payload_size,decode_payload};
+pub use meta_data_stamping::{MetaDataStamper,NODE_ID,SERVICE_NAME,SERVICE_VERSION,StampingPolicy,TENANT_ID,TRACE_ID,USER_ID,meta_data_text,set_stamping_policy,stamping_policy,text_meta_data};
+pub use metrics::{Metrics,MetricsSnapshot};
+pub use parallel_replay::{ParallelReplayConfig,ReplayProgress,ReplayReport,parallel_replay};
+pub use parallel_sourcing::{DEFAULT_SOURCING_CONCURRENCY,EventStoreClientPool,create_event_store_client_pool,create_event_store_client_pool_for,for_each_aggregate,source_aggregates};
+pub use pause::PauseSwitch;
+pub use platform::{PlatformConfig,platform_listener};
+#[cfg(feature = "postgres")]
+pub use postgres_projection::{PostgresDocumentStore,create_postgres_document_store};
+pub use priority::{DEFAULT_HIGH_PRIORITY_THRESHOLD,PriorityLane,message_priority,message_routing_key};
+pub use projection_conflict::{ConflictStrategy,MergeFn,PROJECTION_TOKEN_FIELD,merge_strategy,projection_token,with_projection_token};
+pub use projection_schema::{ProjectionSchema,create_projection_schema};
+pub use quarantine::{InMemoryQuarantineStore,QuarantineStore,QuarantinedCommand,quarantine_key};
+pub use query_processor::query_processor as query_worker;
+pub use query_processor::query_processor_with_config as query_worker_with_config;
+pub use query_processor::{QueryContext,QueryEnvelope,QueryProcessorConfig,QueryResponseSender,QueryResult,query_processor,query_processor_with_config,query_response_key};
+pub use read_only::{ReadOnlyError,is_read_only,set_read_only};
+pub use rebuild_projection::{ProjectionRebuild,REBUILD_PROJECTION,RebuildProjection,RebuildProjectionHandler,create_rebuild_projection_handler};
+pub use reconnect_signal::{ReconnectSignal,create_reconnect_signal};
+pub use redaction::{FieldRedactor,LogSafe,Redact,RedactionPolicy,log_safe,redaction_policy,set_redaction_policy};
+pub use registry_validation::{ProtoDescriptors,RegistryValidation,RegistryValidationError,create_registry_validation,load_proto_descriptors};
+pub use replay::{ReplaySignal,TokenPosition,create_replay_signal,position_token,reset_tracking_token};
+pub use repository::{Repository,create_repository};
+pub use retention::{RetentionPolicy,RetentionReport,create_retention_policy,run_retention};
+pub use retry_policy::{RetryPolicy,RetryablePredicate,create_retry_policy,is_transient_error};
+pub use segments::{Segment,SegmentedProcessorConfig,segmented_event_processor};
+pub use sequence_gaps::{SequenceGapError,SequenceGapHandler,SequenceGapPolicy,SequenceIrregularity,SequenceIrregularityKind,SequenceReport,check_sequence};
+pub use shutdown::{ShutdownSignal,create_shutdown_signal,shutdown_on_signal};
+pub use slow_handler::SlowHandlerThresholds;
+pub use snapshot::{JsonSnapshotSerializer,ProtobufSnapshotSerializer,SnapshotConfig,SnapshotSerializer,SnapshotUpcaster,create_snapshot_config};
+pub use state_machine::{Guard,StateMachine,create_state_machine};
+pub use status_mapping::{ValidationError,error_to_status,validate};
+pub use subscription_query::{DEFAULT_UPDATE_PERMITS,QueryUpdateEmitter,QueryUpdates,SubscriptionQueryResult};
+pub use tenant_quota::{DEFAULT_TENANT,QuotaExceededError,QuotaKind,TenantQuota,TenantQuotas,create_tenant_quota,create_tenant_quotas,set_tenant_quotas,tenant_quotas};
+pub use time_travel::{AsOf,project_aggregate_as_of};
+#[cfg(feature = "postgres")]
+pub use token_store::{DEFAULT_TOKEN_TABLE,PostgresTokenStore,create_postgres_token_store};
+pub use tombstone::{DocumentDeletes,DocumentIdFn,TOMBSTONE_EVENT_SUFFIX,is_tombstone_event};
 
 #[derive(Debug, Clone)]
 pub struct AxonServerHandle {
     pub display_name: String,
+    pub component_name: String,
     pub conn: Channel,
+    pub interceptors: InterceptorChain,
+    pub context: Option<String>,
+    pub max_message_size: Option<usize>,
+    pub health: HealthStatus,
+    pub reconnect: ReconnectSignal,
+    pub metrics: Metrics,
+    pub query_updates: QueryUpdateEmitter,
+    pub tags: HashMap<String,String>,
+    pub server_version: Option<i32>,
 }
 
 #[derive(Debug,Clone)]
 pub struct AxonConnection {
     pub id: String,
+    pub component_name: String,
     pub conn: Channel,
+    pub interceptors: InterceptorChain,
+    pub context: Option<String>,
+    pub max_message_size: Option<usize>,
+    pub health: HealthStatus,
+    pub reconnect: ReconnectSignal,
+    pub metrics: Metrics,
+    pub query_updates: QueryUpdateEmitter,
+    pub tags: HashMap<String,String>,
+    pub server_version: Option<i32>,
 }
 
 pub trait VecU8Message {
@@ -51,11 +221,40 @@ where T: Message + Sized
 #[tonic::async_trait]
 pub trait CommandSink {
     async fn send_command(&self, command_type: &str, command: Box<&(dyn VecU8Message + Sync)>) -> Result<Option<SerializedObject>>;
+
+    /// Like `send_command`, but fails with an `AxonError`, so that the caller can match on the kind of failure.
+    // The signature, including the boxed reference, mirrors `send_command`.
+    #[allow(clippy::redundant_allocation)]
+    async fn try_send_command(&self, command_type: &str, command: Box<&(dyn VecU8Message + Sync)>) -> AxonResult<Option<SerializedObject>>
+    where Self: Sync
+    {
+        self.send_command(command_type, command).await.map_err(AxonError::from)
+    }
 }
 
 #[tonic::async_trait]
 pub trait QuerySink {
     async fn send_query<'a>(&self, query_type: &str, query: Box<&(dyn VecU8Message + Sync)>) -> Result<Vec<SerializedObject>>;
+
+    /// Like `send_query`, but fails with an `AxonError`, so that the caller can match on the kind of failure.
+    // The signature, including the boxed reference, mirrors `send_query`.
+    #[allow(clippy::redundant_allocation)]
+    async fn try_send_query<'a>(&self, query_type: &str, query: Box<&(dyn VecU8Message + Sync)>) -> AxonResult<Vec<SerializedObject>>
+    where Self: Sync
+    {
+        self.send_query(query_type, query).await.map_err(AxonError::from)
+    }
+
+    /// Sends a query and decodes the responses as `R`. Fails if a response is not an `R`.
+    async fn send_typed_query<Q, R>(&self, query_type: &str, query: &Q) -> Result<Vec<R>>
+    where Self: Sync + Sized, Q: Message + Sync, R: Message + Default
+    {
+        let responses = self.send_query(query_type, Box::new(query)).await?;
+        responses.into_iter()
+            .map(|response| R::decode(response.data.as_slice())
+                .map_err(|e| anyhow!("Cannot decode response to {:?}: {:?}: {:?}", query_type, response.r#type, e)))
+            .collect()
+    }
 }
 
 pub fn axon_serialize<T: Message>(type_name: &str, message: &T) -> Result<SerializedObject> {
@@ -66,7 +265,7 @@ pub fn axon_serialize<T: Message>(type_name: &str, message: &T) -> Result<Serial
         revision: "".to_string(),
         data: buf,
     };
-    debug!("Encoded output: {:?}", result);
+    debug!("Encoded output: {:?}", log_safe(&result));
     Ok(result)
 }
 
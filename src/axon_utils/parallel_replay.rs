@@ -0,0 +1,223 @@
+use anyhow::{anyhow,Result};
+use futures_util::future::try_join_all;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64,AtomicU64,Ordering};
+use std::time::{Duration,Instant};
+use tracing::{Instrument,debug,info,info_span};
+use super::{AxonClients,AxonServerHandle,Metrics};
+use super::event_processor::{EventProcessorConfig,TokenStore};
+use super::event_stream::{EventStreamReader,last_token};
+use super::handler_group::HandlerGroup;
+use super::message_size::check_payload_size;
+use super::metrics::label_value;
+use super::replay::{TokenPosition,position_token};
+use super::segments::Segment;
+use crate::axon_server::event::EventWithToken;
+
+const WORKER_NAME: &str = "parallel_replay";
+
+/// Settings for `parallel_replay`.
+#[derive(Debug,Clone)]
+pub struct ParallelReplayConfig {
+    pub partition_count: u32,
+    pub from: TokenPosition,
+    pub batch_size: usize,
+    pub report_interval: Duration,
+}
+
+impl Default for ParallelReplayConfig {
+    fn default() -> Self {
+        ParallelReplayConfig {
+            partition_count: 4,
+            from: TokenPosition::Tail,
+            batch_size: 100,
+            report_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ParallelReplayConfig {
+    pub fn with_partition_count(mut self, partition_count: u32) -> Self {
+        self.partition_count = partition_count;
+        self
+    }
+
+    pub fn with_from(mut self, from: TokenPosition) -> Self {
+        self.from = from;
+        self
+    }
+
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    pub fn with_report_interval(mut self, report_interval: Duration) -> Self {
+        self.report_interval = report_interval;
+        self
+    }
+}
+
+/// Progress of a parallel replay, shared by its partitions. The position is the lowest token that all partitions
+/// passed.
+#[derive(Debug,Clone)]
+pub struct ReplayProgress {
+    pub from_token: i64,
+    pub head_token: i64,
+    started: Instant,
+    positions: Arc<Vec<AtomicI64>>,
+    handled: Arc<AtomicU64>,
+}
+
+impl ReplayProgress {
+    fn new(from_token: i64, head_token: i64, partition_count: u32) -> Self {
+        ReplayProgress {
+            from_token,
+            head_token,
+            started: Instant::now(),
+            positions: Arc::new((0..partition_count).map(|_| AtomicI64::new(from_token)).collect()),
+            handled: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn position(&self) -> i64 {
+        self.positions.iter().map(|position| position.load(Ordering::SeqCst)).min().unwrap_or(self.head_token)
+    }
+
+    /// Returns the number of events that were passed to the handlers, by all partitions together.
+    pub fn handled(&self) -> u64 {
+        self.handled.load(Ordering::SeqCst)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Returns the number of tokens per second that the slowest partition moved forward.
+    pub fn tokens_per_second(&self) -> f64 {
+        let seconds = self.elapsed().as_secs_f64();
+        if seconds <= 0.0 {
+            return 0.0;
+        }
+        (self.position() - self.from_token) as f64 / seconds
+    }
+
+    /// Returns the estimated time until all partitions reach the head, if the replay moved forward.
+    pub fn eta(&self) -> Option<Duration> {
+        let rate = self.tokens_per_second();
+        if rate <= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64((self.head_token - self.position()).max(0) as f64 / rate))
+    }
+}
+
+/// Result of a parallel replay.
+#[derive(Debug,Clone,PartialEq)]
+pub struct ReplayReport {
+    pub from_token: i64,
+    pub head_token: i64,
+    pub handled: u64,
+    pub elapsed: Duration,
+}
+
+/// Replays the events up to the current head of the event store into a projection, with the given number of
+/// partitions running in parallel, e.g., to rebuild a large projection. Each partition handles the events of the
+/// aggregates in its segment (see `Segment`), so the events of an aggregate are handled in order, but the events of
+/// different aggregates are not. Every partition reads the whole event stream, so the replay is faster when the
+/// handlers, not the stream, are the bottleneck.
+///
+/// The partitions meet at a barrier at the head: only when all of them reached it, the token of the head is stored in
+/// the token store of the processor (under the key of its tracking configuration), so an interrupted replay leaves the
+/// token untouched. Start the event processor afterwards to handle the events that were appended meanwhile. Do not
+/// run the processor while replaying.
+///
+/// The handler groups, timeouts, filter, claim check, and maximum payload size of the processor configuration apply.
+/// Progress is logged every `report_interval`, with throughput and the estimated time to completion, and kept in the
+/// `parallel_replay_position`, `parallel_replay_tokens_per_second`, and `parallel_replay_eta_seconds` gauges.
+pub async fn parallel_replay<Q: TokenStore + Send + Sync + Clone>(
+    axon_server_handle: &AxonServerHandle,
+    query_model: &Q,
+    handler_groups: &[HandlerGroup<Q>],
+    processor_config: &EventProcessorConfig,
+    config: ParallelReplayConfig
+) -> Result<ReplayReport> {
+    if config.partition_count == 0 {
+        return Err(anyhow!("Parallel replay without partitions: {:?}", processor_config.tracking.processor_name));
+    }
+    let processor_name = processor_config.tracking.processor_name.clone();
+    let mut client = axon_server_handle.event_store_client();
+    let from_token = position_token(&mut client, config.from).await?;
+    let head_token = last_token(&mut client).await?;
+    let progress = ReplayProgress::new(from_token, head_token, config.partition_count);
+    info!("Parallel replay: start: {:?}: from: {:?}: head: {:?}: partitions: {:?}", processor_name, from_token, head_token, config.partition_count);
+
+    if head_token > from_token {
+        let processor_name = &processor_name;
+        let batch_size = config.batch_size;
+        let partitions = (0..config.partition_count).map(|segment_id| {
+            let segment = Segment {
+                segment_id,
+                segment_count: config.partition_count,
+            };
+            let progress = progress.clone();
+            let span = info_span!("replay_partition", processor = %processor_name, segment_id);
+            async move {
+                let mut client = axon_server_handle.event_store_client();
+                let mut events = EventStreamReader::open(&mut client, &axon_server_handle.display_name, &axon_server_handle.component_name, processor_name, from_token + 1, batch_size).await?;
+                let metrics = &axon_server_handle.metrics;
+                while let Some(EventWithToken { event, token, .. }) = events.next().await? {
+                    if let Some(mut event) = event {
+                        if segment.matches(&event) && !processor_config.filter.skip(WORKER_NAME, metrics, &event) {
+                            if let Some(claim_check) = processor_config.claim_check.as_ref() {
+                                claim_check.resolve_event(&mut event).await?;
+                            }
+                            if let Some(payload) = &event.payload {
+                                check_payload_size(payload, processor_config.max_payload_size)?;
+                                for handler_group in handler_groups {
+                                    handler_group.handle(WORKER_NAME, metrics, processor_config, &event, token, query_model).await?;
+                                }
+                                progress.handled.fetch_add(1, Ordering::SeqCst);
+                            }
+                        }
+                    }
+                    progress.positions[segment_id as usize].store(token, Ordering::SeqCst);
+                    if token >= head_token {
+                        break;
+                    }
+                }
+                debug!("Parallel replay: partition reached the head: {:?}", segment_id);
+                Ok::<(),anyhow::Error>(())
+            }.instrument(span)
+        });
+        tokio::select! {
+            result = try_join_all(partitions) => { result?; }
+            _ = report_progress(&progress, processor_name, &axon_server_handle.metrics, config.report_interval) => (),
+        }
+    }
+
+    query_model.for_tracking(&processor_config.tracking).reset_token(head_token).await?;
+    let report = ReplayReport {
+        from_token,
+        head_token,
+        handled: progress.handled(),
+        elapsed: progress.elapsed(),
+    };
+    info!("Parallel replay: done: {:?}: {:?}", processor_name, report);
+    Ok(report)
+}
+
+// Never returns.
+async fn report_progress(progress: &ReplayProgress, processor_name: &str, metrics: &Metrics, interval: Duration) {
+    loop {
+        tokio::time::delay_for(interval).await;
+        let eta = progress.eta();
+        info!(
+            "Parallel replay: {:?}: position: {:?}/{:?}: handled: {:?}: tokens/s: {:.1}: eta: {:?}",
+            processor_name, progress.position(), progress.head_token, progress.handled(), progress.tokens_per_second(), eta
+        );
+        metrics.set_gauge(&format!("parallel_replay_position{{processor={}}}", label_value(processor_name)), progress.position());
+        metrics.set_gauge(&format!("parallel_replay_tokens_per_second{{processor={}}}", label_value(processor_name)), progress.tokens_per_second() as i64);
+        metrics.set_gauge(&format!("parallel_replay_eta_seconds{{processor={}}}", label_value(processor_name)), eta.map(|eta| eta.as_secs() as i64).unwrap_or(-1));
+    }
+}
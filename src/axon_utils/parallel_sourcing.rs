@@ -0,0 +1,80 @@
+use anyhow::{anyhow,Result};
+use futures_core::Future;
+use futures_util::future::try_join_all;
+use tracing::debug;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use tokio::sync::Semaphore;
+use tonic::transport::Channel;
+use super::{AxonClients,AxonServerHandle};
+use super::event_query::query_events_from_client;
+use crate::axon_server::event::Event;
+use crate::axon_server::event::event_store_client::EventStoreClient;
+
+/// Number of aggregates that are sourced at the same time when a tool does not specify otherwise.
+pub const DEFAULT_SOURCING_CONCURRENCY: usize = 8;
+
+/// Event store clients that are shared by the tasks of a batch tool. Clients are handed out round-robin. Clients
+/// that were created from the same handle share its connection, so a pool over several handles spreads the reads
+/// over several connections. All clones share the same clients.
+#[derive(Debug,Clone)]
+pub struct EventStoreClientPool {
+    clients: Arc<Vec<EventStoreClient<Channel>>>,
+    next: Arc<AtomicUsize>,
+}
+
+pub fn create_event_store_client_pool(clients: Vec<EventStoreClient<Channel>>) -> Result<EventStoreClientPool> {
+    if clients.is_empty() {
+        return Err(anyhow!("An event store client pool needs at least one client"));
+    }
+    Ok(EventStoreClientPool {
+        clients: Arc::new(clients),
+        next: Arc::new(AtomicUsize::new(0)),
+    })
+}
+
+/// Returns a pool with one client for each of the given handles.
+pub fn create_event_store_client_pool_for(axon_server_handles: &[AxonServerHandle]) -> Result<EventStoreClientPool> {
+    create_event_store_client_pool(axon_server_handles.iter().map(AxonClients::event_store_client).collect())
+}
+
+impl EventStoreClientPool {
+    pub fn client(&self) -> EventStoreClient<Channel> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.clients.len();
+        self.clients[index].clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+}
+
+/// Applies the given function to each aggregate, with at most `concurrency` calls in progress at the same time. Each
+/// call gets a client from the pool. Results are returned in the order of the aggregate identifiers. Fails on the
+/// first error; calls that are in progress at that moment are abandoned.
+pub async fn for_each_aggregate<F, Fut, R>(pool: &EventStoreClientPool, aggregate_ids: &[String], concurrency: usize, f: F) -> Result<Vec<R>>
+where F: Fn(EventStoreClient<Channel>, String) -> Fut, Fut: Future<Output=Result<R>>
+{
+    let semaphore = Semaphore::new(concurrency.max(1));
+    let semaphore = &semaphore;
+    let f = &f;
+    let calls = aggregate_ids.iter().map(|aggregate_id| async move {
+        let _permit = semaphore.acquire().await;
+        f(pool.client(), aggregate_id.clone()).await
+    });
+    try_join_all(calls).await
+}
+
+/// Reads the events of each of the given aggregates, with at most `concurrency` reads in progress at the same time.
+pub async fn source_aggregates(pool: &EventStoreClientPool, aggregate_ids: &[String], concurrency: usize) -> Result<HashMap<String,Vec<Event>>> {
+    debug!("Source aggregates: {:?}: concurrency: {:?}", aggregate_ids.len(), concurrency);
+    let events = for_each_aggregate(pool, aggregate_ids, concurrency, |mut client, aggregate_id| async move {
+        query_events_from_client(&mut client, &aggregate_id).await
+    }).await?;
+    Ok(aggregate_ids.iter().cloned().zip(events).collect())
+}
@@ -0,0 +1,51 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::delay_for;
+
+// Workers that share a switch, e.g., the segments of an event processor, miss the notification of all but one of them.
+const RESUME_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Pauses and resumes a worker, e.g., on instruction of AxonServer during a blue/green switch.
+///
+/// A paused command worker stops granting flow-control permits, so that AxonServer routes new commands to other
+/// instances, while the commands that it already received are handled and answered as usual.
+#[derive(Debug,Clone,Default)]
+pub struct PauseSwitch {
+    paused: Arc<AtomicBool>,
+    changed: Arc<Notify>,
+}
+
+impl PauseSwitch {
+    pub fn pause(&self) {
+        if !self.paused.swap(true, Ordering::SeqCst) {
+            self.changed.notify();
+        }
+    }
+
+    pub fn resume(&self) {
+        if self.paused.swap(false, Ordering::SeqCst) {
+            self.changed.notify();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    // Only the worker that owns the switch waits for changes.
+    pub(crate) async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    /// Waits until the switch is resumed.
+    pub(crate) async fn resumed(&self) {
+        while self.is_paused() {
+            tokio::select! {
+                _ = self.changed() => (),
+                _ = delay_for(RESUME_CHECK_INTERVAL) => (),
+            }
+        }
+    }
+}
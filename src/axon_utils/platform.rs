@@ -0,0 +1,214 @@
+use anyhow::{anyhow,Result};
+use tracing::{debug,error,info,warn};
+use std::collections::HashMap;
+use tokio::sync::mpsc::{Sender,channel};
+use tokio::time::delay_for;
+use tonic::{Request,Status};
+use uuid::Uuid;
+use super::{AxonClients,CLIENT_VERSION,AxonServerHandle,PauseSwitch,WorkerHealth};
+use super::error_classification::{ReconnectPolicy,classify_error};
+use crate::axon_server::{ErrorMessage,InstructionAck};
+use crate::axon_server::control::{ClientIdentification,EventProcessorInfo,EventProcessorReference,Heartbeat,PlatformInboundInstruction};
+use crate::axon_server::control::{platform_inbound_instruction,platform_outbound_instruction};
+
+const WORKER_NAME: &str = "platform_listener";
+
+/// Settings for the platform listener.
+///
+/// The listener registers the client under its `component_name` (the component name of the handle by default), so that it shows up in
+/// the dashboard of AxonServer, and answers its heartbeats.
+///
+/// AxonServer pauses and starts processors by name. Each pause switch is registered under the name that AxonServer
+/// uses for it, e.g., the pause switch of the command worker under `"command_worker"`, and the pause switch of an event
+/// processor under its processor name. The status of each switch is reported to AxonServer as an `EventProcessorInfo`.
+///
+/// When AxonServer requests a reconnect, e.g., to move the client to another node of the cluster, the listener
+/// triggers the `ReconnectSignal` of the handle: the channel is rebuilt, and the command worker, the query processor
+/// and the event processors reopen their streams on the new channel. The listener itself reopens the platform stream
+/// right away. When the stream fails or ends, the listener opens a new stream after a delay that the
+/// `reconnect_policy` determines.
+#[derive(Debug,Clone,Default)]
+pub struct PlatformConfig {
+    pub component_name: Option<String>,
+    pub pause_switches: HashMap<String,PauseSwitch>,
+    pub reconnect_policy: ReconnectPolicy,
+}
+
+impl PlatformConfig {
+    pub fn with_component_name(mut self, component_name: &str) -> Self {
+        self.component_name = Some(component_name.to_string());
+        self
+    }
+
+    pub fn with_pause_switch(mut self, processor_name: &str, pause_switch: PauseSwitch) -> Self {
+        self.pause_switches.insert(processor_name.to_string(), pause_switch);
+        self
+    }
+}
+
+/// Registers with AxonServer and follows its platform instructions, reconnecting as needed, until the reconnect policy
+/// gives up.
+pub async fn platform_listener(axon_server_handle: AxonServerHandle, config: PlatformConfig) -> Result<()> {
+    let health = axon_server_handle.health.clone();
+    health.report(WORKER_NAME, WorkerHealth::Starting);
+    let mut attempt = 0;
+    loop {
+        let mut established = false;
+        let error = match follow_platform_stream(&axon_server_handle, &config, &mut established).await {
+            Ok(()) => {
+                info!("Platform listener: reconnect on request of AxonServer");
+                attempt = 0;
+                continue;
+            }
+            Err(error) => error,
+        };
+        if established {
+            attempt = 0;
+        }
+        attempt += 1;
+        match config.reconnect_policy.next_delay(&error, attempt) {
+            Some(delay) => {
+                warn!("Platform listener: reconnect: attempt: {:?}: delay: {:?}: {:?}", attempt, delay, error);
+                health.report(WORKER_NAME, WorkerHealth::Reconnecting {
+                    error_class: classify_error(&error),
+                    attempt,
+                });
+                delay_for(delay).await;
+            }
+            None => return Err(error),
+        }
+    }
+}
+
+// Returns `Ok(())` when AxonServer requested a reconnect, after the channel was rebuilt and the other workers were
+// signalled, and an error when the stream failed or ended.
+async fn follow_platform_stream(axon_server_handle: &AxonServerHandle, config: &PlatformConfig, established: &mut bool) -> Result<()> {
+    let health = axon_server_handle.health.clone();
+    let mut client = axon_server_handle.platform_client();
+    let client_id = axon_server_handle.display_name.clone();
+
+    // The registration and the processor info are queued before the stream is opened, so the channel must hold them all.
+    let (mut tx, mut rx) = channel::<PlatformInboundInstruction>(config.pause_switches.len() + 10);
+    let outbound = async_stream::stream! {
+        while let Some(instruction) = rx.recv().await {
+            debug!("Platform listener: send: {:?}", instruction);
+            yield instruction;
+        }
+    };
+    let client_identification = ClientIdentification {
+        client_id: client_id.clone(),
+        component_name: config.component_name.clone().unwrap_or_else(|| axon_server_handle.component_name.clone()),
+        tags: axon_server_handle.tags.clone(),
+        version: CLIENT_VERSION.to_string(),
+    };
+    send(&mut tx, platform_inbound_instruction::Request::Register(client_identification)).await?;
+    for processor_name in config.pause_switches.keys() {
+        send_processor_info(&mut tx, config, processor_name).await?;
+    }
+
+    let response = client.open_stream(Request::new(outbound)).await
+        .map_err(|e| health.stream_failed(WORKER_NAME, e))?;
+    health.report(WORKER_NAME, WorkerHealth::Running);
+    *established = true;
+
+    let mut inbound = response.into_inner();
+    loop {
+        let instruction = match inbound.message().await {
+            Ok(Some(instruction)) => instruction,
+            Ok(None) => {
+                debug!("Platform listener: stream closed by AxonServer");
+                return Err(health.stream_failed(WORKER_NAME, Status::unavailable("Platform stream closed by AxonServer")).into());
+            }
+            Err(e) => {
+                error!("Error from AxonServer: {:?}", e);
+                return Err(health.stream_failed(WORKER_NAME, e).into());
+            }
+        };
+        debug!("Platform instruction: {:?}", instruction);
+        let result = match instruction.request {
+            Some(platform_outbound_instruction::Request::PauseEventProcessor(EventProcessorReference { processor_name })) => {
+                switch(config, &processor_name, true)
+                    .map(|_| Some(processor_name))
+            }
+            Some(platform_outbound_instruction::Request::StartEventProcessor(EventProcessorReference { processor_name })) => {
+                switch(config, &processor_name, false)
+                    .map(|_| Some(processor_name))
+            }
+            Some(platform_outbound_instruction::Request::RequestEventProcessorInfo(EventProcessorReference { processor_name })) => {
+                Ok(Some(processor_name))
+            }
+            Some(platform_outbound_instruction::Request::Heartbeat(_)) => {
+                send(&mut tx, platform_inbound_instruction::Request::Heartbeat(Heartbeat {})).await?;
+                Ok(None)
+            }
+            Some(platform_outbound_instruction::Request::RequestReconnect(_)) => {
+                if !instruction.instruction_id.is_empty() {
+                    send_ack(&mut tx, &instruction.instruction_id, &Ok(None)).await?;
+                }
+                axon_server_handle.reconnect.request_reconnect().await;
+                return Ok(());
+            }
+            _ => Ok(None),
+        };
+        if !instruction.instruction_id.is_empty() {
+            send_ack(&mut tx, &instruction.instruction_id, &result).await?;
+        }
+        if let Ok(Some(processor_name)) = result {
+            send_processor_info(&mut tx, config, &processor_name).await?;
+        }
+    }
+}
+
+fn switch(config: &PlatformConfig, processor_name: &str, pause: bool) -> Result<()> {
+    let pause_switch = config.pause_switches.get(processor_name)
+        .ok_or_else(|| anyhow!("Unknown processor: {:?}", processor_name))?;
+    if pause {
+        warn!("Platform listener: pause: {:?}", processor_name);
+        pause_switch.pause();
+    } else {
+        warn!("Platform listener: resume: {:?}", processor_name);
+        pause_switch.resume();
+    }
+    Ok(())
+}
+
+async fn send_processor_info(tx: &mut Sender<PlatformInboundInstruction>, config: &PlatformConfig, processor_name: &str) -> Result<()> {
+    let pause_switch = match config.pause_switches.get(processor_name) {
+        Some(pause_switch) => pause_switch,
+        None => return Ok(()),
+    };
+    let info = EventProcessorInfo {
+        processor_name: processor_name.to_string(),
+        mode: "Subscribing".to_string(),
+        active_threads: 1,
+        running: !pause_switch.is_paused(),
+        error: false,
+        segment_status: Vec::new(),
+        available_threads: 0,
+        token_store_identifier: "".to_string(),
+    };
+    send(tx, platform_inbound_instruction::Request::EventProcessorInfo(info)).await
+}
+
+async fn send_ack(tx: &mut Sender<PlatformInboundInstruction>, instruction_id: &str, result: &Result<Option<String>>) -> Result<()> {
+    let ack = InstructionAck {
+        instruction_id: instruction_id.to_string(),
+        success: result.is_ok(),
+        error: result.as_ref().err().map(|e| ErrorMessage {
+            message: e.to_string(),
+            location: "".to_string(),
+            details: Vec::new(),
+            error_code: "ERROR".to_string(),
+        }),
+    };
+    send(tx, platform_inbound_instruction::Request::Ack(ack)).await
+}
+
+async fn send(tx: &mut Sender<PlatformInboundInstruction>, request: platform_inbound_instruction::Request) -> Result<()> {
+    let instruction_id = Uuid::new_v4();
+    let instruction = PlatformInboundInstruction {
+        instruction_id: format!("{:?}", instruction_id.to_simple()),
+        request: Some(request),
+    };
+    tx.send(instruction).await.map_err(|_| anyhow!("Platform listener: output stream closed"))
+}
@@ -0,0 +1,134 @@
+use anyhow::{anyhow,Result};
+use tracing::debug;
+use serde_json::Value;
+use std::sync::Arc;
+use tokio_postgres::Client;
+use super::projection_conflict::{ConflictStrategy,projection_token,with_projection_token};
+use super::tombstone::DocumentDeletes;
+
+const MAX_MERGE_ATTEMPTS: u32 = 10;
+
+/// Keeps the documents of a projection as JSON in a PostgreSQL table, and resolves concurrent writes with a conflict
+/// strategy (see `ConflictStrategy`), so that segments and replays that write the same document produce the same read
+/// model regardless of their timing.
+///
+/// The table has the id, the document, the token of the event that wrote the document last, and a version that every
+/// write increments. Replacing strategies are applied in a single statement; merges read the document and write it
+/// back only if its version did not change in the meantime, and are retried otherwise. Create the table with
+/// `create_document_table`.
+#[derive(Debug,Clone)]
+pub struct PostgresDocumentStore {
+    client: Arc<Client>,
+    table: String,
+    strategy: ConflictStrategy,
+}
+
+/// Creates a document store on the given table. The name is used as is in SQL statements, so it must come from
+/// configuration, never from input.
+pub fn create_postgres_document_store(client: Arc<Client>, table: &str) -> PostgresDocumentStore {
+    PostgresDocumentStore {
+        client,
+        table: table.to_string(),
+        strategy: ConflictStrategy::default(),
+    }
+}
+
+impl PostgresDocumentStore {
+    pub fn with_strategy(mut self, strategy: ConflictStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Creates the table of the document store, if it does not exist yet.
+    pub async fn create_document_table(&self) -> Result<()> {
+        let statement = format!(
+            "CREATE TABLE IF NOT EXISTS {} (\
+                id TEXT PRIMARY KEY, \
+                document JSONB NOT NULL, \
+                token BIGINT NOT NULL, \
+                version BIGINT NOT NULL\
+            )",
+            self.table
+        );
+        self.client.batch_execute(statement.as_str()).await?;
+        Ok(())
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Option<Value>> {
+        Ok(self.read(id).await?.map(|(document, _)| document))
+    }
+
+    /// Writes a document that was projected from the event with the given token, and returns whether the stored
+    /// document changed.
+    pub async fn upsert(&self, id: &str, document: Value, token: i64) -> Result<bool> {
+        let guard = match &self.strategy {
+            ConflictStrategy::Overwrite => "",
+            ConflictStrategy::LastWriterWins => "WHERE d.token <= EXCLUDED.token",
+            ConflictStrategy::IgnoreOlder => "WHERE d.token < EXCLUDED.token",
+            ConflictStrategy::Merge(_) => return self.merge(id, document, token).await,
+        };
+        let document = with_projection_token(document, token)?;
+        let statement = format!(
+            "INSERT INTO {} AS d (id, document, token, version) VALUES ($1, $2::TEXT::JSONB, $3, 0) \
+            ON CONFLICT (id) DO UPDATE SET document = EXCLUDED.document, token = EXCLUDED.token, version = d.version + 1 {}",
+            self.table, guard
+        );
+        let count = self.client.execute(statement.as_str(), &[&id, &document.to_string(), &token]).await?;
+        Ok(count > 0)
+    }
+
+    pub async fn delete(&self, id: &str) -> Result<()> {
+        let statement = format!("DELETE FROM {} WHERE id = $1", self.table);
+        self.client.execute(statement.as_str(), &[&id]).await?;
+        Ok(())
+    }
+
+    async fn merge(&self, id: &str, document: Value, token: i64) -> Result<bool> {
+        for attempt in 1..=MAX_MERGE_ATTEMPTS {
+            let stored = self.read(id).await?;
+            let merged = match self.strategy.resolve(stored.as_ref().map(|(document, _)| document), document.clone(), token)? {
+                Some(merged) => merged,
+                None => return Ok(false),
+            };
+            let merged_token = projection_token(&merged).unwrap_or(token);
+            let count = match stored {
+                Some((_, version)) => {
+                    let statement = format!(
+                        "UPDATE {} SET document = $2::TEXT::JSONB, token = $3, version = version + 1 WHERE id = $1 AND version = $4",
+                        self.table
+                    );
+                    self.client.execute(statement.as_str(), &[&id, &merged.to_string(), &merged_token, &version]).await?
+                }
+                None => {
+                    let statement = format!(
+                        "INSERT INTO {} (id, document, token, version) VALUES ($1, $2::TEXT::JSONB, $3, 0) ON CONFLICT (id) DO NOTHING",
+                        self.table
+                    );
+                    self.client.execute(statement.as_str(), &[&id, &merged.to_string(), &merged_token]).await?
+                }
+            };
+            if count > 0 {
+                return Ok(true);
+            }
+            debug!("Merge conflict: {:?}: {:?}: attempt: {:?}", self.table, id, attempt);
+        }
+        Err(anyhow!("Gave up merging document after {:?} attempts: {:?}: {:?}", MAX_MERGE_ATTEMPTS, self.table, id))
+    }
+
+    async fn read(&self, id: &str) -> Result<Option<(Value,i64)>> {
+        let statement = format!("SELECT document::TEXT, version FROM {} WHERE id = $1", self.table);
+        let row = match self.client.query_opt(statement.as_str(), &[&id]).await? {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let document: String = row.get(0);
+        Ok(Some((serde_json::from_str(&document)?, row.get(1))))
+    }
+}
+
+#[tonic::async_trait]
+impl DocumentDeletes for PostgresDocumentStore {
+    async fn delete_document_by_id(&self, id: &str) -> Result<()> {
+        self.delete(id).await
+    }
+}
@@ -0,0 +1,101 @@
+use tokio::sync::mpsc::{Receiver,Sender,channel};
+use tokio::sync::mpsc::error::{SendError,TrySendError};
+use crate::axon_server::{ProcessingInstruction,ProcessingKey};
+use crate::axon_server::meta_data_value::Data;
+
+/// Messages with a priority of at least this value are handled in the high-priority lane.
+pub const DEFAULT_HIGH_PRIORITY_THRESHOLD: i64 = 1;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum PriorityLane {
+    High,
+    Normal,
+}
+
+impl PriorityLane {
+    pub fn for_priority(priority: i64, high_priority_threshold: i64) -> Self {
+        if priority >= high_priority_threshold {
+            PriorityLane::High
+        } else {
+            PriorityLane::Normal
+        }
+    }
+}
+
+/// Returns the value of the `PRIORITY` processing instruction, or zero if the message has none.
+pub fn message_priority(processing_instructions: &[ProcessingInstruction]) -> i64 {
+    processing_instructions.iter()
+        .filter(|instruction| instruction.key == ProcessingKey::Priority as i32)
+        .filter_map(|instruction| instruction.value.as_ref().and_then(|value| value.data.as_ref()))
+        .filter_map(|data| match data {
+            Data::NumberValue(number) => Some(*number),
+            Data::TextValue(text) => text.parse().ok(),
+            _ => None,
+        })
+        .next()
+        .unwrap_or(0)
+}
+
+/// Returns the value of the `ROUTING_KEY` processing instruction, which usually is the aggregate identifier.
+pub fn message_routing_key(processing_instructions: &[ProcessingInstruction]) -> Option<String> {
+    processing_instructions.iter()
+        .filter(|instruction| instruction.key == ProcessingKey::RoutingKey as i32)
+        .filter_map(|instruction| instruction.value.as_ref().and_then(|value| value.data.as_ref()))
+        .filter_map(|data| match data {
+            Data::TextValue(text) => Some(text.clone()),
+            _ => None,
+        })
+        .next()
+}
+
+/// Sending half of a pair of bounded queues, one for high-priority messages and one for the rest.
+#[derive(Debug)]
+pub(crate) struct LaneSenders<T> {
+    high: Sender<T>,
+    normal: Sender<T>,
+}
+
+/// Receiving half of a pair of bounded queues. High-priority messages are always taken first.
+#[derive(Debug)]
+pub(crate) struct LaneReceivers<T> {
+    high: Receiver<T>,
+    normal: Receiver<T>,
+}
+
+pub(crate) fn priority_lanes<T>(capacity: usize) -> (LaneSenders<T>, LaneReceivers<T>) {
+    let (high_tx, high_rx) = channel(capacity);
+    let (normal_tx, normal_rx) = channel(capacity);
+    (
+        LaneSenders { high: high_tx, normal: normal_tx },
+        LaneReceivers { high: high_rx, normal: normal_rx },
+    )
+}
+
+impl<T> LaneSenders<T> {
+    pub(crate) async fn send(&mut self, lane: PriorityLane, item: T) -> Result<(), SendError<T>> {
+        match lane {
+            PriorityLane::High => self.high.send(item).await,
+            PriorityLane::Normal => self.normal.send(item).await,
+        }
+    }
+
+    pub(crate) fn try_send(&mut self, lane: PriorityLane, item: T) -> Result<(), TrySendError<T>> {
+        match lane {
+            PriorityLane::High => self.high.try_send(item),
+            PriorityLane::Normal => self.normal.try_send(item),
+        }
+    }
+}
+
+impl<T> LaneReceivers<T> {
+    pub(crate) async fn recv(&mut self) -> Option<T> {
+        if let Ok(item) = self.high.try_recv() {
+            return Some(item);
+        }
+        tokio::select! {
+            Some(item) = self.high.recv() => Some(item),
+            Some(item) = self.normal.recv() => Some(item),
+            else => None,
+        }
+    }
+}
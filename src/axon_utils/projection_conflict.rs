@@ -0,0 +1,81 @@
+use anyhow::{anyhow,Result};
+use serde_json::Value;
+use std::fmt::{Debug,Formatter};
+use std::sync::Arc;
+
+/// Field of a projected document that holds the token of the event that wrote it last.
+pub const PROJECTION_TOKEN_FIELD: &str = "projection_token";
+
+/// Combines the stored document with an incoming document. Both carry their token in `PROJECTION_TOKEN_FIELD`.
+pub type MergeFn = Arc<dyn Fn(&Value, Value) -> Result<Value> + Send + Sync>;
+
+/// How a projection resolves a write to a document that may already have been written for a later event, e.g., by
+/// another segment, or by the live processor while a replay is running. Without a strategy, the order in which the
+/// writes arrive decides the contents of the read model.
+///
+/// Each write carries the token of the event that caused it, which is stored in the document. With `LastWriterWins`,
+/// a write replaces the document unless the document has a higher token; a write with the same token, e.g., from a
+/// replay, is applied again. With `IgnoreOlder`, a write is only applied when its token is higher than that of the
+/// document, so that redelivered events leave the document alone. With `Merge`, the closure combines the stored
+/// document and the incoming document; the result gets the highest of both tokens. `Overwrite` applies every write,
+/// as a plain upsert does.
+#[derive(Clone,Default)]
+pub enum ConflictStrategy {
+    #[default]
+    Overwrite,
+    LastWriterWins,
+    IgnoreOlder,
+    Merge(MergeFn),
+}
+
+impl Debug for ConflictStrategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConflictStrategy::Overwrite => write!(f, "Overwrite"),
+            ConflictStrategy::LastWriterWins => write!(f, "LastWriterWins"),
+            ConflictStrategy::IgnoreOlder => write!(f, "IgnoreOlder"),
+            ConflictStrategy::Merge(_) => write!(f, "Merge"),
+        }
+    }
+}
+
+/// Returns a strategy that merges documents with the given closure, e.g., to add up counters or to take the union of
+/// lists.
+pub fn merge_strategy(merge: impl Fn(&Value, Value) -> Result<Value> + Send + Sync + 'static) -> ConflictStrategy {
+    ConflictStrategy::Merge(Arc::new(merge))
+}
+
+impl ConflictStrategy {
+    /// Returns the document to store for an incoming document with the given token, or `None` if the stored document
+    /// is to be kept as it is.
+    pub fn resolve(&self, stored: Option<&Value>, incoming: Value, token: i64) -> Result<Option<Value>> {
+        let incoming = with_projection_token(incoming, token)?;
+        let stored = match stored {
+            Some(stored) => stored,
+            None => return Ok(Some(incoming)),
+        };
+        let stored_token = projection_token(stored);
+        match self {
+            ConflictStrategy::Overwrite => Ok(Some(incoming)),
+            ConflictStrategy::LastWriterWins => Ok(Some(incoming).filter(|_| stored_token.map(|stored_token| stored_token <= token).unwrap_or(true))),
+            ConflictStrategy::IgnoreOlder => Ok(Some(incoming).filter(|_| stored_token.map(|stored_token| stored_token < token).unwrap_or(true))),
+            ConflictStrategy::Merge(merge) => {
+                let merged = merge(stored, incoming)?;
+                let merged_token = stored_token.map(|stored_token| stored_token.max(token)).unwrap_or(token);
+                Ok(Some(with_projection_token(merged, merged_token)?))
+            }
+        }
+    }
+}
+
+/// Returns the token of the event that wrote the document last, if it was written with a conflict strategy.
+pub fn projection_token(document: &Value) -> Option<i64> {
+    document[PROJECTION_TOKEN_FIELD].as_i64()
+}
+
+/// Stores the token in the document.
+pub fn with_projection_token(mut document: Value, token: i64) -> Result<Value> {
+    let fields = document.as_object_mut().ok_or_else(|| anyhow!("Projected document is not an object"))?;
+    fields.insert(PROJECTION_TOKEN_FIELD.to_string(), Value::from(token));
+    Ok(document)
+}
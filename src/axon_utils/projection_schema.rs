@@ -0,0 +1,71 @@
+use anyhow::{anyhow,Result};
+use tracing::{info,warn};
+use std::fmt::{Debug,Formatter};
+use std::sync::Arc;
+use super::TokenStore;
+use super::rebuild_projection::ProjectionRebuild;
+
+/// Version of the schema of a projection, e.g., the layout of its indices or tables.
+///
+/// The version is stored next to the tracking token. When an event processor starts with a version that differs from
+/// the stored version, the projection is wiped by the rebuild (if any) and all events are replayed. Without
+/// `rebuild_on_mismatch` the processor refuses to start instead.
+#[derive(Clone)]
+pub struct ProjectionSchema {
+    pub version: i64,
+    pub rebuild_on_mismatch: bool,
+    pub rebuild: Option<Arc<dyn ProjectionRebuild>>,
+}
+
+impl Debug for ProjectionSchema {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProjectionSchema")
+            .field("version", &self.version)
+            .field("rebuild_on_mismatch", &self.rebuild_on_mismatch)
+            .field("rebuild", &self.rebuild.is_some())
+            .finish()
+    }
+}
+
+pub fn create_projection_schema(version: i64) -> ProjectionSchema {
+    ProjectionSchema {
+        version,
+        rebuild_on_mismatch: true,
+        rebuild: None,
+    }
+}
+
+impl ProjectionSchema {
+    pub fn with_rebuild(mut self, rebuild: impl ProjectionRebuild + 'static) -> Self {
+        self.rebuild = Some(Arc::new(rebuild));
+        self
+    }
+
+    pub fn with_rebuild_on_mismatch(mut self, rebuild_on_mismatch: bool) -> Self {
+        self.rebuild_on_mismatch = rebuild_on_mismatch;
+        self
+    }
+}
+
+/// Compares the schema version of the projection with the stored version and rebuilds the projection if they differ.
+/// A projection without a stored version is considered up to date if it did not process any events yet.
+pub(crate) async fn ensure_schema_version<T: TokenStore + Sync>(token_store: &T, schema: &ProjectionSchema) -> Result<()> {
+    let stored = token_store.retrieve_schema_version().await?;
+    if stored == Some(schema.version) {
+        return Ok(());
+    }
+    if stored.is_none() && token_store.retrieve_token().await?.unwrap_or(-1) < 0 {
+        return token_store.store_schema_version(schema.version).await;
+    }
+    if !schema.rebuild_on_mismatch {
+        return Err(anyhow!("Projection schema version mismatch: found: {:?}: expected: {:?}", stored, schema.version));
+    }
+    warn!("Projection schema version mismatch: found: {:?}: expected: {:?}: rebuild", stored, schema.version);
+    if let Some(rebuild) = &schema.rebuild {
+        rebuild.rebuild().await?;
+    }
+    token_store.store_token(-1).await?;
+    token_store.store_schema_version(schema.version).await?;
+    info!("Projection schema version: {:?}: replay all events", schema.version);
+    Ok(())
+}
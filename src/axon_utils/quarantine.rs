@@ -0,0 +1,84 @@
+use anyhow::Result;
+use tracing::error;
+use sha2::{Digest,Sha256};
+use std::collections::HashMap;
+use std::fmt::{Debug,Display,Formatter};
+use std::sync::{Arc,Mutex};
+use crate::axon_server::SerializedObject;
+use crate::axon_server::command::Command;
+
+/// A command that was taken out of circulation because its handler crashed on it repeatedly.
+#[derive(Debug,Clone)]
+pub struct QuarantinedCommand {
+    pub key: String,
+    pub command_name: String,
+    pub message_identifier: String,
+    pub payload: Option<SerializedObject>,
+    pub failures: u32,
+    pub last_error: String,
+}
+
+/// Keeps quarantined commands for inspection.
+///
+/// The store is consulted for every incoming command, so that a redelivered poison command is rejected without
+/// invoking the handler again.
+#[tonic::async_trait]
+pub trait QuarantineStore: Debug + Send + Sync {
+    async fn quarantine(&self, command: QuarantinedCommand) -> Result<()>;
+    async fn is_quarantined(&self, key: &str) -> Result<bool>;
+}
+
+/// Quarantine store that keeps the quarantined commands in memory. Quarantined commands are logged as errors.
+#[derive(Debug,Clone,Default)]
+pub struct InMemoryQuarantineStore {
+    commands: Arc<Mutex<HashMap<String,QuarantinedCommand>>>,
+}
+
+impl InMemoryQuarantineStore {
+    pub fn list(&self) -> Vec<QuarantinedCommand> {
+        self.commands.lock().map(|commands| commands.values().cloned().collect()).unwrap_or_default()
+    }
+
+    pub fn release(&self, key: &str) -> Option<QuarantinedCommand> {
+        self.commands.lock().ok().and_then(|mut commands| commands.remove(key))
+    }
+}
+
+#[tonic::async_trait]
+impl QuarantineStore for InMemoryQuarantineStore {
+    async fn quarantine(&self, command: QuarantinedCommand) -> Result<()> {
+        error!("Quarantined command: {:?}: {:?}: failures: {:?}: {}", command.command_name, command.key, command.failures, command.last_error);
+        if let Ok(mut commands) = self.commands.lock() {
+            commands.insert(command.key.clone(), command);
+        }
+        Ok(())
+    }
+
+    async fn is_quarantined(&self, key: &str) -> Result<bool> {
+        Ok(self.commands.lock().map(|commands| commands.contains_key(key)).unwrap_or(false))
+    }
+}
+
+/// Identifies a command by its name and payload, so that redeliveries of the same command map to the same key.
+pub fn quarantine_key(command: &Command) -> String {
+    let mut hasher = Sha256::new();
+    Digest::update(&mut hasher, &command.name);
+    if let Some(payload) = &command.payload {
+        Digest::update(&mut hasher, &payload.r#type);
+        Digest::update(&mut hasher, &payload.data);
+    }
+    base64::encode(hasher.finalize())
+}
+
+#[derive(Debug)]
+pub(crate) struct QuarantinedError {
+    pub(crate) key: String,
+}
+
+impl Display for QuarantinedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Command is quarantined: {}", self.key)
+    }
+}
+
+impl std::error::Error for QuarantinedError {}
@@ -1,19 +1,173 @@
 use anyhow::{Result,anyhow};
 use async_stream::stream;
 use futures_core::stream::Stream;
-use log::{debug,error,warn};
+use tracing::{Instrument,debug,debug_span,error,info,info_span,warn};
+use prost::Message;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::time::{Duration,Instant};
+use tokio::sync::Notify;
 use tokio::sync::mpsc::{Sender,Receiver, channel};
 use tonic::Request;
 use uuid::Uuid;
+use super::flow_control::{FlowControlMode,PermitController};
+use super::message_size::{check_message_size,check_payload_size};
+use super::priority::{DEFAULT_HIGH_PRIORITY_THRESHOLD,LaneReceivers,PriorityLane,message_priority,priority_lanes};
 use super::handler_registry::TheHandlerRegistry;
-use crate::axon_server::{FlowControl,SerializedObject};
+use super::redaction::{Redact,RedactionPolicy,log_safe};
+use super::slow_handler::SlowHandlerThresholds;
+use crate::axon_server::{ErrorMessage,FlowControl,MetaDataValue,ProcessingInstruction,ProcessingKey,SerializedObject};
+use crate::axon_server::meta_data_value::Data;
 use crate::axon_server::query::{QueryComplete,QueryRequest,QueryResponse,QuerySubscription};
 use crate::axon_server::query::{QueryProviderOutbound,query_provider_inbound,query_provider_outbound};
-use crate::axon_server::query::query_service_client::QueryServiceClient;
-use crate::axon_utils::AxonServerHandle;
+use crate::axon_server::query::{QueryUpdate,QueryUpdateComplete,SubscriptionQuery,SubscriptionQueryResponse,subscription_query_request,subscription_query_response};
+use crate::axon_utils::{AxonClients,AxonServerHandle,Metrics,WorkerHealth,axon_serialize};
 
-pub trait QueryContext {
+pub trait QueryContext: Clone {
+    /// Returns the context that is passed to the handler of a query. Override this method to give handlers access to
+    /// the sender for incremental responses. By default the sender is ignored and each handler returns one response.
+    fn for_query(&self, _responses: QueryResponseSender) -> Self {
+        self.clone()
+    }
+
+    /// Returns the context that is passed to the handler of the given query. Override this method to give handlers
+    /// access to the meta-data, deadline and expected number of results of the query, e.g., to return cheap results
+    /// when the deadline is near, or to echo correlation data in responses. By default the envelope is ignored.
+    fn for_query_envelope(&self, _envelope: &QueryEnvelope) -> Self {
+        self.clone()
+    }
+}
+
+/// The envelope of an incoming query: everything but the payload.
+#[derive(Debug,Clone)]
+pub struct QueryEnvelope {
+    pub message_identifier: String,
+    pub query: String,
+    pub meta_data: HashMap<String,MetaDataValue>,
+    /// Value of the `TIMEOUT` processing instruction.
+    pub timeout: Option<Duration>,
+    /// Moment at which the requester stops waiting: the time the query was received plus the timeout.
+    pub deadline: Option<Instant>,
+    /// Value of the `NR_OF_RESULTS` processing instruction. `None` means unlimited.
+    pub expected_results: Option<usize>,
+}
+
+impl QueryEnvelope {
+    fn from_request(query: &QueryRequest, received: Instant) -> Self {
+        let timeout = instruction_number(&query.processing_instructions, ProcessingKey::Timeout)
+            .filter(|millis| *millis > 0)
+            .map(|millis| Duration::from_millis(millis as u64));
+        let expected_results = instruction_number(&query.processing_instructions, ProcessingKey::NrOfResults)
+            .filter(|results| *results >= 0)
+            .map(|results| results as usize);
+        QueryEnvelope {
+            message_identifier: query.message_identifier.clone(),
+            query: query.query.clone(),
+            meta_data: query.meta_data.clone(),
+            timeout,
+            deadline: timeout.map(|timeout| received + timeout),
+            expected_results,
+        }
+    }
+
+    /// Returns the time that is left until the deadline, or `None` if the query has no deadline.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+fn instruction_number(processing_instructions: &[ProcessingInstruction], key: ProcessingKey) -> Option<i64> {
+    processing_instructions.iter()
+        .filter(|instruction| instruction.key == key as i32)
+        .filter_map(|instruction| instruction.value.as_ref().and_then(|value| value.data.as_ref()))
+        .filter_map(|data| match data {
+            Data::NumberValue(number) => Some(*number),
+            Data::TextValue(text) => text.parse().ok(),
+            _ => None,
+        })
+        .next()
+}
+
+const RESPONSE_TYPE_SEPARATOR: char = '|';
+
+/// Returns the key under which to register a query handler that only answers queries that expect the given response
+/// type. Handlers that are registered under the plain query name answer queries for any response type that has no
+/// handler of its own, or whose expected response type cannot be decoded. For queries from Java clients, the response
+/// type is the `expectedResponseType` of the `ResponseType` of the query, i.e., the name of a Java class.
+pub fn query_response_key(query_name: &str, response_type: &str) -> String {
+    format!("{}{}{}", query_name, RESPONSE_TYPE_SEPARATOR, response_type)
+}
+
+// Package of the `ResponseType` classes of Axon Framework, e.g., `InstanceResponseType`, that Java clients send instead
+// of the type of the response itself.
+const JAVA_RESPONSE_TYPE_PACKAGE: &str = "org.axonframework.messaging.responsetypes.";
+
+/// Returns the type of the response that the sender of a query expects. Java clients send a serialized `ResponseType`
+/// (XStream or Jackson) that holds the `expectedResponseType`. Returns `None` when the expected type cannot be decoded,
+/// so that the query is routed by its name only.
+fn expected_response_type(response_type: &SerializedObject) -> Option<String> {
+    if !response_type.r#type.starts_with(JAVA_RESPONSE_TYPE_PACKAGE) {
+        return Some(response_type.r#type.clone()).filter(|expected| !expected.is_empty());
+    }
+    let data = std::str::from_utf8(&response_type.data).ok()?;
+    if let Ok(serde_json::Value::Object(fields)) = serde_json::from_str(data) {
+        return fields.get("expectedResponseType").and_then(|expected| expected.as_str()).map(str::to_string);
+    }
+    let start = data.find("<expectedResponseType>")? + "<expectedResponseType>".len();
+    let end = start + data[start..].find("</expectedResponseType>")?;
+    Some(data[start..end].trim().to_string())
+}
+
+// Splits a registry key into the query name and the response type, which is `*` (any) for a plain query name.
+pub(crate) fn split_query_key(key: &str) -> (&str, &str) {
+    match key.find(RESPONSE_TYPE_SEPARATOR) {
+        Some(position) => (&key[..position], &key[position + 1..]),
+        None => (key, "*"),
+    }
+}
+
+/// Sends responses to a query while the handler is still running, each as a separate `QueryResponse`. The query is
+/// completed when the handler returns. The result of the handler is only sent as an additional response when it has a
+/// payload, or when nothing was sent before.
+///
+/// For the initial result of a subscription query, the first response is the initial result and the responses after
+/// it are sent as updates.
+#[derive(Debug,Clone)]
+pub struct QueryResponseSender {
+    request_identifier: String,
+    subscription_identifier: Option<String>,
+    tx: Sender<AxonQueryOutput>,
+    sent: Arc<AtomicUsize>,
+}
+
+impl QueryResponseSender {
+    pub async fn send(&self, payload: SerializedObject) -> Result<()> {
+        self.send_with_meta_data(payload, HashMap::new()).await
+    }
+
+    /// Sends a response with meta-data, e.g., correlation data that is copied from the `QueryEnvelope`.
+    pub async fn send_with_meta_data(&self, payload: SerializedObject, meta_data: HashMap<String,MetaDataValue>) -> Result<()> {
+        let request_identifier = self.request_identifier.clone();
+        let output = match self.subscription_identifier.clone() {
+            None => AxonQueryOutput::Response { request_identifier, payload: Some(payload), meta_data },
+            Some(subscription_identifier) if self.sent() == 0 => AxonQueryOutput::InitialResult { subscription_identifier, request_identifier, payload: Some(payload), meta_data },
+            Some(subscription_identifier) => AxonQueryOutput::Update { subscription_identifier, payload, meta_data },
+        };
+        self.tx.clone().send(output).await
+            .map_err(|_| anyhow!("Query processor: output stream closed"))?;
+        self.sent.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub async fn send_message<T: Message>(&self, type_name: &str, message: &T) -> Result<()> {
+        self.send(axon_serialize(type_name, message)?).await
+    }
+
+    /// Returns the number of responses that were sent so far.
+    pub fn sent(&self) -> usize {
+        self.sent.load(Ordering::SeqCst)
+    }
 }
 
 #[derive(Debug,Clone)]
@@ -21,86 +175,337 @@ pub struct QueryResult {
     pub payload: Option<SerializedObject>,
 }
 
+impl Redact for QueryResult {
+    fn redact(&mut self, policy: &RedactionPolicy) {
+        self.payload.redact(policy);
+    }
+}
+
+const WORKER_NAME: &str = "query_processor";
+const PERMIT_WINDOW: &str = "query_processor_permit_window";
+pub(crate) const PERMITS_OUTSTANDING: &str = "query_processor_permits_outstanding";
+pub(crate) const IN_FLIGHT: &str = "query_processor_in_flight";
+const HANDLER_LATENCY_MS: &str = "query_processor_handler_latency_ms";
+
+/// Settings for the query processor.
+///
+/// Queries with a `PRIORITY` processing instruction of at least `high_priority_threshold` are queued in a separate
+/// lane that is always served before the normal lane. Each lane holds at most `mailbox_capacity` queries. Handlers that
+/// take longer than their `slow_handler` threshold are logged and counted. Queries with a payload that is larger than
+/// `max_payload_size` are not passed to their handler.
+#[derive(Debug,Clone)]
+pub struct QueryProcessorConfig {
+    pub flow_control: FlowControlMode,
+    pub mailbox_capacity: usize,
+    pub high_priority_threshold: i64,
+    pub slow_handler: SlowHandlerThresholds,
+    pub max_payload_size: Option<usize>,
+}
+
+impl Default for QueryProcessorConfig {
+    fn default() -> Self {
+        QueryProcessorConfig {
+            flow_control: FlowControlMode::default(),
+            mailbox_capacity: 10,
+            high_priority_threshold: DEFAULT_HIGH_PRIORITY_THRESHOLD,
+            slow_handler: SlowHandlerThresholds::default(),
+            max_payload_size: None,
+        }
+    }
+}
+
 #[derive(Debug)]
-struct AxonQueryResult {
-    message_identifier: String,
-    result: Option<SerializedObject>,
+pub(crate) enum AxonQueryOutput {
+    Response {
+        request_identifier: String,
+        payload: Option<SerializedObject>,
+        meta_data: HashMap<String,MetaDataValue>,
+    },
+    Complete {
+        request_identifier: String,
+        received: Instant,
+    },
+    InitialResult {
+        subscription_identifier: String,
+        request_identifier: String,
+        payload: Option<SerializedObject>,
+        meta_data: HashMap<String,MetaDataValue>,
+    },
+    Update {
+        subscription_identifier: String,
+        payload: SerializedObject,
+        meta_data: HashMap<String,MetaDataValue>,
+    },
+    UpdateComplete {
+        subscription_identifier: String,
+    },
+    /// An inbound instruction that needs no response was handled.
+    Handled {
+        received: Instant,
+    },
 }
 
-pub async fn query_processor<Q: QueryContext + Send + Sync + Clone>(
+/// Subscribes the query handlers of the registry with AxonServer, and answers incoming queries with their results.
+/// This is the query counterpart of the command worker, and it is also exported as `query_worker`.
+pub async fn query_processor<Q: QueryContext + Send + Sync + Clone + 'static>(
     axon_server_handle: AxonServerHandle,
     query_context: Q,
     query_handler_registry: TheHandlerRegistry<Q,QueryResult>
 ) -> Result<()> {
-    debug!("Query processor: start");
+    query_processor_with_config(axon_server_handle, query_context, query_handler_registry, QueryProcessorConfig::default()).await
+}
+
+pub async fn query_processor_with_config<Q: QueryContext + Send + Sync + Clone + 'static>(
+    axon_server_handle: AxonServerHandle,
+    query_context: Q,
+    query_handler_registry: TheHandlerRegistry<Q,QueryResult>,
+    config: QueryProcessorConfig
+) -> Result<()> {
+    let span = info_span!("query_processor", client_id = %axon_server_handle.display_name);
+    run_query_processor(axon_server_handle, query_context, query_handler_registry, config).instrument(span).await
+}
+
+async fn run_query_processor<Q: QueryContext + Send + Sync + Clone + 'static>(
+    axon_server_handle: AxonServerHandle,
+    mut query_context: Q,
+    mut query_handler_registry: TheHandlerRegistry<Q,QueryResult>,
+    config: QueryProcessorConfig
+) -> Result<()> {
+    debug!("Query processor: start: {:?}", config);
+    let health = axon_server_handle.health.clone();
+    health.report(WORKER_NAME, WorkerHealth::Starting);
 
-    let mut client = QueryServiceClient::new(axon_server_handle.conn);
-    let client_id = axon_server_handle.display_name.clone();
+    let provider = QueryProvider {
+        client_id: axon_server_handle.display_name.clone(),
+        component_name: axon_server_handle.component_name.clone(),
+    };
 
     let mut query_vec: Vec<String> = vec![];
     for (query_name, _) in &query_handler_registry.handlers {
         query_vec.push((*query_name).clone());
     }
-    let query_box = Box::new(query_vec);
+    health.report_subscriptions(WORKER_NAME, query_vec.clone());
+
+    loop {
+        let (context, registry) = run_query_stream(&axon_server_handle, &provider, &query_vec, query_context, query_handler_registry, &config).await?;
+        query_context = context;
+        query_handler_registry = registry;
+        info!("Query processor: reconnect on request of AxonServer");
+    }
+}
+
+// Opens a stream to AxonServer, subscribes to the queries, and passes the queries that arrive to the mailbox until the
+// stream fails or AxonServer requests a reconnect. On a reconnect request, returns the query context and the handlers
+// when the mailbox finished, so that the next stream can use them.
+async fn run_query_stream<Q: QueryContext + Send + Sync + Clone + 'static>(
+    axon_server_handle: &AxonServerHandle,
+    provider: &QueryProvider,
+    query_vec: &[String],
+    query_context: Q,
+    query_handler_registry: TheHandlerRegistry<Q,QueryResult>,
+    config: &QueryProcessorConfig
+) -> Result<(Q,TheHandlerRegistry<Q,QueryResult>)> {
+    let metrics = axon_server_handle.metrics.clone();
+    let health = axon_server_handle.health.clone();
+    let reconnect = axon_server_handle.reconnect.clone();
+    let generation = reconnect.generation();
 
-    let (mut tx, rx): (Sender<AxonQueryResult>, Receiver<AxonQueryResult>) = channel(10);
+    let mut client = axon_server_handle.query_client();
+    let query_box = Box::new(query_vec.to_vec());
 
-    let outbound = create_output_stream(client_id, query_box, rx);
+    let (tx, rx): (Sender<AxonQueryOutput>, Receiver<AxonQueryOutput>) = channel(10);
+
+    let in_flight = Arc::new(AtomicUsize::new(0));
+
+    let (mut mailbox_tx, mailbox_rx) = priority_lanes::<(QueryRequest,Instant,Option<String>)>(config.mailbox_capacity);
+    let high_priority_threshold = config.high_priority_threshold;
+
+    let max_message_size = axon_server_handle.max_message_size();
+    let outbound = create_output_stream(provider.clone(), query_box, rx, in_flight.clone(), config.clone(), metrics.clone(), max_message_size);
 
     debug!("Query processor: calling open_stream");
-    let response = client.open_stream(Request::new(outbound)).await?;
+    let response = client.open_stream(Request::new(outbound)).await
+        .map_err(|e| health.stream_failed(WORKER_NAME, e))?;
     debug!("Stream response: {:?}", response);
+    health.report(WORKER_NAME, WorkerHealth::Running);
+
+    let query_updates = axon_server_handle.query_updates.clone();
+    let mut output_tx = tx.clone();
+    let stop = Arc::new(Notify::new());
+    let mailbox = tokio::spawn(handle_mailbox(mailbox_rx, query_context, query_handler_registry, tx, config.clone(), metrics.clone(), stop.clone()).in_current_span());
 
     let mut inbound = response.into_inner();
     loop {
-        match inbound.message().await {
+        let message = tokio::select! {
+            message = inbound.message() => message,
+            _ = reconnect.requested_since(generation) => break,
+        };
+        match message {
             Ok(Some(inbound)) => {
-                debug!("Inbound message: {:?}", inbound);
-                if let Some(query_provider_inbound::Request::Query(query)) = inbound.request {
-                    let query_name = query.query.clone();
-                    let mut result = Err(anyhow!("Could not find aggregate handler"));
-                    if let Some(query_handle) = query_handler_registry.handlers.get(&query_name) {
-                        if let QueryRequest { payload: Some(serialized_object), .. } = query {
-                            result = query_handle.handle(serialized_object.data, query_context.clone()).await
+                debug!("Inbound message: {:?}", log_safe(&inbound));
+                let (query, subscription_identifier) = match inbound.request {
+                    Some(query_provider_inbound::Request::Query(query)) => (query, None),
+                    Some(query_provider_inbound::Request::SubscriptionQueryRequest(request)) => match request.request {
+                        Some(subscription_query_request::Request::GetInitialResult(SubscriptionQuery { subscription_identifier, query_request: Some(query), .. })) => {
+                            (query, Some(subscription_identifier))
                         }
-                    }
-
-                    match result.as_ref() {
-                        Err(e) => warn!("Error while handling query: {:?}", e),
-                        Ok(Some(result)) => debug!("Result from query handler: {:?}", result),
-                        Ok(None) => debug!("Result from query handler: None"),
-                    }
-
-                    let axon_query_result = AxonQueryResult {
-                        message_identifier: query.message_identifier,
-                        result: result.unwrap_or(None).map(|query_result| query_result.payload).flatten(),
-                    };
-                    tx.send(axon_query_result).await.unwrap();
-                }
+                        request => {
+                            match &request {
+                                Some(subscription_query_request::Request::Subscribe(subscription)) => query_updates.subscribe(subscription, output_tx.clone()),
+                                Some(subscription_query_request::Request::Unsubscribe(subscription)) => query_updates.unsubscribe(&subscription.subscription_identifier),
+                                _ => debug!("Query processor: ignore subscription query request: {:?}", request),
+                            }
+                            in_flight.fetch_add(1, Ordering::SeqCst);
+                            output_tx.send(AxonQueryOutput::Handled { received: Instant::now() }).await
+                                .map_err(|_| anyhow!("Query processor: output stream closed"))?;
+                            continue;
+                        }
+                    },
+                    _ => continue,
+                };
+                let lane = PriorityLane::for_priority(message_priority(&query.processing_instructions), high_priority_threshold);
+                debug!("Query processor: lane: {:?}: {:?}", lane, query.query);
+                let depth = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                metrics.set_gauge(IN_FLIGHT, depth as i64);
+                mailbox_tx.send(lane, (query, Instant::now(), subscription_identifier)).await
+                    .map_err(|_| anyhow!("Query processor: mailbox closed"))?;
             }
             Ok(None) => {
                 debug!("None incoming");
             }
             Err(e) => {
                 error!("Error from AxonServer: {:?}", e);
-                return Err(anyhow!(e.code()));
+                return Err(health.stream_failed(WORKER_NAME, e).into());
             }
         }
     }
+
+    // AxonServer no longer waits for the results of the queries that are still in the mailbox, and may have routed
+    // them to another instance, so the mailbox drops them.
+    stop.notify();
+    drop(mailbox_tx);
+    mailbox.await.map_err(|e| anyhow!("Query processor: mailbox failed: {:?}", e))
 }
 
-fn create_output_stream(client_id: String, query_box: Box<Vec<String>>, mut rx: Receiver<AxonQueryResult>) -> impl Stream<Item = QueryProviderOutbound> {
+async fn handle_mailbox<Q: QueryContext + Send + Sync + Clone>(
+    mut mailbox_rx: LaneReceivers<(QueryRequest,Instant,Option<String>)>,
+    query_context: Q,
+    query_handler_registry: TheHandlerRegistry<Q,QueryResult>,
+    mut tx: Sender<AxonQueryOutput>,
+    config: QueryProcessorConfig,
+    metrics: Metrics,
+    stop: Arc<Notify>
+) -> (Q,TheHandlerRegistry<Q,QueryResult>) {
+    loop {
+        let (query, received, subscription_identifier) = tokio::select! {
+            next = mailbox_rx.recv() => match next {
+                Some(next) => next,
+                None => break,
+            },
+            _ = stop.notified() => break,
+        };
+        let query_name = query.query.clone();
+        let responses = QueryResponseSender {
+            request_identifier: query.message_identifier.clone(),
+            subscription_identifier: subscription_identifier.clone(),
+            tx: tx.clone(),
+            sent: Arc::new(AtomicUsize::new(0)),
+        };
+        let mut result = Err(anyhow!("Could not find aggregate handler"));
+        let handler_key = query.response_type.as_ref()
+            .and_then(expected_response_type)
+            .map(|response_type| query_response_key(&query_name, &response_type))
+            .filter(|key| query_handler_registry.handlers.contains_key(key))
+            .unwrap_or_else(|| query_name.clone());
+        if let Some(query_handle) = query_handler_registry.handlers.get(&handler_key) {
+            if let QueryRequest { payload: Some(serialized_object), .. } = &query {
+                if let Err(e) = check_payload_size(serialized_object, config.max_payload_size) {
+                    result = Err(e);
+                } else {
+                    let envelope = QueryEnvelope::from_request(&query, received);
+                    let context = query_context.for_query(responses.clone()).for_query_envelope(&envelope);
+                    let started = Instant::now();
+                    let span = debug_span!("query", name = %query_name, message_identifier = %query.message_identifier);
+                    result = query_handle.handle(serialized_object.data.clone(), context).instrument(span).await;
+                    if let Some(labels) = query_handler_registry.labels(&handler_key) {
+                        labels.record(&metrics, "query", &query_name, started.elapsed(), result.is_ok());
+                    }
+                    config.slow_handler.check(WORKER_NAME, &metrics, &query_name, None, started.elapsed());
+                }
+            }
+        }
+
+        match result.as_ref() {
+            Err(e) => warn!("Error while handling query: {:?}", e),
+            Ok(Some(result)) => debug!("Result from query handler: {:?}", log_safe(result)),
+            Ok(None) => debug!("Result from query handler: None"),
+        }
+
+        let payload = result.unwrap_or(None).map(|query_result| query_result.payload).flatten();
+        if payload.is_some() || responses.sent() == 0 {
+            let request_identifier = query.message_identifier.clone();
+            let meta_data = HashMap::new();
+            let response = match subscription_identifier.clone() {
+                None => AxonQueryOutput::Response { request_identifier, payload, meta_data },
+                Some(subscription_identifier) => match payload {
+                    Some(payload) if responses.sent() > 0 => AxonQueryOutput::Update { subscription_identifier, payload, meta_data },
+                    payload => AxonQueryOutput::InitialResult { subscription_identifier, request_identifier, payload, meta_data },
+                },
+            };
+            let sent = tokio::select! {
+                result = tx.send(response) => result.is_ok(),
+                _ = stop.notified() => false,
+            };
+            if !sent {
+                debug!("Query processor: output stream closed");
+                break;
+            }
+        }
+        let complete = match subscription_identifier {
+            None => AxonQueryOutput::Complete { request_identifier: query.message_identifier, received },
+            Some(_) => AxonQueryOutput::Handled { received },
+        };
+        let sent = tokio::select! {
+            result = tx.send(complete) => result.is_ok(),
+            _ = stop.notified() => false,
+        };
+        if !sent {
+            debug!("Query processor: output stream closed");
+            break;
+        }
+    }
+    debug!("Query processor: mailbox: stop");
+    (query_context, query_handler_registry)
+}
+
+// Identifies this query processor to AxonServer in subscriptions, responses and updates.
+#[derive(Clone)]
+struct QueryProvider {
+    client_id: String,
+    component_name: String,
+}
+
+fn create_output_stream(
+    provider: QueryProvider,
+    query_box: Box<Vec<String>>,
+    mut rx: Receiver<AxonQueryOutput>,
+    in_flight: Arc<AtomicUsize>,
+    config: QueryProcessorConfig,
+    metrics: Metrics,
+    max_message_size: Option<usize>
+) -> impl Stream<Item = QueryProviderOutbound> {
     stream! {
         debug!("Query processor: stream: start: {:?}", rx);
-        for query_name in query_box.iter() {
-            debug!("Query processor: stream: subscribe to query type: {:?}", query_name);
+        for query_key in query_box.iter() {
+            let (query_name, result_name) = split_query_key(query_key);
+            debug!("Query processor: stream: subscribe to query type: {:?}: response type: {:?}", query_name, result_name);
             let subscription_id = Uuid::new_v4();
             let subscription = QuerySubscription {
                 message_id: format!("{:?}", subscription_id.to_simple()),
-                query: query_name.to_string().clone(),
-                result_name: "*".to_string(),
-                client_id: client_id.clone(),
-                component_name: client_id.clone(),
+                query: query_name.to_string(),
+                result_name: result_name.to_string(),
+                client_id: provider.client_id.clone(),
+                component_name: provider.component_name.clone(),
             };
             debug!("Subscribe query: Subscription: {:?}", subscription);
             let instruction_id = Uuid::new_v4();
@@ -112,11 +517,14 @@ fn create_output_stream(client_id: String, query_box: Box<Vec<String>>, mut rx:
             yield instruction.to_owned();
         }
 
-        let permits_batch_size: i64 = 3;
-        let mut permits = permits_batch_size * 2;
+        let mut permit_controller = PermitController::new(config.flow_control.clone());
+        let permits = permit_controller.window();
+        permit_controller.granted(permits);
+        metrics.set_gauge(PERMIT_WINDOW, permits);
+        metrics.set_gauge(PERMITS_OUTSTANDING, permit_controller.outstanding());
         debug!("Query processor: stream: send initial flow-control permits: amount: {:?}", permits);
         let flow_control = FlowControl {
-            client_id: client_id.clone(),
+            client_id: provider.client_id.clone(),
             permits,
         };
         let instruction_id = Uuid::new_v4();
@@ -126,45 +534,83 @@ fn create_output_stream(client_id: String, query_box: Box<Vec<String>>, mut rx:
         };
         yield instruction.to_owned();
 
-        while let Some(axon_query_result) = rx.recv().await {
-            debug!("Send query response: {:?}", axon_query_result);
-            let response_id = Uuid::new_v4();
-            let response = QueryResponse {
-                message_identifier: format!("{:?}", response_id.to_simple()),
-                error_code: "".to_string(),
-                error_message: None,
-                payload: axon_query_result.result.clone(),
-                meta_data: HashMap::new(),
-                processing_instructions: Vec::new(),
-                request_identifier: axon_query_result.message_identifier.clone(),
-            };
-            let instruction_id = Uuid::new_v4();
-            let instruction = QueryProviderOutbound {
-                instruction_id: format!("{:?}", instruction_id.to_simple()),
-                request: Some(query_provider_outbound::Request::QueryResponse(response)),
-            };
-            debug!("QueryResponse instruction: {:?}", instruction);
-            yield instruction.to_owned();
-
-            let complete_id = Uuid::new_v4();
-            let complete = QueryComplete {
-                message_id: format!("{:?}", complete_id.to_simple()),
-                request_id: axon_query_result.message_identifier.clone(),
-            };
-            let complete_instruction_id = Uuid::new_v4();
-            let complete_instruction = QueryProviderOutbound {
-                instruction_id: format!("{:?}", complete_instruction_id.to_simple()),
-                request: Some(query_provider_outbound::Request::QueryComplete(complete)),
+        while let Some(output) = rx.recv().await {
+            let received = match output {
+                AxonQueryOutput::Response { request_identifier, payload, meta_data } => {
+                    debug!("Send query response: {:?}: {:?}", request_identifier, log_safe(&payload));
+                    let response = query_response(request_identifier, payload, meta_data, max_message_size);
+                    let instruction_id = Uuid::new_v4();
+                    let instruction = QueryProviderOutbound {
+                        instruction_id: format!("{:?}", instruction_id.to_simple()),
+                        request: Some(query_provider_outbound::Request::QueryResponse(response)),
+                    };
+                    debug!("QueryResponse instruction: {:?}", log_safe(&instruction));
+                    yield instruction.to_owned();
+                    continue;
+                }
+                AxonQueryOutput::InitialResult { subscription_identifier, request_identifier, payload, meta_data } => {
+                    debug!("Send initial result: {:?}: {:?}", subscription_identifier, log_safe(&payload));
+                    let response = query_response(request_identifier, payload, meta_data, max_message_size);
+                    yield subscription_query_instruction(subscription_identifier, subscription_query_response::Response::InitialResult(response));
+                    continue;
+                }
+                AxonQueryOutput::Update { subscription_identifier, payload, meta_data } => {
+                    debug!("Send query update: {:?}: {:?}", subscription_identifier, log_safe(&payload));
+                    if let Err(e) = check_message_size("QueryUpdate", &payload, max_message_size) {
+                        warn!("Query processor: stream: refuse query update: {:?}", e);
+                        continue;
+                    }
+                    let update = QueryUpdate {
+                        message_identifier: format!("{:?}", Uuid::new_v4().to_simple()),
+                        payload: Some(payload),
+                        meta_data,
+                        client_id: provider.client_id.clone(),
+                        component_name: provider.component_name.clone(),
+                        error_code: "".to_string(),
+                        error_message: None,
+                    };
+                    yield subscription_query_instruction(subscription_identifier, subscription_query_response::Response::Update(update));
+                    continue;
+                }
+                AxonQueryOutput::UpdateComplete { subscription_identifier } => {
+                    debug!("Complete subscription query: {:?}", subscription_identifier);
+                    let complete = QueryUpdateComplete {
+                        client_id: provider.client_id.clone(),
+                        component_name: provider.component_name.clone(),
+                    };
+                    yield subscription_query_instruction(subscription_identifier, subscription_query_response::Response::Complete(complete));
+                    continue;
+                }
+                AxonQueryOutput::Complete { request_identifier, received } => {
+                    let complete_id = Uuid::new_v4();
+                    let complete = QueryComplete {
+                        message_id: format!("{:?}", complete_id.to_simple()),
+                        request_id: request_identifier,
+                    };
+                    let complete_instruction_id = Uuid::new_v4();
+                    let complete_instruction = QueryProviderOutbound {
+                        instruction_id: format!("{:?}", complete_instruction_id.to_simple()),
+                        request: Some(query_provider_outbound::Request::QueryComplete(complete)),
+                    };
+                    debug!("Complete instruction: {:?}", complete_instruction);
+                    yield complete_instruction.to_owned();
+                    received
+                }
+                AxonQueryOutput::Handled { received } => received,
             };
-            debug!("Complete instruction: {:?}", complete_instruction);
-            yield complete_instruction.to_owned();
 
-            permits -= 1;
-            if permits <= permits_batch_size {
-                debug!("Query processor: stream: send more flow-control permits: amount: {:?}", permits_batch_size);
+            let latency = received.elapsed();
+            let remaining = in_flight.fetch_sub(1, Ordering::SeqCst) - 1;
+            metrics.set_gauge(IN_FLIGHT, remaining as i64);
+            permit_controller.record_response(latency, remaining);
+            metrics.set_gauge(HANDLER_LATENCY_MS, latency.as_millis() as i64);
+            metrics.set_gauge(PERMIT_WINDOW, permit_controller.window());
+            let permits = permit_controller.permits_due();
+            if permits > 0 {
+                debug!("Query processor: stream: send more flow-control permits: amount: {:?}", permits);
                 let flow_control = FlowControl {
-                    client_id: client_id.clone(),
-                    permits: permits_batch_size,
+                    client_id: provider.client_id.clone(),
+                    permits,
                 };
                 let instruction_id = Uuid::new_v4();
                 let instruction = QueryProviderOutbound {
@@ -172,11 +618,51 @@ fn create_output_stream(client_id: String, query_box: Box<Vec<String>>, mut rx:
                     request: Some(query_provider_outbound::Request::FlowControl(flow_control)),
                 };
                 yield instruction.to_owned();
-                permits += permits_batch_size;
+                permit_controller.granted(permits);
             }
-            debug!("Query processor: stream: flow-control permits: balance: {:?}", permits);
+            metrics.set_gauge(PERMITS_OUTSTANDING, permit_controller.outstanding());
+            debug!("Query processor: stream: flow-control permits: balance: {:?}", permit_controller.outstanding());
         }
 
         // debug!("Query processor: stream: stop");
     }
 }
+
+fn query_response(request_identifier: String, payload: Option<SerializedObject>, meta_data: HashMap<String,MetaDataValue>, max_message_size: Option<usize>) -> QueryResponse {
+    let response_id = Uuid::new_v4();
+    let mut response = QueryResponse {
+        message_identifier: format!("{:?}", response_id.to_simple()),
+        error_code: "".to_string(),
+        error_message: None,
+        payload,
+        meta_data,
+        processing_instructions: Vec::new(),
+        request_identifier,
+    };
+    if let Some(payload) = response.payload.as_ref() {
+        if let Err(e) = check_message_size("QueryResponse", payload, max_message_size) {
+            warn!("Query processor: stream: refuse query response: {:?}", e);
+            response.payload = None;
+            response.error_code = "ERROR".to_string();
+            response.error_message = Some(ErrorMessage {
+                message: e.to_string(),
+                location: "".to_string(),
+                details: Vec::new(),
+                error_code: "ERROR".to_string(),
+            });
+        }
+    }
+    response
+}
+
+fn subscription_query_instruction(subscription_identifier: String, response: subscription_query_response::Response) -> QueryProviderOutbound {
+    let subscription_query_response = SubscriptionQueryResponse {
+        message_identifier: format!("{:?}", Uuid::new_v4().to_simple()),
+        subscription_identifier,
+        response: Some(response),
+    };
+    QueryProviderOutbound {
+        instruction_id: format!("{:?}", Uuid::new_v4().to_simple()),
+        request: Some(query_provider_outbound::Request::SubscriptionQueryResponse(subscription_query_response)),
+    }
+}
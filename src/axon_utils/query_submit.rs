@@ -1,19 +1,22 @@
 use anyhow::Result;
-use log::{debug};
+use tracing::{debug};
 use std::collections::HashMap;
 use std::vec::Vec;
 use uuid::Uuid;
-use super::{QuerySink, AxonServerHandle, VecU8Message};
+use super::{AxonClients, QuerySink, AxonServerHandle, VecU8Message};
+use super::axon_error::{AxonError,AxonResult};
+use super::message_size::{check_message_size,explain_status};
+use super::redaction::log_safe;
+use super::tenant_quota::max_message_size;
 use crate::axon_server::SerializedObject;
 use crate::axon_server::query::{QueryRequest,QueryResponse};
-use crate::axon_server::query::query_service_client::QueryServiceClient;
 
 #[tonic::async_trait]
 impl QuerySink for AxonServerHandle {
     async fn send_query<'a>(&self, query_type: &str, query: Box<&(dyn VecU8Message + Sync)>) -> Result<Vec<SerializedObject>> {
         debug!("Sending query: {:?}: {:?}", query_type, self.display_name);
         let mut buf = Vec::new();
-        query.encode_u8(&mut buf).unwrap();
+        query.encode_u8(&mut buf).map_err(|e| AxonError::Serialization(e.to_string()))?;
         let buffer_length = buf.len();
         debug!("Buffer length: {:?}", buffer_length);
         let serialized_command = SerializedObject {
@@ -21,16 +24,14 @@ impl QuerySink for AxonServerHandle {
             revision: "1".to_string(),
             data: buf,
         };
-        submit_query(self, &serialized_command).await
+        submit_query(self, &serialized_command).await.map_err(AxonError::into_anyhow)
     }
 }
 
-async fn submit_query<'a>(this: &AxonServerHandle, message: &SerializedObject) -> Result<Vec<SerializedObject>> {
-    debug!("Message: {:?}", message);
-    let this = this.clone();
-    let conn = this.conn;
-    let client_id = this.display_name;
-    let mut client = QueryServiceClient::new(conn);
+async fn submit_query<'a>(this: &AxonServerHandle, message: &SerializedObject) -> AxonResult<Vec<SerializedObject>> {
+    debug!("Message: {:?}", log_safe(message));
+    let client_id = this.display_name.clone();
+    let mut client = this.query_client();
     debug!("Query Service Client: {:?}", client);
     let uuid = Uuid::new_v4();
     let query_request = QueryRequest {
@@ -39,13 +40,14 @@ async fn submit_query<'a>(this: &AxonServerHandle, message: &SerializedObject) -
         response_type: None,
         payload: Some(message.clone()),
         client_id,
-        component_name: "RustCommandClient".to_string(),
+        component_name: this.component_name.clone(),
         meta_data: HashMap::new(),
         processing_instructions: Vec::new(),
         timestamp: 0,
     };
-    let response = client.query(query_request).await?;
-    debug!("Response: {:?}", response);
+    check_message_size("QueryRequest", &query_request, max_message_size(this.context.as_deref(), this.max_message_size()))?;
+    let response = client.query(query_request).await.map_err(|status| AxonError::from(explain_status(status)))?;
+    debug!("Response stream: {:?}: {:?}", message.r#type, response.metadata());
     let mut response = response.into_inner();
 
     let mut result = Vec::new();
@@ -54,7 +56,7 @@ async fn submit_query<'a>(this: &AxonServerHandle, message: &SerializedObject) -
 
         if let Some(QueryResponse { payload: Some(payload), ..}) = query_response {
             let payload = payload.clone();
-            debug!("Query response: payload: {:?}", payload);
+            debug!("Query response: payload: {:?}", log_safe(&payload));
             result.push(payload);
         } else {
             break;
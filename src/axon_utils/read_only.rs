@@ -0,0 +1,40 @@
+use anyhow::Result;
+use std::fmt::{Display,Formatter};
+use std::sync::atomic::{AtomicBool,Ordering};
+
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Error for a command or an append that is refused because the client is in read-only mode.
+#[derive(Debug,Clone)]
+pub struct ReadOnlyError {
+    pub operation: String,
+}
+
+impl Display for ReadOnlyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Read-only mode: refused: {}", self.operation)
+    }
+}
+
+impl std::error::Error for ReadOnlyError {}
+
+/// Switches the whole client to read-only mode, or back, e.g., during a maintenance window or on a replica for
+/// disaster recovery. In read-only mode, sending commands and appending events and snapshots fail with a
+/// `ReadOnlyError`, before anything is sent to AxonServer. Queries, query handlers and event processors keep working,
+/// except for processors that append events themselves, such as aggregate factories. Command handlers of this client
+/// still receive commands, but fail them when they try to append their events.
+pub fn set_read_only(read_only: bool) {
+    READ_ONLY.store(read_only, Ordering::SeqCst);
+}
+
+pub fn is_read_only() -> bool {
+    READ_ONLY.load(Ordering::SeqCst)
+}
+
+// Returns a `ReadOnlyError` for the operation if the client is in read-only mode.
+pub(crate) fn check_writable(operation: &str) -> Result<()> {
+    if is_read_only() {
+        return Err(ReadOnlyError { operation: operation.to_string() }.into());
+    }
+    Ok(())
+}
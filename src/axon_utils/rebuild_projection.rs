@@ -0,0 +1,65 @@
+use anyhow::{anyhow,Result};
+use tracing::info;
+use prost::Message;
+use std::collections::HashMap;
+use tonic::transport::Channel;
+use super::command_worker::{AggregateHandle,CommandOutcome};
+use super::message_size::decode_payload;
+use crate::axon_server::command::Command;
+use crate::axon_server::event::event_store_client::EventStoreClient;
+
+/// Name of the command that triggers the rebuild of a projection.
+pub const REBUILD_PROJECTION: &str = "RebuildProjection";
+
+/// Command that asks for the rebuild of the projection that is maintained by the named event processor.
+#[derive(Clone,PartialEq,Message)]
+pub struct RebuildProjection {
+    #[prost(string, tag = "1")]
+    pub processor: String,
+}
+
+/// Rebuilds a projection, e.g., by recreating its indices and resetting the tracking token of its processor.
+#[tonic::async_trait]
+pub trait ProjectionRebuild: Send + Sync {
+    async fn rebuild(&self) -> Result<()>;
+}
+
+/// Handles `RebuildProjection` commands for a set of named processors, so that rebuilds can be triggered through
+/// AxonServer. The handler is opt-in: insert it in the aggregate registry of a command worker. It does not emit events.
+pub struct RebuildProjectionHandler {
+    rebuilds: HashMap<String,Box<dyn ProjectionRebuild>>,
+}
+
+pub fn create_rebuild_projection_handler() -> RebuildProjectionHandler {
+    RebuildProjectionHandler {
+        rebuilds: HashMap::new(),
+    }
+}
+
+impl RebuildProjectionHandler {
+    pub fn with_rebuild(mut self, processor: &str, rebuild: impl ProjectionRebuild + 'static) -> Self {
+        self.rebuilds.insert(processor.to_string(), Box::new(rebuild));
+        self
+    }
+}
+
+#[tonic::async_trait]
+impl AggregateHandle for RebuildProjectionHandler {
+    fn name(&self) -> String {
+        REBUILD_PROJECTION.to_string()
+    }
+
+    async fn handle(&self, command: &Command, _client: &mut EventStoreClient<Channel>) -> Result<CommandOutcome> {
+        let payload = command.payload.as_ref().ok_or(anyhow!("No payload data for: {:?}", command.name))?;
+        let request: RebuildProjection = decode_payload(payload, None)?;
+        let rebuild = self.rebuilds.get(&request.processor)
+            .ok_or_else(|| anyhow!("No rebuild registered for processor: {:?}", request.processor))?;
+        info!("Rebuild projection: {:?}", request.processor);
+        rebuild.rebuild().await?;
+        Ok(CommandOutcome::Handled { response: None })
+    }
+
+    fn command_names(&self) -> Vec<String> {
+        vec![REBUILD_PROJECTION.to_string()]
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::{Result,anyhow};
+use tracing::{info,warn};
+use std::fmt::{Debug,Formatter};
+use std::sync::{Arc,RwLock};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::timeout;
+use tonic::transport::{Channel,Endpoint};
+
+/// Signal that tells the workers of a connection to reopen their streams, because AxonServer requested a reconnect,
+/// e.g., to move the client to another node of the cluster.
+///
+/// All clones share the same state, so all handles and connections that share a channel also share the signal. Before
+/// the workers are signalled, the channel is rebuilt from the endpoint of the connection, so that the streams that the
+/// workers reopen, and the clients that are created afterwards, use a new connection to AxonServer.
+#[derive(Clone)]
+pub struct ReconnectSignal {
+    endpoint: Option<Endpoint>,
+    connect_timeout: Option<Duration>,
+    channel: Arc<RwLock<Option<Channel>>>,
+    sender: Arc<watch::Sender<u64>>,
+    receiver: watch::Receiver<u64>,
+}
+
+/// Creates a reconnect signal that rebuilds the channel from the given endpoint. Without an endpoint, the workers
+/// reopen their streams on the channel that they already have.
+pub fn create_reconnect_signal(endpoint: Option<Endpoint>, connect_timeout: Option<Duration>) -> ReconnectSignal {
+    let (sender, receiver) = watch::channel(0);
+    ReconnectSignal {
+        endpoint,
+        connect_timeout,
+        channel: Arc::new(RwLock::new(None)),
+        sender: Arc::new(sender),
+        receiver,
+    }
+}
+
+impl Default for ReconnectSignal {
+    fn default() -> Self {
+        create_reconnect_signal(None, None)
+    }
+}
+
+impl Debug for ReconnectSignal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReconnectSignal")
+            .field("endpoint", &self.endpoint)
+            .field("generation", &self.generation())
+            .field("rebuilt", &self.channel().is_some())
+            .finish()
+    }
+}
+
+impl ReconnectSignal {
+    /// Number of reconnects that AxonServer requested so far.
+    pub fn generation(&self) -> u64 {
+        *self.receiver.borrow()
+    }
+
+    /// Returns the channel that was rebuilt on the last reconnect request, if any.
+    pub fn channel(&self) -> Option<Channel> {
+        self.channel.read().ok().and_then(|channel| channel.clone())
+    }
+
+    /// Rebuilds the channel and tells the workers to reopen their streams. When the channel cannot be rebuilt, the
+    /// workers reopen their streams on the current channel.
+    pub async fn request_reconnect(&self) {
+        if let Some(endpoint) = &self.endpoint {
+            match connect(endpoint, self.connect_timeout).await {
+                Ok(channel) => {
+                    if let Ok(mut current) = self.channel.write() {
+                        *current = Some(channel);
+                    }
+                }
+                Err(e) => warn!("Cannot rebuild the channel to AxonServer: {:?}", e),
+            }
+        }
+        let generation = self.generation() + 1;
+        info!("Reconnect requested: generation: {:?}", generation);
+        self.sender.broadcast(generation).ok();
+    }
+
+    /// Returns when a reconnect is requested after the given generation.
+    pub async fn requested_since(&self, generation: u64) {
+        let mut receiver = self.receiver.clone();
+        while *receiver.borrow() <= generation {
+            if receiver.recv().await.is_none() {
+                return;
+            }
+        }
+    }
+}
+
+async fn connect(endpoint: &Endpoint, connect_timeout: Option<Duration>) -> Result<Channel> {
+    let channel = match connect_timeout {
+        Some(connect_timeout) => timeout(connect_timeout, endpoint.connect()).await
+            .map_err(|_| anyhow!("Timeout while connecting to AxonServer"))??,
+        None => endpoint.connect().await?,
+    };
+    Ok(channel)
+}
@@ -0,0 +1,229 @@
+use anyhow::Result;
+use bytes::Bytes;
+use once_cell::sync::Lazy;
+use prost::Message;
+use std::collections::{HashMap,HashSet};
+use std::fmt::{Debug,Formatter};
+use std::sync::{Arc,RwLock};
+use super::event_transformation::EventTransformation;
+use crate::axon_server::SerializedObject;
+use crate::axon_server::command::{Command,CommandProviderInbound,CommandProviderOutbound,CommandResponse,command_provider_inbound,command_provider_outbound};
+use crate::axon_server::event::{Event,EventWithToken};
+use crate::axon_server::query::{QueryProviderInbound,QueryProviderOutbound,QueryRequest,QueryResponse,SubscriptionQuery,query_provider_inbound,query_provider_outbound,subscription_query_request};
+
+pub type FieldRedactor = Arc<dyn Fn(&[u8]) -> Result<Vec<u8>> + Send + Sync>;
+
+static REDACTION_POLICY: Lazy<RwLock<Arc<RedactionPolicy>>> = Lazy::new(|| RwLock::new(Arc::new(RedactionPolicy::default())));
+
+/// Determines which payloads are hidden from the logs of the workers, so that personal data does not leak into logs.
+///
+/// The data of a redacted payload is logged as empty, while its type, its identifiers and its meta-data remain
+/// visible. A payload type can opt out of logging entirely, or register a field redactor that blanks the sensitive
+/// fields of the decoded message before it is logged. Decoded messages (e.g., the events emitted by a command handler)
+/// are logged by type only when their type is redacted in any way. By default, nothing is redacted.
+#[derive(Clone,Default)]
+pub struct RedactionPolicy {
+    pub redact_all: bool,
+    pub redacted_types: HashSet<String>,
+    pub field_redactors: HashMap<String,FieldRedactor>,
+}
+
+impl Debug for RedactionPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedactionPolicy")
+            .field("redact_all", &self.redact_all)
+            .field("redacted_types", &self.redacted_types)
+            .field("field_redactors", &self.field_redactors.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl RedactionPolicy {
+    pub fn with_redact_all(mut self) -> Self {
+        self.redact_all = true;
+        self
+    }
+
+    pub fn with_redacted_type(mut self, type_name: &str) -> Self {
+        self.redacted_types.insert(type_name.to_string());
+        self
+    }
+
+    /// Blanks fields of payloads of the given type before they are logged, e.g.,
+    /// `with_field_redactor("GreetCommand", |command: &mut GreetCommand| command.message = None)`.
+    pub fn with_field_redactor<T: Message + Default>(mut self, type_name: &str, redact: impl Fn(&mut T) + Send + Sync + 'static) -> Self {
+        let redactor: FieldRedactor = Arc::new(move |data| {
+            let mut message = T::decode(Bytes::copy_from_slice(data))?;
+            redact(&mut message);
+            let mut buf = Vec::new();
+            message.encode(&mut buf)?;
+            Ok(buf)
+        });
+        self.field_redactors.insert(type_name.to_string(), redactor);
+        self
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.redact_all || !self.redacted_types.is_empty() || !self.field_redactors.is_empty()
+    }
+
+    pub fn is_redacted(&self, type_name: &str) -> bool {
+        self.redact_all || self.redacted_types.contains(type_name) || self.field_redactors.contains_key(type_name)
+    }
+
+    fn redact_payload(&self, payload: &mut SerializedObject) {
+        if self.redact_all || self.redacted_types.contains(&payload.r#type) {
+            payload.data.clear();
+        } else if let Some(redactor) = self.field_redactors.get(&payload.r#type) {
+            // A payload that cannot be decoded is hidden entirely.
+            payload.data = redactor(&payload.data).unwrap_or_default();
+        }
+    }
+}
+
+/// Replaces the redaction policy for all workers.
+pub fn set_redaction_policy(policy: RedactionPolicy) {
+    if let Ok(mut current) = REDACTION_POLICY.write() {
+        *current = Arc::new(policy);
+    }
+}
+
+pub fn redaction_policy() -> Arc<RedactionPolicy> {
+    REDACTION_POLICY.read().map(|policy| policy.clone()).unwrap_or_default()
+}
+
+/// Messages that carry payloads that may have to be redacted.
+pub trait Redact {
+    fn redact(&mut self, policy: &RedactionPolicy);
+}
+
+/// Wraps a message for logging. The message is only copied and redacted when the log line is actually written.
+pub struct LogSafe<'a, T>(&'a T);
+
+pub fn log_safe<T: Redact + Clone + Debug>(message: &T) -> LogSafe<'_, T> {
+    LogSafe(message)
+}
+
+impl<'a, T: Redact + Clone + Debug> Debug for LogSafe<'a, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let policy = redaction_policy();
+        if !policy.is_active() {
+            return self.0.fmt(f);
+        }
+        let mut message = self.0.clone();
+        message.redact(&policy);
+        message.fmt(f)
+    }
+}
+
+impl Redact for SerializedObject {
+    fn redact(&mut self, policy: &RedactionPolicy) {
+        policy.redact_payload(self);
+    }
+}
+
+impl<T: Redact> Redact for Option<T> {
+    fn redact(&mut self, policy: &RedactionPolicy) {
+        if let Some(message) = self {
+            message.redact(policy);
+        }
+    }
+}
+
+impl<T: Redact> Redact for Vec<T> {
+    fn redact(&mut self, policy: &RedactionPolicy) {
+        for message in self.iter_mut() {
+            message.redact(policy);
+        }
+    }
+}
+
+impl Redact for Command {
+    fn redact(&mut self, policy: &RedactionPolicy) {
+        self.payload.redact(policy);
+    }
+}
+
+impl Redact for CommandResponse {
+    fn redact(&mut self, policy: &RedactionPolicy) {
+        self.payload.redact(policy);
+    }
+}
+
+impl Redact for CommandProviderInbound {
+    fn redact(&mut self, policy: &RedactionPolicy) {
+        if let Some(command_provider_inbound::Request::Command(command)) = &mut self.request {
+            command.redact(policy);
+        }
+    }
+}
+
+impl Redact for CommandProviderOutbound {
+    fn redact(&mut self, policy: &RedactionPolicy) {
+        if let Some(command_provider_outbound::Request::CommandResponse(response)) = &mut self.request {
+            response.redact(policy);
+        }
+    }
+}
+
+impl Redact for Event {
+    fn redact(&mut self, policy: &RedactionPolicy) {
+        self.payload.redact(policy);
+    }
+}
+
+impl Redact for EventWithToken {
+    fn redact(&mut self, policy: &RedactionPolicy) {
+        self.event.redact(policy);
+    }
+}
+
+impl Redact for QueryRequest {
+    fn redact(&mut self, policy: &RedactionPolicy) {
+        self.payload.redact(policy);
+    }
+}
+
+impl Redact for QueryResponse {
+    fn redact(&mut self, policy: &RedactionPolicy) {
+        self.payload.redact(policy);
+    }
+}
+
+impl Redact for SubscriptionQuery {
+    fn redact(&mut self, policy: &RedactionPolicy) {
+        self.query_request.redact(policy);
+    }
+}
+
+impl Redact for QueryProviderInbound {
+    fn redact(&mut self, policy: &RedactionPolicy) {
+        match &mut self.request {
+            Some(query_provider_inbound::Request::Query(query)) => query.redact(policy),
+            Some(query_provider_inbound::Request::SubscriptionQueryRequest(request)) => match &mut request.request {
+                Some(subscription_query_request::Request::Subscribe(query))
+                | Some(subscription_query_request::Request::Unsubscribe(query))
+                | Some(subscription_query_request::Request::GetInitialResult(query))
+                | Some(subscription_query_request::Request::FlowControl(query)) => query.redact(policy),
+                None => (),
+            },
+            _ => (),
+        }
+    }
+}
+
+impl Redact for QueryProviderOutbound {
+    fn redact(&mut self, policy: &RedactionPolicy) {
+        if let Some(query_provider_outbound::Request::QueryResponse(response)) = &mut self.request {
+            response.redact(policy);
+        }
+    }
+}
+
+impl Redact for EventTransformation {
+    fn redact(&mut self, policy: &RedactionPolicy) {
+        if let EventTransformation::Replace(payload) = self {
+            payload.redact(policy);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use anyhow::Result;
+use bytes::Bytes;
+use tracing::{debug,info};
+use prost::Message;
+use prost_types::{DescriptorProto,FileDescriptorSet};
+use std::collections::{BTreeMap,HashSet};
+use std::fmt::{Display,Formatter};
+use super::command_worker::TheAggregateRegistry;
+use super::handler_registry::TheHandlerRegistry;
+use super::query_processor::split_query_key;
+
+/// Names of the protobuf messages in a descriptor set, both as simple names (`GreetCommand`) and as full names
+/// (`grpc_example.GreetCommand`). Nested messages are included under their dotted names (`Outer.Inner`).
+///
+/// Generate the descriptor set in the build script, e.g., with `protoc --include_imports -o <file>`, and include it
+/// with `include_bytes!`.
+#[derive(Debug,Clone,Default)]
+pub struct ProtoDescriptors {
+    names: HashSet<String>,
+}
+
+pub fn load_proto_descriptors(descriptor_set: &[u8]) -> Result<ProtoDescriptors> {
+    let descriptor_set = FileDescriptorSet::decode(Bytes::from(descriptor_set.to_vec()))?;
+    let mut descriptors = ProtoDescriptors::default();
+    for file in &descriptor_set.file {
+        for message in &file.message_type {
+            descriptors.add_message(file.package(), "", message);
+        }
+    }
+    debug!("Loaded proto descriptors: {:?}", descriptors.names.len());
+    Ok(descriptors)
+}
+
+impl ProtoDescriptors {
+    /// Adds the messages of another descriptor set.
+    pub fn with_descriptor_set(mut self, descriptor_set: &[u8]) -> Result<Self> {
+        self.names.extend(load_proto_descriptors(descriptor_set)?.names);
+        Ok(self)
+    }
+
+    /// Adds a message that is only defined in Rust, e.g., `RebuildProjection`.
+    pub fn with_message(mut self, name: &str) -> Self {
+        self.names.insert(name.to_string());
+        self
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.contains(name)
+    }
+
+    fn add_message(&mut self, package: &str, parent: &str, message: &DescriptorProto) {
+        let name = if parent.is_empty() {
+            message.name().to_string()
+        } else {
+            format!("{}.{}", parent, message.name())
+        };
+        if !package.is_empty() {
+            self.names.insert(format!("{}.{}", package, name));
+        }
+        for nested in &message.nested_type {
+            self.add_message(package, &name, nested);
+        }
+        self.names.insert(name);
+    }
+}
+
+/// Checks at startup that the registries of an application fit together: that every command, event, and query that
+/// has a handler is a known protobuf message, and that no command is handled by more than one aggregate. Without these
+/// checks, a typo in a type name only shows up when a message fails to decode, or never arrives at its handler.
+pub struct RegistryValidation<'a> {
+    descriptors: &'a ProtoDescriptors,
+    problems: Vec<String>,
+    command_owners: BTreeMap<String,Vec<String>>,
+}
+
+pub fn create_registry_validation(descriptors: &ProtoDescriptors) -> RegistryValidation<'_> {
+    RegistryValidation {
+        descriptors,
+        problems: Vec::new(),
+        command_owners: BTreeMap::new(),
+    }
+}
+
+impl<'a> RegistryValidation<'a> {
+    /// Checks the commands and the sourced events of the aggregates. Pass the registries of all command workers of the
+    /// application, so that commands that are handled by aggregates in different workers are found too.
+    pub fn with_aggregates(mut self, aggregate_registry: &TheAggregateRegistry) -> Self {
+        for (aggregate_name, aggregate_handle) in &aggregate_registry.handlers {
+            for command_name in aggregate_handle.command_names() {
+                self.check_type("Command", &command_name, aggregate_name);
+                self.command_owners.entry(command_name).or_default().push(aggregate_name.clone());
+            }
+            for event_name in aggregate_handle.event_names() {
+                self.check_type("Sourced event", &event_name, aggregate_name);
+            }
+        }
+        self
+    }
+
+    pub fn with_event_handlers<P: Send, W: Clone>(mut self, processor_name: &str, handler_registry: &TheHandlerRegistry<P,W>) -> Self {
+        for event_name in handler_registry.handlers.keys() {
+            self.check_type("Event", event_name, processor_name);
+        }
+        self
+    }
+
+    /// Checks the queries and, for handlers that were registered with `query_response_key`, the response types.
+    pub fn with_query_handlers<P: Send, W: Clone>(mut self, processor_name: &str, handler_registry: &TheHandlerRegistry<P,W>) -> Self {
+        for key in handler_registry.handlers.keys() {
+            let (query_name, response_type) = split_query_key(key);
+            self.check_type("Query", query_name, processor_name);
+            if response_type != "*" {
+                self.check_type("Query response", response_type, processor_name);
+            }
+        }
+        self
+    }
+
+    /// Returns a `RegistryValidationError` that lists all problems, if there are any.
+    pub fn validate(self) -> Result<()> {
+        let mut problems = self.problems;
+        for (command_name, aggregate_names) in &self.command_owners {
+            if aggregate_names.len() > 1 {
+                problems.push(format!("Command {:?} is handled by more than one aggregate: {:?}: keep one handler per command", command_name, aggregate_names));
+            }
+        }
+        if !problems.is_empty() {
+            return Err(RegistryValidationError { problems }.into());
+        }
+        info!("Registries are valid");
+        Ok(())
+    }
+
+    fn check_type(&mut self, kind: &str, type_name: &str, owner: &str) {
+        if !self.descriptors.contains(type_name) {
+            self.problems.push(format!(
+                "{} {:?} of {:?} is not a known protobuf message: check the spelling, or add the message to the descriptors",
+                kind, type_name, owner
+            ));
+        }
+    }
+}
+
+/// Error that lists the problems that were found in the registries of an application.
+#[derive(Debug,Clone)]
+pub struct RegistryValidationError {
+    pub problems: Vec<String>,
+}
+
+impl Display for RegistryValidationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid registries: {}", self.problems.join("; "))
+    }
+}
+
+impl std::error::Error for RegistryValidationError {}
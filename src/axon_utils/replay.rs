@@ -0,0 +1,97 @@
+use anyhow::Result;
+use tracing::info;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tonic::transport::Channel;
+use super::{AxonClients,AxonServerHandle};
+use super::event_processor::TokenStore;
+use super::event_stream::{first_token,last_token,token_at};
+use crate::axon_server::event::event_store_client::EventStoreClient;
+
+/// Position in the event store from which an event processor handles events.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum TokenPosition {
+    /// From the first event in the event store: replays all events.
+    Tail,
+    /// After the last event in the event store: only handles new events.
+    Head,
+    /// From the event with this token.
+    Token(i64),
+    /// From the first event at or after this time (in milliseconds since the epoch), or from the head if there is none.
+    Timestamp(i64),
+}
+
+/// Returns the tracking token that makes an event processor start at the given position: the token of the event
+/// before it.
+pub async fn position_token(client: &mut EventStoreClient<Channel>, position: TokenPosition) -> Result<i64> {
+    let token = match position {
+        TokenPosition::Tail => first_token(client).await? - 1,
+        TokenPosition::Head => last_token(client).await?,
+        TokenPosition::Token(token) => token - 1,
+        TokenPosition::Timestamp(timestamp) => match token_at(client, timestamp).await? {
+            token if token >= 0 => token - 1,
+            _ => last_token(client).await?,
+        },
+    };
+    Ok(token.max(-1))
+}
+
+/// Resets the tracking token in the token store to the given position, e.g., in a maintenance script while the event
+/// processor is stopped. Returns the token that was stored. Use a `ReplaySignal` to reset a running processor.
+pub async fn reset_tracking_token<T: TokenStore + Sync>(axon_server_handle: &AxonServerHandle, token_store: &T, position: TokenPosition) -> Result<i64> {
+    let mut client = axon_server_handle.event_store_client();
+    let token = position_token(&mut client, position).await?;
+    info!("Reset tracking token: {:?}: {:?}", position, token);
+    token_store.reset_token(token).await?;
+    Ok(token)
+}
+
+/// Signal that tells running event processors to reset their tracking token to a position, and to handle the events
+/// from there, e.g., to replay all events into a projection that was cleared.
+///
+/// All clones share the same state. Every processor that was configured with a clone of the signal (every segment of
+/// a segmented processor) resets its own token when the signal is triggered.
+#[derive(Debug,Clone)]
+pub struct ReplaySignal {
+    sender: Arc<watch::Sender<Option<TokenPosition>>>,
+    receiver: watch::Receiver<Option<TokenPosition>>,
+}
+
+pub fn create_replay_signal() -> ReplaySignal {
+    let (sender, receiver) = watch::channel(None);
+    ReplaySignal {
+        sender: Arc::new(sender),
+        receiver,
+    }
+}
+
+impl Default for ReplaySignal {
+    fn default() -> Self {
+        create_replay_signal()
+    }
+}
+
+impl ReplaySignal {
+    pub fn replay(&self, position: TokenPosition) {
+        info!("Replay requested: {:?}", position);
+        self.sender.broadcast(Some(position)).ok();
+    }
+
+    /// Returns a receiver that only sees positions that are requested after this call.
+    pub(crate) async fn subscribe(&self) -> watch::Receiver<Option<TokenPosition>> {
+        let mut receiver = self.receiver.clone();
+        receiver.recv().await;
+        receiver
+    }
+}
+
+/// Returns the next position that is requested through the receiver. Never returns when the signal is dropped.
+pub(crate) async fn requested_position(receiver: &mut watch::Receiver<Option<TokenPosition>>) -> TokenPosition {
+    loop {
+        match receiver.recv().await {
+            Some(Some(position)) => return position,
+            Some(None) => (),
+            None => futures_util::future::pending::<()>().await,
+        }
+    }
+}
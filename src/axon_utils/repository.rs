@@ -0,0 +1,86 @@
+use anyhow::Result;
+use tracing::debug;
+use std::collections::HashMap;
+use tonic::transport::Channel;
+use super::{AxonClients,AxonServerHandle,VecU8Message};
+use super::aggregate_migration::read_highest_sequence_nr;
+use super::command_worker::{AggregateContext,AggregateDefinition,EmitApplicableEventsAndResponse,SourcingPosition};
+use super::meta_data_stamping::stamp_meta_data;
+use super::tenant_quota::TenantScope;
+use crate::axon_server::{MetaDataValue,SerializedObject};
+use crate::axon_server::event::event_store_client::EventStoreClient;
+
+/// Loads and changes aggregates outside the command worker, e.g., in migration scripts.
+///
+/// Aggregates are sourced and stored in the same way as in the command worker: from the latest snapshot if snapshots
+/// are enabled, with payloads resolved by the claim check, and with new snapshots when the threshold is reached. The
+/// event store of the aggregate definition is used if it has one.
+pub struct Repository<P: VecU8Message + Send + Clone + 'static> {
+    aggregate_definition: AggregateDefinition<P>,
+    client: EventStoreClient<Channel>,
+    tenant: TenantScope,
+}
+
+pub fn create_repository<P: VecU8Message + AggregateContext + Send + Clone + std::fmt::Debug + 'static>(
+    axon_server_handle: &AxonServerHandle,
+    aggregate_definition: AggregateDefinition<P>
+) -> Repository<P> {
+    let client = aggregate_definition.event_store_client().cloned().unwrap_or_else(|| axon_server_handle.event_store_client());
+    Repository {
+        aggregate_definition,
+        client,
+        tenant: TenantScope {
+            context: axon_server_handle.context.clone(),
+            metrics: axon_server_handle.metrics.clone(),
+        },
+    }
+}
+
+impl<P: VecU8Message + AggregateContext + Send + Clone + std::fmt::Debug + 'static> Repository<P> {
+    /// Returns the projection of the aggregate, or `None` if it has no events.
+    pub async fn load(&self, aggregate_id: &str) -> Result<Option<P>> {
+        let mut client = self.client.clone();
+        if read_highest_sequence_nr(&mut client, aggregate_id).await? < 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.load_or_default(aggregate_id).await?))
+    }
+
+    /// Returns the projection of the aggregate, or an empty projection if it has no events.
+    pub async fn load_or_default(&self, aggregate_id: &str) -> Result<P> {
+        let mut client = self.client.clone();
+        let (restored, events, _) = self.aggregate_definition.load_events(&mut client, aggregate_id, true).await?;
+        self.aggregate_definition.replay(restored, events).await
+    }
+
+    /// Sources the aggregate, passes its projection to the handler, and stores the events that the handler emits.
+    /// Returns the response of the handler. The events are appended after the events that the projection was sourced
+    /// from, so the call fails when the aggregate changed in the meantime. The events are stamped according to the
+    /// stamping policy.
+    pub async fn execute<F>(&self, aggregate_id: &str, handler: F) -> Result<Option<SerializedObject>>
+    where F: FnOnce(&P) -> Result<EmitApplicableEventsAndResponse<P>>
+    {
+        self.execute_with_meta_data(aggregate_id, HashMap::new(), handler).await
+    }
+
+    /// Like `execute`, but adds the given meta-data to the events, e.g., the `follow_up_meta_data` of an
+    /// `EventCorrelation`, so that the events are part of the same workflow.
+    pub async fn execute_with_meta_data<F>(&self, aggregate_id: &str, meta_data: HashMap<String,MetaDataValue>, handler: F) -> Result<Option<SerializedObject>>
+    where F: FnOnce(&P) -> Result<EmitApplicableEventsAndResponse<P>>
+    {
+        let mut client = self.client.clone();
+        let (restored, events, last_sequence_nr) = self.aggregate_definition.load_events(&mut client, aggregate_id, true).await?;
+        let position = SourcingPosition {
+            events_since_snapshot: events.len(),
+            last_sequence_nr,
+        };
+        let projection = self.aggregate_definition.replay(restored, events).await?;
+        let result = handler(&projection)?;
+        debug!("Repository: execute: {:?}: events: {:?}", aggregate_id, result.events.len());
+        if !result.events.is_empty() {
+            self.tenant.check_event_quota(result.events.len())?;
+            self.aggregate_definition.store_result(&mut client, aggregate_id, position, projection, &result, stamp_meta_data(None, meta_data)).await?;
+        }
+        Ok(result.response)
+    }
+}
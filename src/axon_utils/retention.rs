@@ -0,0 +1,104 @@
+use anyhow::Result;
+use tracing::{debug,info};
+use prost::Message;
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+use tonic::transport::Channel;
+use super::{AxonClients,AxonServerHandle,TokenStore};
+use super::event_stream::{EventStreamReader,last_token,token_at};
+use crate::axon_server::event::EventWithToken;
+use crate::axon_server::event::event_store_client::EventStoreClient;
+use crate::object_storage_utils::ObjectSink;
+
+/// Archives the events of one aggregate type once they are older than `max_age`.
+///
+/// Archived events are written to the object sink in batches of (at most) `batch_size` events, as length-delimited
+/// protobuf `Event` messages, under `<key_prefix>/<first token>-<last token>.pb`.
+#[derive(Debug,Clone)]
+pub struct RetentionPolicy {
+    pub aggregate_type: String,
+    pub max_age: Duration,
+    pub key_prefix: String,
+    pub batch_size: usize,
+}
+
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct RetentionReport {
+    pub events_scanned: usize,
+    pub events_archived: usize,
+    pub objects_written: usize,
+    pub checkpoint: Option<i64>,
+}
+
+pub fn create_retention_policy(aggregate_type: &str, max_age: Duration) -> RetentionPolicy {
+    RetentionPolicy {
+        aggregate_type: aggregate_type.to_string(),
+        max_age,
+        key_prefix: format!("archive/{}", aggregate_type),
+        batch_size: 1000,
+    }
+}
+
+/// Archives the events that became older than the policy allows since the previous run.
+///
+/// The global event stream is read from the checkpoint in the token store up to the first event that is younger than
+/// `max_age`. The checkpoint is only advanced after a batch was written, so an interrupted run archives some events
+/// again on the next run. Run one instance per policy, each with its own token store.
+///
+/// The event store API does not offer a way to delete events or snapshots, so pruning has to be done on AxonServer
+/// itself, e.g., by copying the remaining events with `copy_transform_events`.
+pub async fn run_retention<T: TokenStore>(axon_server_handle: &AxonServerHandle, policy: &RetentionPolicy, sink: &dyn ObjectSink, checkpoint_store: &T) -> Result<RetentionReport> {
+    let mut client = axon_server_handle.event_store_client();
+    let from_token = checkpoint_store.retrieve_token().await?.unwrap_or(-1) + 1;
+    let cutoff = SystemTime::now().checked_sub(policy.max_age).unwrap_or(UNIX_EPOCH);
+    let cutoff = cutoff.duration_since(UNIX_EPOCH)?.as_millis() as i64;
+    let end_token = retention_end_token(&mut client, cutoff).await?;
+    info!("Retention: {:?}: from: {:?}: to: {:?}: cutoff: {:?}", policy.aggregate_type, from_token, end_token, cutoff);
+
+    let mut report = RetentionReport::default();
+    if end_token <= from_token {
+        return Ok(report);
+    }
+
+    let mut events = EventStreamReader::open(&mut client, &axon_server_handle.display_name, &axon_server_handle.component_name, "Retention", from_token, policy.batch_size).await?;
+    let mut buffer = Vec::new();
+    let mut batch_count = 0;
+    let mut batch_start = from_token;
+    while let Some(EventWithToken { event, token, .. }) = events.next().await? {
+        report.events_scanned += 1;
+        if let Some(event) = event {
+            if event.aggregate_type == policy.aggregate_type && event.timestamp < cutoff {
+                event.encode_length_delimited(&mut buffer)?;
+                batch_count += 1;
+            }
+        }
+        let last = token + 1 >= end_token;
+        if batch_count >= policy.batch_size || last {
+            if batch_count > 0 {
+                let key = format!("{}/{:020}-{:020}.pb", policy.key_prefix, batch_start, token);
+                debug!("Retention: archive batch: {:?}: events: {:?}", key, batch_count);
+                sink.put_object(&key, "application/x-protobuf", std::mem::take(&mut buffer)).await?;
+                report.events_archived += batch_count;
+                report.objects_written += 1;
+                batch_count = 0;
+            }
+            checkpoint_store.store_token(token).await?;
+            report.checkpoint = Some(token);
+            batch_start = token + 1;
+        }
+        if last {
+            break;
+        }
+    }
+
+    info!("Retention: {:?}: {:?}", policy.aggregate_type, report);
+    Ok(report)
+}
+
+// Returns the token of the first event that is younger than the cutoff, i.e., the first token that is not archived.
+async fn retention_end_token(client: &mut EventStoreClient<Channel>, cutoff: i64) -> Result<i64> {
+    let token = token_at(client, cutoff).await?;
+    if token >= 0 {
+        return Ok(token);
+    }
+    Ok(last_token(client).await? + 1)
+}
@@ -0,0 +1,126 @@
+use anyhow::Error;
+use std::fmt::{Debug,Formatter};
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+use tonic::Status;
+use super::axon_error::AxonError;
+use super::command_worker::BUSY_ERROR_CODE;
+use super::error_classification::{AxonStreamError,ErrorClass,classify_status};
+use super::tenant_quota::{QuotaExceededError,QuotaKind};
+
+/// Decides whether an error of an event handler is worth another attempt.
+pub type RetryablePredicate = Arc<dyn Fn(&Error) -> bool + Send + Sync>;
+
+/// How an event processor tries again when a handler fails, so that a projection survives a hiccup of Elastic Search
+/// or the network.
+///
+/// A handler is called at most `max_attempts` times for an event. After the n-th failed attempt, the processor waits
+/// `backoff_base * 2^(n-1)`, at most `max_backoff`, shortened by a random fraction of at most `jitter`, so that
+/// instances that failed at the same time do not retry in lockstep. Only errors for which `retryable` returns true are
+/// tried again; by default those are the errors that `is_transient_error` recognizes. When the attempts are exhausted,
+/// the policy of the handler group applies. The default policy makes a single attempt.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub backoff_base: Duration,
+    pub max_backoff: Duration,
+    pub jitter: f64,
+    pub retryable: RetryablePredicate,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        create_retry_policy(1, Duration::from_millis(100))
+    }
+}
+
+impl Debug for RetryPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("backoff_base", &self.backoff_base)
+            .field("max_backoff", &self.max_backoff)
+            .field("jitter", &self.jitter)
+            .finish()
+    }
+}
+
+pub fn create_retry_policy(max_attempts: u32, backoff_base: Duration) -> RetryPolicy {
+    RetryPolicy {
+        max_attempts,
+        backoff_base,
+        max_backoff: Duration::from_secs(30),
+        jitter: 0.2,
+        retryable: Arc::new(is_transient_error),
+    }
+}
+
+impl RetryPolicy {
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Sets the largest fraction by which a backoff is shortened at random, between 0 and 1.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_retryable<F: Fn(&Error) -> bool + Send + Sync + 'static>(mut self, retryable: F) -> Self {
+        self.retryable = Arc::new(retryable);
+        self
+    }
+
+    /// Returns the delay before the next attempt of a handler that failed with the given error on the given attempt,
+    /// or `None` if it should not be tried again. Attempts are counted from 1.
+    pub fn next_delay(&self, error: &Error, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_attempts || !(self.retryable)(error) {
+            return None;
+        }
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let backoff = self.backoff_base.checked_mul(factor).unwrap_or(self.max_backoff).min(self.max_backoff);
+        Some(backoff.mul_f64(1.0 - self.jitter * random_fraction()))
+    }
+}
+
+// Jitter only needs to differ between instances and attempts, not to be unpredictable.
+fn random_fraction() -> f64 {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.subsec_nanos()).unwrap_or(0);
+    f64::from(nanos % 1_000_000) / 1_000_000.0
+}
+
+/// Recognizes errors that are likely to go away by themselves: retryable gRPC statuses, connection failures, commands
+/// that were rejected because the mailbox of the command worker was full, and exceeded command rate quotas. Use `is_transient_es_error` for errors of Elastic Search. A `HandlerTimeoutError` is
+/// not transient, because the abandoned handler may have done part of its work (see `HandlerTimeouts`).
+pub fn is_transient_error(error: &Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(status) = cause.downcast_ref::<Status>() {
+            return classify_status(status) == ErrorClass::Retryable;
+        }
+        if let Some(stream_error) = cause.downcast_ref::<AxonStreamError>() {
+            return stream_error.error_class == ErrorClass::Retryable;
+        }
+        if let Some(axon_error) = cause.downcast_ref::<AxonError>() {
+            return match axon_error {
+                AxonError::Connection(_) => true,
+                AxonError::Status(status) => classify_status(status) == ErrorClass::Retryable,
+                AxonError::ErrorResponse { error_code, .. } => error_code == BUSY_ERROR_CODE,
+                AxonError::Other(error) => is_transient_error(error),
+                _ => false,
+            };
+        }
+        if let Some(io_error) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(
+                io_error.kind(),
+                ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+                    | ErrorKind::NotConnected | ErrorKind::BrokenPipe | ErrorKind::TimedOut | ErrorKind::Interrupted
+            );
+        }
+        if let Some(quota_exceeded) = cause.downcast_ref::<QuotaExceededError>() {
+            return quota_exceeded.quota == QuotaKind::CommandsPerSecond;
+        }
+        false
+    })
+}
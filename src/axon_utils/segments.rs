@@ -0,0 +1,172 @@
+use anyhow::{anyhow,Result};
+use futures_util::future::try_join_all;
+use tracing::{Instrument,debug,info,info_span,warn};
+use std::time::Duration;
+use super::{AxonClients,AxonServerHandle};
+use super::diagnostics::processor_token_gauge;
+use super::event_processor::{EventProcessorConfig,TokenStore,run_event_processor};
+use super::event_stream::last_token;
+use super::handler_group::HandlerGroup;
+use crate::axon_server::event::Event;
+
+/// Part of the events of a segmented event processor: the events of the aggregates whose identifier hashes to
+/// `segment_id` modulo `segment_count`. All events of an aggregate belong to the same segment, so they are handled in
+/// order.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Segment {
+    pub segment_id: u32,
+    pub segment_count: u32,
+}
+
+impl Segment {
+    pub fn matches(&self, event: &Event) -> bool {
+        let key = if event.aggregate_identifier.is_empty() {
+            &event.message_identifier
+        } else {
+            &event.aggregate_identifier
+        };
+        stable_hash(key) % (self.segment_count.max(1) as u64) == self.segment_id as u64
+    }
+
+    /// Returns the name under which this segment of the named processor keeps its token and reports its progress.
+    pub fn key(&self, name: &str) -> String {
+        format!("{}#{}", name, self.segment_id)
+    }
+}
+
+// FNV-1a, so that all instances agree on the segment of an aggregate.
+fn stable_hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Settings for a segmented event processor.
+///
+/// The processor tries to claim each of the `segment_count` segments through the token store, and runs the segments
+/// that it claimed concurrently. It tries again to claim the other segments every `claim_interval`, so that segments
+/// of an instance that stopped are taken over. It also renews the claims that it holds every `claim_interval`, as long
+/// as the segment handled events since the last renewal or has caught up with the head of the event store, so that the
+/// segment of a processor that is stuck is taken over when its claim expires. Each segment keeps its own token, under the token key of the tracking
+/// configuration followed by `#<segment_id>`. The number of segments must not change while tokens are kept.
+#[derive(Debug,Clone)]
+pub struct SegmentedProcessorConfig {
+    pub segment_count: u32,
+    pub claim_interval: Duration,
+}
+
+impl Default for SegmentedProcessorConfig {
+    fn default() -> Self {
+        SegmentedProcessorConfig {
+            segment_count: 1,
+            claim_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+impl SegmentedProcessorConfig {
+    pub fn with_segment_count(mut self, segment_count: u32) -> Self {
+        self.segment_count = segment_count;
+        self
+    }
+
+    pub fn with_claim_interval(mut self, claim_interval: Duration) -> Self {
+        self.claim_interval = claim_interval;
+        self
+    }
+}
+
+/// Runs an event processor that is divided in segments, so that a projection can scale horizontally across instances.
+/// It returns when one of the claimed segments fails, or loses its claim. Claims of segments that were interrupted are
+/// not released, so token stores should let claims expire.
+pub async fn segmented_event_processor<Q: TokenStore + Send + Sync + Clone>(
+    axon_server_handle: AxonServerHandle,
+    query_model: Q,
+    handler_groups: Vec<HandlerGroup<Q>>,
+    config: EventProcessorConfig,
+    segments: SegmentedProcessorConfig
+) -> Result<()> {
+    if segments.segment_count == 0 {
+        return Err(anyhow!("Segmented event processor without segments: {:?}", config.tracking.processor_name));
+    }
+    let mut tracking = config.tracking.clone();
+    if tracking.owner.is_empty() {
+        tracking.owner = axon_server_handle.display_name.clone();
+    }
+    let runners = (0..segments.segment_count).map(|segment_id| {
+        let segment = Segment {
+            segment_id,
+            segment_count: segments.segment_count,
+        };
+        let mut segment_config = config.clone();
+        segment_config.tracking = tracking.clone();
+        segment_config.segment = Some(segment);
+        let span = info_span!("segment", processor = %config.tracking.processor_name, segment_id);
+        run_segment(axon_server_handle.clone(), query_model.clone(), &handler_groups, segment_config, segments.claim_interval).instrument(span)
+    });
+    try_join_all(runners).await?;
+    Ok(())
+}
+
+async fn run_segment<Q: TokenStore + Send + Sync + Clone>(
+    axon_server_handle: AxonServerHandle,
+    query_model: Q,
+    handler_groups: &[HandlerGroup<Q>],
+    config: EventProcessorConfig,
+    claim_interval: Duration
+) -> Result<()> {
+    let segment = config.segment.ok_or_else(|| anyhow!("Missing segment"))?;
+    let mut segment_tracking = config.tracking.clone();
+    segment_tracking.token_key = segment.key(&segment_tracking.token_key);
+    let token_store = query_model.for_tracking(&segment_tracking);
+    let owner = config.tracking.owner.clone();
+    let segment_name = segment.key(&config.tracking.processor_name);
+    loop {
+        match token_store.claim_segment(&owner).await {
+            Ok(true) => break,
+            Ok(false) => debug!("Segment claimed by another instance: {:?}", segment_name),
+            Err(e) => warn!("Cannot claim segment: {:?}: {:?}", segment_name, e),
+        }
+        tokio::time::delay_for(claim_interval).await;
+    }
+    info!("Claimed segment: {:?}: owner: {:?}", segment_name, owner);
+    let metrics = axon_server_handle.metrics.clone();
+    let mut client = axon_server_handle.event_store_client();
+    let token_gauge = processor_token_gauge(&segment_name);
+    let mut renewed_token = None;
+    let processor = run_event_processor(axon_server_handle, query_model, handler_groups, config);
+    tokio::pin!(processor);
+    let result = loop {
+        tokio::select! {
+            result = &mut processor => break result,
+            _ = tokio::time::delay_for(claim_interval) => (),
+        }
+        // Only renew the claim while the segment makes progress, or has nothing to do, so that the segment of a
+        // processor that is stuck is taken over when the claim expires.
+        let token = metrics.gauge(&token_gauge);
+        let progressed = renewed_token.map(|renewed| token > renewed).unwrap_or(true);
+        if !progressed {
+            match last_token(&mut client).await {
+                Ok(head) if token >= head => (),
+                Ok(head) => {
+                    warn!("Segment makes no progress: claim not renewed: {:?}: token: {:?}: head: {:?}", segment_name, token, head);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Cannot read head token: claim not renewed: {:?}: {:?}", segment_name, e);
+                    continue;
+                }
+            }
+        }
+        match token_store.renew_segment_claim(&owner).await {
+            Ok(true) => {
+                debug!("Renewed claim of segment: {:?}: token: {:?}", segment_name, token);
+                renewed_token = Some(token);
+            }
+            Ok(false) => return Err(anyhow!("Lost claim of segment: {:?}: owner: {:?}", segment_name, owner)),
+            Err(e) => warn!("Cannot renew claim of segment: {:?}: {:?}", segment_name, e),
+        }
+    };
+    if let Err(e) = token_store.release_segment(&owner).await {
+        warn!("Cannot release segment: {:?}: {:?}", segment_name, e);
+    }
+    result
+}
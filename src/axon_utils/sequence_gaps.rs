@@ -0,0 +1,144 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::fmt::{Debug,Display,Formatter};
+use std::sync::Arc;
+use tracing::warn;
+use crate::axon_server::event::Event;
+
+/// Way in which the sequence number of an event of an aggregate differs from the expected one.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Serialize)]
+pub enum SequenceIrregularityKind {
+    /// One or more sequence numbers are missing before this event.
+    Gap,
+    /// The sequence number was already used by the previous event.
+    Duplicate,
+    /// The sequence number is lower than that of the previous event.
+    OutOfOrder,
+}
+
+/// An event of an aggregate with a sequence number that differs from the sequence number of the previous event plus
+/// one (or from the first expected sequence number).
+#[derive(Debug,Clone,PartialEq,Serialize)]
+pub struct SequenceIrregularity {
+    pub kind: SequenceIrregularityKind,
+    pub expected: i64,
+    pub found: i64,
+    pub message_identifier: String,
+}
+
+/// The irregularities in the sequence numbers of the events that an aggregate is sourced from.
+#[derive(Debug,Clone,Default,PartialEq,Serialize)]
+pub struct SequenceReport {
+    pub aggregate_id: String,
+    pub first_expected: i64,
+    pub events: usize,
+    pub irregularities: Vec<SequenceIrregularity>,
+}
+
+impl SequenceReport {
+    pub fn is_regular(&self) -> bool {
+        self.irregularities.is_empty()
+    }
+}
+
+/// Checks that the sequence numbers of the events count up by one, starting at the given sequence number.
+pub fn check_sequence(aggregate_id: &str, first_expected: i64, events: &[Event]) -> SequenceReport {
+    let mut irregularities = Vec::new();
+    let mut expected = first_expected;
+    for event in events {
+        let found = event.aggregate_sequence_number;
+        if found != expected {
+            let kind = if found > expected {
+                SequenceIrregularityKind::Gap
+            } else if found == expected - 1 {
+                SequenceIrregularityKind::Duplicate
+            } else {
+                SequenceIrregularityKind::OutOfOrder
+            };
+            irregularities.push(SequenceIrregularity {
+                kind,
+                expected,
+                found,
+                message_identifier: event.message_identifier.clone(),
+            });
+        }
+        expected = found + 1;
+    }
+    SequenceReport {
+        aggregate_id: aggregate_id.to_string(),
+        first_expected,
+        events: events.len(),
+        irregularities,
+    }
+}
+
+/// Decides what happens with an aggregate whose events have irregular sequence numbers, e.g., after a repair or a
+/// migration of the event store.
+pub type SequenceGapHandler = Arc<dyn Fn(&SequenceReport) -> Result<()> + Send + Sync>;
+
+/// What the command worker does when the events that an aggregate is sourced from have gaps, duplicates, or sequence
+/// numbers that are out of order. By default, the irregularities are logged and the events are applied in the order in
+/// which they arrive.
+#[derive(Clone,Default)]
+pub enum SequenceGapPolicy {
+    /// Refuse to source the aggregate, with a `SequenceGapError`.
+    Fail,
+    /// Log the irregularities and apply the events anyway.
+    #[default]
+    Warn,
+    /// Apply the events without checking their sequence numbers.
+    Tolerate,
+    /// Let the handler decide: an error refuses to source the aggregate.
+    Custom(SequenceGapHandler),
+}
+
+impl Debug for SequenceGapPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SequenceGapPolicy::Fail => write!(f, "Fail"),
+            SequenceGapPolicy::Warn => write!(f, "Warn"),
+            SequenceGapPolicy::Tolerate => write!(f, "Tolerate"),
+            SequenceGapPolicy::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+impl SequenceGapPolicy {
+    /// Checks the sequence numbers of the events of the aggregate, unless they are tolerated, and applies the policy to
+    /// the irregularities, if any.
+    pub fn apply(&self, aggregate_id: &str, first_expected: i64, events: &[Event]) -> Result<()> {
+        if let SequenceGapPolicy::Tolerate = self {
+            return Ok(());
+        }
+        let report = check_sequence(aggregate_id, first_expected, events);
+        if report.is_regular() {
+            return Ok(());
+        }
+        match self {
+            SequenceGapPolicy::Fail => Err(SequenceGapError { report }.into()),
+            SequenceGapPolicy::Custom(handler) => handler(&report),
+            _ => {
+                warn!("Irregular sequence numbers: {:?}: {:?}", aggregate_id, report.irregularities);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Error for an aggregate that is not sourced, because the sequence numbers of its events are irregular.
+#[derive(Debug,Clone)]
+pub struct SequenceGapError {
+    pub report: SequenceReport,
+}
+
+impl Display for SequenceGapError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Irregular sequence numbers: aggregate: {:?}: irregularities: {}", self.report.aggregate_id, self.report.irregularities.len())?;
+        if let Some(first) = self.report.irregularities.first() {
+            write!(f, ": first: {:?}: expected: {}: found: {}", first.kind, first.expected, first.found)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SequenceGapError {}
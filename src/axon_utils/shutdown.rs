@@ -0,0 +1,74 @@
+use tracing::{info,warn};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Signal that tells the parts of a process to stop, e.g., to let the gRPC server finish in-flight requests.
+///
+/// All clones share the same state: once one of them is triggered, `wait` returns for every clone.
+#[derive(Debug,Clone)]
+pub struct ShutdownSignal {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+pub fn create_shutdown_signal() -> ShutdownSignal {
+    let (sender, receiver) = watch::channel(false);
+    ShutdownSignal {
+        sender: Arc::new(sender),
+        receiver,
+    }
+}
+
+impl Default for ShutdownSignal {
+    fn default() -> Self {
+        create_shutdown_signal()
+    }
+}
+
+impl ShutdownSignal {
+    pub fn shutdown(&self) {
+        if !self.is_shutting_down() {
+            info!("Shutdown requested");
+            self.sender.broadcast(true).ok();
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Returns when shutdown is requested.
+    pub async fn wait(&self) {
+        let mut receiver = self.receiver.clone();
+        while !*receiver.borrow() {
+            if receiver.recv().await.is_none() {
+                return;
+            }
+        }
+    }
+}
+
+/// Triggers the shutdown signal on Ctrl-C (SIGINT) or, on Unix, on SIGTERM (e.g., `docker stop`). Run it in a separate
+/// task.
+pub async fn shutdown_on_signal(shutdown_signal: ShutdownSignal) {
+    if let Err(e) = wait_for_signal().await {
+        warn!("Cannot listen for signals: {:?}", e);
+        return;
+    }
+    shutdown_signal.shutdown();
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{SignalKind,signal};
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
@@ -0,0 +1,47 @@
+use tracing::warn;
+use std::collections::HashMap;
+use std::time::Duration;
+use super::Metrics;
+
+/// Latency thresholds for handlers, by message name, with a default for the other messages.
+///
+/// A handler that takes longer than its threshold is logged as a warning with the name of the message, the routing key
+/// (if any) and the elapsed time, and counted in the `<worker>_slow_handlers` metric. The routing key is the routing
+/// key that AxonServer used for a command, and the aggregate identifier of an event. Without thresholds, nothing is
+/// checked.
+#[derive(Debug,Clone,Default)]
+pub struct SlowHandlerThresholds {
+    pub default: Option<Duration>,
+    pub per_message: HashMap<String,Duration>,
+}
+
+impl SlowHandlerThresholds {
+    pub fn with_default(mut self, threshold: Duration) -> Self {
+        self.default = Some(threshold);
+        self
+    }
+
+    pub fn with_threshold(mut self, message_name: &str, threshold: Duration) -> Self {
+        self.per_message.insert(message_name.to_string(), threshold);
+        self
+    }
+
+    pub fn threshold(&self, message_name: &str) -> Option<Duration> {
+        self.per_message.get(message_name).cloned().or(self.default)
+    }
+
+    pub(crate) fn check(&self, worker: &str, metrics: &Metrics, message_name: &str, routing_key: Option<&str>, elapsed: Duration) {
+        let threshold = match self.threshold(message_name) {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        if elapsed <= threshold {
+            return;
+        }
+        warn!(
+            "Slow handler: worker={} message={:?} routing_key={:?} elapsed_ms={} threshold_ms={}",
+            worker, message_name, routing_key.unwrap_or(""), elapsed.as_millis(), threshold.as_millis()
+        );
+        metrics.increment(&format!("{}_slow_handlers", worker), 1);
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::{anyhow,Result};
+use bytes::Bytes;
+use prost::Message;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+use super::aggregate_migration::now_millis;
+use crate::axon_server::SerializedObject;
+use crate::axon_server::event::Event;
+
+/// Turns the projection of an aggregate into the data of a snapshot and back. The representation of snapshots is
+/// independent of the representation of events, e.g., protobuf events with JSON snapshots for debuggability.
+pub trait SnapshotSerializer<P>: Send + Sync {
+    fn serialize(&self, projection: &P) -> Result<Vec<u8>>;
+    fn deserialize(&self, data: &[u8]) -> Result<P>;
+}
+
+/// Stores snapshots in the protobuf encoding of the projection.
+#[derive(Debug,Clone,Copy,Default)]
+pub struct ProtobufSnapshotSerializer;
+
+impl<P: Message + Default> SnapshotSerializer<P> for ProtobufSnapshotSerializer {
+    fn serialize(&self, projection: &P) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        projection.encode(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn deserialize(&self, data: &[u8]) -> Result<P> {
+        Ok(P::decode(Bytes::copy_from_slice(data))?)
+    }
+}
+
+/// Stores snapshots as JSON, so that they can be read in the event store console.
+#[derive(Debug,Clone,Copy,Default)]
+pub struct JsonSnapshotSerializer;
+
+impl<P: Serialize + DeserializeOwned> SnapshotSerializer<P> for JsonSnapshotSerializer {
+    fn serialize(&self, projection: &P) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(projection)?)
+    }
+
+    fn deserialize(&self, data: &[u8]) -> Result<P> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+/// Converts the data of a snapshot from one revision to the next.
+pub type SnapshotUpcaster = Arc<dyn Fn(Vec<u8>) -> Result<Vec<u8>> + Send + Sync>;
+
+/// Describes how and when the command worker stores snapshots of an aggregate.
+///
+/// A snapshot is stored after a command when at least `threshold` events were applied to the projection since the
+/// last snapshot. Snapshots are stored with the current revision. A snapshot with an older revision is upcast to the
+/// current revision, one upcaster at a time. A snapshot that cannot be restored is ignored: the aggregate is then
+/// sourced from its first event.
+pub struct SnapshotConfig<P> {
+    pub threshold: usize,
+    pub revision: String,
+    serializer: Arc<dyn SnapshotSerializer<P>>,
+    upcasters: HashMap<String,(String,SnapshotUpcaster)>,
+}
+
+pub fn create_snapshot_config<P>(threshold: usize, revision: &str, serializer: impl SnapshotSerializer<P> + 'static) -> SnapshotConfig<P> {
+    SnapshotConfig {
+        threshold: threshold.max(1),
+        revision: revision.to_string(),
+        serializer: Arc::new(serializer),
+        upcasters: HashMap::new(),
+    }
+}
+
+impl<P> SnapshotConfig<P> {
+    pub fn with_upcaster(mut self, from_revision: &str, to_revision: &str, upcaster: impl Fn(Vec<u8>) -> Result<Vec<u8>> + Send + Sync + 'static) -> Self {
+        self.upcasters.insert(from_revision.to_string(), (to_revision.to_string(), Arc::new(upcaster)));
+        self
+    }
+
+    /// Returns the projection that was stored in the snapshot, upcast to the current revision.
+    pub fn restore(&self, snapshot: &SerializedObject) -> Result<P> {
+        let mut revision = snapshot.revision.clone();
+        let mut data = snapshot.data.clone();
+        let mut steps = 0;
+        while revision != self.revision {
+            let (next_revision, upcaster) = self.upcasters.get(&revision)
+                .ok_or_else(|| anyhow!("No snapshot upcaster for revision: {:?}", revision))?;
+            steps += 1;
+            if steps > self.upcasters.len() {
+                return Err(anyhow!("Snapshot upcasters do not lead to revision: {:?}", self.revision));
+            }
+            data = upcaster(data)?;
+            revision = next_revision.clone();
+        }
+        self.serializer.deserialize(&data)
+    }
+
+    pub(crate) fn snapshot_event(&self, aggregate_type: &str, aggregate_id: &str, sequence_nr: i64, projection: &P) -> Result<Event> {
+        Ok(Event {
+            message_identifier: format!("{:?}", Uuid::new_v4().to_simple()),
+            timestamp: now_millis()?,
+            aggregate_identifier: aggregate_id.to_string(),
+            aggregate_sequence_number: sequence_nr,
+            aggregate_type: aggregate_type.to_string(),
+            payload: Some(SerializedObject {
+                r#type: aggregate_type.to_string(),
+                revision: self.revision.clone(),
+                data: self.serializer.serialize(projection)?,
+            }),
+            meta_data: HashMap::new(),
+            snapshot: true,
+        })
+    }
+}
@@ -0,0 +1,81 @@
+use anyhow::{anyhow,Result};
+use std::collections::HashMap;
+use std::fmt::{Debug,Formatter};
+use std::sync::Arc;
+
+pub type Guard<S> = Arc<dyn Fn(&S) -> bool + Send + Sync>;
+
+/// States and the transitions between them, keyed by event type.
+///
+/// Command handlers use `accepts` to check whether the event they are about to emit is allowed in the current state.
+/// Sourcing handlers must accept every event that is in the event store, so they set the state that the event implies
+/// (or use `next` with a fallback) instead of failing with `apply`, which is meant for state that is not sourced from
+/// the event store. A transition applies when the current state matches its source state (or it has none, meaning: from
+/// any state) and its guard (if any) holds. The first transition that applies, in the order in which they were added,
+/// wins.
+pub struct StateMachine<S> {
+    transitions: HashMap<String,Vec<Transition<S>>>,
+}
+
+struct Transition<S> {
+    from: Option<S>,
+    to: S,
+    guard: Option<Guard<S>>,
+}
+
+pub fn create_state_machine<S: Clone + PartialEq + Debug>() -> StateMachine<S> {
+    StateMachine {
+        transitions: HashMap::new(),
+    }
+}
+
+impl<S: Clone + PartialEq + Debug> StateMachine<S> {
+    pub fn with_transition(self, event_type: &str, from: S, to: S) -> Self {
+        self.add(event_type, Some(from), to, None)
+    }
+
+    pub fn with_transition_from_any(self, event_type: &str, to: S) -> Self {
+        self.add(event_type, None, to, None)
+    }
+
+    pub fn with_guarded_transition(self, event_type: &str, from: S, to: S, guard: impl Fn(&S) -> bool + Send + Sync + 'static) -> Self {
+        self.add(event_type, Some(from), to, Some(Arc::new(guard)))
+    }
+
+    fn add(mut self, event_type: &str, from: Option<S>, to: S, guard: Option<Guard<S>>) -> Self {
+        self.transitions.entry(event_type.to_string()).or_default().push(Transition { from, to, guard });
+        self
+    }
+
+    /// Returns the state after the event, or `None` if no transition for the event applies in the given state.
+    pub fn next(&self, state: &S, event_type: &str) -> Option<S> {
+        self.transitions.get(event_type)?.iter()
+            .find(|transition| {
+                transition.from.as_ref().map(|from| from == state).unwrap_or(true)
+                    && transition.guard.as_ref().map(|guard| guard(state)).unwrap_or(true)
+            })
+            .map(|transition| transition.to.clone())
+    }
+
+    pub fn accepts(&self, state: &S, event_type: &str) -> bool {
+        self.next(state, event_type).is_some()
+    }
+
+    /// Returns the state after the event, or an error if the event is not allowed in the given state.
+    pub fn apply(&self, state: &S, event_type: &str) -> Result<S> {
+        self.next(state, event_type)
+            .ok_or_else(|| anyhow!("No transition for event {:?} in state {:?}", event_type, state))
+    }
+}
+
+impl<S: Debug> Debug for StateMachine<S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut transitions = f.debug_map();
+        for (event_type, event_transitions) in &self.transitions {
+            for transition in event_transitions {
+                transitions.entry(event_type, &(&transition.from, &transition.to, transition.guard.is_some()));
+            }
+        }
+        transitions.finish()
+    }
+}
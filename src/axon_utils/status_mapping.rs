@@ -0,0 +1,102 @@
+use anyhow::{Error,Result};
+use std::fmt::{Display,Formatter};
+use tonic::Status;
+use super::await_projection::ProjectionTimeoutError;
+use super::axon_error::AxonError;
+use super::business_rules::{BusinessRuleError,DELETED_ERROR_CODE};
+use super::command_worker::{BUSY_ERROR_CODE,QUARANTINED_ERROR_CODE};
+use super::conflict::CONFLICT_ERROR_CODE;
+use super::handler_errors::{CONCURRENCY_EXCEPTION,NO_HANDLER_FOR_COMMAND};
+use super::message_size::{MessageTooLargeError,PayloadTooLargeError};
+use super::read_only::ReadOnlyError;
+use super::tenant_quota::QuotaExceededError;
+
+/// Error for a request that is invalid in itself, before it is turned into a command or a query.
+#[derive(Debug,Clone)]
+pub struct ValidationError {
+    pub field: String,
+    pub message: String,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid {}: {}", self.field, self.message)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Returns a `ValidationError` for the given field unless the condition holds, e.g.,
+/// `validate(!message.is_empty(), "message", "must not be empty")?`.
+pub fn validate(condition: bool, field: &str, message: &str) -> Result<()> {
+    if condition {
+        return Ok(());
+    }
+    Err(ValidationError {
+        field: field.to_string(),
+        message: message.to_string(),
+    }.into())
+}
+
+/// Maps an error from validating a request, or from sending a command or query, to the status that a gRPC API
+/// returns to its caller.
+///
+/// Business rule violations are mapped by error code: `CONFLICT` to `Aborted`, `DELETED` to `NotFound` and any other
+/// code to `FailedPrecondition`. Statuses from AxonServer keep
+/// their code. Commands that are refused in read-only mode are mapped to `Unavailable`, and those that exceed the quota
+/// of the tenant to `ResourceExhausted`. Errors that are not recognized are mapped to `Unknown`.
+///
+/// An `AxonError` is mapped by kind: connection errors to `Unavailable`, serialization errors to `Internal` and
+/// missing handlers to `Unimplemented`. Error responses are mapped by error code: `AXONIQ-4000` (no handler) to
+/// `Unimplemented`, `AXONIQ-4004` (concurrency) to `Aborted`, `BUSY` (a full mailbox of the command worker) to
+/// `Unavailable` (the caller may retry) and `QUARANTINED` (a command that crashed its handler too often) to `Internal`.
+pub fn error_to_status(error: &Error) -> Status {
+    if let Some(validation_error) = error.downcast_ref::<ValidationError>() {
+        return Status::invalid_argument(validation_error.to_string());
+    }
+    if let Some(business_rule_error) = error.downcast_ref::<BusinessRuleError>() {
+        return business_rule_status(business_rule_error);
+    }
+    if let Some(axon_error) = error.downcast_ref::<AxonError>() {
+        return match axon_error {
+            AxonError::Connection(_) => Status::unavailable(error.to_string()),
+            AxonError::Serialization(_) => Status::internal(error.to_string()),
+            AxonError::MissingHandler(_) => Status::unimplemented(error.to_string()),
+            AxonError::Status(status) => Status::new(status.code(), error.to_string()),
+            AxonError::ErrorResponse { error_code, .. } => match error_code.as_str() {
+                NO_HANDLER_FOR_COMMAND => Status::unimplemented(error.to_string()),
+                CONCURRENCY_EXCEPTION => Status::aborted(error.to_string()),
+                BUSY_ERROR_CODE => Status::unavailable(error.to_string()),
+                QUARANTINED_ERROR_CODE => Status::internal(error.to_string()),
+                _ => Status::unknown(error.to_string()),
+            },
+            AxonError::Business(business_rule_error) => business_rule_status(business_rule_error),
+            AxonError::Other(error) => error_to_status(error),
+        };
+    }
+    if error.is::<MessageTooLargeError>() || error.is::<PayloadTooLargeError>() {
+        return Status::resource_exhausted(error.to_string());
+    }
+    if error.is::<ProjectionTimeoutError>() {
+        return Status::deadline_exceeded(error.to_string());
+    }
+    if error.is::<QuotaExceededError>() {
+        return Status::resource_exhausted(error.to_string());
+    }
+    if error.is::<ReadOnlyError>() {
+        return Status::unavailable(error.to_string());
+    }
+    if let Some(status) = error.chain().find_map(|cause| cause.downcast_ref::<Status>()) {
+        return Status::new(status.code(), error.to_string());
+    }
+    Status::unknown(error.to_string())
+}
+
+fn business_rule_status(business_rule_error: &BusinessRuleError) -> Status {
+    let message = format!("{}: {}", business_rule_error.error_code, business_rule_error.message);
+    match business_rule_error.error_code.as_str() {
+        CONFLICT_ERROR_CODE => Status::aborted(message),
+        DELETED_ERROR_CODE => Status::not_found(message),
+        _ => Status::failed_precondition(message),
+    }
+}
@@ -0,0 +1,274 @@
+use anyhow::{anyhow,Result};
+use async_stream::stream;
+use bytes::Bytes;
+use futures_core::stream::Stream;
+use tracing::{debug,warn};
+use prost::Message;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc,Mutex};
+use tokio::sync::mpsc::{Sender,channel};
+use tonic::Request;
+use uuid::Uuid;
+use super::{AxonClients,AxonServerHandle,VecU8Message,axon_serialize};
+use super::message_size::{check_message_size,explain_status};
+use super::query_processor::AxonQueryOutput;
+use super::redaction::log_safe;
+use crate::axon_server::SerializedObject;
+use crate::axon_server::query::{QueryRequest,QueryUpdate,SubscriptionQuery,SubscriptionQueryRequest,SubscriptionQueryResponse};
+use crate::axon_server::query::subscription_query_request::Request as SubscriptionRequest;
+use crate::axon_server::query::subscription_query_response::Response as SubscriptionResponse;
+
+/// Number of updates that AxonServer may send to a subscriber before it has to wait for more permits. The subscriber
+/// grants more permits when half of them are used.
+pub const DEFAULT_UPDATE_PERMITS: i64 = 32;
+
+pub type QueryUpdates = Pin<Box<dyn Stream<Item=Result<SerializedObject>> + Send>>;
+
+/// The initial result of a subscription query and the stream of updates that follow it.
+///
+/// The stream of updates ends when the query handler completes the subscription. It yields an error when the query
+/// handler completes it exceptionally. The subscription ends when the result and the stream are dropped, or when it is
+/// unsubscribed explicitly.
+pub struct SubscriptionQueryResult {
+    pub subscription_identifier: String,
+    pub initial_result: Option<SerializedObject>,
+    pub updates: QueryUpdates,
+    subscription: SubscriptionQuery,
+    requests: Sender<SubscriptionQueryRequest>,
+}
+
+impl SubscriptionQueryResult {
+    pub async fn unsubscribe(mut self) -> Result<()> {
+        let request = SubscriptionQueryRequest {
+            request: Some(SubscriptionRequest::Unsubscribe(self.subscription.clone())),
+        };
+        self.requests.send(request).await.map_err(|_| anyhow!("Subscription query: stream closed"))
+    }
+}
+
+impl AxonServerHandle {
+    /// Sends a subscription query. Returns when the initial result arrived. Updates that arrive before the initial
+    /// result are kept for the stream of updates.
+    pub async fn send_subscription_query(&self, query_type: &str, query: Box<&(dyn VecU8Message + Sync)>) -> Result<SubscriptionQueryResult> {
+        debug!("Sending subscription query: {:?}: {:?}", query_type, self.display_name);
+        let mut buf = Vec::new();
+        query.encode_u8(&mut buf)?;
+        let payload = SerializedObject {
+            r#type: query_type.to_string(),
+            revision: "1".to_string(),
+            data: buf,
+        };
+        let query_request = QueryRequest {
+            message_identifier: format!("{:?}", Uuid::new_v4().to_simple()),
+            query: query_type.to_string(),
+            response_type: None,
+            payload: Some(payload),
+            client_id: self.display_name.clone(),
+            component_name: self.component_name.clone(),
+            meta_data: HashMap::new(),
+            processing_instructions: Vec::new(),
+            timestamp: 0,
+        };
+        check_message_size("QueryRequest", &query_request, self.max_message_size())?;
+        let subscription = SubscriptionQuery {
+            subscription_identifier: format!("{:?}", Uuid::new_v4().to_simple()),
+            number_of_permits: DEFAULT_UPDATE_PERMITS,
+            query_request: Some(query_request),
+            update_response_type: None,
+        };
+
+        let (mut requests, mut rx) = channel::<SubscriptionQueryRequest>(10);
+        let outbound = stream! {
+            while let Some(request) = rx.recv().await {
+                yield request;
+            }
+        };
+        for request in [SubscriptionRequest::Subscribe(subscription.clone()), SubscriptionRequest::GetInitialResult(subscription.clone())] {
+            requests.send(SubscriptionQueryRequest { request: Some(request) }).await
+                .map_err(|_| anyhow!("Subscription query: stream closed"))?;
+        }
+
+        let mut client = self.query_client();
+        let response = client.subscription(Request::new(outbound)).await.map_err(explain_status)?;
+        let mut inbound = response.into_inner();
+
+        let mut early_updates = Vec::new();
+        let initial_result = loop {
+            match inbound.message().await? {
+                Some(SubscriptionQueryResponse { response: Some(response), .. }) => match response {
+                    SubscriptionResponse::InitialResult(query_response) => {
+                        if !query_response.error_code.is_empty() {
+                            return Err(anyhow!("Subscription query failed: {:?}: {:?}", query_response.error_code, query_response.error_message));
+                        }
+                        break query_response.payload;
+                    }
+                    SubscriptionResponse::Update(update) => early_updates.push(update),
+                    SubscriptionResponse::Complete(_) => return Err(anyhow!("Subscription query completed before the initial result")),
+                    SubscriptionResponse::CompleteExceptionally(complete) => {
+                        return Err(anyhow!("Subscription query failed: {:?}: {:?}", complete.error_code, complete.error_message));
+                    }
+                },
+                Some(_) => (),
+                None => return Err(anyhow!("Subscription query: stream closed before the initial result")),
+            }
+        };
+        debug!("Subscription query: initial result: {:?}", log_safe(&initial_result));
+
+        let mut flow_control = requests.clone();
+        let flow_control_subscription = subscription.clone();
+        let updates = stream! {
+            let mut received = 0;
+            let mut early_updates = early_updates.into_iter();
+            loop {
+                let update = match early_updates.next() {
+                    Some(update) => update,
+                    None => match inbound.message().await {
+                        Ok(Some(SubscriptionQueryResponse { response: Some(SubscriptionResponse::Update(update)), .. })) => update,
+                        Ok(Some(SubscriptionQueryResponse { response: Some(SubscriptionResponse::CompleteExceptionally(complete)), .. })) => {
+                            yield Err(anyhow!("Subscription query failed: {:?}: {:?}", complete.error_code, complete.error_message));
+                            break;
+                        }
+                        Ok(Some(SubscriptionQueryResponse { response: Some(SubscriptionResponse::Complete(_)), .. })) | Ok(None) => break,
+                        Ok(Some(_)) => continue,
+                        Err(e) => {
+                            yield Err(e.into());
+                            break;
+                        }
+                    }
+                };
+                yield update_payload(update);
+                received += 1;
+                if received >= DEFAULT_UPDATE_PERMITS / 2 {
+                    let permits = SubscriptionQuery {
+                        subscription_identifier: flow_control_subscription.subscription_identifier.clone(),
+                        number_of_permits: received,
+                        ..Default::default()
+                    };
+                    let request = SubscriptionQueryRequest {
+                        request: Some(SubscriptionRequest::FlowControl(permits)),
+                    };
+                    if flow_control.send(request).await.is_err() {
+                        break;
+                    }
+                    received = 0;
+                }
+            }
+            debug!("Subscription query: updates: stop");
+        };
+
+        Ok(SubscriptionQueryResult {
+            subscription_identifier: subscription.subscription_identifier.clone(),
+            initial_result,
+            updates: Box::pin(updates),
+            subscription,
+            requests,
+        })
+    }
+}
+
+fn update_payload(update: QueryUpdate) -> Result<SerializedObject> {
+    if !update.error_code.is_empty() {
+        return Err(anyhow!("Subscription query update failed: {:?}: {:?}", update.error_code, update.error_message));
+    }
+    update.payload.ok_or_else(|| anyhow!("Subscription query update without payload"))
+}
+
+/// Pushes updates to the subscription queries that the query processor of the same `AxonServerHandle` accepted.
+///
+/// Projections emit an update when they apply an event that changes the answer to a query. The filter selects the
+/// subscriptions by the payload of their query. Subscriptions whose query processor stopped are dropped.
+#[derive(Debug,Clone,Default)]
+pub struct QueryUpdateEmitter {
+    subscriptions: Arc<Mutex<HashMap<String,QuerySubscriber>>>,
+}
+
+#[derive(Debug,Clone)]
+struct QuerySubscriber {
+    query: String,
+    payload: Option<SerializedObject>,
+    tx: Sender<AxonQueryOutput>,
+}
+
+impl QueryUpdateEmitter {
+    pub(crate) fn subscribe(&self, subscription: &SubscriptionQuery, tx: Sender<AxonQueryOutput>) {
+        let query_request = subscription.query_request.as_ref();
+        let subscriber = QuerySubscriber {
+            query: query_request.map(|query_request| query_request.query.clone()).unwrap_or_default(),
+            payload: query_request.and_then(|query_request| query_request.payload.clone()),
+            tx,
+        };
+        debug!("Subscription query: subscribe: {:?}: {:?}", subscription.subscription_identifier, subscriber.query);
+        if let Ok(mut subscriptions) = self.subscriptions.lock() {
+            subscriptions.insert(subscription.subscription_identifier.clone(), subscriber);
+        }
+    }
+
+    pub(crate) fn unsubscribe(&self, subscription_identifier: &str) {
+        debug!("Subscription query: unsubscribe: {:?}", subscription_identifier);
+        if let Ok(mut subscriptions) = self.subscriptions.lock() {
+            subscriptions.remove(subscription_identifier);
+        }
+    }
+
+    /// Returns the number of subscriptions on the named query.
+    pub fn subscription_count(&self, query_name: &str) -> usize {
+        self.subscriptions.lock()
+            .map(|subscriptions| subscriptions.values().filter(|subscriber| subscriber.query == query_name).count())
+            .unwrap_or(0)
+    }
+
+    /// Sends the update to the subscriptions on the named query whose payload passes the filter. Returns the number of
+    /// subscriptions that received the update.
+    pub async fn emit(&self, query_name: &str, filter: impl Fn(&SerializedObject) -> bool, update: SerializedObject) -> usize {
+        let mut sent = 0;
+        for (subscription_identifier, mut tx) in self.matching(query_name, filter) {
+            let output = AxonQueryOutput::Update {
+                subscription_identifier: subscription_identifier.clone(),
+                payload: update.clone(),
+                meta_data: HashMap::new(),
+            };
+            if tx.send(output).await.is_ok() {
+                sent += 1;
+            } else {
+                warn!("Subscription query: query processor stopped: {:?}", subscription_identifier);
+                self.unsubscribe(&subscription_identifier);
+            }
+        }
+        sent
+    }
+
+    /// Sends the message as an update to the subscriptions on the named query whose decoded payload passes the filter.
+    /// Subscriptions whose payload cannot be decoded as `Q` are skipped.
+    pub async fn emit_message<Q: Message + Default, T: Message>(&self, query_name: &str, filter: impl Fn(&Q) -> bool, type_name: &str, message: &T) -> Result<usize> {
+        let update = axon_serialize(type_name, message)?;
+        let filter = |payload: &SerializedObject| Q::decode(Bytes::from(payload.data.clone())).map(|query| filter(&query)).unwrap_or(false);
+        Ok(self.emit(query_name, filter, update).await)
+    }
+
+    /// Tells the subscriptions on the named query whose payload passes the filter that no more updates follow, and
+    /// forgets them.
+    pub async fn complete(&self, query_name: &str, filter: impl Fn(&SerializedObject) -> bool) -> usize {
+        let mut completed = 0;
+        for (subscription_identifier, mut tx) in self.matching(query_name, filter) {
+            self.unsubscribe(&subscription_identifier);
+            let output = AxonQueryOutput::UpdateComplete {
+                subscription_identifier,
+            };
+            if tx.send(output).await.is_ok() {
+                completed += 1;
+            }
+        }
+        completed
+    }
+
+    fn matching(&self, query_name: &str, filter: impl Fn(&SerializedObject) -> bool) -> Vec<(String,Sender<AxonQueryOutput>)> {
+        self.subscriptions.lock()
+            .map(|subscriptions| subscriptions.iter()
+                .filter(|(_, subscriber)| subscriber.query == query_name)
+                .filter(|(_, subscriber)| subscriber.payload.as_ref().map(&filter).unwrap_or(false))
+                .map(|(subscription_identifier, subscriber)| (subscription_identifier.clone(), subscriber.tx.clone()))
+                .collect())
+            .unwrap_or_default()
+    }
+}
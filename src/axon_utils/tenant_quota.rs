@@ -0,0 +1,289 @@
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fmt::{Display,Formatter};
+use std::sync::{Arc,Mutex,RwLock};
+use std::time::{Duration,Instant,SystemTime,UNIX_EPOCH};
+use tonic::{Code,Status};
+use tonic::metadata::{MetadataMap,MetadataValue};
+use super::Metrics;
+use super::connection::InterceptorFn;
+use super::metrics::label_value;
+
+/// Tenant of connections without an AxonServer context.
+pub const DEFAULT_TENANT: &str = "default";
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// Metadata of the status of a command that the quota interceptor refused, from which the `QuotaExceededError` is
+// restored.
+const QUOTA_TENANT_HEADER: &str = "tenant-quota-tenant";
+const QUOTA_KIND_HEADER: &str = "tenant-quota-kind";
+const QUOTA_LIMIT_HEADER: &str = "tenant-quota-limit";
+
+static TENANT_QUOTAS: Lazy<RwLock<Arc<TenantQuotas>>> = Lazy::new(|| RwLock::new(Arc::new(TenantQuotas::default())));
+
+/// Limits on what one tenant may send to AxonServer: commands per second, events per day (UTC) and the size of
+/// commands and queries. A limit that is `None` is not enforced.
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct TenantQuota {
+    pub commands_per_second: Option<u32>,
+    pub events_per_day: Option<u64>,
+    pub max_message_size: Option<usize>,
+}
+
+pub fn create_tenant_quota() -> TenantQuota {
+    TenantQuota::default()
+}
+
+impl TenantQuota {
+    pub fn with_commands_per_second(mut self, commands_per_second: u32) -> Self {
+        self.commands_per_second = Some(commands_per_second);
+        self
+    }
+
+    pub fn with_events_per_day(mut self, events_per_day: u64) -> Self {
+        self.events_per_day = Some(events_per_day);
+        self
+    }
+
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = Some(max_message_size);
+        self
+    }
+}
+
+/// Quotas of the tenants of this client, so that one tenant cannot exhaust the capacity of AxonServer that it shares
+/// with the others. The tenant of a connection is its AxonServer context (see `ConnectionConfig::with_context`), or
+/// `DEFAULT_TENANT`. Tenants without a quota of their own get the default quota, if any.
+///
+/// Commands are counted by an interceptor of the client that dispatches the commands of a `CommandSink`. Events are
+/// counted when a command worker or a `Repository` appends the events of an aggregate, and when they are appended with
+/// `append_event_transaction`. Usage is counted per process, for all connections with the same context.
+#[derive(Debug,Default)]
+pub struct TenantQuotas {
+    quotas: HashMap<String,TenantQuota>,
+    default_quota: Option<TenantQuota>,
+    usage: Mutex<HashMap<String,TenantUsage>>,
+}
+
+#[derive(Debug)]
+struct TenantUsage {
+    second_started: Instant,
+    commands_this_second: u32,
+    day: u64,
+    events_today: u64,
+}
+
+pub fn create_tenant_quotas() -> TenantQuotas {
+    TenantQuotas::default()
+}
+
+impl TenantQuotas {
+    pub fn with_quota(mut self, tenant: &str, quota: TenantQuota) -> Self {
+        self.quotas.insert(tenant.to_string(), quota);
+        self
+    }
+
+    pub fn with_default_quota(mut self, quota: TenantQuota) -> Self {
+        self.default_quota = Some(quota);
+        self
+    }
+
+    pub fn quota(&self, tenant: &str) -> Option<&TenantQuota> {
+        self.quotas.get(tenant).or(self.default_quota.as_ref())
+    }
+
+    /// Counts a command for the tenant, or fails with a `QuotaExceededError` if the tenant already sent its quota of
+    /// commands in the current second.
+    pub fn acquire_command(&self, tenant: &str) -> Result<()> {
+        let limit = match self.quota(tenant).and_then(|quota| quota.commands_per_second) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        self.with_usage(tenant, |usage| {
+            if usage.second_started.elapsed() >= Duration::from_secs(1) {
+                usage.second_started = Instant::now();
+                usage.commands_this_second = 0;
+            }
+            if usage.commands_this_second >= limit {
+                return Err(quota_exceeded(tenant, QuotaKind::CommandsPerSecond, u64::from(limit)));
+            }
+            usage.commands_this_second += 1;
+            Ok(())
+        })
+    }
+
+    /// Counts the events for the tenant, or fails with a `QuotaExceededError`, without counting any of them, if they
+    /// do not fit in what is left of the quota of the tenant for the current day.
+    pub fn acquire_events(&self, tenant: &str, count: usize) -> Result<()> {
+        let limit = match self.quota(tenant).and_then(|quota| quota.events_per_day) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let today = current_day();
+        self.with_usage(tenant, |usage| {
+            if usage.day != today {
+                usage.day = today;
+                usage.events_today = 0;
+            }
+            if usage.events_today + count as u64 > limit {
+                return Err(quota_exceeded(tenant, QuotaKind::EventsPerDay, limit));
+            }
+            usage.events_today += count as u64;
+            Ok(())
+        })
+    }
+
+    fn with_usage<F: FnOnce(&mut TenantUsage) -> Result<()>>(&self, tenant: &str, f: F) -> Result<()> {
+        let mut usage = match self.usage.lock() {
+            Ok(usage) => usage,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let usage = usage.entry(tenant.to_string()).or_insert_with(|| TenantUsage {
+            second_started: Instant::now(),
+            commands_this_second: 0,
+            day: current_day(),
+            events_today: 0,
+        });
+        f(usage)
+    }
+}
+
+/// Replaces the quotas of the tenants of this client. The usage that was counted so far is reset.
+pub fn set_tenant_quotas(quotas: TenantQuotas) {
+    if let Ok(mut current) = TENANT_QUOTAS.write() {
+        *current = Arc::new(quotas);
+    }
+}
+
+pub fn tenant_quotas() -> Arc<TenantQuotas> {
+    TENANT_QUOTAS.read().map(|quotas| quotas.clone()).unwrap_or_default()
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum QuotaKind {
+    CommandsPerSecond,
+    EventsPerDay,
+}
+
+impl QuotaKind {
+    fn label(self) -> &'static str {
+        match self {
+            QuotaKind::CommandsPerSecond => "commands_per_second",
+            QuotaKind::EventsPerDay => "events_per_day",
+        }
+    }
+
+    fn from_label(label: &str) -> Option<QuotaKind> {
+        match label {
+            "commands_per_second" => Some(QuotaKind::CommandsPerSecond),
+            "events_per_day" => Some(QuotaKind::EventsPerDay),
+            _ => None,
+        }
+    }
+}
+
+/// Error for a command or an append that is refused because the tenant exceeded its quota.
+#[derive(Debug,Clone)]
+pub struct QuotaExceededError {
+    pub tenant: String,
+    pub quota: QuotaKind,
+    pub limit: u64,
+}
+
+impl Display for QuotaExceededError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Quota exceeded: tenant: {:?}: {}: {}", self.tenant, self.quota.label(), self.limit)
+    }
+}
+
+impl std::error::Error for QuotaExceededError {}
+
+fn quota_exceeded(tenant: &str, quota: QuotaKind, limit: u64) -> anyhow::Error {
+    QuotaExceededError { tenant: tenant.to_string(), quota, limit }.into()
+}
+
+fn current_day() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs() / SECONDS_PER_DAY).unwrap_or(0)
+}
+
+pub(crate) fn tenant(context: Option<&str>) -> &str {
+    context.unwrap_or(DEFAULT_TENANT)
+}
+
+/// The tenant that appended events are counted against, and the metrics in which refusals are counted.
+#[derive(Debug,Clone,Default)]
+pub(crate) struct TenantScope {
+    pub(crate) context: Option<String>,
+    pub(crate) metrics: Metrics,
+}
+
+impl TenantScope {
+    // Counts appended events against the quota of the tenant, and counts refusals in `tenant_quota_exceeded`.
+    pub(crate) fn check_event_quota(&self, count: usize) -> Result<()> {
+        let tenant = tenant(self.context.as_deref());
+        count_refusal(tenant_quotas().acquire_events(tenant, count), tenant, QuotaKind::EventsPerDay, &self.metrics)
+    }
+}
+
+// Counts a command against the quota of the tenant of the connection, and counts refusals in `tenant_quota_exceeded`.
+pub(crate) fn check_command_quota(context: Option<&str>, metrics: &Metrics) -> Result<()> {
+    let tenant = tenant(context);
+    count_refusal(tenant_quotas().acquire_command(tenant), tenant, QuotaKind::CommandsPerSecond, metrics)
+}
+
+// Returns the dispatch interceptor that counts each command against the quota of the tenant. A refused command never
+// reaches AxonServer: it fails with a `RESOURCE_EXHAUSTED` status, from which `quota_exceeded_from_status` restores the
+// `QuotaExceededError`. The signature of the interceptor, including the size of `Status`, is imposed by tonic.
+#[allow(clippy::result_large_err)]
+pub(crate) fn command_quota_interceptor(context: Option<&str>, metrics: &Metrics) -> InterceptorFn {
+    let context = context.map(str::to_string);
+    let metrics = metrics.clone();
+    Arc::new(move |request| match check_command_quota(context.as_deref(), &metrics) {
+        Ok(()) => Ok(request),
+        Err(e) => Err(quota_status(e)),
+    })
+}
+
+fn quota_status(error: anyhow::Error) -> Status {
+    let mut metadata = MetadataMap::new();
+    if let Some(quota_exceeded) = error.downcast_ref::<QuotaExceededError>() {
+        if let Ok(tenant) = quota_exceeded.tenant.parse() {
+            metadata.insert(QUOTA_TENANT_HEADER, tenant);
+        }
+        metadata.insert(QUOTA_KIND_HEADER, MetadataValue::from_static(quota_exceeded.quota.label()));
+        if let Ok(limit) = quota_exceeded.limit.to_string().parse() {
+            metadata.insert(QUOTA_LIMIT_HEADER, limit);
+        }
+    }
+    Status::with_metadata(Code::ResourceExhausted, error.to_string(), metadata)
+}
+
+pub(crate) fn quota_exceeded_from_status(status: &Status) -> Option<QuotaExceededError> {
+    if status.code() != Code::ResourceExhausted {
+        return None;
+    }
+    let header = |key: &str| status.metadata().get(key).and_then(|value| value.to_str().ok());
+    Some(QuotaExceededError {
+        tenant: header(QUOTA_TENANT_HEADER)?.to_string(),
+        quota: QuotaKind::from_label(header(QUOTA_KIND_HEADER)?)?,
+        limit: header(QUOTA_LIMIT_HEADER)?.parse().ok()?,
+    })
+}
+
+// The smaller of the maximum message size of the connection and that of the tenant.
+pub(crate) fn max_message_size(context: Option<&str>, max_message_size: Option<usize>) -> Option<usize> {
+    let tenant_max = tenant_quotas().quota(tenant(context)).and_then(|quota| quota.max_message_size);
+    match (max_message_size, tenant_max) {
+        (Some(connection_max), Some(tenant_max)) => Some(connection_max.min(tenant_max)),
+        (connection_max, tenant_max) => connection_max.or(tenant_max),
+    }
+}
+
+fn count_refusal(result: Result<()>, tenant: &str, quota: QuotaKind, metrics: &Metrics) -> Result<()> {
+    if result.is_err() {
+        metrics.increment(&format!("tenant_quota_exceeded{{tenant={},quota={}}}", label_value(tenant), label_value(quota.label())), 1);
+    }
+    result
+}
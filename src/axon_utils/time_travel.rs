@@ -0,0 +1,43 @@
+use anyhow::Result;
+use tracing::debug;
+use super::{AxonClients,AxonServerHandle,VecU8Message};
+use super::command_worker::{AggregateContext,AggregateDefinition};
+use super::event_query::query_events_up_to;
+
+/// Point in the history of an aggregate.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum AsOf {
+    /// Up to and including the event with this sequence number, which must not be negative.
+    Sequence(i64),
+    /// Up to and including the last event with a timestamp (in milliseconds since the epoch) that is not after this one.
+    Timestamp(i64),
+}
+
+/// Sources the aggregate up to the given point in its history and returns the resulting projection, e.g., for "as-of"
+/// views on audit and debugging screens. Nothing is written to the event store.
+pub async fn project_aggregate_as_of<P: VecU8Message + AggregateContext + Send + Clone + std::fmt::Debug + 'static>(
+    axon_server_handle: &AxonServerHandle,
+    aggregate_definition: &AggregateDefinition<P>,
+    aggregate_id: &str,
+    as_of: AsOf
+) -> Result<P> {
+    let mut client = axon_server_handle.event_store_client();
+    let max_sequence = match as_of {
+        AsOf::Sequence(sequence) => sequence,
+        AsOf::Timestamp(_) => i64::MAX,
+    };
+    let mut events = query_events_up_to(&mut client, aggregate_id, max_sequence).await?;
+    if let AsOf::Timestamp(timestamp) = as_of {
+        if let Some(position) = events.iter().position(|event| event.timestamp > timestamp) {
+            events.truncate(position);
+        }
+    }
+    debug!("Project aggregate as of: {:?}: {:?}: events: {:?}", aggregate_id, as_of, events.len());
+    aggregate_definition.sequence_gap_policy().apply(aggregate_id, 0, &events)?;
+    if let Some(claim_check) = aggregate_definition.claim_check() {
+        for event in events.iter_mut() {
+            claim_check.resolve_event(event).await?;
+        }
+    }
+    aggregate_definition.replay(aggregate_definition.empty_projection(), events).await
+}
@@ -0,0 +1,238 @@
+use anyhow::{anyhow,Result};
+use tracing::{debug,warn};
+use std::sync::{Arc,Mutex};
+use std::time::Duration;
+use tokio_postgres::Client;
+use super::event_processor::{TokenStore,TrackingConfig};
+
+/// Name of the table in which a `PostgresTokenStore` keeps tracking tokens by default.
+pub const DEFAULT_TOKEN_TABLE: &str = "tracking_token";
+
+/// Keeps the tracking token of an event processor in a row of a PostgreSQL table, so that projections that are stored
+/// in a relational database need no other store for their tokens. Query models delegate their `TokenStore` methods to
+/// it, like the example delegates to Elastic Search.
+///
+/// The row of a token key has a version that is incremented by every write. The store remembers the version that it
+/// last read or wrote, and only stores a token when the row still has that version. When another instance took the
+/// row over, e.g., after the claim of this instance expired, the token is not stored and the conflict is logged; the
+/// events since the last stored token are handled again by the instance that holds the row.
+///
+/// The token of a row that was created by a claim or by a schema version stays empty until the event processor
+/// initializes it, so that the initial position of the processor applies.
+///
+/// Claims of segments expire after `claim_timeout` without a stored token or a renewal, so that segments of an
+/// instance that stopped are taken over. A renewal does not change the version of the row. Choose a timeout that is
+/// longer than the `claim_interval` of the segmented event processor. Create the table with `create_token_table`.
+#[derive(Clone)]
+pub struct PostgresTokenStore {
+    client: Arc<Client>,
+    table: String,
+    tracking: TrackingConfig,
+    claim_timeout: Duration,
+    version: Arc<Mutex<Option<i64>>>,
+}
+
+pub fn create_postgres_token_store(client: Arc<Client>, tracking: &TrackingConfig) -> PostgresTokenStore {
+    PostgresTokenStore {
+        client,
+        table: DEFAULT_TOKEN_TABLE.to_string(),
+        tracking: tracking.clone(),
+        claim_timeout: Duration::from_secs(60),
+        version: Arc::new(Mutex::new(None)),
+    }
+}
+
+impl PostgresTokenStore {
+    /// Keeps the tokens in the given table. The name is used as is in SQL statements, so it must come from
+    /// configuration, never from input.
+    pub fn with_table(mut self, table: &str) -> Self {
+        self.table = table.to_string();
+        self
+    }
+
+    pub fn with_claim_timeout(mut self, claim_timeout: Duration) -> Self {
+        self.claim_timeout = claim_timeout;
+        self
+    }
+
+    /// Creates the table of the token store, if it does not exist yet.
+    pub async fn create_token_table(&self) -> Result<()> {
+        let statement = format!(
+            "CREATE TABLE IF NOT EXISTS {} (\
+                token_key TEXT PRIMARY KEY, \
+                processor_name TEXT NOT NULL, \
+                token BIGINT, \
+                schema_version BIGINT, \
+                owner TEXT, \
+                claimed_at TIMESTAMPTZ, \
+                recent_events JSONB, \
+                version BIGINT NOT NULL\
+            ); \
+            ALTER TABLE {} ADD COLUMN IF NOT EXISTS recent_events JSONB",
+            self.table, self.table
+        );
+        self.client.batch_execute(statement.as_str()).await?;
+        Ok(())
+    }
+
+    fn expected_version(&self) -> Option<i64> {
+        self.version.lock().ok().and_then(|version| *version)
+    }
+
+    fn remember_version(&self, new_version: Option<i64>) {
+        if let Ok(mut version) = self.version.lock() {
+            *version = new_version;
+        }
+    }
+
+    async fn write_token(&self, token: i64, window: Option<&[String]>) -> Result<()> {
+        let expected_version = match self.expected_version() {
+            Some(version) => version,
+            None => self.read_version().await?,
+        };
+        let statement = format!(
+            "UPDATE {} SET token = $1, claimed_at = now(), recent_events = COALESCE($4::TEXT::JSONB, recent_events), \
+            version = version + 1 WHERE token_key = $2 AND version = $3 RETURNING version",
+            self.table
+        );
+        let window = window.map(serde_json::to_string).transpose()?;
+        let row = self.client.query_opt(statement.as_str(), &[&token, &self.tracking.token_key, &expected_version, &window]).await?;
+        match row {
+            Some(row) => {
+                self.remember_version(Some(row.get(0)));
+                Ok(())
+            }
+            None => {
+                self.remember_version(None);
+                Err(anyhow!("Tracking token was changed by another instance: {:?}: expected version: {:?}", self.tracking.token_key, expected_version))
+            }
+        }
+    }
+
+    async fn read_version(&self) -> Result<i64> {
+        let statement = format!("SELECT version FROM {} WHERE token_key = $1", self.table);
+        let row = self.client.query_opt(statement.as_str(), &[&self.tracking.token_key]).await?
+            .ok_or_else(|| anyhow!("No tracking token: {:?}", self.tracking.token_key))?;
+        let version = row.get(0);
+        self.remember_version(Some(version));
+        Ok(version)
+    }
+
+    async fn upsert_token(&self, token: i64, overwrite: bool) -> Result<()> {
+        let on_conflict = if overwrite {
+            "DO UPDATE SET token = EXCLUDED.token, recent_events = NULL, version = tt.version + 1"
+        } else {
+            "DO UPDATE SET token = EXCLUDED.token, version = tt.version + 1 WHERE tt.token IS NULL"
+        };
+        let statement = format!(
+            "INSERT INTO {} AS tt (token_key, processor_name, token, version) VALUES ($1, $2, $3, 0) \
+            ON CONFLICT (token_key) {} RETURNING version",
+            self.table, on_conflict
+        );
+        let row = self.client.query_opt(statement.as_str(), &[&self.tracking.token_key, &self.tracking.processor_name, &token]).await?;
+        self.remember_version(row.map(|row| row.get(0)));
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl TokenStore for PostgresTokenStore {
+    async fn store_token(&self, token: i64) -> Result<()> {
+        self.write_token(token, None).await
+            .map_err(|e| anyhow!("Cannot store tracking token: {:?}: {:?}: {:?}", self.tracking.token_key, token, e))
+    }
+
+    async fn retrieve_token(&self) -> Result<Option<i64>> {
+        let statement = format!("SELECT token, version FROM {} WHERE token_key = $1", self.table);
+        let row = match self.client.query_opt(statement.as_str(), &[&self.tracking.token_key]).await? {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        self.remember_version(Some(row.get(1)));
+        let token: Option<i64> = row.get(0);
+        debug!("Retrieved token: {:?}: {:?}", self.tracking.token_key, token);
+        Ok(token)
+    }
+
+    async fn store_token_with_window(&self, token: i64, window: &[String]) -> Result<()> {
+        self.write_token(token, Some(window)).await
+            .map_err(|e| anyhow!("Cannot store tracking token: {:?}: {:?}: {:?}", self.tracking.token_key, token, e))
+    }
+
+    async fn retrieve_deduplication_window(&self) -> Result<Vec<String>> {
+        let statement = format!("SELECT recent_events::TEXT FROM {} WHERE token_key = $1", self.table);
+        let row = self.client.query_opt(statement.as_str(), &[&self.tracking.token_key]).await?;
+        match row.and_then(|row| row.get::<_,Option<String>>(0)) {
+            Some(window) => Ok(serde_json::from_str(&window)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    async fn store_schema_version(&self, schema_version: i64) -> Result<()> {
+        let statement = format!(
+            "INSERT INTO {} AS tt (token_key, processor_name, schema_version, version) VALUES ($1, $2, $3, 0) \
+            ON CONFLICT (token_key) DO UPDATE SET schema_version = EXCLUDED.schema_version, version = tt.version + 1 \
+            RETURNING version",
+            self.table
+        );
+        let row = self.client.query_one(statement.as_str(), &[&self.tracking.token_key, &self.tracking.processor_name, &schema_version]).await?;
+        self.remember_version(Some(row.get(0)));
+        Ok(())
+    }
+
+    async fn retrieve_schema_version(&self) -> Result<Option<i64>> {
+        let statement = format!("SELECT schema_version FROM {} WHERE token_key = $1", self.table);
+        let row = self.client.query_opt(statement.as_str(), &[&self.tracking.token_key]).await?;
+        Ok(row.and_then(|row| row.get(0)))
+    }
+
+    fn for_tracking(&self, tracking: &TrackingConfig) -> Self {
+        let mut store = self.clone();
+        store.tracking = tracking.clone();
+        store.version = Arc::new(Mutex::new(None));
+        store
+    }
+
+    async fn claim_segment(&self, owner: &str) -> Result<bool> {
+        let statement = format!(
+            "INSERT INTO {} AS tt (token_key, processor_name, owner, claimed_at, version) VALUES ($1, $2, $3, now(), 0) \
+            ON CONFLICT (token_key) DO UPDATE SET owner = EXCLUDED.owner, claimed_at = now(), version = tt.version + 1 \
+            WHERE tt.owner IS NULL OR tt.owner = EXCLUDED.owner OR tt.claimed_at IS NULL \
+            OR tt.claimed_at < now() - make_interval(secs => $4) \
+            RETURNING version",
+            self.table
+        );
+        let claim_timeout = self.claim_timeout.as_secs_f64();
+        let row = self.client.query_opt(statement.as_str(), &[&self.tracking.token_key, &self.tracking.processor_name, &owner, &claim_timeout]).await?;
+        let claimed = row.is_some();
+        self.remember_version(row.map(|row| row.get(0)));
+        Ok(claimed)
+    }
+
+    async fn renew_segment_claim(&self, owner: &str) -> Result<bool> {
+        let statement = format!("UPDATE {} SET claimed_at = now() WHERE token_key = $1 AND owner = $2", self.table);
+        let renewed = self.client.execute(statement.as_str(), &[&self.tracking.token_key, &owner]).await?;
+        Ok(renewed > 0)
+    }
+
+    async fn release_segment(&self, owner: &str) -> Result<()> {
+        let statement = format!(
+            "UPDATE {} SET owner = NULL, claimed_at = NULL, version = version + 1 WHERE token_key = $1 AND owner = $2",
+            self.table
+        );
+        let released = self.client.execute(statement.as_str(), &[&self.tracking.token_key, &owner]).await?;
+        if released == 0 {
+            warn!("Segment was not claimed by this owner: {:?}: {:?}", self.tracking.token_key, owner);
+        }
+        self.remember_version(None);
+        Ok(())
+    }
+
+    async fn reset_token(&self, token: i64) -> Result<()> {
+        self.upsert_token(token, true).await
+    }
+
+    async fn initialize_token(&self, token: i64) -> Result<()> {
+        self.upsert_token(token, false).await
+    }
+}
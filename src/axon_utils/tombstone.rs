@@ -0,0 +1,82 @@
+use anyhow::{anyhow,Result};
+use bytes::Bytes;
+use prost::DecodeError;
+use std::sync::Arc;
+use tracing::{debug,warn};
+use super::handler_registry::{SubscriptionHandle,TheHandlerRegistry};
+
+/// Suffix of the names of tombstone events: events that mark the end of an entity, e.g., `GreetingDeletedEvent`.
+pub const TOMBSTONE_EVENT_SUFFIX: &str = "DeletedEvent";
+
+pub fn is_tombstone_event(event_name: &str) -> bool {
+    event_name.ends_with(TOMBSTONE_EVENT_SUFFIX) && event_name.len() > TOMBSTONE_EVENT_SUFFIX.len()
+}
+
+/// A store of query model documents that can remove a document by its id, e.g., a `PostgresDocumentStore` or an
+/// `EsDocumentIndex`. Deleting a document that does not exist is not an error, so that tombstone events can be
+/// replayed.
+#[tonic::async_trait]
+pub trait DocumentDeletes: Send + Sync {
+    async fn delete_document_by_id(&self, id: &str) -> Result<()>;
+}
+
+/// Derives the id of the document that a tombstone event removes.
+pub type DocumentIdFn<T> = Arc<dyn Fn(&T) -> String + Send + Sync>;
+
+impl<P: DocumentDeletes + Send + 'static, W: Clone + 'static> TheHandlerRegistry<P,W> {
+    /// Registers a handler for a tombstone event that deletes the document with the id that the given function derives
+    /// from the event, from the query model itself. Tombstone events are named `XxxDeletedEvent` by convention; other
+    /// names are accepted, with a warning.
+    pub fn deletes_document_by<T: Send + 'static>(
+        &mut self,
+        name: &str,
+        deserializer: &'static (dyn Fn(Bytes) -> Result<T,DecodeError> + Sync),
+        id_fn: impl Fn(&T) -> String + Send + Sync + 'static
+    ) -> Result<()> {
+        if self.handlers.contains_key(name) {
+            return Err(anyhow!("Handler already registered: {:?}", name))
+        }
+        if !is_tombstone_event(name) {
+            warn!("Tombstone event does not follow naming convention: {:?}: expected suffix: {:?}", name, TOMBSTONE_EVENT_SUFFIX);
+        }
+        let handle: Box<dyn SubscriptionHandle<P,W>> = Box::new(TombstoneSubscription {
+            name: name.to_string(),
+            deserializer,
+            id_fn: Arc::new(id_fn),
+        });
+        self.handlers.insert(name.to_string(), handle);
+        Ok(())
+    }
+}
+
+struct TombstoneSubscription<T: 'static> {
+    name: String,
+    deserializer: &'static (dyn Fn(Bytes) -> Result<T,DecodeError> + Sync),
+    id_fn: DocumentIdFn<T>,
+}
+
+#[tonic::async_trait]
+impl<P: DocumentDeletes + Send + 'static, T: Send + 'static, W: Clone + 'static> SubscriptionHandle<P,W> for TombstoneSubscription<T>
+{
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    async fn handle(&self, buf: Vec<u8>, projection: P) -> Result<Option<W>> {
+        let id = {
+            let message: T = (self.deserializer)(Bytes::from(buf))?;
+            (self.id_fn)(&message)
+        };
+        debug!("Delete document for tombstone event: {:?}: {:?}", self.name, id);
+        projection.delete_document_by_id(&id).await?;
+        Ok(None)
+    }
+
+    fn box_clone(&self) -> Box<dyn SubscriptionHandle<P,W>> {
+        Box::from(TombstoneSubscription {
+            name: self.name.clone(),
+            deserializer: self.deserializer,
+            id_fn: self.id_fn.clone(),
+        })
+    }
+}
@@ -0,0 +1,339 @@
+use anyhow::{anyhow,Result};
+use elasticsearch::{BulkParts,Elasticsearch};
+use elasticsearch::http::request::JsonBody;
+use tracing::{debug,error,info,warn};
+use serde_json::{json,Value};
+use std::collections::{HashSet,VecDeque};
+use std::sync::{Arc,Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{Receiver,Sender,channel};
+use tokio::sync::oneshot;
+use tokio::time::{Instant,delay_for,timeout_at};
+
+#[derive(Debug,Clone)]
+pub enum BulkOperation {
+    Index { index: String, id: String, document: Value },
+    Update { index: String, id: String, partial_document: Value },
+    ScriptedUpsert { index: String, id: String, script: Value, upsert: Value },
+    Delete { index: String, id: String },
+}
+
+/// Settings of a bulk writer.
+///
+/// The buffer is flushed when it contains `max_actions` operations or when the oldest operation in the buffer is
+/// `flush_interval` old, whichever comes first. When the queue contains `queue_capacity` operations, callers have to
+/// wait until the writer catches up, so that a slow Elastic Search slows down the event processor instead of
+/// letting the queue grow without bounds. Operations that Elastic Search rejects are retried `max_retries` times,
+/// starting after `retry_backoff`, and doubling it each time.
+#[derive(Debug,Clone)]
+pub struct BulkWriterConfig {
+    pub max_actions: usize,
+    pub flush_interval: Duration,
+    pub queue_capacity: usize,
+    pub max_retries: u32,
+    pub retry_backoff: Duration,
+}
+
+impl Default for BulkWriterConfig {
+    fn default() -> Self {
+        BulkWriterConfig {
+            max_actions: 500,
+            flush_interval: Duration::from_millis(200),
+            queue_capacity: 1000,
+            max_retries: 5,
+            retry_backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum BulkCommand {
+    Operation(BulkOperation),
+    Checkpoint(BulkOperation),
+    Flush(oneshot::Sender<Result<()>>),
+}
+
+#[derive(Debug,Clone)]
+struct PendingOperation {
+    operation: BulkOperation,
+    checkpoint: bool,
+}
+
+#[derive(Debug,Clone)]
+pub struct BulkWriter {
+    tx: Sender<BulkCommand>,
+    stalled: Arc<Mutex<Option<String>>>,
+}
+
+/// Spawns the task that writes the buffered operations and returns a handle to submit operations to it.
+///
+/// Operations are written in the order in which they are submitted: an operation on a document is never written
+/// before an earlier operation on the same document, and a checkpoint (e.g., a tracking token) is only written after
+/// all earlier operations were acknowledged. Operations that cannot be written are kept, in order, and retried until
+/// they are written. In the meantime the writer is stalled: new operations are refused with an error, so that the
+/// caller stops instead of running ahead of what is stored.
+pub fn create_bulk_writer(client: Elasticsearch, config: BulkWriterConfig) -> BulkWriter {
+    let (tx, rx) = channel(config.queue_capacity);
+    let stalled = Arc::new(Mutex::new(None));
+    tokio::spawn(bulk_writer_task(client, config, rx, stalled.clone()));
+    BulkWriter { tx, stalled }
+}
+
+impl BulkWriter {
+    pub async fn index(&self, index: &str, id: &str, document: Value) -> Result<()> {
+        self.submit(BulkOperation::Index {
+            index: index.to_string(),
+            id: id.to_string(),
+            document,
+        }).await
+    }
+
+    pub async fn update(&self, index: &str, id: &str, partial_document: Value) -> Result<()> {
+        self.submit(BulkOperation::Update {
+            index: index.to_string(),
+            id: id.to_string(),
+            partial_document,
+        }).await
+    }
+
+    /// Runs the script on the document, or indexes `upsert` if the document does not exist yet.
+    pub async fn scripted_upsert(&self, index: &str, id: &str, script: Value, upsert: Value) -> Result<()> {
+        self.submit(BulkOperation::ScriptedUpsert {
+            index: index.to_string(),
+            id: id.to_string(),
+            script,
+            upsert,
+        }).await
+    }
+
+    pub async fn delete(&self, index: &str, id: &str) -> Result<()> {
+        self.submit(BulkOperation::Delete {
+            index: index.to_string(),
+            id: id.to_string(),
+        }).await
+    }
+
+    pub async fn submit(&self, operation: BulkOperation) -> Result<()> {
+        self.send(BulkCommand::Operation(operation)).await
+    }
+
+    /// Indexes a document that records progress, e.g., a tracking token, once all operations that were submitted
+    /// before it are written.
+    pub async fn checkpoint(&self, index: &str, id: &str, document: Value) -> Result<()> {
+        self.send(BulkCommand::Checkpoint(BulkOperation::Index {
+            index: index.to_string(),
+            id: id.to_string(),
+            document,
+        })).await
+    }
+
+    /// Waits until all operations that were submitted before are written.
+    pub async fn flush(&self) -> Result<()> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let mut tx = self.tx.clone();
+        tx.send(BulkCommand::Flush(reply_tx)).await.map_err(|_| anyhow!("Bulk writer stopped"))?;
+        reply_rx.await?
+    }
+
+    async fn send(&self, command: BulkCommand) -> Result<()> {
+        if let Some(error) = self.stalled.lock().ok().and_then(|stalled| stalled.clone()) {
+            return Err(anyhow!("Bulk writer stalled: {}", error));
+        }
+        let mut tx = self.tx.clone();
+        tx.send(command).await.map_err(|_| anyhow!("Bulk writer stopped"))
+    }
+}
+
+async fn bulk_writer_task(client: Elasticsearch, config: BulkWriterConfig, mut rx: Receiver<BulkCommand>, stalled: Arc<Mutex<Option<String>>>) {
+    let mut buffer: VecDeque<PendingOperation> = VecDeque::new();
+    let mut deadline: Option<Instant> = None;
+    loop {
+        let next = match deadline {
+            Some(deadline) => timeout_at(deadline, rx.recv()).await.ok(),
+            None => Some(rx.recv().await),
+        };
+        let flush = match next {
+            None => {
+                debug!("Bulk writer: flush interval expired");
+                true
+            }
+            Some(None) => {
+                debug!("Bulk writer: stop");
+                if let Err(e) = flush_buffer(&client, &config, &mut buffer, &stalled).await {
+                    error!("Bulk writer: stopped with operations that were not written: {:?}: {:?}", buffer.len(), e);
+                }
+                break;
+            }
+            Some(Some(BulkCommand::Operation(operation))) => {
+                buffer.push_back(PendingOperation { operation, checkpoint: false });
+                buffer.len() >= config.max_actions
+            }
+            Some(Some(BulkCommand::Checkpoint(operation))) => {
+                buffer.push_back(PendingOperation { operation, checkpoint: true });
+                buffer.len() >= config.max_actions
+            }
+            Some(Some(BulkCommand::Flush(reply))) => {
+                let result = flush_buffer(&client, &config, &mut buffer, &stalled).await;
+                reply.send(result).ok();
+                false
+            }
+        };
+        if flush {
+            flush_buffer(&client, &config, &mut buffer, &stalled).await.ok();
+        }
+        deadline = if buffer.is_empty() {
+            None
+        } else if is_stalled(&stalled) {
+            Some(Instant::now() + config.retry_backoff)
+        } else {
+            deadline.or_else(|| Some(Instant::now() + config.flush_interval))
+        };
+    }
+}
+
+fn is_stalled(stalled: &Arc<Mutex<Option<String>>>) -> bool {
+    stalled.lock().map(|stalled| stalled.is_some()).unwrap_or(false)
+}
+
+// Writes the buffer chunk by chunk, and stops at the first chunk that cannot be written completely. The operations of
+// that chunk that were not written, and all operations after it, stay in the buffer, and the writer is stalled until
+// they are written.
+async fn flush_buffer(client: &Elasticsearch, config: &BulkWriterConfig, buffer: &mut VecDeque<PendingOperation>, stalled: &Arc<Mutex<Option<String>>>) -> Result<()> {
+    while !buffer.is_empty() {
+        let chunk = take_chunk(buffer);
+        let unwritten = write_chunk(client, config, chunk).await;
+        if !unwritten.is_empty() {
+            let count = unwritten.len();
+            for pending in unwritten.into_iter().rev() {
+                buffer.push_front(pending);
+            }
+            let error = format!("{:?} operations not written, {:?} waiting", count, buffer.len() - count);
+            error!("Bulk writer: stalled: {}", error);
+            if let Ok(mut stalled) = stalled.lock() {
+                *stalled = Some(error.clone());
+            }
+            return Err(anyhow!("Bulk write failed: {}", error));
+        }
+    }
+    if let Ok(mut stalled) = stalled.lock() {
+        if stalled.take().is_some() {
+            info!("Bulk writer: resumed");
+        }
+    }
+    Ok(())
+}
+
+// Takes the operations from the head of the buffer that can be sent in a single bulk request: operations on distinct
+// documents, so that retrying some of them does not reorder operations on the same document. A checkpoint starts a new
+// chunk, so that it is only sent after all earlier operations were written.
+fn take_chunk(buffer: &mut VecDeque<PendingOperation>) -> Vec<PendingOperation> {
+    let mut chunk: Vec<PendingOperation> = Vec::new();
+    let mut documents = HashSet::new();
+    while let Some(pending) = buffer.front() {
+        let key = document_key(&pending.operation);
+        if !chunk.is_empty() && (pending.checkpoint || documents.contains(&key)) {
+            break;
+        }
+        documents.insert(key);
+        chunk.extend(buffer.pop_front());
+    }
+    chunk
+}
+
+fn document_key(operation: &BulkOperation) -> (String,String) {
+    let (index, id) = match operation {
+        BulkOperation::Index { index, id, .. } => (index, id),
+        BulkOperation::Update { index, id, .. } => (index, id),
+        BulkOperation::ScriptedUpsert { index, id, .. } => (index, id),
+        BulkOperation::Delete { index, id } => (index, id),
+    };
+    (index.clone(), id.clone())
+}
+
+// Returns the operations of the chunk that were not written, in order.
+async fn write_chunk(client: &Elasticsearch, config: &BulkWriterConfig, chunk: Vec<PendingOperation>) -> Vec<PendingOperation> {
+    let mut pending = chunk;
+    let mut failed = Vec::new();
+    let mut backoff = config.retry_backoff;
+    for attempt in 0..=config.max_retries {
+        if attempt > 0 {
+            debug!("Bulk writer: retry: attempt: {:?}: operations: {:?}: backoff: {:?}", attempt, pending.len(), backoff);
+            delay_for(backoff).await;
+            backoff *= 2;
+        }
+        let operations: Vec<BulkOperation> = pending.iter().map(|pending| pending.operation.clone()).collect();
+        match send_bulk(client, &operations).await {
+            Ok((rejected, item_failures)) => {
+                let mut retry = Vec::new();
+                for (index, pending_operation) in pending.into_iter().enumerate() {
+                    if rejected.contains(&index) {
+                        retry.push(pending_operation);
+                    } else if item_failures.contains(&index) {
+                        failed.push(pending_operation);
+                    }
+                }
+                pending = retry;
+                if pending.is_empty() {
+                    break;
+                }
+            }
+            Err(e) => {
+                warn!("Bulk writer: request failed: {:?}", e);
+            }
+        }
+    }
+    if !pending.is_empty() {
+        warn!("Bulk writer: gave up after {:?} retries: {:?} operations not written", config.max_retries, pending.len());
+    }
+    failed.extend(pending);
+    failed
+}
+
+// Returns the positions of the operations that were rejected because Elastic Search was too busy (status 429) and of
+// the operations that failed for other reasons. A rejection of the request as a whole is returned as an error.
+async fn send_bulk(client: &Elasticsearch, operations: &[BulkOperation]) -> Result<(Vec<usize>,Vec<usize>)> {
+    let mut body: Vec<JsonBody<Value>> = Vec::with_capacity(operations.len() * 2);
+    for operation in operations {
+        match operation {
+            BulkOperation::Index { index, id, document } => {
+                body.push(json!({ "index": { "_index": index, "_id": id } }).into());
+                body.push(document.clone().into());
+            }
+            BulkOperation::Update { index, id, partial_document } => {
+                body.push(json!({ "update": { "_index": index, "_id": id } }).into());
+                body.push(json!({ "doc": partial_document }).into());
+            }
+            BulkOperation::ScriptedUpsert { index, id, script, upsert } => {
+                body.push(json!({ "update": { "_index": index, "_id": id } }).into());
+                body.push(json!({ "script": script, "upsert": upsert }).into());
+            }
+            BulkOperation::Delete { index, id } => {
+                body.push(json!({ "delete": { "_index": index, "_id": id } }).into());
+            }
+        }
+    }
+    let response = client.bulk(BulkParts::None).body(body).send().await?;
+    let status_code = response.status_code();
+    if !status_code.is_success() {
+        return Err(anyhow!("Bulk request rejected: {:?}", status_code));
+    }
+    let value = response.json::<Value>().await?;
+    let mut rejected = Vec::new();
+    let mut failed = Vec::new();
+    if value["errors"].as_bool().unwrap_or(false) {
+        let items = value["items"].as_array().cloned().unwrap_or_default();
+        for (position, (operation, item)) in operations.iter().zip(items.iter()).enumerate() {
+            let result = item.as_object().and_then(|o| o.values().next()).cloned().unwrap_or(Value::Null);
+            match result["status"].as_u64() {
+                Some(429) => rejected.push(position),
+                Some(status) if status >= 300 && !(status == 404 && matches!(operation, BulkOperation::Delete { .. })) => {
+                    warn!("Bulk writer: operation failed: {:?}: {:?}", operation, result["error"]);
+                    failed.push(position);
+                }
+                _ => (),
+            }
+        }
+    }
+    debug!("Bulk writer: written: {:?}: rejected: {:?}: failed: {:?}", operations.len(), rejected.len(), failed.len());
+    Ok((rejected, failed))
+}
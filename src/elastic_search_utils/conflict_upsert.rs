@@ -0,0 +1,106 @@
+use anyhow::{anyhow,Result};
+use elasticsearch::{Elasticsearch,GetParts,IndexParts,UpdateParts};
+use elasticsearch::params::OpType;
+use tracing::debug;
+use serde_json::{Value,json};
+use super::BulkWriter;
+use crate::axon_utils::{ConflictStrategy,PROJECTION_TOKEN_FIELD,with_projection_token};
+
+const MAX_MERGE_ATTEMPTS: u32 = 10;
+
+// Painless script that replaces the document unless the stored token is higher (or, to ignore older writes, at least
+// as high).
+const GUARDED_REPLACE: &str = "def stored = ctx._source[params.field]; \
+    if (stored == null || (params.strict ? stored < params.token : stored <= params.token)) { \
+        ctx._source.clear(); ctx._source.putAll(params.document); \
+    } else { ctx.op = 'none'; }";
+
+impl BulkWriter {
+    /// Writes a document that was projected from the event with the given token, according to the conflict strategy.
+    /// Merges need the stored document, so they cannot be buffered: use `upsert_with_strategy` for those.
+    pub async fn upsert_with_token(&self, index: &str, id: &str, document: Value, token: i64, strategy: &ConflictStrategy) -> Result<()> {
+        let document = with_projection_token(document, token)?;
+        match guarded_replace_script(strategy, &document, token) {
+            Some(script) => self.scripted_upsert(index, id, script, document).await,
+            None if matches!(strategy, ConflictStrategy::Overwrite) => self.index(index, id, document).await,
+            None => Err(anyhow!("Conflict strategy needs the stored document: {:?}: use upsert_with_strategy", strategy)),
+        }
+    }
+}
+
+/// Writes a document that was projected from the event with the given token, according to the conflict strategy, and
+/// returns whether the stored document changed. The write is atomic: a merge is retried when the document was changed
+/// by another writer in the meantime.
+pub async fn upsert_with_strategy(client: &Elasticsearch, index: &str, id: &str, document: Value, token: i64, strategy: &ConflictStrategy) -> Result<bool> {
+    if !matches!(strategy, ConflictStrategy::Merge(_)) {
+        return replace_with_strategy(client, index, id, document, token, strategy).await;
+    }
+    for attempt in 1..=MAX_MERGE_ATTEMPTS {
+        let response = client.get(GetParts::IndexId(index, id)).send().await?;
+        let stored = match response.status_code().as_u16() {
+            404 => None,
+            status if status < 300 => Some(response.json::<Value>().await?),
+            _ => return Err(anyhow!("Could not read document: {:?}: {:?}: {:?}", index, id, response.status_code())),
+        };
+        let source = stored.as_ref().map(|stored| &stored["_source"]);
+        let merged = match strategy.resolve(source, document.clone(), token)? {
+            Some(merged) => merged,
+            None => return Ok(false),
+        };
+        let request = client.index(IndexParts::IndexId(index, id));
+        let request = match &stored {
+            Some(stored) => request
+                .if_seq_no(stored["_seq_no"].as_i64().unwrap_or(0))
+                .if_primary_term(stored["_primary_term"].as_i64().unwrap_or(0)),
+            None => request.op_type(OpType::Create),
+        };
+        let response = request.body(merged).send().await?;
+        match response.status_code().as_u16() {
+            409 => debug!("Merge conflict: {:?}: {:?}: attempt: {:?}", index, id, attempt),
+            status if status < 300 => return Ok(true),
+            _ => return Err(anyhow!("Could not write document: {:?}: {:?}: {:?}", index, id, response.status_code())),
+        }
+    }
+    Err(anyhow!("Gave up merging document after {:?} attempts: {:?}: {:?}", MAX_MERGE_ATTEMPTS, index, id))
+}
+
+async fn replace_with_strategy(client: &Elasticsearch, index: &str, id: &str, document: Value, token: i64, strategy: &ConflictStrategy) -> Result<bool> {
+    let document = with_projection_token(document, token)?;
+    let script = match guarded_replace_script(strategy, &document, token) {
+        Some(script) => script,
+        None => {
+            let response = client.index(IndexParts::IndexId(index, id)).body(document).send().await?;
+            if !response.status_code().is_success() {
+                return Err(anyhow!("Could not write document: {:?}: {:?}: {:?}", index, id, response.status_code()));
+            }
+            return Ok(true);
+        }
+    };
+    let response = client.update(UpdateParts::IndexId(index, id))
+        .body(json!({ "script": script, "upsert": document }))
+        .send()
+        .await?;
+    if !response.status_code().is_success() {
+        return Err(anyhow!("Could not write document: {:?}: {:?}: {:?}", index, id, response.status_code()));
+    }
+    let value = response.json::<Value>().await?;
+    Ok(value["result"].as_str() != Some("noop"))
+}
+
+fn guarded_replace_script(strategy: &ConflictStrategy, document: &Value, token: i64) -> Option<Value> {
+    let strict = match strategy {
+        ConflictStrategy::LastWriterWins => false,
+        ConflictStrategy::IgnoreOlder => true,
+        _ => return None,
+    };
+    Some(json!({
+        "source": GUARDED_REPLACE,
+        "lang": "painless",
+        "params": {
+            "field": PROJECTION_TOKEN_FIELD,
+            "strict": strict,
+            "token": token,
+            "document": document,
+        },
+    }))
+}
@@ -0,0 +1,118 @@
+use anyhow::{anyhow,Result};
+use bytes::Bytes;
+use elasticsearch::{DeleteParts,Elasticsearch,IndexParts};
+use elasticsearch::params::Refresh;
+use futures_util::{StreamExt,pin_mut};
+use prost::Message;
+use serde_json::{Value,json};
+use tracing::{debug,error};
+use super::{IndexDefinition,IndexStatus,create_index_definition,create_search_after,ensure_index,search_after_stream};
+use crate::axon_server::event::Event;
+use crate::axon_utils::{DeadLetterStore,DeadLetteredEvent};
+
+/// Dead-letter store that keeps each dead-lettered event as a document in an Elastic Search index, under the key of the
+/// dead letter. The event is stored as base64-encoded protobuf in the `event` field, so that it can be re-delivered with
+/// its meta-data. The other fields are for inspection in Kibana. Create the index with `ensure_dead_letter_index`.
+#[derive(Debug,Clone)]
+pub struct EsDeadLetterStore {
+    client: Elasticsearch,
+    index: String,
+}
+
+pub fn create_es_dead_letter_store(client: Elasticsearch, index: &str) -> EsDeadLetterStore {
+    EsDeadLetterStore {
+        client,
+        index: index.to_string(),
+    }
+}
+
+impl EsDeadLetterStore {
+    pub fn index_definition(&self) -> IndexDefinition {
+        create_index_definition(&self.index, 1, json!({
+            "properties": {
+                "key": { "type": "keyword" },
+                "token": { "type": "long" },
+                "message_identifier": { "type": "keyword" },
+                "aggregate_identifier": { "type": "keyword" },
+                "payload_type": { "type": "keyword" },
+                "group": { "type": "keyword" },
+                "reason": { "type": "text" },
+                "timestamp": { "type": "date", "format": "epoch_millis" },
+                "event": { "type": "binary" },
+            }
+        }))
+    }
+
+    pub async fn ensure_dead_letter_index(&self) -> Result<IndexStatus> {
+        ensure_index(&self.client, &self.index_definition()).await
+    }
+}
+
+#[tonic::async_trait]
+impl DeadLetterStore for EsDeadLetterStore {
+    async fn dead_letter(&self, event: DeadLetteredEvent) -> Result<()> {
+        let key = event.key();
+        error!("Dead-lettered event: {:?}: token: {:?}: {}", key, event.token, event.reason);
+        let response = self.client
+            .index(IndexParts::IndexId(&self.index, &key))
+            .refresh(Refresh::WaitFor)
+            .body(to_document(&event)?)
+            .send()
+            .await?;
+        if !response.status_code().is_success() {
+            return Err(anyhow!("Could not store dead letter: {:?}: {:?}", key, response.status_code()));
+        }
+        Ok(())
+    }
+
+    async fn dead_letters(&self) -> Result<Vec<DeadLetteredEvent>> {
+        let search = create_search_after(&self.index, json!({ "match_all": {} }), json!([{ "token": "asc" }, { "key": "asc" }]));
+        let hits = search_after_stream(self.client.clone(), search);
+        pin_mut!(hits);
+        let mut dead_letters = Vec::new();
+        while let Some(document) = hits.next().await {
+            dead_letters.push(from_document(&document?["_source"])?);
+        }
+        debug!("Dead letters: {:?}: {:?}", self.index, dead_letters.len());
+        Ok(dead_letters)
+    }
+
+    async fn remove_dead_letter(&self, key: &str) -> Result<()> {
+        let response = self.client
+            .delete(DeleteParts::IndexId(&self.index, key))
+            .refresh(Refresh::WaitFor)
+            .send()
+            .await?;
+        if !response.status_code().is_success() {
+            return Err(anyhow!("Could not remove dead letter: {:?}: {:?}", key, response.status_code()));
+        }
+        Ok(())
+    }
+}
+
+fn to_document(dead_letter: &DeadLetteredEvent) -> Result<Value> {
+    let event = &dead_letter.event;
+    let mut buf = Vec::new();
+    event.encode(&mut buf)?;
+    Ok(json!({
+        "key": dead_letter.key(),
+        "token": dead_letter.token,
+        "message_identifier": event.message_identifier,
+        "aggregate_identifier": event.aggregate_identifier,
+        "payload_type": event.payload.as_ref().map(|payload| payload.r#type.clone()),
+        "group": dead_letter.group,
+        "reason": dead_letter.reason,
+        "timestamp": event.timestamp,
+        "event": base64::encode(buf),
+    }))
+}
+
+fn from_document(source: &Value) -> Result<DeadLetteredEvent> {
+    let encoded = source["event"].as_str().ok_or_else(|| anyhow!("Dead letter without event: {:?}", source["key"]))?;
+    Ok(DeadLetteredEvent {
+        token: source["token"].as_i64().ok_or_else(|| anyhow!("Dead letter without token: {:?}", source["key"]))?,
+        event: Event::decode(Bytes::from(base64::decode(encoded)?))?,
+        reason: source["reason"].as_str().unwrap_or_default().to_string(),
+        group: source["group"].as_str().map(String::from),
+    })
+}
@@ -0,0 +1,52 @@
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::Value;
+use super::BulkWriter;
+use crate::axon_utils::DocumentDeletes;
+
+/// Maps a struct to a document in an Elastic Search index.
+///
+/// The serialized struct is the body of the document.
+pub trait EsDocument: Serialize {
+    fn index_name() -> &'static str;
+    fn id(&self) -> String;
+}
+
+impl BulkWriter {
+    /// Replaces the document with the same id, or creates it if it does not exist.
+    pub async fn upsert<D: EsDocument>(&self, document: &D) -> Result<()> {
+        let body = serde_json::to_value(document)?;
+        self.index(D::index_name(), &document.id(), body).await
+    }
+
+    /// Updates the given fields of an existing document.
+    pub async fn patch<D: EsDocument>(&self, id: &str, partial_document: Value) -> Result<()> {
+        self.update(D::index_name(), id, partial_document).await
+    }
+
+    pub async fn delete_document<D: EsDocument>(&self, id: &str) -> Result<()> {
+        self.delete(D::index_name(), id).await
+    }
+
+    /// Returns the documents in the index of `D`, e.g., to delete them in response to tombstone events.
+    pub fn document_index<D: EsDocument>(&self) -> EsDocumentIndex {
+        EsDocumentIndex {
+            bulk_writer: self.clone(),
+            index: D::index_name().to_string(),
+        }
+    }
+}
+
+/// The documents in one Elastic Search index, written through a bulk writer.
+#[derive(Debug,Clone)]
+pub struct EsDocumentIndex {
+    bulk_writer: BulkWriter,
+    index: String,
+}
+
+#[tonic::async_trait]
+impl DocumentDeletes for EsDocumentIndex {
+    async fn delete_document_by_id(&self, id: &str) -> Result<()> {
+        self.bulk_writer.delete(&self.index, id).await
+    }
+}
@@ -0,0 +1,156 @@
+use anyhow::{anyhow,Result};
+use elasticsearch::Elasticsearch;
+use elasticsearch::ilm::IlmPutLifecycleParts;
+use elasticsearch::indices::{IndicesCreateParts,IndicesDeleteParts,IndicesExistsParts,IndicesGetMappingParts};
+use tracing::{debug,info,warn};
+use serde_json::{json,Value};
+
+/// Describes an Elastic Search index that is owned by a projection.
+///
+/// The version is stored as `_meta.version` in the mapping of the index, so that a change in the mapping can be
+/// detected the next time the projection starts.
+#[derive(Debug,Clone)]
+pub struct IndexDefinition {
+    pub name: String,
+    pub version: i64,
+    pub settings: Value,
+    pub mappings: Value,
+    pub lifecycle_policy: Option<LifecyclePolicy>,
+}
+
+#[derive(Debug,Clone)]
+pub struct LifecyclePolicy {
+    pub name: String,
+    pub policy: Value,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum IndexStatus {
+    Created,
+    UpToDate,
+    VersionMismatch { found: Option<i64>, expected: i64 },
+}
+
+pub fn create_index_definition(name: &str, version: i64, mappings: Value) -> IndexDefinition {
+    IndexDefinition {
+        name: name.to_string(),
+        version,
+        settings: json!({}),
+        mappings,
+        lifecycle_policy: None,
+    }
+}
+
+impl IndexDefinition {
+    pub fn with_settings(mut self, settings: Value) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    pub fn with_lifecycle_policy(mut self, name: &str, policy: Value) -> Self {
+        self.lifecycle_policy = Some(LifecyclePolicy {
+            name: name.to_string(),
+            policy,
+        });
+        self
+    }
+}
+
+/// Creates the index if it does not exist yet. If it does exist, the version marker in the mapping is compared
+/// with the version in the definition. The existing index is never modified by this function.
+pub async fn ensure_index(client: &Elasticsearch, definition: &IndexDefinition) -> Result<IndexStatus> {
+    if !index_exists(client, &definition.name).await? && create_index(client, definition).await? {
+        return Ok(IndexStatus::Created);
+    }
+    let found = retrieve_index_version(client, &definition.name).await?;
+    debug!("Index version: {:?}: found: {:?}: expected: {:?}", definition.name, found, definition.version);
+    if found == Some(definition.version) {
+        Ok(IndexStatus::UpToDate)
+    } else {
+        warn!("Index version mismatch: {:?}: found: {:?}: expected: {:?}", definition.name, found, definition.version);
+        Ok(IndexStatus::VersionMismatch { found, expected: definition.version })
+    }
+}
+
+/// Drops the index (if it exists) and creates it again according to the definition. All documents are lost, so
+/// the caller is responsible for rebuilding the projection, e.g., by resetting the tracking token.
+pub async fn recreate_index(client: &Elasticsearch, definition: &IndexDefinition) -> Result<()> {
+    info!("Recreate index: {:?}: version: {:?}", definition.name, definition.version);
+    if index_exists(client, &definition.name).await? {
+        let response = client.indices()
+            .delete(IndicesDeleteParts::Index(&[&definition.name]))
+            .send()
+            .await?;
+        debug!("Delete index response: {:?}", response);
+        if !response.status_code().is_success() {
+            return Err(anyhow!("Could not delete index: {:?}: {:?}", definition.name, response.status_code()));
+        }
+    }
+    if !create_index(client, definition).await? {
+        return Err(anyhow!("Index was recreated concurrently: {:?}", definition.name));
+    }
+    Ok(())
+}
+
+async fn index_exists(client: &Elasticsearch, index_name: &str) -> Result<bool> {
+    let response = client.indices()
+        .exists(IndicesExistsParts::Index(&[index_name]))
+        .send()
+        .await?;
+    Ok(response.status_code().is_success())
+}
+
+async fn retrieve_index_version(client: &Elasticsearch, index_name: &str) -> Result<Option<i64>> {
+    let response = client.indices()
+        .get_mapping(IndicesGetMappingParts::Index(&[index_name]))
+        .send()
+        .await?;
+    let value = response.json::<Value>().await?;
+    debug!("Mapping of index: {:?}: {:?}", index_name, value);
+    Ok(value[index_name]["mappings"]["_meta"]["version"].as_i64())
+}
+
+// Returns false if the index was created concurrently by another process.
+async fn create_index(client: &Elasticsearch, definition: &IndexDefinition) -> Result<bool> {
+    let mut settings = definition.settings.clone();
+    if let Some(lifecycle_policy) = &definition.lifecycle_policy {
+        put_lifecycle_policy(client, lifecycle_policy).await?;
+        settings["index.lifecycle.name"] = Value::String(lifecycle_policy.name.clone());
+    }
+    let mut mappings = definition.mappings.clone();
+    mappings["_meta"] = json!({ "version": definition.version });
+    let response = client.indices()
+        .create(IndicesCreateParts::Index(&definition.name))
+        .body(json!({
+            "settings": settings,
+            "mappings": mappings,
+        }))
+        .send()
+        .await?;
+    debug!("Create index response: {:?}", response);
+    if !response.status_code().is_success() {
+        let body = response.json::<Value>().await?;
+        if body["error"]["type"] == "resource_already_exists_exception" {
+            debug!("Index already exists: {:?}", definition.name);
+            return Ok(false);
+        }
+        return Err(anyhow!("Could not create index: {:?}: {:?}", definition.name, body));
+    }
+    info!("Created index: {:?}: version: {:?}", definition.name, definition.version);
+    Ok(true)
+}
+
+async fn put_lifecycle_policy(client: &Elasticsearch, lifecycle_policy: &LifecyclePolicy) -> Result<()> {
+    let response = client.ilm()
+        .put_lifecycle(IlmPutLifecycleParts::Policy(&lifecycle_policy.name))
+        .body(json!({
+            "policy": lifecycle_policy.policy,
+        }))
+        .send()
+        .await?;
+    debug!("Put lifecycle policy response: {:?}", response);
+    if !response.status_code().is_success() {
+        return Err(anyhow!("Could not put lifecycle policy: {:?}: {:?}", lifecycle_policy.name, response.status_code()));
+    }
+    Ok(())
+}
@@ -0,0 +1,146 @@
+use anyhow::{Error,Result,anyhow};
+use elasticsearch::Elasticsearch;
+use elasticsearch::http::transport::Transport;
+use tracing::{debug,warn};
+use std::future::Future;
+use std::sync::{Arc,RwLock};
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/// Settings of a managed Elastic Search client.
+///
+/// The client is pinged every `ping_interval`. When a ping fails, the client is replaced by a new one for `url`.
+/// Calls that are wrapped in `with_retry` are retried `max_retries` times, with a backoff that starts at
+/// `retry_backoff` and doubles after each attempt.
+#[derive(Debug,Clone)]
+pub struct ManagedClientConfig {
+    pub url: String,
+    pub ping_interval: Duration,
+    pub max_retries: u32,
+    pub retry_backoff: Duration,
+}
+
+impl Default for ManagedClientConfig {
+    fn default() -> Self {
+        ManagedClientConfig {
+            url: super::ELASTIC_SEARCH_URL.to_string(),
+            ping_interval: Duration::from_secs(30),
+            max_retries: 5,
+            retry_backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Elastic Search client for long-lived projections that survives restarts of Elastic Search.
+///
+/// The current client is shared by all clones of the handle, so a rebuild is visible to all of them.
+#[derive(Debug,Clone)]
+pub struct ManagedClient {
+    client: Arc<RwLock<Elasticsearch>>,
+    config: ManagedClientConfig,
+}
+
+/// Wraps the client and spawns the task that pings it periodically.
+pub fn create_managed_client(client: Elasticsearch, config: ManagedClientConfig) -> ManagedClient {
+    let managed_client = ManagedClient {
+        client: Arc::new(RwLock::new(client)),
+        config,
+    };
+    tokio::spawn(ping_task(managed_client.clone()));
+    managed_client
+}
+
+impl ManagedClient {
+    /// Returns the current client. Don't hold on to it: it may be replaced after a failed ping.
+    pub fn client(&self) -> Elasticsearch {
+        self.client.read().map(|client| client.clone()).unwrap_or_else(|e| e.into_inner().clone())
+    }
+
+    /// Checks the current client and replaces it if Elastic Search can't be reached with it. Returns whether the
+    /// client is healthy afterwards.
+    pub async fn check(&self) -> bool {
+        if ping(&self.client()).await.is_ok() {
+            return true;
+        }
+        match connect(&self.config.url).await {
+            Ok(client) => {
+                debug!("Elastic Search: replaced stale client");
+                match self.client.write() {
+                    Ok(mut current) => *current = client,
+                    Err(e) => *e.into_inner() = client,
+                }
+                true
+            }
+            Err(e) => {
+                warn!("Elastic Search is not available: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// Calls the operation with the current client, and retries with backoff when it fails. Before each retry the
+    /// client is checked, so that a restart of Elastic Search does not exhaust the retries on a stale client.
+    pub async fn with_retry<T, F, R>(&self, label: &str, mut operation: F) -> Result<T>
+    where F: FnMut(Elasticsearch) -> R,
+          R: Future<Output = Result<T>>
+    {
+        let mut backoff = self.config.retry_backoff;
+        let mut last_error = None;
+        for attempt in 0..=self.config.max_retries {
+            if attempt > 0 {
+                debug!("Elastic Search: retry: {:?}: attempt: {:?}: backoff: {:?}", label, attempt, backoff);
+                delay_for(backoff).await;
+                backoff *= 2;
+                self.check().await;
+            }
+            match operation(self.client()).await {
+                Ok(result) => return Ok(result),
+                Err(e) => {
+                    warn!("Elastic Search: {:?} failed: {:?}", label, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("No attempts")).context(format!("Elastic Search: {:?}: gave up after {:?} retries", label, self.config.max_retries)))
+    }
+}
+
+async fn ping_task(managed_client: ManagedClient) {
+    loop {
+        delay_for(managed_client.config.ping_interval).await;
+        if Arc::strong_count(&managed_client.client) <= 1 {
+            debug!("Elastic Search: all handles dropped: stop pinging");
+            return;
+        }
+        managed_client.check().await;
+    }
+}
+
+async fn ping(client: &Elasticsearch) -> Result<()> {
+    let response = client.ping().send().await?;
+    let status_code = response.status_code();
+    if !status_code.is_success() {
+        return Err(anyhow!("Ping failed: {:?}", status_code));
+    }
+    Ok(())
+}
+
+async fn connect(url: &str) -> Result<Elasticsearch> {
+    let transport = Transport::single_node(url)?;
+    let client = Elasticsearch::new(transport);
+    ping(&client).await?;
+    Ok(client)
+}
+
+/// Recognizes errors of the Elastic Search client that are likely to go away by themselves: timeouts, connection
+/// failures, and responses that ask to try again later. Combine it with `is_transient_error` in a `RetryPolicy`.
+pub fn is_transient_es_error(error: &Error) -> bool {
+    error.chain().any(|cause| match cause.downcast_ref::<elasticsearch::Error>() {
+        Some(es_error) => match es_error.status_code() {
+            Some(status_code) => matches!(status_code.as_u16(), 429 | 502 | 503 | 504),
+            // Timeouts and connection failures come without a response.
+            None => !es_error.is_json(),
+        },
+        None => false,
+    })
+}
@@ -1,16 +1,38 @@
 use anyhow::Result;
 use elasticsearch::Elasticsearch;
 use elasticsearch::http::transport::Transport;
-use log::{debug,warn};
+use tracing::{debug,warn};
 use serde_json::Value;
 use std::time;
 use tokio::time::delay_for;
 use elasticsearch::cluster::ClusterStatsParts;
 
+mod bulk_writer;
+mod conflict_upsert;
+mod dead_letter_store;
+mod document;
+mod index_lifecycle;
+mod managed_client;
+mod search_after;
+
+pub use bulk_writer::{BulkOperation,BulkWriter,BulkWriterConfig,create_bulk_writer};
+pub use conflict_upsert::upsert_with_strategy;
+pub use dead_letter_store::{EsDeadLetterStore,create_es_dead_letter_store};
+pub use document::{EsDocument,EsDocumentIndex};
+pub use index_lifecycle::{IndexDefinition,IndexStatus,LifecyclePolicy,create_index_definition,ensure_index,recreate_index};
+pub use managed_client::{ManagedClient,ManagedClientConfig,create_managed_client,is_transient_es_error};
+pub use search_after::{SearchAfter,create_search_after,search_after_stream};
+
+const ELASTIC_SEARCH_URL: &str = "http://elastic-search:9200";
+
 pub async fn wait_for_elastic_search() -> Result<Elasticsearch> {
+    wait_for_elastic_search_at(ELASTIC_SEARCH_URL).await
+}
+
+pub async fn wait_for_elastic_search_at(url: &str) -> Result<Elasticsearch> {
     let interval = time::Duration::from_secs(1);
     loop {
-        match try_to_connect().await {
+        match try_to_connect(url).await {
             Err(e) => {
                 warn!("Elastic Search is not ready (yet): {:?}", e);
             },
@@ -20,8 +42,8 @@ pub async fn wait_for_elastic_search() -> Result<Elasticsearch> {
     }
 }
 
-async fn try_to_connect() -> Result<Elasticsearch> {
-    let transport = Transport::single_node("http://elastic-search:9200")?;
+async fn try_to_connect(url: &str) -> Result<Elasticsearch> {
+    let transport = Transport::single_node(url)?;
     let client = Elasticsearch::new(transport);
     let response = client
         .info()
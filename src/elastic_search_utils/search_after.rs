@@ -0,0 +1,108 @@
+use anyhow::{anyhow,Result};
+use async_stream::try_stream;
+use elasticsearch::{Elasticsearch,OpenPointInTimeParts,SearchParts};
+use futures_core::stream::Stream;
+use tracing::{debug,warn};
+use serde_json::{json,Value};
+
+/// Parameters for a search that is read page by page using `search_after`.
+///
+/// The sort must end with a field that is unique for each document, otherwise documents with equal sort values can
+/// be skipped at page boundaries. When a keep-alive for a point in time is given, all pages are read from the same
+/// point in time (requires Elastic Search 7.10 or later).
+#[derive(Debug,Clone)]
+pub struct SearchAfter {
+    pub index: String,
+    pub query: Value,
+    pub sort: Value,
+    pub source: Option<Value>,
+    pub page_size: i64,
+    pub point_in_time_keep_alive: Option<String>,
+}
+
+pub fn create_search_after(index: &str, query: Value, sort: Value) -> SearchAfter {
+    SearchAfter {
+        index: index.to_string(),
+        query,
+        sort,
+        source: None,
+        page_size: 100,
+        point_in_time_keep_alive: None,
+    }
+}
+
+/// Streams the hits of a search one by one, fetching the next page only when the previous page is exhausted.
+pub fn search_after_stream(client: Elasticsearch, search: SearchAfter) -> impl Stream<Item = Result<Value>> {
+    try_stream! {
+        let mut point_in_time_id = None;
+        if let Some(keep_alive) = &search.point_in_time_keep_alive {
+            point_in_time_id = Some(open_point_in_time(&client, &search.index, keep_alive).await?);
+        }
+        let mut search_after: Option<Value> = None;
+        loop {
+            let mut body = json!({
+                "size": search.page_size,
+                "query": search.query,
+                "sort": search.sort,
+            });
+            if let Some(source) = &search.source {
+                body["_source"] = source.clone();
+            }
+            if let Some(search_after) = &search_after {
+                body["search_after"] = search_after.clone();
+            }
+            let response = match (&point_in_time_id, &search.point_in_time_keep_alive) {
+                (Some(id), Some(keep_alive)) => {
+                    body["pit"] = json!({ "id": id, "keep_alive": keep_alive });
+                    client.search(SearchParts::None).body(body).send().await?
+                }
+                _ => client.search(SearchParts::Index(&[&search.index])).body(body).send().await?
+            };
+            let value = response.json::<Value>().await?;
+            if let Some(error) = value.get("error") {
+                Err(anyhow!("Search after failed: {:?}", error))?;
+            }
+            if let Some(id) = value["pit_id"].as_str() {
+                point_in_time_id = Some(id.to_string());
+            }
+            let hits = value["hits"]["hits"].as_array().cloned().unwrap_or_default();
+            debug!("Search after: page: {:?}: hits: {:?}", search_after, hits.len());
+            if hits.is_empty() {
+                break;
+            }
+            search_after = hits.last().map(|hit| hit["sort"].clone());
+            let last_page = (hits.len() as i64) < search.page_size;
+            for hit in hits {
+                yield hit;
+            }
+            if last_page {
+                break;
+            }
+        }
+        if let Some(id) = point_in_time_id {
+            close_point_in_time(&client, &id).await;
+        }
+    }
+}
+
+async fn open_point_in_time(client: &Elasticsearch, index: &str, keep_alive: &str) -> Result<String> {
+    let response = client
+        .open_point_in_time(OpenPointInTimeParts::Index(&[index]))
+        .keep_alive(keep_alive)
+        .send()
+        .await?;
+    let value = response.json::<Value>().await?;
+    debug!("Open point in time: {:?}", value);
+    value["id"].as_str().map(|id| id.to_string()).ok_or(anyhow!("Could not open point in time for: {:?}: {:?}", index, value))
+}
+
+async fn close_point_in_time(client: &Elasticsearch, id: &str) {
+    let result = client
+        .close_point_in_time()
+        .body(json!({ "id": id }))
+        .send()
+        .await;
+    if let Err(e) = result {
+        warn!("Could not close point in time: {:?}", e);
+    }
+}
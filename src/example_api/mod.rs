@@ -1,12 +1,22 @@
 use anyhow::{Error,Result};
 use bytes::Bytes;
-use log::{debug};
+use tracing::{debug};
 use prost::Message;
 use tokio::sync::mpsc;
-use tonic::{Request, Response, Status};
-use crate::axon_utils::{AxonServerHandle, CommandSink, QuerySink, init_command_sender, query_events};
+use tonic::{Request, Response, Status, Streaming};
+use crate::axon_utils::{AxonConnectionBuilder, AxonServerHandle, CommandSink, ConnectionConfig, QuerySink, error_to_status, init_command_sender, init_command_sender_with_builder, init_command_sender_with_config, init_command_sender_with_server, query_events, validate};
 use crate::grpc_example::greeter_service_server::GreeterService;
-use crate::grpc_example::{Acknowledgement, Empty, GreetedEvent, Greeting, GreetCommand, RecordCommand, StopCommand, SearchQuery, SearchResponse};
+use crate::grpc_example::chat_response;
+use crate::grpc_example::{Acknowledgement, ChatResponse, Empty, GreetedEvent, Greeting, GreetingCount, GreetingCountsQuery, GreetingCountsResponse, GreetCommand, RecordCommand, StopCommand, SearchQuery, SearchResponse};
+
+/// Maximum length of a greeting, in characters.
+pub const MAX_GREETING_LENGTH: usize = 256;
+
+/// Maximum length of a search query, in characters.
+pub const MAX_SEARCH_QUERY_LENGTH: usize = 1024;
+
+/// Number of greetings in a chat after which the greeting counts are sent.
+pub const CHAT_COUNTS_INTERVAL: usize = 10;
 
 #[derive(Debug)]
 pub struct GreeterServer {
@@ -20,27 +30,8 @@ impl GreeterService for GreeterServer {
         request: Request<Greeting>,
     ) -> Result<Response<Acknowledgement>, Status> {
         debug!("Got a greet request: {:?}", request);
-        let inner_request = request.into_inner();
-        let result_message = inner_request.message.clone();
-
-        let command = GreetCommand {
-            aggregate_identifier: "xxx".to_string(),
-            message: Some(inner_request),
-        };
-
-        if let Some(serialized) = self.axon_server_handle.send_command("GreetCommand", Box::new(&command)).await
-            .map_err(to_status)?
-        {
-            let reply_from_command_handler = Message::decode(Bytes::from(serialized.data)).map_err(decode_error_to_status)?;
-            debug!("Reply from command handler: {:?}", reply_from_command_handler);
-            return Ok(Response::new(reply_from_command_handler));
-        }
-
-        let default_reply = Acknowledgement {
-            message: format!("Hello {}!", result_message).into(),
-        };
-
-        Ok(Response::new(default_reply))
+        let reply = send_greeting(&self.axon_server_handle, request.into_inner()).await.map_err(to_status)?;
+        Ok(Response::new(reply))
     }
 
     async fn record(
@@ -111,16 +102,15 @@ impl GreeterService for GreeterServer {
     async fn search(&self, request: Request<SearchQuery>) -> Result<Response<Self::SearchStream>, Status> {
         let (mut tx, rx) = mpsc::channel(4);
         let query = request.into_inner();
-        let query_response = self.axon_server_handle.send_query("SearchQuery", Box::new(&query)).await.map_err(to_status)?;
+        validate_search_query(&query).map_err(to_status)?;
+        let query_response: Vec<SearchResponse> = self.axon_server_handle.send_typed_query("SearchQuery", &query).await.map_err(to_status)?;
 
         tokio::spawn(async move {
-            for serialized_object in query_response {
-                if let Ok(search_response) = SearchResponse::decode(Bytes::from(serialized_object.data)) {
-                    debug!("Search response: {:?}", search_response);
-                    for greeting in search_response.greetings {
-                        debug!("Greeting: {:?}", greeting);
-                        tx.send(Ok(greeting)).await.ok();
-                    }
+            for search_response in query_response {
+                debug!("Search response: {:?}", search_response);
+                for greeting in search_response.greetings {
+                    debug!("Greeting: {:?}", greeting);
+                    tx.send(Ok(greeting)).await.ok();
                 }
                 debug!("Next!");
             }
@@ -129,16 +119,132 @@ impl GreeterService for GreeterServer {
 
         Ok(Response::new(rx))
     }
+
+    type GreetingCountsStream = mpsc::Receiver<Result<GreetingCount, Status>>;
+
+    async fn greeting_counts(&self, _request: Request<Empty>) -> Result<Response<Self::GreetingCountsStream>, Status> {
+        let (mut tx, rx) = mpsc::channel(4);
+        let query = GreetingCountsQuery {};
+        let query_response: Vec<GreetingCountsResponse> = self.axon_server_handle.send_typed_query("GreetingCountsQuery", &query).await.map_err(to_status)?;
+
+        tokio::spawn(async move {
+            for greeting_counts_response in query_response {
+                debug!("Greeting counts response: {:?}", greeting_counts_response);
+                for greeting_count in greeting_counts_response.counts {
+                    tx.send(Ok(greeting_count)).await.ok();
+                }
+            }
+        });
+
+        Ok(Response::new(rx))
+    }
+
+    type ChatStream = mpsc::Receiver<Result<ChatResponse, Status>>;
+
+    /// Sends a `GreetCommand` for each greeting from the client and streams back the acknowledgements, in order. The
+    /// greeting counts from the query model are streamed after every `CHAT_COUNTS_INTERVAL` greetings and at the end of
+    /// the client stream. The bounded channel applies back pressure: a client that does not read its responses is not
+    /// served new greetings. The first greeting that fails ends the stream with the corresponding status.
+    async fn chat(&self, request: Request<Streaming<Greeting>>) -> Result<Response<Self::ChatStream>, Status> {
+        debug!("Got a chat request: {:?}", request.metadata());
+        let mut greetings = request.into_inner();
+        let axon_server_handle = self.axon_server_handle.clone();
+        let (mut tx, rx) = mpsc::channel(4);
+
+        tokio::spawn(async move {
+            let mut count: usize = 0;
+            loop {
+                let greeting = match greetings.message().await {
+                    Ok(Some(greeting)) => greeting,
+                    Ok(None) => break,
+                    Err(e) => {
+                        debug!("Chat stream ended with an error: {:?}", e);
+                        return;
+                    }
+                };
+                count += 1;
+                let response = send_greeting(&axon_server_handle, greeting).await
+                    .map(|acknowledgement| ChatResponse { response: Some(chat_response::Response::Acknowledgement(acknowledgement)) })
+                    .map_err(to_status);
+                let failed = response.is_err();
+                if tx.send(response).await.is_err() || failed {
+                    return;
+                }
+                // `usize::is_multiple_of` needs a newer toolchain than this crate supports.
+                #[allow(clippy::manual_is_multiple_of)]
+                let send_counts = count % CHAT_COUNTS_INTERVAL == 0;
+                if send_counts && !send_greeting_counts(&axon_server_handle, &mut tx).await {
+                    return;
+                }
+            }
+            send_greeting_counts(&axon_server_handle, &mut tx).await;
+            debug!("End of chat: greetings: {:?}", count);
+        });
+
+        Ok(Response::new(rx))
+    }
+}
+
+async fn send_greeting(axon_server_handle: &AxonServerHandle, greeting: Greeting) -> Result<Acknowledgement> {
+    validate_greeting(&greeting)?;
+    let result_message = greeting.message.clone();
+
+    let command = GreetCommand {
+        aggregate_identifier: "xxx".to_string(),
+        message: Some(greeting),
+    };
+
+    if let Some(serialized) = axon_server_handle.send_command("GreetCommand", Box::new(&command)).await? {
+        let reply_from_command_handler = Acknowledgement::decode(Bytes::from(serialized.data))?;
+        debug!("Reply from command handler: {:?}", reply_from_command_handler);
+        return Ok(reply_from_command_handler);
+    }
+
+    Ok(Acknowledgement {
+        message: format!("Hello {}!", result_message),
+    })
+}
+
+// Returns false if the client is gone.
+async fn send_greeting_counts(axon_server_handle: &AxonServerHandle, tx: &mut mpsc::Sender<Result<ChatResponse, Status>>) -> bool {
+    let response = query_greeting_counts(axon_server_handle).await
+        .map(|greeting_counts| ChatResponse { response: Some(chat_response::Response::GreetingCounts(greeting_counts)) })
+        .map_err(to_status);
+    tx.send(response).await.is_ok()
+}
+
+async fn query_greeting_counts(axon_server_handle: &AxonServerHandle) -> Result<GreetingCountsResponse> {
+    let query = GreetingCountsQuery {};
+    let query_response: Vec<GreetingCountsResponse> = axon_server_handle.send_typed_query("GreetingCountsQuery", &query).await?;
+    let counts = query_response.into_iter().flat_map(|response| response.counts).collect();
+    Ok(GreetingCountsResponse { counts })
 }
 
 pub async fn init() -> Result<GreeterServer> {
     init_command_sender().await.map(|command_sink| {GreeterServer{ axon_server_handle: command_sink }})
 }
 
-fn to_status(e: Error) -> Status {
-    Status::unknown(e.to_string())
+pub async fn init_with_server(host: &str, port: u32) -> Result<GreeterServer> {
+    init_command_sender_with_server(host, port).await.map(|command_sink| {GreeterServer{ axon_server_handle: command_sink }})
+}
+
+pub async fn init_with_config(host: &str, port: u32, config: ConnectionConfig) -> Result<GreeterServer> {
+    init_command_sender_with_config(host, port, config).await.map(|command_sink| {GreeterServer{ axon_server_handle: command_sink }})
 }
 
-fn decode_error_to_status(e: prost::DecodeError) -> Status {
-    Status::unknown(e.to_string())
+pub async fn init_with_builder(builder: AxonConnectionBuilder) -> Result<GreeterServer> {
+    init_command_sender_with_builder(builder).await.map(|command_sink| {GreeterServer{ axon_server_handle: command_sink }})
+}
+
+fn validate_greeting(greeting: &Greeting) -> Result<()> {
+    validate(!greeting.message.trim().is_empty(), "message", "must not be empty")?;
+    validate(greeting.message.chars().count() <= MAX_GREETING_LENGTH, "message", &format!("must not be longer than {} characters", MAX_GREETING_LENGTH))
+}
+
+fn validate_search_query(query: &SearchQuery) -> Result<()> {
+    validate(query.query.chars().count() <= MAX_SEARCH_QUERY_LENGTH, "query", &format!("must not be longer than {} characters", MAX_SEARCH_QUERY_LENGTH))
+}
+
+fn to_status(e: Error) -> Status {
+    error_to_status(&e)
 }
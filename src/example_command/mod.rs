@@ -1,8 +1,12 @@
 use anyhow::{Context,Result,anyhow};
-use log::{debug,error};
+use tracing::{debug,error};
 use prost::{Message};
-use crate::axon_utils::{ApplicableTo, AxonConnection, AxonServerHandle, EmitApplicableEventsAndResponse, HandlerRegistry, command_worker, create_aggregate_definition, emit_applicable, emit_applicable_events_and_response, empty_handler_registry, empty_aggregate_registry};
-use crate::grpc_example::{Acknowledgement,GreetCommand,GreetedEvent,GreeterProjection,RecordCommand,StartedRecordingEvent,StopCommand,StoppedRecordingEvent};
+use crate::axon_utils::{AggregateContext, ApplicableTo, AxonConnection, AxonServerHandle, CommandResult, EmitApplicableEventsAndResponse, HandlerRegistry, ProtobufSnapshotSerializer, StateMachine, command_worker, create_aggregate_definition, create_registry_validation, create_snapshot_config, create_state_machine, empty_handler_registry, empty_aggregate_registry, load_proto_descriptors};
+use crate::grpc_example::{Acknowledgement,DESCRIPTOR_SET,GreetCommand,GreetedEvent,GreeterProjection,RecordCommand,StartedRecordingEvent,StopCommand,StoppedRecordingEvent};
+
+/// Number of events after which a new snapshot of the greeter aggregate is stored. All greetings go to the same
+/// aggregate, so without snapshots every command would replay the whole history.
+const SNAPSHOT_THRESHOLD: usize = 100;
 
 pub async fn handle_commands(axon_server_handle : AxonServerHandle) {
     if let Err(e) = internal_handle_commands(axon_server_handle).await {
@@ -15,7 +19,17 @@ async fn internal_handle_commands(axon_server_handle : AxonServerHandle) -> Resu
     debug!("Handle commands for example application");
     let axon_connection = AxonConnection {
         id: axon_server_handle.display_name,
+        component_name: axon_server_handle.component_name,
         conn: axon_server_handle.conn,
+        interceptors: axon_server_handle.interceptors,
+        context: axon_server_handle.context,
+        max_message_size: axon_server_handle.max_message_size,
+        health: axon_server_handle.health,
+        reconnect: axon_server_handle.reconnect,
+        metrics: axon_server_handle.metrics,
+        query_updates: axon_server_handle.query_updates,
+        tags: axon_server_handle.tags,
+        server_version: axon_server_handle.server_version,
     };
     debug!("Axon connection: {:?}", axon_connection);
 
@@ -89,11 +103,14 @@ async fn internal_handle_commands(axon_server_handle : AxonServerHandle) -> Resu
         aggregate_id_extractor_registry,
         command_handler_registry,
         sourcing_handler_registry
-    );
+    ).with_aggregate_type("Greeting").with_snapshots(create_snapshot_config(SNAPSHOT_THRESHOLD, "1", ProtobufSnapshotSerializer));
 
     let mut aggregate_registry = empty_aggregate_registry();
     aggregate_registry.handlers.insert(aggregate_definition.projection_name.clone(), Box::from(aggregate_definition));
 
+    let descriptors = load_proto_descriptors(DESCRIPTOR_SET)?;
+    create_registry_validation(&descriptors).with_aggregates(&aggregate_registry).validate()?;
+
     command_worker(axon_connection, aggregate_registry).await.context("Error while handling commands")
 }
 
@@ -107,6 +124,14 @@ async fn fixed_aggregate_id() -> Result<Option<String>> {
     Ok(Some("xxx".to_string()))
 }
 
+impl AggregateContext for GreeterProjection {}
+
+fn recording_state_machine() -> StateMachine<bool> {
+    create_state_machine()
+        .with_transition("StartedRecordingEvent", false, true)
+        .with_transition("StoppedRecordingEvent", true, false)
+}
+
 impl ApplicableTo<GreeterProjection> for GreetedEvent {
 
     fn apply_to(self: &Self, projection: &mut GreeterProjection) -> Result<()> {
@@ -157,32 +182,29 @@ async fn handle_greet_command (command: GreetCommand, projection: GreeterProject
     if message == "ERROR" {
         return Err(anyhow!("Panicked at reading 'ERROR'"));
     }
-    let mut emit_events = emit_applicable_events_and_response("Acknowledgement", &Acknowledgement {
+    let emit_events = CommandResult::reply(Acknowledgement {
         message: format!("ACK! {}", message),
-    })?;
-    emit_applicable(&mut emit_events, "GreetedEvent", Box::from(GreetedEvent {
+    }).event(GreetedEvent {
         message: greeting,
-    }))?;
+    });
     debug!("Emit events and response: {:?}", emit_events);
     Ok(Some(emit_events))
 }
 
 async fn handle_record_command (command: RecordCommand, projection: GreeterProjection) -> Result<Option<EmitApplicableEventsAndResponse<GreeterProjection>>> {
     debug!("Record command handler: {:?}", command);
-    if projection.is_recording {
+    if !recording_state_machine().accepts(&projection.is_recording, "StartedRecordingEvent") {
         return Ok(None)
     }
-    let mut emit_events = emit_applicable_events_and_response("Empty", &())?;
-    emit_applicable(&mut emit_events, "StartedRecordingEvent", Box::from(StartedRecordingEvent {}))?;
+    let emit_events = CommandResult::reply(()).event(StartedRecordingEvent {});
     Ok(Some(emit_events))
 }
 
 async fn handle_stop_command (command: StopCommand, projection: GreeterProjection) -> Result<Option<EmitApplicableEventsAndResponse<GreeterProjection>>> {
     debug!("Stop command handler: {:?}", command);
-    if !projection.is_recording {
+    if !recording_state_machine().accepts(&projection.is_recording, "StoppedRecordingEvent") {
         return Ok(None)
     }
-    let mut emit_events = emit_applicable_events_and_response("Empty", &())?;
-    emit_applicable(&mut emit_events, "StoppedRecordingEvent", Box::from(StoppedRecordingEvent {}))?;
+    let emit_events = CommandResult::reply(()).event(StoppedRecordingEvent {});
     Ok(Some(emit_events))
 }
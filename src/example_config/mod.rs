@@ -0,0 +1,213 @@
+use anyhow::{Result,anyhow};
+use clap::{App,Arg,ArgMatches};
+use std::fmt::{Debug,Formatter};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing_subscriber::{EnvFilter,Registry,reload};
+use tracing_subscriber::prelude::*;
+use crate::axon_utils::{AxonConnectionBuilder,ConnectionConfig,LogFilterControl,create_axon_connection_builder};
+
+pub const COMMANDS: &str = "commands";
+pub const EVENTS: &str = "events";
+pub const STATISTICS: &str = "statistics";
+pub const QUERIES: &str = "queries";
+
+/// Name under which the example application reports itself to AxonServer.
+pub const COMPONENT_NAME: &str = "rustic-dendrite";
+
+const COMPONENTS: [&str; 4] = [COMMANDS, EVENTS, STATISTICS, QUERIES];
+
+/// Configuration of the example application, from command line arguments and environment variables.
+///
+/// Every option can also be given as an environment variable, e.g., `AXON_SERVER_HOST=localhost`. The log level uses
+/// the syntax of `RUST_LOG` and falls back to it. The components list the parts of the example that are started. The
+/// concurrency file holds the concurrency limits of the event processors as JSON (see `ConcurrencyConfig`); it is read
+/// again when the process receives SIGHUP. With `admin_log_filter`, the log filter can be changed at runtime with the
+/// `SetLogFilter` query. The AxonServer token is sent with every request to AxonServer, and is not logged. In read-only
+/// mode, the example answers queries but refuses commands (see `set_read_only`).
+#[derive(Clone)]
+pub struct ExampleConfig {
+    pub axon_server_host: String,
+    pub axon_server_port: u32,
+    pub axon_server_token: Option<String>,
+    pub axon_server_context: Option<String>,
+    pub elastic_search_url: String,
+    pub bind_address: SocketAddr,
+    pub metrics_address: SocketAddr,
+    pub log_level: String,
+    pub components: Vec<String>,
+    pub concurrency_file: Option<PathBuf>,
+    pub admin_log_filter: bool,
+    pub read_only: bool,
+}
+
+impl ExampleConfig {
+    pub fn is_enabled(&self, component: &str) -> bool {
+        self.components.iter().any(|enabled| enabled == component)
+    }
+
+    /// Returns the builder for the connection to AxonServer, with the component name of the example application.
+    pub fn connection_builder(&self) -> AxonConnectionBuilder {
+        create_axon_connection_builder()
+            .with_host(&self.axon_server_host)
+            .with_port(self.axon_server_port)
+            .with_component_name(COMPONENT_NAME)
+            .with_config(self.connection_config())
+    }
+
+    pub fn connection_config(&self) -> ConnectionConfig {
+        let mut config = ConnectionConfig::default();
+        if let Some(context) = &self.axon_server_context {
+            config = config.with_context(context);
+        }
+        match &self.axon_server_token {
+            Some(token) => config.with_access_token(token),
+            None => config,
+        }
+    }
+}
+
+impl Debug for ExampleConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExampleConfig")
+            .field("axon_server_host", &self.axon_server_host)
+            .field("axon_server_port", &self.axon_server_port)
+            .field("axon_server_token", &self.axon_server_token.as_ref().map(|_| "<redacted>"))
+            .field("axon_server_context", &self.axon_server_context)
+            .field("elastic_search_url", &self.elastic_search_url)
+            .field("bind_address", &self.bind_address)
+            .field("metrics_address", &self.metrics_address)
+            .field("log_level", &self.log_level)
+            .field("components", &self.components)
+            .field("concurrency_file", &self.concurrency_file)
+            .field("admin_log_filter", &self.admin_log_filter)
+            .field("read_only", &self.read_only)
+            .finish()
+    }
+}
+
+pub fn parse_config() -> Result<ExampleConfig> {
+    let matches = App::new("rustic-dendrite")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Example application of Rustic Dendrite")
+        .arg(Arg::with_name("axon-server-host")
+            .long("axon-server-host")
+            .env("AXON_SERVER_HOST")
+            .default_value("proxy")
+            .help("Host name of AxonServer"))
+        .arg(Arg::with_name("axon-server-port")
+            .long("axon-server-port")
+            .env("AXON_SERVER_PORT")
+            .default_value("8124")
+            .help("gRPC port of AxonServer"))
+        .arg(Arg::with_name("axon-server-token")
+            .long("axon-server-token")
+            .env("AXON_SERVER_TOKEN")
+            .hide_env_values(true)
+            .takes_value(true)
+            .help("Access token for AxonServer with access control enabled"))
+        .arg(Arg::with_name("axon-server-context")
+            .long("axon-server-context")
+            .env("AXON_SERVER_CONTEXT")
+            .takes_value(true)
+            .help("AxonServer context, instead of the default context"))
+        .arg(Arg::with_name("elastic-search-url")
+            .long("elastic-search-url")
+            .env("ELASTIC_SEARCH_URL")
+            .default_value("http://elastic-search:9200")
+            .help("URL of Elastic Search"))
+        .arg(Arg::with_name("bind-address")
+            .long("bind-address")
+            .env("API_SERVER_BIND_ADDRESS")
+            .default_value("0.0.0.0:8181")
+            .help("Address of the gRPC API"))
+        .arg(Arg::with_name("metrics-address")
+            .long("metrics-address")
+            .env("METRICS_BIND_ADDRESS")
+            .default_value("0.0.0.0:9181")
+            .help("Address of the metrics endpoint for Prometheus"))
+        .arg(Arg::with_name("log-level")
+            .long("log-level")
+            .env("RUST_LOG")
+            .default_value("info")
+            .help("Log filter, e.g., info,rustic_dendrite=debug"))
+        .arg(Arg::with_name("components")
+            .long("components")
+            .env("EXAMPLE_COMPONENTS")
+            .use_delimiter(true)
+            .possible_values(&COMPONENTS)
+            .default_value("commands,events,statistics,queries")
+            .help("Components of the example to start"))
+        .arg(Arg::with_name("concurrency-file")
+            .long("concurrency-file")
+            .env("HANDLER_CONCURRENCY_FILE")
+            .takes_value(true)
+            .help("JSON file with concurrency limits of event processors and handlers, reloaded on SIGHUP"))
+        .arg(Arg::with_name("admin-log-filter")
+            .long("admin-log-filter")
+            .env("ADMIN_LOG_FILTER")
+            .possible_values(&["true", "false"])
+            .default_value("false")
+            .help("Handle the SetLogFilter query, which changes the log filter at runtime"))
+        .arg(Arg::with_name("read-only")
+            .long("read-only")
+            .env("READ_ONLY")
+            .possible_values(&["true", "false"])
+            .default_value("false")
+            .help("Refuse commands and event appends, e.g., during maintenance or on a replica"))
+        .get_matches();
+    create_config(&matches)
+}
+
+fn create_config(matches: &ArgMatches) -> Result<ExampleConfig> {
+    Ok(ExampleConfig {
+        axon_server_host: value(matches, "axon-server-host")?.to_string(),
+        axon_server_port: value(matches, "axon-server-port")?.parse()
+            .map_err(|e| anyhow!("Invalid AxonServer port: {:?}", e))?,
+        axon_server_token: matches.value_of("axon-server-token").map(String::from),
+        axon_server_context: matches.value_of("axon-server-context").map(String::from),
+        elastic_search_url: value(matches, "elastic-search-url")?.to_string(),
+        bind_address: value(matches, "bind-address")?.parse()
+            .map_err(|e| anyhow!("Invalid bind address: {:?}", e))?,
+        metrics_address: value(matches, "metrics-address")?.parse()
+            .map_err(|e| anyhow!("Invalid metrics address: {:?}", e))?,
+        log_level: value(matches, "log-level")?.to_string(),
+        components: matches.values_of("components")
+            .map(|components| components.map(String::from).collect())
+            .unwrap_or_default(),
+        concurrency_file: matches.value_of("concurrency-file").map(PathBuf::from),
+        admin_log_filter: value(matches, "admin-log-filter")? == "true",
+        read_only: value(matches, "read-only")? == "true",
+    })
+}
+
+fn value<'a>(matches: &'a ArgMatches, name: &str) -> Result<&'a str> {
+    matches.value_of(name).ok_or_else(|| anyhow!("Missing value for: {:?}", name))
+}
+
+/// Installs a tracing subscriber that logs to stderr with the given filter, and returns the control that replaces the
+/// filter at runtime. Log records of dependencies that use the `log` crate are passed on to the subscriber.
+pub fn init_logging(log_level: &str) -> Result<Arc<dyn LogFilterControl>> {
+    let (filter, handle) = reload::Layer::new(EnvFilter::try_new(log_level)?);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .try_init()?;
+    Ok(Arc::new(ReloadableLogFilter { handle }))
+}
+
+struct ReloadableLogFilter {
+    handle: reload::Handle<EnvFilter,Registry>,
+}
+
+impl LogFilterControl for ReloadableLogFilter {
+    fn current_filter(&self) -> String {
+        self.handle.with_current(|filter| filter.to_string()).unwrap_or_default()
+    }
+
+    fn set_filter(&self, filter: &str) -> Result<()> {
+        self.handle.reload(EnvFilter::try_new(filter)?)?;
+        Ok(())
+    }
+}
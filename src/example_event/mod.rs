@@ -1,64 +1,194 @@
 use anyhow::{anyhow,Context,Result};
-use elasticsearch::{Elasticsearch, IndexParts, GetParts};
-use log::{debug,error};
+use chrono::{TimeZone,Utc};
+use elasticsearch::{Elasticsearch, GetParts};
+use tracing::{debug,error};
 use prost::Message;
+use serde::Serialize;
 use serde_json::{json, Value};
 use sha2::{Sha256, Digest};
-use super::elastic_search_utils::wait_for_elastic_search;
-use crate::axon_utils::{AsyncApplicableTo, AxonServerHandle, HandlerRegistry, TheHandlerRegistry, TokenStore, event_processor, empty_handler_registry};
-use crate::grpc_example::{GreetedEvent,Greeting};
+use std::time::Duration;
+use super::elastic_search_utils::{BulkWriter,EsDocument,IndexStatus,ManagedClient,ManagedClientConfig,create_bulk_writer,create_index_definition,create_managed_client,ensure_index,is_transient_es_error,recreate_index,wait_for_elastic_search_at};
+use crate::axon_server::event::Event;
+use crate::axon_utils::{AsyncApplicableTo, AxonServerHandle, ConcurrencyLimits, EventProcessorConfig, HandlerRegistry, PauseSwitch, QueryUpdateEmitter, RetryPolicy, TheHandlerRegistry, TokenStore, TrackingConfig, create_handler_labels, create_registry_validation, create_retry_policy, create_tracking_config, event_processor_with_config, empty_handler_registry, is_transient_error, load_proto_descriptors};
+use crate::grpc_example::{DESCRIPTOR_SET,GreetedEvent,Greeting,SearchQuery,SearchResponse};
 
 #[derive(Clone)]
 struct ExampleQueryModel {
-    es_client: Elasticsearch,
+    es_client: ManagedClient,
+    bulk_writer: BulkWriter,
+    tracking: TrackingConfig,
+    query_updates: QueryUpdateEmitter,
+}
+
+#[derive(Clone)]
+struct GreetingStatisticsModel {
+    es_client: ManagedClient,
+    bulk_writer: BulkWriter,
+    tracking: TrackingConfig,
+    event_timestamp: i64,
+    event_token: i64,
+}
+
+#[derive(Serialize)]
+struct GreetingDocument {
+    id: String,
+    value: String,
+}
+
+impl EsDocument for GreetingDocument {
+    fn index_name() -> &'static str {
+        "greetings"
+    }
+
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+}
+
+#[derive(Serialize)]
+struct GreetingCountDocument {
+    day: String,
+    count: i64,
+    token: i64,
+}
+
+impl EsDocument for GreetingCountDocument {
+    fn index_name() -> &'static str {
+        "greeting-counts"
+    }
+
+    fn id(&self) -> String {
+        self.day.clone()
+    }
 }
 
 #[tonic::async_trait]
 impl TokenStore for ExampleQueryModel {
-    async fn store_token(&self, token: i64) {
-        let result = self.es_client
-            .index(IndexParts::IndexId("tracking-token", "greeting"))
-            .body(json!({
-                    "id": "greeting",
-                    "token": token,
-                }))
-            .send()
-            .await
-        ;
-        debug!("Elastic Search store token result: {:?}", result);
-    }
-
-    async fn retrieve_token(&self) -> Result<i64> {
-        let response = self.es_client
-            .get(GetParts::IndexId("tracking-token", "greeting"))
-            ._source(&["token"])
-            .send()
-            .await?
-        ;
-        let value = response.json::<Value>().await?;
-        debug!("Retrieved response value: {:?}", value);
-        if let Value::Number(token) = &value["_source"]["token"] {
+    async fn store_token(&self, token: i64) -> Result<()> {
+        store_tracking_token(&self.bulk_writer, &self.tracking, token, &[]).await
+    }
+
+    async fn retrieve_token(&self) -> Result<Option<i64>> {
+        let tracking = &self.tracking;
+        self.es_client.with_retry("retrieve tracking token", |client| async move { retrieve_tracking_token(&client, tracking).await }).await
+    }
+
+    fn for_tracking(&self, tracking: &TrackingConfig) -> Self {
+        let mut model = self.clone();
+        model.tracking = tracking.clone();
+        model
+    }
+}
+
+#[tonic::async_trait]
+impl TokenStore for GreetingStatisticsModel {
+    async fn store_token(&self, token: i64) -> Result<()> {
+        store_tracking_token(&self.bulk_writer, &self.tracking, token, &[]).await
+    }
+
+    async fn retrieve_token(&self) -> Result<Option<i64>> {
+        let tracking = &self.tracking;
+        self.es_client.with_retry("retrieve tracking token", |client| async move { retrieve_tracking_token(&client, tracking).await }).await
+    }
+
+    async fn store_token_with_window(&self, token: i64, window: &[String]) -> Result<()> {
+        store_tracking_token(&self.bulk_writer, &self.tracking, token, window).await
+    }
+
+    async fn retrieve_deduplication_window(&self) -> Result<Vec<String>> {
+        let tracking = &self.tracking;
+        self.es_client.with_retry("retrieve recent events", |client| async move { retrieve_recent_events(&client, tracking).await }).await
+    }
+
+    fn for_tracking(&self, tracking: &TrackingConfig) -> Self {
+        let mut model = self.clone();
+        model.tracking = tracking.clone();
+        model
+    }
+
+    fn for_event(&self, event: &Event, token: i64) -> Self {
+        let mut model = self.clone();
+        model.event_timestamp = event.timestamp;
+        model.event_token = token;
+        model
+    }
+}
+
+// The token is written as a checkpoint, so that it is only persisted after the documents of the events before it.
+async fn store_tracking_token(bulk_writer: &BulkWriter, tracking: &TrackingConfig, token: i64, recent_events: &[String]) -> Result<()> {
+    bulk_writer
+        .checkpoint(&tracking.token_index, &tracking.token_key, json!({
+            "id": tracking.token_key,
+            "token": token,
+            "owner": tracking.owner,
+            "recent_events": recent_events,
+        }))
+        .await
+}
+
+async fn retrieve_tracking_token(es_client: &Elasticsearch, tracking: &TrackingConfig) -> Result<Option<i64>> {
+    let response = es_client
+        .get(GetParts::IndexId(&tracking.token_index, &tracking.token_key))
+        ._source(&["token"])
+        .send()
+        .await?
+    ;
+    let status_code = response.status_code();
+    if status_code.as_u16() == 404 {
+        debug!("No tracking token: {:?}", tracking.token_key);
+        return Ok(None);
+    }
+    if !status_code.is_success() {
+        return Err(anyhow!("Cannot retrieve tracking token: {:?}: {:?}", tracking.token_key, status_code));
+    }
+    let value = response.json::<Value>().await?;
+    debug!("Retrieved response value: {:?}", value);
+    match &value["_source"]["token"] {
+        Value::Number(token) => {
             debug!("Retrieved token: {:?}", token);
-            return token.as_i64().ok_or(anyhow!("Token is not an i64"));
+            token.as_i64().map(Some).ok_or(anyhow!("Token is not an i64"))
         }
-        Ok(-1)
+        _ => Ok(None),
     }
 }
 
-pub async fn process_events(axon_server_handle : AxonServerHandle) {
-    if let Err(e) = internal_process_events(axon_server_handle).await {
+async fn retrieve_recent_events(es_client: &Elasticsearch, tracking: &TrackingConfig) -> Result<Vec<String>> {
+    let response = es_client
+        .get(GetParts::IndexId(&tracking.token_index, &tracking.token_key))
+        ._source(&["recent_events"])
+        .send()
+        .await?
+    ;
+    let value = response.json::<Value>().await?;
+    Ok(serde_json::from_value(value["_source"]["recent_events"].clone()).unwrap_or_default())
+}
+
+pub async fn process_events(axon_server_handle : AxonServerHandle, elastic_search_url: String, concurrency: ConcurrencyLimits, pause_switch: PauseSwitch) {
+    if let Err(e) = internal_process_events(axon_server_handle, &elastic_search_url, concurrency, pause_switch).await {
         error!("Error while handling commands: {:?}", e);
     }
     debug!("Stopped handling commands for example application");
 }
 
-async fn internal_process_events(axon_server_handle : AxonServerHandle) -> Result<()> {
-    let client = wait_for_elastic_search().await?;
+pub async fn process_statistics(axon_server_handle : AxonServerHandle, elastic_search_url: String, concurrency: ConcurrencyLimits, pause_switch: PauseSwitch) {
+    if let Err(e) = internal_process_statistics(axon_server_handle, &elastic_search_url, concurrency, pause_switch).await {
+        error!("Error while processing statistics: {:?}", e);
+    }
+    debug!("Stopped processing statistics for example application");
+}
+
+async fn internal_process_events(axon_server_handle : AxonServerHandle, elastic_search_url: &str, concurrency: ConcurrencyLimits, pause_switch: PauseSwitch) -> Result<()> {
+    let client = wait_for_elastic_search_at(elastic_search_url).await?;
     debug!("Elastic Search client: {:?}", client);
 
+    let tracking = create_tracking_config("greeting");
     let query_model = ExampleQueryModel {
-        es_client: client,
+        es_client: create_managed_client(client.clone(), managed_client_config(elastic_search_url)),
+        bulk_writer: create_bulk_writer(client, Default::default()),
+        tracking: tracking.clone(),
+        query_updates: axon_server_handle.query_updates.clone(),
     };
+    bootstrap_indices(&query_model).await?;
 
     let mut event_handler_registry: TheHandlerRegistry<ExampleQueryModel,Option<ExampleQueryModel>> = empty_handler_registry();
 
@@ -67,8 +197,121 @@ async fn internal_process_events(axon_server_handle : AxonServerHandle) -> Resul
         &GreetedEvent::decode,
         &(|c, p| Box::pin(handle_event(Box::from(c), p)))
     )?;
+    event_handler_registry.label("GreetedEvent", create_handler_labels("GreetedEvent->ES").with_label("projection", "greeting"))?;
+
+    let descriptors = load_proto_descriptors(DESCRIPTOR_SET)?;
+    create_registry_validation(&descriptors).with_event_handlers(&tracking.processor_name, &event_handler_registry).validate()?;
+
+    let config = EventProcessorConfig {
+        tracking,
+        concurrency,
+        retry: projection_retry_policy(),
+        pause_switch,
+        ..Default::default()
+    };
+    event_processor_with_config(axon_server_handle, query_model, event_handler_registry, config).await.context("Error while handling commands")
+}
+
+async fn internal_process_statistics(axon_server_handle : AxonServerHandle, elastic_search_url: &str, concurrency: ConcurrencyLimits, pause_switch: PauseSwitch) -> Result<()> {
+    let client = wait_for_elastic_search_at(elastic_search_url).await?;
+    debug!("Elastic Search client: {:?}", client);
+
+    let tracking = create_tracking_config("greeting-statistics");
+    let statistics_model = GreetingStatisticsModel {
+        es_client: create_managed_client(client.clone(), managed_client_config(elastic_search_url)),
+        bulk_writer: create_bulk_writer(client, Default::default()),
+        tracking: tracking.clone(),
+        event_timestamp: 0,
+        event_token: 0,
+    };
+    bootstrap_statistics_indices(&statistics_model).await?;
+
+    let mut event_handler_registry: TheHandlerRegistry<GreetingStatisticsModel,Option<GreetingStatisticsModel>> = empty_handler_registry();
+
+    event_handler_registry.insert(
+        "GreetedEvent",
+        &GreetedEvent::decode,
+        &(|c, p| Box::pin(handle_event(Box::from(c), p)))
+    )?;
+    event_handler_registry.label("GreetedEvent", create_handler_labels("GreetedEvent->ES").with_label("projection", "greeting-statistics"))?;
+
+    let descriptors = load_proto_descriptors(DESCRIPTOR_SET)?;
+    create_registry_validation(&descriptors).with_event_handlers(&tracking.processor_name, &event_handler_registry).validate()?;
+
+    let config = EventProcessorConfig {
+        tracking,
+        concurrency,
+        retry: projection_retry_policy(),
+        pause_switch,
+        ..Default::default()
+    };
+    event_processor_with_config(axon_server_handle, statistics_model, event_handler_registry, config).await.context("Error while processing statistics")
+}
+
+// Replaces the client of the projection when Elastic Search restarts.
+fn managed_client_config(elastic_search_url: &str) -> ManagedClientConfig {
+    ManagedClientConfig {
+        url: elastic_search_url.to_string(),
+        ..Default::default()
+    }
+}
+
+// Rides out restarts of Elastic Search and AxonServer, instead of stopping the projection.
+fn projection_retry_policy() -> RetryPolicy {
+    create_retry_policy(5, Duration::from_millis(200))
+        .with_retryable(|e| is_transient_error(e) || is_transient_es_error(e))
+}
+
+async fn bootstrap_tracking_token_index(client: &Elasticsearch, tracking: &TrackingConfig) -> Result<()> {
+    let tracking_token_index = create_index_definition(&tracking.token_index, 1, json!({
+        "properties": {
+            "id": { "type": "keyword" },
+            "token": { "type": "long" },
+        }
+    }));
+    if let IndexStatus::VersionMismatch { .. } = ensure_index(client, &tracking_token_index).await? {
+        recreate_index(client, &tracking_token_index).await?;
+    }
+    Ok(())
+}
+
+async fn bootstrap_statistics_indices(statistics_model: &GreetingStatisticsModel) -> Result<()> {
+    let client = &statistics_model.es_client.client();
+    bootstrap_tracking_token_index(client, &statistics_model.tracking).await?;
+
+    let greeting_counts_index = create_index_definition("greeting-counts", 2, json!({
+        "properties": {
+            "day": { "type": "keyword" },
+            "count": { "type": "long" },
+            "token": { "type": "long" },
+        }
+    }));
+    if let IndexStatus::VersionMismatch { .. } = ensure_index(client, &greeting_counts_index).await? {
+        recreate_index(client, &greeting_counts_index).await?;
+        debug!("Rebuild greeting counts index: replay all events");
+        statistics_model.store_token(-1).await?;
+        statistics_model.bulk_writer.flush().await?;
+    }
+    Ok(())
+}
+
+async fn bootstrap_indices(query_model: &ExampleQueryModel) -> Result<()> {
+    let client = &query_model.es_client.client();
+    bootstrap_tracking_token_index(client, &query_model.tracking).await?;
 
-    event_processor(axon_server_handle, query_model, event_handler_registry).await.context("Error while handling commands")
+    let greetings_index = create_index_definition("greetings", 1, json!({
+        "properties": {
+            "id": { "type": "keyword" },
+            "value": { "type": "text" },
+        }
+    }));
+    if let IndexStatus::VersionMismatch { .. } = ensure_index(client, &greetings_index).await? {
+        recreate_index(client, &greetings_index).await?;
+        debug!("Rebuild greetings index: replay all events");
+        query_model.store_token(-1).await?;
+        query_model.bulk_writer.flush().await?;
+    }
+    Ok(())
 }
 
 async fn handle_event<T: AsyncApplicableTo<P>,P: Clone>(event: Box<T>, projection: P) -> Result<()> {
@@ -82,23 +325,26 @@ impl AsyncApplicableTo<ExampleQueryModel> for GreetedEvent {
 
     async fn apply_to(self: &Self, projection: &mut ExampleQueryModel) -> Result<()> {
         debug!("Apply greeted event to ExampleQueryModel");
-        let es_client = projection.es_client.clone();
         if let Some(Greeting {message}) = self.message.clone() {
             let value = message.clone();
             let mut hasher = Sha256::new();
             Digest::update(&mut hasher,&message);
             let hash: Vec<u8> = hasher.finalize().to_vec();
             let hash = base64::encode(hash);
-            let response = es_client
-                .index(IndexParts::IndexId("greetings", hash.as_str()))
-                .body(json!({
-                    "id": hash,
-                    "value": value,
-                }))
-                .send()
-                .await
-            ;
-            debug!("Elastic Search response: {:?}", response);
+            projection.bulk_writer
+                .upsert(&GreetingDocument {
+                    id: hash,
+                    value,
+                })
+                .await?;
+            let update = SearchResponse {
+                greetings: vec![Greeting { message: message.clone() }],
+            };
+            // Approximates the query string of Elastic Search: a subscriber is updated when its query occurs in the
+            // greeting.
+            projection.query_updates
+                .emit_message("SearchQuery", |query: &SearchQuery| query.query == "*" || message.contains(&query.query), "SearchResponse", &update)
+                .await?;
         }
         Ok(())
     }
@@ -107,3 +353,40 @@ impl AsyncApplicableTo<ExampleQueryModel> for GreetedEvent {
         Box::from(GreetedEvent::clone(self))
     }
 }
+
+
+#[tonic::async_trait]
+impl AsyncApplicableTo<GreetingStatisticsModel> for GreetedEvent {
+
+    async fn apply_to(&self, projection: &mut GreetingStatisticsModel) -> Result<()> {
+        let day = Utc.timestamp_millis_opt(projection.event_timestamp).single()
+            .ok_or(anyhow!("Invalid event timestamp: {:?}", projection.event_timestamp))?
+            .format("%Y-%m-%d")
+            .to_string();
+        debug!("Apply greeted event to GreetingStatisticsModel: {:?}", day);
+        let initial_count = GreetingCountDocument {
+            day: day.clone(),
+            count: 1,
+            token: projection.event_token,
+        };
+        // The token of the last counted event makes counting idempotent: an event that is redelivered after a restart
+        // is not counted again.
+        projection.bulk_writer
+            .scripted_upsert(
+                GreetingCountDocument::index_name(),
+                &initial_count.id(),
+                json!({
+                    "source": "if (ctx._source.token == null || params.token > ctx._source.token) { ctx._source.count += 1; ctx._source.token = params.token } else { ctx.op = 'noop' }",
+                    "lang": "painless",
+                    "params": { "token": projection.event_token },
+                }),
+                serde_json::to_value(&initial_count)?
+            )
+            .await?;
+        Ok(())
+    }
+
+    fn box_clone(&self) -> Box<dyn AsyncApplicableTo<GreetingStatisticsModel>> {
+        Box::from(GreetedEvent::clone(self))
+    }
+}
@@ -0,0 +1,68 @@
+use serde_json::{Value,json};
+
+/// Identifier of the example dashboard in Grafana, so that importing it again replaces the previous version.
+pub const DASHBOARD_UID: &str = "rustic-dendrite";
+
+const PANEL_WIDTH: u32 = 12;
+const PANEL_HEIGHT: u32 = 8;
+
+/// Returns a Grafana dashboard for the metrics of the example application, with a Prometheus data source that is
+/// chosen on import. The panels are laid out two per row, in the order of the workers: commands, events, and queries.
+pub fn example_dashboard() -> Value {
+    let panels = [
+        ("Commands handled", "rate(command_worker_commands_handled[1m])", "ops"),
+        ("Commands shed and quarantined", "rate(command_worker_commands_shed[1m]) or rate(command_worker_commands_quarantined[1m])", "ops"),
+        ("Command mailbox depth", "command_worker_mailbox_depth", "short"),
+        ("Command permit window", "command_worker_permit_window", "short"),
+        ("Command handler latency", "command_worker_handler_latency_ms", "ms"),
+        ("Event processor token", "event_processor_token", "short"),
+        ("Event handler calls", "sum by (name) (rate(event_handler_calls[1m]))", "ops"),
+        ("Event handler latency", "sum by (name) (rate(event_handler_latency_ms[1m])) / sum by (name) (rate(event_handler_calls[1m]))", "ms"),
+        ("Event handler failures", "sum by (group) (rate(event_processor_handler_failures[1m]))", "ops"),
+        ("Event handler timeouts and skipped events", "rate(event_processor_handler_timeouts[1m]) or rate(event_processor_skipped_events[1m])", "ops"),
+        ("Query permit window", "query_processor_permit_window", "short"),
+        ("Query handler latency", "query_processor_handler_latency_ms", "ms"),
+    ];
+    let panels: Vec<Value> = panels.iter().enumerate()
+        .map(|(index, (title, expression, unit))| time_series_panel(index as u32, title, expression, unit))
+        .collect();
+    json!({
+        "uid": DASHBOARD_UID,
+        "title": "Rustic Dendrite",
+        "tags": ["rustic-dendrite", "axon"],
+        "timezone": "browser",
+        "schemaVersion": 27,
+        "refresh": "10s",
+        "time": { "from": "now-1h", "to": "now" },
+        "__inputs": [{
+            "name": "DS_PROMETHEUS",
+            "label": "Prometheus",
+            "type": "datasource",
+            "pluginId": "prometheus",
+        }],
+        "panels": panels,
+    })
+}
+
+fn time_series_panel(index: u32, title: &str, expression: &str, unit: &str) -> Value {
+    json!({
+        "id": index + 1,
+        "type": "timeseries",
+        "title": title,
+        "datasource": "${DS_PROMETHEUS}",
+        "gridPos": {
+            "x": (index % 2) * PANEL_WIDTH,
+            "y": (index / 2) * PANEL_HEIGHT,
+            "w": PANEL_WIDTH,
+            "h": PANEL_HEIGHT,
+        },
+        "fieldConfig": {
+            "defaults": { "unit": unit },
+            "overrides": [],
+        },
+        "targets": [{
+            "refId": "A",
+            "expr": expression,
+        }],
+    })
+}
@@ -0,0 +1,58 @@
+use anyhow::Result;
+use hyper::{Body,Method,Request,Response,Server,StatusCode};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn,service_fn};
+use tracing::{debug,error,info};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use crate::axon_utils::{Metrics,ShutdownSignal};
+
+mod dashboard;
+
+pub use dashboard::{DASHBOARD_UID,example_dashboard};
+
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+const DASHBOARD_PATH: &str = "/dashboards/rustic-dendrite.json";
+
+/// Serves the metrics of the example application on `/metrics` for Prometheus, and the example dashboard on
+/// `/dashboards/rustic-dendrite.json` for import in Grafana, until the shutdown signal is given.
+pub async fn serve_metrics(metrics: Metrics, bind_address: SocketAddr, shutdown_signal: ShutdownSignal) {
+    if let Err(e) = internal_serve_metrics(metrics, bind_address, shutdown_signal).await {
+        error!("Error while serving metrics: {:?}", e);
+    }
+    debug!("Stopped serving metrics for example application");
+}
+
+async fn internal_serve_metrics(metrics: Metrics, bind_address: SocketAddr, shutdown_signal: ShutdownSignal) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_,Infallible>(service_fn(move |request| respond(metrics.clone(), request)))
+        }
+    });
+    info!("Serving metrics: {:?}", bind_address);
+    Server::try_bind(&bind_address)?
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown_signal.wait())
+        .await?;
+    Ok(())
+}
+
+async fn respond(metrics: Metrics, request: Request<Body>) -> Result<Response<Body>,Infallible> {
+    debug!("Metrics request: {:?} {:?}", request.method(), request.uri().path());
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => text_response(StatusCode::OK, PROMETHEUS_CONTENT_TYPE, metrics.snapshot().to_prometheus()),
+        (&Method::GET, DASHBOARD_PATH) => text_response(StatusCode::OK, "application/json", example_dashboard().to_string()),
+        _ => text_response(StatusCode::NOT_FOUND, "text/plain", "Not found\n".to_string()),
+    };
+    Ok(response)
+}
+
+fn text_response(status: StatusCode, content_type: &str, body: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    if let Ok(content_type) = content_type.parse() {
+        response.headers_mut().insert(CONTENT_TYPE, content_type);
+    }
+    response
+}
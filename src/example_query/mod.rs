@@ -1,31 +1,55 @@
-use anyhow::{Context,Result};
-use elasticsearch::{Elasticsearch, SearchParts};
-use log::{debug,error};
+use anyhow::{Context,Result,anyhow};
+use futures_util::{StreamExt,pin_mut};
+use tracing::{debug,error};
 use prost::Message;
-use super::elastic_search_utils::wait_for_elastic_search;
-use crate::axon_utils::{AxonServerHandle, HandlerRegistry, QueryContext, QueryResult, TheHandlerRegistry, empty_handler_registry, query_processor, axon_serialize};
-use crate::grpc_example::{SearchQuery,SearchResponse,Greeting};
+use serde_json::json;
+use std::sync::Arc;
+use super::elastic_search_utils::{ManagedClient,ManagedClientConfig,create_managed_client,create_search_after,search_after_stream,wait_for_elastic_search_at};
+use crate::axon_utils::{AxonServerHandle, HandlerRegistry, LogFilterContext, LogFilterControl, QueryContext, QueryResponseSender, QueryResult, SET_LOG_FILTER, SetLogFilter, TheHandlerRegistry, create_registry_validation, empty_handler_registry, handle_set_log_filter, load_proto_descriptors, query_processor, axon_serialize};
+use crate::grpc_example::{DESCRIPTOR_SET,GreetingCount,GreetingCountsQuery,GreetingCountsResponse,SearchQuery,SearchResponse,Greeting};
 
 #[derive(Clone)]
 struct ExampleQueryContext {
-    es_client: Elasticsearch,
+    es_client: ManagedClient,
+    responses: Option<QueryResponseSender>,
+    log_filter: Option<Arc<dyn LogFilterControl>>,
 }
 
-impl QueryContext for ExampleQueryContext {}
+impl QueryContext for ExampleQueryContext {
+    fn for_query(&self, responses: QueryResponseSender) -> Self {
+        ExampleQueryContext {
+            es_client: self.es_client.clone(),
+            responses: Some(responses),
+            log_filter: self.log_filter.clone(),
+        }
+    }
+}
 
-pub async fn process_queries(axon_server_handle : AxonServerHandle) {
-    if let Err(e) = internal_process_queries(axon_server_handle).await {
+impl LogFilterContext for ExampleQueryContext {
+    fn log_filter_control(&self) -> Option<Arc<dyn LogFilterControl>> {
+        self.log_filter.clone()
+    }
+}
+
+/// Handles the queries of the example. With a log filter, it also handles the `SetLogFilter` admin query.
+pub async fn process_queries(axon_server_handle : AxonServerHandle, elastic_search_url: String, log_filter: Option<Arc<dyn LogFilterControl>>) {
+    if let Err(e) = internal_process_queries(axon_server_handle, &elastic_search_url, log_filter).await {
         error!("Error while handling queries: {:?}", e);
     }
     debug!("Stopped handling commands for example application");
 }
 
-async fn internal_process_queries(axon_server_handle : AxonServerHandle) -> Result<()> {
-    let client = wait_for_elastic_search().await?;
+async fn internal_process_queries(axon_server_handle : AxonServerHandle, elastic_search_url: &str, log_filter: Option<Arc<dyn LogFilterControl>>) -> Result<()> {
+    let client = wait_for_elastic_search_at(elastic_search_url).await?;
     debug!("Elastic Search client: {:?}", client);
 
     let query_context = ExampleQueryContext {
-        es_client: client,
+        es_client: create_managed_client(client, ManagedClientConfig {
+            url: elastic_search_url.to_string(),
+            ..Default::default()
+        }),
+        responses: None,
+        log_filter: log_filter.clone(),
     };
 
     let mut query_handler_registry: TheHandlerRegistry<ExampleQueryContext,QueryResult> = empty_handler_registry();
@@ -36,39 +60,82 @@ async fn internal_process_queries(axon_server_handle : AxonServerHandle) -> Resu
         &(|c, p| Box::pin(handle_search_query(c, p)))
     )?;
 
+    query_handler_registry.insert_with_output(
+        "GreetingCountsQuery",
+        &GreetingCountsQuery::decode,
+        &(|c, p| Box::pin(handle_greeting_counts_query(c, p)))
+    )?;
+
+    if log_filter.is_some() {
+        query_handler_registry.insert_with_output(
+            SET_LOG_FILTER,
+            &SetLogFilter::decode,
+            &(|c, p| Box::pin(handle_set_log_filter(c, p)))
+        )?;
+    }
+
+    let descriptors = load_proto_descriptors(DESCRIPTOR_SET)?.with_message(SET_LOG_FILTER);
+    create_registry_validation(&descriptors).with_query_handlers("example", &query_handler_registry).validate()?;
+
     query_processor(axon_server_handle, query_context, query_handler_registry).await.context("Error while handling queries")
 }
 
 async fn handle_search_query(search_query: SearchQuery, projection: ExampleQueryContext) -> Result<Option<QueryResult>> {
-    let search_response = projection.es_client
-        .search(SearchParts::Index(&["greetings"]))
-        .q(&search_query.query)
-        ._source(&["value"])
-        .send()
-        .await?;
-    let json_value : serde_json::Value = search_response.json().await?;
-    debug!("Search response: {:?}", json_value);
-    let hits = &json_value["hits"]["hits"];
-    debug!("Hits: {:?}", hits);
-    let mut greetings = Vec::new();
-    if let serde_json::Value::Array(hits) = hits {
-        for document in hits {
-            if let serde_json::Value::String(message) = &document["_source"]["value"] {
-                let greeting = Greeting {
-                    message: message.clone(),
-                };
-                greetings.push(greeting);
-            }
+    let responses = projection.responses.ok_or_else(|| anyhow!("Missing query response sender"))?;
+    let mut search = create_search_after(
+        "greetings",
+        json!({ "query_string": { "query": search_query.query } }),
+        json!([{ "id": "asc" }])
+    );
+    search.source = Some(json!(["value"]));
+    let hits = search_after_stream(projection.es_client.client(), search);
+    pin_mut!(hits);
+    while let Some(document) = hits.next().await {
+        let document = document?;
+        debug!("Hit: {:?}", document);
+        if let serde_json::Value::String(message) = &document["_source"]["value"] {
+            let greeting = Greeting {
+                message: message.clone(),
+            };
+            let response = SearchResponse {
+                greetings: vec![greeting],
+            };
+            responses.send_message("SearchResponse", &response).await?;
         }
     }
     let greeting = Greeting {
         message: "Test!".to_string(),
     };
-    greetings.push(greeting);
     let response = SearchResponse {
-        greetings,
+        greetings: vec![greeting],
+    };
+    responses.send_message("SearchResponse", &response).await?;
+    Ok(None)
+}
+
+async fn handle_greeting_counts_query(_query: GreetingCountsQuery, projection: ExampleQueryContext) -> Result<Option<QueryResult>> {
+    let search = create_search_after(
+        "greeting-counts",
+        json!({ "match_all": {} }),
+        json!([{ "day": "asc" }])
+    );
+    let hits = search_after_stream(projection.es_client.client(), search);
+    pin_mut!(hits);
+    let mut counts = Vec::new();
+    while let Some(document) = hits.next().await {
+        let document = document?;
+        debug!("Hit: {:?}", document);
+        if let (Some(day), Some(count)) = (document["_source"]["day"].as_str(), document["_source"]["count"].as_i64()) {
+            counts.push(GreetingCount {
+                day: day.to_string(),
+                count,
+            });
+        }
+    }
+    let response = GreetingCountsResponse {
+        counts,
     };
-    let result = axon_serialize("SearchResponse", &response)?;
+    let result = axon_serialize("GreetingCountsResponse", &response)?;
     let query_result = QueryResult {
         payload: Some(result),
     };
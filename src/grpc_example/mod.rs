@@ -1 +1,4 @@
 tonic::include_proto!("grpc_example"); // The string specified here must match the proto package name
+
+/// Descriptors of the messages in `grpc_example.proto`, generated by the build script.
+pub const DESCRIPTOR_SET: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/grpc_example_descriptor_set.bin"));
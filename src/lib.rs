@@ -5,5 +5,8 @@ pub mod grpc_example;
 pub mod elastic_search_utils;
 pub mod example_api;
 pub mod example_command;
+pub mod example_config;
 pub mod example_event;
+pub mod example_metrics;
 pub mod example_query;
+pub mod object_storage_utils;
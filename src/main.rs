@@ -1,33 +1,72 @@
 use std::error::Error;
-use log::info;
+use tracing::info;
 
 use tonic::transport::Server;
 
-use rustic_dendrite::example_api::init;
+use rustic_dendrite::axon_utils::{ConcurrencyLimits,PauseSwitch,PlatformConfig,StampingPolicy,USER_ID,create_shutdown_signal,load_concurrency_config,log_diagnostics_on_signal,platform_listener,reload_concurrency_on_signal,set_read_only,set_stamping_policy,shutdown_on_signal};
+use rustic_dendrite::example_api::init_with_builder;
 use rustic_dendrite::example_command::handle_commands;
-use rustic_dendrite::example_event::process_events;
+use rustic_dendrite::example_config::{COMMANDS,COMPONENT_NAME,EVENTS,QUERIES,STATISTICS,init_logging,parse_config};
+use rustic_dendrite::example_event::{process_events,process_statistics};
+use rustic_dendrite::example_metrics::serve_metrics;
 use rustic_dendrite::example_query::process_queries;
 use rustic_dendrite::grpc_example::greeter_service_server::GreeterServiceServer;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::init();
+    let config = parse_config()?;
+    let log_filter = init_logging(&config.log_level)?;
     info!("Rustic dendrite API service started");
+    info!("Configuration: {:?}", config);
 
-    let greeter_server = init().await.unwrap();
+    set_read_only(config.read_only);
 
-    tokio::spawn(handle_commands(greeter_server.axon_server_handle.clone()));
+    let shutdown_signal = create_shutdown_signal();
+    tokio::spawn(shutdown_on_signal(shutdown_signal.clone()));
 
-    tokio::spawn(process_events(greeter_server.axon_server_handle.clone()));
+    let greeter_server = init_with_builder(config.connection_builder()).await.unwrap();
+    set_stamping_policy(StampingPolicy::default()
+        .with_service(COMPONENT_NAME, env!("CARGO_PKG_VERSION"))
+        .with_node_id(&greeter_server.axon_server_handle.display_name)
+        .with_command_key(USER_ID));
+    tokio::spawn(log_diagnostics_on_signal(greeter_server.axon_server_handle.clone()));
+    tokio::spawn(serve_metrics(greeter_server.axon_server_handle.metrics.clone(), config.metrics_address, shutdown_signal.clone()));
 
-    tokio::spawn(process_queries(greeter_server.axon_server_handle.clone()));
+    let concurrency = ConcurrencyLimits::default();
+    if let Some(concurrency_file) = &config.concurrency_file {
+        concurrency.apply(&load_concurrency_config(concurrency_file).await?);
+        tokio::spawn(reload_concurrency_on_signal(concurrency.clone(), concurrency_file.clone()));
+    }
+
+    let events_pause_switch = PauseSwitch::default();
+    let statistics_pause_switch = PauseSwitch::default();
+    let platform_config = PlatformConfig::default()
+        .with_pause_switch("greeting", events_pause_switch.clone())
+        .with_pause_switch("greeting-statistics", statistics_pause_switch.clone());
+    tokio::spawn(platform_listener(greeter_server.axon_server_handle.clone(), platform_config));
+
+    if config.is_enabled(COMMANDS) {
+        tokio::spawn(handle_commands(greeter_server.axon_server_handle.clone()));
+    }
+
+    if config.is_enabled(EVENTS) {
+        tokio::spawn(process_events(greeter_server.axon_server_handle.clone(), config.elastic_search_url.clone(), concurrency.clone(), events_pause_switch));
+    }
+
+    if config.is_enabled(STATISTICS) {
+        tokio::spawn(process_statistics(greeter_server.axon_server_handle.clone(), config.elastic_search_url.clone(), concurrency.clone(), statistics_pause_switch));
+    }
+
+    if config.is_enabled(QUERIES) {
+        tokio::spawn(process_queries(greeter_server.axon_server_handle.clone(), config.elastic_search_url.clone(), Some(log_filter).filter(|_| config.admin_log_filter)));
+    }
 
-    let addr = "0.0.0.0:8181".parse()?;
     info!("Starting gRPC server");
     Server::builder()
         .add_service(GreeterServiceServer::new(greeter_server))
-        .serve(addr)
+        .serve_with_shutdown(config.bind_address, shutdown_signal.wait())
         .await?;
+    info!("Stopped gRPC server");
 
     Ok(())
 }
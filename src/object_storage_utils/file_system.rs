@@ -0,0 +1,38 @@
+use anyhow::Result;
+use tracing::debug;
+use std::path::PathBuf;
+use super::{ObjectSink,ObjectSource};
+
+/// Stores (and retrieves) objects as files below a root directory. The key of an object is used as its relative path.
+#[derive(Debug,Clone)]
+pub struct FileSystemSink {
+    pub root: PathBuf,
+}
+
+pub fn create_file_system_sink(root: &str) -> FileSystemSink {
+    FileSystemSink {
+        root: PathBuf::from(root),
+    }
+}
+
+#[tonic::async_trait]
+impl ObjectSink for FileSystemSink {
+    async fn put_object(&self, key: &str, _content_type: &str, body: Vec<u8>) -> Result<String> {
+        let path = self.root.join(key.trim_start_matches('/'));
+        debug!("Put object: {:?}: size: {:?}", path, body.len());
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&path, body).await?;
+        Ok(key.to_string())
+    }
+}
+
+#[tonic::async_trait]
+impl ObjectSource for FileSystemSink {
+    async fn get_object(&self, key: &str) -> Result<Vec<u8>> {
+        let path = self.root.join(key.trim_start_matches('/'));
+        debug!("Get object: {:?}", path);
+        Ok(tokio::fs::read(&path).await?)
+    }
+}
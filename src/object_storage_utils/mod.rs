@@ -0,0 +1,41 @@
+use anyhow::Result;
+use chrono::{DateTime,Utc};
+use std::fmt::Debug;
+
+mod file_system;
+#[cfg(feature = "s3")]
+mod s3;
+
+pub use file_system::{FileSystemSink,create_file_system_sink};
+#[cfg(feature = "s3")]
+pub use s3::{S3Config,S3Sink,ServerSideEncryption,create_s3_config,create_s3_sink};
+
+/// Destination for objects that are exported or archived, e.g., batches of events.
+#[tonic::async_trait]
+pub trait ObjectSink: Debug + Send + Sync {
+    /// Stores the object and returns the key under which it was stored, which differs from the given key when the
+    /// sink adds a prefix.
+    async fn put_object(&self, key: &str, content_type: &str, body: Vec<u8>) -> Result<String>;
+}
+
+/// Retrieves objects by the key that was returned by `put_object`.
+#[tonic::async_trait]
+pub trait ObjectSource: Debug + Send + Sync {
+    async fn get_object(&self, key: &str) -> Result<Vec<u8>>;
+}
+
+/// Object store that can both store and retrieve objects.
+pub trait ObjectStore: ObjectSink + ObjectSource {}
+
+impl<T: ObjectSink + ObjectSource> ObjectStore for T {}
+
+/// Replaces the placeholders `{date}` (`YYYY-MM-DD`), `{year}`, `{month}`, `{day}` and `{hour}` in a key template with
+/// the given time, e.g., `exports/{year}/{month}/{day}` becomes `exports/2020/12/31`.
+pub fn render_key_template(template: &str, time: DateTime<Utc>) -> String {
+    template
+        .replace("{date}", &time.format("%Y-%m-%d").to_string())
+        .replace("{year}", &time.format("%Y").to_string())
+        .replace("{month}", &time.format("%m").to_string())
+        .replace("{day}", &time.format("%d").to_string())
+        .replace("{hour}", &time.format("%H").to_string())
+}
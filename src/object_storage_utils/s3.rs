@@ -0,0 +1,313 @@
+use anyhow::{anyhow,Result};
+use chrono::Utc;
+use tracing::debug;
+use reqwest::{Client,Method,Response};
+use sha2::{Digest,Sha256};
+use std::collections::BTreeMap;
+use super::{ObjectSink,ObjectSource,render_key_template};
+
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// Connection settings for an S3-compatible object store.
+///
+/// With `path_style` the bucket is part of the path (`https://endpoint/bucket/key`), which is what most self-hosted
+/// stores (e.g., MinIO) expect. Otherwise the bucket is part of the host name (`https://bucket.endpoint/key`).
+///
+/// When a `key_prefix_template` is set, it is rendered with `render_key_template` and prepended to every key.
+/// Objects larger than `multipart_threshold` bytes are uploaded in parts of `part_size` bytes (at least 5 MiB).
+#[derive(Clone)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub path_style: bool,
+    pub key_prefix_template: Option<String>,
+    pub server_side_encryption: Option<ServerSideEncryption>,
+    pub multipart_threshold: usize,
+    pub part_size: usize,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum ServerSideEncryption {
+    /// Encryption with keys that are managed by the object store (`AES256`).
+    S3Managed,
+    /// Encryption with a key from the key management service (`aws:kms`). Without a key id the default key is used.
+    Kms { key_id: Option<String> },
+}
+
+pub fn create_s3_config(endpoint: &str, region: &str, bucket: &str, access_key_id: &str, secret_access_key: &str) -> S3Config {
+    S3Config {
+        endpoint: endpoint.to_string(),
+        region: region.to_string(),
+        bucket: bucket.to_string(),
+        access_key_id: access_key_id.to_string(),
+        secret_access_key: secret_access_key.to_string(),
+        path_style: true,
+        key_prefix_template: None,
+        server_side_encryption: None,
+        multipart_threshold: 16 * 1024 * 1024,
+        part_size: 8 * 1024 * 1024,
+    }
+}
+
+impl S3Config {
+    pub fn with_virtual_host_style(mut self) -> Self {
+        self.path_style = false;
+        self
+    }
+
+    pub fn with_key_prefix_template(mut self, template: &str) -> Self {
+        self.key_prefix_template = Some(template.to_string());
+        self
+    }
+
+    pub fn with_server_side_encryption(mut self, server_side_encryption: ServerSideEncryption) -> Self {
+        self.server_side_encryption = Some(server_side_encryption);
+        self
+    }
+
+    pub fn with_multipart(mut self, multipart_threshold: usize, part_size: usize) -> Self {
+        self.multipart_threshold = multipart_threshold;
+        self.part_size = part_size.max(MIN_PART_SIZE);
+        self
+    }
+}
+
+impl std::fmt::Debug for S3Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Config")
+            .field("endpoint", &self.endpoint)
+            .field("region", &self.region)
+            .field("bucket", &self.bucket)
+            .field("access_key_id", &self.access_key_id)
+            .field("path_style", &self.path_style)
+            .field("key_prefix_template", &self.key_prefix_template)
+            .field("server_side_encryption", &self.server_side_encryption)
+            .field("multipart_threshold", &self.multipart_threshold)
+            .field("part_size", &self.part_size)
+            .finish()
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct S3Sink {
+    config: S3Config,
+    client: Client,
+}
+
+pub fn create_s3_sink(config: S3Config) -> S3Sink {
+    S3Sink {
+        config,
+        client: Client::new(),
+    }
+}
+
+#[tonic::async_trait]
+impl ObjectSink for S3Sink {
+    async fn put_object(&self, key: &str, content_type: &str, body: Vec<u8>) -> Result<String> {
+        let key = self.object_key(key);
+        if body.len() > self.config.multipart_threshold {
+            self.put_multipart(&key, content_type, body).await?;
+            return Ok(key);
+        }
+        let mut headers = self.encryption_headers();
+        headers.insert("content-type".to_string(), content_type.to_string());
+        let response = self.send(Method::PUT, &key, &[], headers, body).await?;
+        check_response(response).await?;
+        Ok(key)
+    }
+}
+
+#[tonic::async_trait]
+impl ObjectSource for S3Sink {
+    /// Retrieves an object by its full key, i.e., the key prefix template is not applied.
+    async fn get_object(&self, key: &str) -> Result<Vec<u8>> {
+        let response = check_response(self.send(Method::GET, key, &[], BTreeMap::new(), Vec::new()).await?).await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+}
+
+impl S3Sink {
+    fn object_key(&self, key: &str) -> String {
+        let key = key.trim_start_matches('/');
+        match &self.config.key_prefix_template {
+            Some(template) => {
+                let prefix = render_key_template(template, Utc::now());
+                format!("{}/{}", prefix.trim_end_matches('/'), key)
+            }
+            None => key.to_string(),
+        }
+    }
+
+    fn encryption_headers(&self) -> BTreeMap<String,String> {
+        let mut headers = BTreeMap::new();
+        match &self.config.server_side_encryption {
+            Some(ServerSideEncryption::S3Managed) => {
+                headers.insert("x-amz-server-side-encryption".to_string(), "AES256".to_string());
+            }
+            Some(ServerSideEncryption::Kms { key_id }) => {
+                headers.insert("x-amz-server-side-encryption".to_string(), "aws:kms".to_string());
+                if let Some(key_id) = key_id {
+                    headers.insert("x-amz-server-side-encryption-aws-kms-key-id".to_string(), key_id.clone());
+                }
+            }
+            None => (),
+        }
+        headers
+    }
+
+    async fn put_multipart(&self, key: &str, content_type: &str, body: Vec<u8>) -> Result<()> {
+        let mut headers = self.encryption_headers();
+        headers.insert("content-type".to_string(), content_type.to_string());
+        let response = check_response(self.send(Method::POST, key, &[("uploads", "")], headers, Vec::new()).await?).await?;
+        let upload_id = xml_element(&response.text().await?, "UploadId")
+            .ok_or_else(|| anyhow!("Missing upload id for multipart upload: {:?}", key))?;
+        debug!("Multipart upload: {:?}: {:?}: size: {:?}", key, upload_id, body.len());
+
+        match self.put_parts(key, &upload_id, body).await {
+            Ok(etags) => {
+                let parts: String = etags.iter().enumerate()
+                    .map(|(index, etag)| format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", index + 1, etag))
+                    .collect();
+                let complete = format!("<CompleteMultipartUpload>{}</CompleteMultipartUpload>", parts);
+                let response = self.send(Method::POST, key, &[("uploadId", &upload_id)], BTreeMap::new(), complete.into_bytes()).await?;
+                let response = check_response(response).await?;
+                // The store can report a failure in the body of a successful response.
+                let text = response.text().await?;
+                if text.contains("<Error>") {
+                    return Err(anyhow!("Could not complete multipart upload: {:?}: {}", key, text));
+                }
+                Ok(())
+            }
+            Err(e) => {
+                let abort = self.send(Method::DELETE, key, &[("uploadId", &upload_id)], BTreeMap::new(), Vec::new()).await;
+                debug!("Abort multipart upload: {:?}: {:?}", key, abort.map(|response| response.status()));
+                Err(e)
+            }
+        }
+    }
+
+    async fn put_parts(&self, key: &str, upload_id: &str, body: Vec<u8>) -> Result<Vec<String>> {
+        let mut etags = Vec::new();
+        for (index, part) in body.chunks(self.config.part_size.max(MIN_PART_SIZE)).enumerate() {
+            let part_number = (index + 1).to_string();
+            let query = [("partNumber", part_number.as_str()), ("uploadId", upload_id)];
+            let response = check_response(self.send(Method::PUT, key, &query, BTreeMap::new(), part.to_vec()).await?).await?;
+            let etag = response.headers().get("etag")
+                .and_then(|etag| etag.to_str().ok())
+                .ok_or_else(|| anyhow!("Missing ETag for part: {:?}: {:?}", key, part_number))?;
+            etags.push(etag.to_string());
+        }
+        Ok(etags)
+    }
+
+    /// Sends a request that is signed with AWS Signature Version 4.
+    async fn send(&self, method: Method, key: &str, query: &[(&str, &str)], mut headers: BTreeMap<String,String>, body: Vec<u8>) -> Result<Response> {
+        let endpoint = self.config.endpoint.trim_end_matches('/');
+        let (scheme, host) = match endpoint.find("://") {
+            Some(index) => (&endpoint[..index], &endpoint[index + 3..]),
+            None => ("https", endpoint),
+        };
+        let key = uri_encode(key.trim_start_matches('/'), false);
+        let (host, path) = if self.config.path_style {
+            (host.to_string(), format!("/{}/{}", uri_encode(&self.config.bucket, true), key))
+        } else {
+            (format!("{}.{}", self.config.bucket, host), format!("/{}", key))
+        };
+        let mut query: Vec<(String,String)> = query.iter().map(|(k, v)| (uri_encode(k, true), uri_encode(v, true))).collect();
+        query.sort();
+        let query_string = query.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<String>>().join("&");
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = hex(&Sha256::digest(&body));
+        headers.insert("host".to_string(), host.clone());
+        headers.insert("x-amz-content-sha256".to_string(), payload_hash.clone());
+        headers.insert("x-amz-date".to_string(), amz_date.clone());
+        let authorization = sign(&self.config, method.as_str(), &path, &query_string, &headers, &payload_hash, &amz_date);
+        headers.insert("authorization".to_string(), authorization);
+
+        let url = if query_string.is_empty() {
+            format!("{}://{}{}", scheme, host, path)
+        } else {
+            format!("{}://{}{}?{}", scheme, host, path, query_string)
+        };
+        debug!("S3 request: {:?}: {:?}", method, url);
+        let mut request = self.client.request(method, &url).body(body);
+        for (name, value) in headers.iter().filter(|(name, _)| name.as_str() != "host") {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        Ok(request.send().await?)
+    }
+}
+
+async fn check_response(response: Response) -> Result<Response> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    Err(anyhow!("S3 request failed: {:?}: {}", status, body))
+}
+
+fn sign(config: &S3Config, method: &str, path: &str, query_string: &str, headers: &BTreeMap<String,String>, payload_hash: &str, amz_date: &str) -> String {
+    let signed_headers = headers.keys().cloned().collect::<Vec<String>>().join(";");
+    let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect();
+    let canonical_request = format!("{}\n{}\n{}\n{}\n{}\n{}", method, path, query_string, canonical_headers, signed_headers, payload_hash);
+
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/s3/aws4_request", date, config.region);
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, hex(&Sha256::digest(canonical_request.as_bytes())));
+
+    let date_key = hmac_sha256(format!("AWS4{}", config.secret_access_key).as_bytes(), date.as_bytes());
+    let region_key = hmac_sha256(&date_key, config.region.as_bytes());
+    let service_key = hmac_sha256(&region_key, b"s3");
+    let signing_key = hmac_sha256(&service_key, b"aws4_request");
+    let signature = hex(&hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+
+    format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}", config.access_key_id, scope, signed_headers, signature)
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut key_block = [0u8; 64];
+    if key.len() > key_block.len() {
+        key_block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        key_block[..key.len()].copy_from_slice(key);
+    }
+    let inner_pad: Vec<u8> = key_block.iter().map(|b| b ^ 0x36).collect();
+    let outer_pad: Vec<u8> = key_block.iter().map(|b| b ^ 0x5c).collect();
+    let mut inner = Sha256::new();
+    Digest::update(&mut inner, &inner_pad);
+    Digest::update(&mut inner, data);
+    let mut outer = Sha256::new();
+    Digest::update(&mut outer, &outer_pad);
+    Digest::update(&mut outer, inner.finalize());
+    outer.finalize().to_vec()
+}
+
+fn xml_element(xml: &str, name: &str) -> Option<String> {
+    let start_tag = format!("<{}>", name);
+    let end_tag = format!("</{}>", name);
+    let start = xml.find(&start_tag)? + start_tag.len();
+    let end = xml[start..].find(&end_tag)? + start;
+    Some(xml[start..end].to_string())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut result = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => result.push(byte as char),
+            b'/' if !encode_slash => result.push('/'),
+            _ => result.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    result
+}
@@ -0,0 +1,165 @@
+use anyhow::{anyhow,Result};
+use prost::Message;
+use serde::Deserialize;
+use rustic_dendrite::axon_server::{MetaDataValue,SerializedObject};
+use rustic_dendrite::axon_server::command::Command;
+use rustic_dendrite::axon_server::event::Event;
+use rustic_dendrite::axon_server::meta_data_value::Data;
+use rustic_dendrite::axon_server::query::QueryRequest;
+use rustic_dendrite::axon_utils::{CORRELATION_ID,DEFAULT_MAX_MESSAGE_SIZE,EXPECTED_VERSION,axon_serialize,correlation_id,correlation_meta_data,decode_payload,expected_version,expected_version_meta_data,message_priority,message_routing_key};
+use rustic_dendrite::grpc_example::{GreetCommand,GreetedEvent,SearchQuery};
+
+// Recorded messages of a Java application that uses Axon Framework (see tests/interop/README.md).
+const GREETED_EVENT: &str = include_str!("interop/greeted_event.json");
+const GREET_COMMAND: &str = include_str!("interop/greet_command.json");
+const SEARCH_QUERY: &str = include_str!("interop/search_query.json");
+
+const AGGREGATE_ID: &str = "a3d2c1b0-9f8e-4d7c-8b6a-5f4e3d2c1b0a";
+const TRACE_ID: &str = "9d3e5c7a-1b2f-4a6d-8e0c-3f5a7b9d1e2c";
+
+#[derive(Deserialize)]
+struct Fixture {
+    message: String,
+    data: String,
+}
+
+fn load_fixture<M: Message + Default>(fixture: &str, message_type: &str) -> Result<(M,Vec<u8>)> {
+    let fixture: Fixture = serde_json::from_str(fixture)?;
+    if fixture.message != message_type {
+        return Err(anyhow!("Fixture holds a {:?}, not a {:?}", fixture.message, message_type));
+    }
+    let data = base64::decode(&fixture.data)?;
+    Ok((M::decode(data.as_slice())?, data))
+}
+
+fn meta_data_value<'a>(meta_data: &'a std::collections::HashMap<String,MetaDataValue>, key: &str) -> Result<&'a Data> {
+    meta_data.get(key)
+        .and_then(|value| value.data.as_ref())
+        .ok_or_else(|| anyhow!("Missing meta-data: {:?}", key))
+}
+
+fn encode<M: Message>(message: &M) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    message.encode(&mut buf)?;
+    Ok(buf)
+}
+
+#[test]
+fn decodes_event_of_java_aggregate() -> Result<()> {
+    let (event, _) = load_fixture::<Event>(GREETED_EVENT, "Event")?;
+    assert_eq!(event.message_identifier, "0f8c6a2e-5b4d-4c3a-9e1f-7a6b5c4d3e2f");
+    assert_eq!(event.aggregate_identifier, AGGREGATE_ID);
+    assert_eq!(event.aggregate_sequence_number, 3);
+    assert_eq!(event.aggregate_type, "GreeterAggregate");
+    assert_eq!(event.timestamp, 1606128000123);
+    assert!(!event.snapshot);
+
+    let payload = event.payload.as_ref().ok_or_else(|| anyhow!("Missing payload"))?;
+    assert_eq!(payload.r#type, "GreetedEvent");
+    assert_eq!(payload.revision, "");
+    let greeted: GreetedEvent = decode_payload(payload, Some(DEFAULT_MAX_MESSAGE_SIZE))?;
+    assert_eq!(greeted.message.map(|greeting| greeting.message), Some("Hello from Java".to_string()));
+    Ok(())
+}
+
+#[test]
+fn decodes_java_meta_data_types() -> Result<()> {
+    let (event, _) = load_fixture::<Event>(GREETED_EVENT, "Event")?;
+    let meta_data = &event.meta_data;
+    assert_eq!(meta_data.len(), 6);
+    assert_eq!(meta_data_value(meta_data, "traceId")?, &Data::TextValue(TRACE_ID.to_string()));
+    assert_eq!(meta_data_value(meta_data, CORRELATION_ID)?, &Data::TextValue(TRACE_ID.to_string()));
+    assert_eq!(meta_data_value(meta_data, EXPECTED_VERSION)?, &Data::NumberValue(2));
+    assert_eq!(meta_data_value(meta_data, "replayed")?, &Data::BooleanValue(false));
+    assert_eq!(meta_data_value(meta_data, "confidence")?, &Data::DoubleValue(0.75));
+    match meta_data_value(meta_data, "requestId")? {
+        Data::BytesValue(SerializedObject { r#type, data, .. }) => {
+            assert_eq!(r#type, "java.util.UUID");
+            assert!(String::from_utf8(data.clone())?.starts_with("<java.util.UUID>"));
+        }
+        other => return Err(anyhow!("Unexpected meta-data value: {:?}", other)),
+    }
+    Ok(())
+}
+
+#[test]
+fn reads_correlation_data_of_java_command() -> Result<()> {
+    let (command, _) = load_fixture::<Command>(GREET_COMMAND, "Command")?;
+    assert_eq!(command.name, "GreetCommand");
+    assert_eq!(command.component_name, "greeter-java");
+    assert_eq!(correlation_id(&command), TRACE_ID);
+    assert_eq!(expected_version(&command)?, Some(2));
+    assert_eq!(message_routing_key(&command.processing_instructions), Some(AGGREGATE_ID.to_string()));
+    assert_eq!(message_priority(&command.processing_instructions), 5);
+
+    let payload = command.payload.as_ref().ok_or_else(|| anyhow!("Missing payload"))?;
+    let greet: GreetCommand = decode_payload(payload, Some(DEFAULT_MAX_MESSAGE_SIZE))?;
+    assert_eq!(greet.aggregate_identifier, AGGREGATE_ID);
+    assert_eq!(greet.message.map(|greeting| greeting.message), Some("Hello from Java".to_string()));
+    Ok(())
+}
+
+#[test]
+fn falls_back_to_java_message_identifier() -> Result<()> {
+    let (mut command, _) = load_fixture::<Command>(GREET_COMMAND, "Command")?;
+    command.meta_data.remove(CORRELATION_ID);
+    assert_eq!(correlation_id(&command), "6e5d4c3b-2a19-4f08-8e7d-6c5b4a392817");
+    Ok(())
+}
+
+#[test]
+fn decodes_java_query() -> Result<()> {
+    let (query, _) = load_fixture::<QueryRequest>(SEARCH_QUERY, "QueryRequest")?;
+    assert_eq!(query.query, "SearchQuery");
+    assert_eq!(message_priority(&query.processing_instructions), 0);
+    assert_eq!(message_routing_key(&query.processing_instructions), None);
+
+    let payload = query.payload.as_ref().ok_or_else(|| anyhow!("Missing payload"))?;
+    let search: SearchQuery = decode_payload(payload, Some(DEFAULT_MAX_MESSAGE_SIZE))?;
+    assert_eq!(search.query, "hello");
+
+    let response_type = query.response_type.as_ref().ok_or_else(|| anyhow!("Missing response type"))?;
+    assert_eq!(response_type.r#type, "org.axonframework.messaging.responsetypes.MultipleInstancesResponseType");
+    Ok(())
+}
+
+#[test]
+fn serializes_payloads_like_java() -> Result<()> {
+    let (event, _) = load_fixture::<Event>(GREETED_EVENT, "Event")?;
+    let recorded = event.payload.ok_or_else(|| anyhow!("Missing payload"))?;
+    let greeted: GreetedEvent = decode_payload(&recorded, None)?;
+    assert_eq!(encode(&axon_serialize("GreetedEvent", &greeted)?)?, encode(&recorded)?);
+
+    let (command, _) = load_fixture::<Command>(GREET_COMMAND, "Command")?;
+    let recorded = command.payload.ok_or_else(|| anyhow!("Missing payload"))?;
+    let greet: GreetCommand = decode_payload(&recorded, None)?;
+    assert_eq!(encode(&axon_serialize("GreetCommand", &greet)?)?, encode(&recorded)?);
+    Ok(())
+}
+
+#[test]
+fn encodes_meta_data_like_java() -> Result<()> {
+    let (event, _) = load_fixture::<Event>(GREETED_EVENT, "Event")?;
+    let recorded = event.meta_data.get(EXPECTED_VERSION).ok_or_else(|| anyhow!("Missing expected version"))?;
+    assert_eq!(encode(&expected_version_meta_data(2))?, encode(recorded)?);
+
+    let (command, _) = load_fixture::<Command>(GREET_COMMAND, "Command")?;
+    let recorded = command.meta_data.get(CORRELATION_ID).ok_or_else(|| anyhow!("Missing correlation id"))?;
+    let produced = correlation_meta_data(&command);
+    let produced = produced.get(CORRELATION_ID).ok_or_else(|| anyhow!("Missing correlation id"))?;
+    assert_eq!(encode(produced)?, encode(recorded)?);
+    Ok(())
+}
+
+#[test]
+fn reencodes_java_messages_without_loss() -> Result<()> {
+    // Maps are encoded in an unspecified order, so compare the decoded messages rather than the bytes.
+    let (event, _) = load_fixture::<Event>(GREETED_EVENT, "Event")?;
+    assert_eq!(Event::decode(encode(&event)?.as_slice())?, event);
+    let (command, _) = load_fixture::<Command>(GREET_COMMAND, "Command")?;
+    assert_eq!(Command::decode(encode(&command)?.as_slice())?, command);
+    let (query, data) = load_fixture::<QueryRequest>(SEARCH_QUERY, "QueryRequest")?;
+    assert_eq!(QueryRequest::decode(encode(&query)?.as_slice())?, query);
+    assert_eq!(encode(&query)?.len(), data.len());
+    Ok(())
+}